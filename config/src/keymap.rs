@@ -1,4 +1,5 @@
 use serde::{Serialize, Deserialize};
+use input::Key;
 use qapi_spec::Enum;
 use qapi_qmp::QKeyCode;
 
@@ -94,6 +95,11 @@ pub struct Keymap {
     apple_adb: Option<u16>,
 }
 
+/// The QEMU key named `name`, ie. `sysrq` or `s`
+pub fn qkeycode(name: &str) -> Option<QKeyCode> {
+    QKeyCode::from_name(name)
+}
+
 impl Keymap {
     pub fn qemu_qkeycode(&self) -> Option<QKeyCode> {
         self.qemu_qkeycode.as_ref()
@@ -142,6 +148,57 @@ impl Keymaps {
             .unwrap_or(0)
         ).collect()
     }
+
+    pub fn linux_key(&self, name: &str) -> Option<Key> {
+        self.keymaps.iter().find(|k| k.linux_name.as_deref() == Some(name))
+            .and_then(|k| Key::from_code(k.linux_keycode).ok())
+    }
+
+    /// The key that types `c` on a US layout, and whether shift must be held for it
+    pub fn char_key(&self, c: char) -> Option<(Key, bool)> {
+        let (name, shift) = match c {
+            'a'..='z' => return self.linux_key(&format!("KEY_{}", c.to_ascii_uppercase())).map(|k| (k, false)),
+            'A'..='Z' => return self.linux_key(&format!("KEY_{}", c)).map(|k| (k, true)),
+            '0'..='9' => return self.linux_key(&format!("KEY_{}", c)).map(|k| (k, false)),
+            ' ' => ("KEY_SPACE", false),
+            '\n' => ("KEY_ENTER", false),
+            '\t' => ("KEY_TAB", false),
+            '-' => ("KEY_MINUS", false),
+            '_' => ("KEY_MINUS", true),
+            '=' => ("KEY_EQUAL", false),
+            '+' => ("KEY_EQUAL", true),
+            '[' => ("KEY_LEFTBRACE", false),
+            '{' => ("KEY_LEFTBRACE", true),
+            ']' => ("KEY_RIGHTBRACE", false),
+            '}' => ("KEY_RIGHTBRACE", true),
+            ';' => ("KEY_SEMICOLON", false),
+            ':' => ("KEY_SEMICOLON", true),
+            '\'' => ("KEY_APOSTROPHE", false),
+            '"' => ("KEY_APOSTROPHE", true),
+            '`' => ("KEY_GRAVE", false),
+            '~' => ("KEY_GRAVE", true),
+            '\\' => ("KEY_BACKSLASH", false),
+            '|' => ("KEY_BACKSLASH", true),
+            ',' => ("KEY_COMMA", false),
+            '<' => ("KEY_COMMA", true),
+            '.' => ("KEY_DOT", false),
+            '>' => ("KEY_DOT", true),
+            '/' => ("KEY_SLASH", false),
+            '?' => ("KEY_SLASH", true),
+            '!' => ("KEY_1", true),
+            '@' => ("KEY_2", true),
+            '#' => ("KEY_3", true),
+            '$' => ("KEY_4", true),
+            '%' => ("KEY_5", true),
+            '^' => ("KEY_6", true),
+            '&' => ("KEY_7", true),
+            '*' => ("KEY_8", true),
+            '(' => ("KEY_9", true),
+            ')' => ("KEY_0", true),
+            _ => return None,
+        };
+        self.linux_key(name).map(|k| (k, shift))
+    }
 }
 
 #[test]
@@ -150,3 +207,44 @@ fn keymaps_csv() {
     println!("{:#?}", keymaps);
     println!("{:#?}", keymaps.qkeycode_keycodes());
 }
+
+#[test]
+fn keymaps_char_key() {
+    let keymaps = Keymaps::from_csv();
+    assert_eq!(keymaps.char_key('a'), Some((Key::A, false)));
+    assert_eq!(keymaps.char_key('Q'), Some((Key::Q, true)));
+    assert_eq!(keymaps.char_key('?'), Some((Key::Slash, true)));
+    assert_eq!(keymaps.char_key('\n'), Some((Key::Enter, false)));
+    assert_eq!(keymaps.char_key('é'), None);
+}
+
+/// Every linux keycode in keymaps.csv along with the QEMU codes it's sent as.
+/// Set `SCREENSTUB_BLESS=1` to rewrite keymaps.golden after an intended change.
+#[test]
+fn keymaps_golden() {
+    use std::fmt::Write;
+    use std::{env, fs};
+
+    let keymaps = Keymaps::from_csv();
+    let (qnums, qcodes) = (keymaps.qnum_keycodes(), keymaps.qkeycode_keycodes());
+    let mut table = String::new();
+    for code in 0..qnums.len() {
+        let keymap = match keymaps.keymaps.iter().find(|k| k.linux_keycode as usize == code) {
+            Some(keymap) => keymap,
+            None => continue,
+        };
+        writeln!(table, "{} {} {:#04x} {}",
+            code, keymap.linux_name.as_deref().unwrap_or("-"), qnums[code], qcodes[code].name()
+        ).unwrap();
+    }
+
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/keymaps.golden");
+    if env::var_os("SCREENSTUB_BLESS").is_some() {
+        fs::write(path, &table).unwrap();
+    }
+    let golden = fs::read_to_string(path).unwrap();
+    for (i, (actual, expected)) in table.lines().zip(golden.lines()).enumerate() {
+        assert_eq!(actual, expected, "keymaps.golden line {}", i + 1);
+    }
+    assert_eq!(table.lines().count(), golden.lines().count());
+}
@@ -1,17 +1,28 @@
 extern crate input_linux as input;
 
 use std::collections::HashMap;
+use std::fs::{self, File};
+use std::env;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
+use std::num::NonZeroU32;
 use std::fmt;
+use anyhow::{Error, format_err};
+use serde_yaml::{Mapping, Value};
 use enumflags2::BitFlags;
 use serde::{Serialize, Deserialize};
-use input::{Key, InputEvent, EventRef};
+use input::{Key, SwitchKind, InputEvent, EventRef};
 
 pub mod keymap;
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
+    /// Other config files to merge in first, with later ones taking precedence
+    /// and this file over all of them. Only honoured by `Config::load`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub screens: Vec<ConfigScreen>,
 
@@ -20,11 +31,299 @@ pub struct Config {
 
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub hotkeys: Vec<ConfigHotkey>,
+    /// Events for switches like a laptop lid, seen through evdev grabs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub switch_events: Vec<ConfigSwitchEvent>,
+    /// Keys and buttons to swap around on their way to the guest
     #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     pub key_remap: HashMap<Key, Key>,
+    /// Remapping applied before hotkeys are matched, separately from `key_remap`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub host_key_remap: HashMap<Key, Key>,
+    /// Scroll direction and speed in the guest, for evdev and window input alike
+    #[serde(default, skip_serializing_if = "ConfigWheel::is_default")]
+    pub wheel: ConfigWheel,
+    /// Wheel adjustments before hotkeys are matched, separately from `wheel`
+    #[serde(default, skip_serializing_if = "ConfigWheel::is_default")]
+    pub host_wheel: ConfigWheel,
+    /// Hotkey modifiers released this recently still count as held, for sticky keys
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "humantime_serde")]
+    pub sticky_keys: Option<Duration>,
 
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub exit_events: Vec<ConfigEvent>,
+    #[serde(default)]
+    pub exit: ConfigExit,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub quirks: Vec<ConfigMonitorQuirks>,
+
+    /// Number of recent events and state changes to remember
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_size: Option<usize>,
+
+    /// Shows a message inside the guest after switching to or from it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest_notify: Option<ConfigGuestNotify>,
+
+    /// Let the host screensaver blank or lock while the guest is shown,
+    /// which otherwise gets held off because no host input arrives
+    #[serde(default)]
+    pub allow_host_idle: bool,
+
+    /// Pauses the host compositor while the guest is shown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compositor: Option<ConfigCompositor>,
+
+    /// Running the X window under a Wayland compositor
+    #[serde(default)]
+    pub xwayland: ConfigXwayland,
+
+    /// Lets the guest ask for the host back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest_request: Option<ConfigGuestRequest>,
+
+    /// Unix socket to accept runtime commands on
+    #[serde(default, deserialize_with = "expand_path_opt", skip_serializing_if = "Option::is_none")]
+    pub control_socket: Option<String>,
+
+    /// Session D-Bus name to serve state and control on, such as `org.arcnmx.screenstub`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dbus_name: Option<String>,
+
+    /// What to do with input events that none of the routes handle
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub unknown_events: HashMap<ConfigInputEvent, ConfigUnknownEvent>,
+
+    /// Audible confirmation of grabs and switches
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sounds: Option<ConfigSounds>,
+
+    /// Named sets of overrides, picked with `--profile` or the `profile` event
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub profiles: HashMap<String, ConfigProfile>,
+
+    /// Buffer sizes between the tasks that input passes through
+    #[serde(default)]
+    pub channels: ConfigChannels,
+
+    /// Priority of the threads that handle input, against a loaded host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scheduling: Option<ConfigScheduling>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigScheduling {
+    /// SCHED_FIFO priority from 1 to 99, which needs CAP_SYS_NICE or an RLIMIT_RTPRIO
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub realtime_priority: Option<i32>,
+    /// Niceness for when realtime scheduling isn't configured or allowed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nice: Option<i32>,
+    /// Keeps screenstub out of swap with mlockall
+    #[serde(default)]
+    pub lock_memory: bool,
+}
+
+/// Larger buffers absorb bursts from high polling rate devices or a guest
+/// that stalls briefly, at the cost of input arriving late instead of being
+/// held back at the source.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigChannels {
+    /// Host input waiting for hotkeys and remapping
+    #[serde(default = "ConfigChannels::default_buffer")]
+    pub input: usize,
+    /// Input waiting on each guest route
+    #[serde(default = "ConfigChannels::default_buffer")]
+    pub route: usize,
+    /// Events from hotkeys waiting to run
+    #[serde(default = "ConfigChannels::default_buffer")]
+    pub user: usize,
+    /// Input from the window waiting to be forwarded
+    #[serde(default = "ConfigChannels::default_window")]
+    pub window: usize,
+    /// Grab and cursor requests waiting on the window
+    #[serde(default = "ConfigChannels::default_buffer")]
+    pub window_requests: usize,
+}
+
+impl ConfigChannels {
+    fn default_buffer() -> usize {
+        0x08
+    }
+
+    fn default_window() -> usize {
+        0x20
+    }
+}
+
+impl Default for ConfigChannels {
+    fn default() -> Self {
+        Self {
+            input: Self::default_buffer(),
+            route: Self::default_buffer(),
+            user: Self::default_buffer(),
+            window: Self::default_window(),
+            window_requests: Self::default_buffer(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigSounds {
+    /// Command line that plays a sound file, with `{}` replaced by its path
+    #[serde(default = "ConfigSounds::default_player")]
+    pub player: Vec<String>,
+    #[serde(default, deserialize_with = "expand_path_opt", skip_serializing_if = "Option::is_none")]
+    pub grab: Option<String>,
+    #[serde(default, deserialize_with = "expand_path_opt", skip_serializing_if = "Option::is_none")]
+    pub ungrab: Option<String>,
+    #[serde(default, deserialize_with = "expand_path_opt", skip_serializing_if = "Option::is_none")]
+    pub show_guest: Option<String>,
+    #[serde(default, deserialize_with = "expand_path_opt", skip_serializing_if = "Option::is_none")]
+    pub show_host: Option<String>,
+}
+
+impl ConfigSounds {
+    fn default_player() -> Vec<String> {
+        vec!["paplay".into(), "{}".into()]
+    }
+}
+
+impl Default for ConfigSounds {
+    fn default() -> Self {
+        Self {
+            player: Self::default_player(),
+            grab: None,
+            ungrab: None,
+            show_guest: None,
+            show_host: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigProfile {
+    /// Merged over the rest of the config, the same way as an included file
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub config: Value,
+    /// Run after switching to this profile while running, e.g. to grab again
+    /// with different settings
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<ConfigEvent>,
+}
+
+impl Config {
+    /// Reads a YAML config file, or TOML if it has a `.toml` extension, along
+    /// with any files it includes
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        Self::load_profile(path, None)
+    }
+
+    /// Loads the config with one of its `profiles` applied
+    pub fn load_profile(path: &Path, profile: Option<&str>) -> Result<Self, Error> {
+        let mut value = Self::load_value(path, &mut Vec::new())?;
+        if let Some(name) = profile {
+            let overrides = value.get("profiles").and_then(|profiles| profiles.get(name))
+                .ok_or_else(|| format_err!("unknown profile {}", name))?
+                .get("config").cloned();
+            match overrides {
+                Some(overrides) if !overrides.is_null() => merge_value(&mut value, overrides),
+                _ => (),
+            }
+        }
+        Ok(serde_yaml::from_value(value)?)
+    }
+
+    pub fn is_toml(path: &Path) -> bool {
+        path.extension().map(|ext| ext == "toml").unwrap_or(false)
+    }
+
+    fn load_value(path: &Path, including: &mut Vec<PathBuf>) -> Result<Value, Error> {
+        let canonical = fs::canonicalize(path)
+            .map_err(|e| format_err!("{}: {}", path.display(), e))?;
+        if including.contains(&canonical) {
+            return Err(format_err!("{} includes itself", path.display()))
+        }
+
+        let mut value = if Self::is_toml(path) {
+            serde_yaml::to_value(toml::from_str::<toml::Value>(&fs::read_to_string(path)?)?)?
+        } else {
+            serde_yaml::from_reader(File::open(path)?)?
+        };
+        let include: Vec<String> = match value {
+            Value::Mapping(ref mut config) => match config.remove(&"include".into()) {
+                Some(include) => serde_yaml::from_value(include)?,
+                None => Vec::new(),
+            },
+            _ => Vec::new(),
+        };
+
+        // includes are relative to the file that names them
+        let dir = path.parent().unwrap_or(Path::new(""));
+        including.push(canonical);
+        let mut merged = Value::Mapping(Mapping::new());
+        for include in include {
+            merge_value(&mut merged, Self::load_value(&dir.join(include), including)?);
+        }
+        including.pop();
+        merge_value(&mut merged, value);
+
+        Ok(merged)
+    }
+}
+
+/// Mappings are merged key by key, anything else in `over` replaces `base`
+fn merge_value(base: &mut Value, over: Value) {
+    match (base, over) {
+        (Value::Mapping(base), Value::Mapping(over)) => for (key, value) in over {
+            match base.get_mut(&key) {
+                Some(base) => merge_value(base, value),
+                None => {
+                    base.insert(key, value);
+                },
+            }
+        },
+        (base, over) => *base = over,
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigExit {
+    /// Longest that any one exit event may run
+    #[serde(default = "ConfigExit::default_event_timeout", with = "humantime_serde")]
+    pub event_timeout: Duration,
+    /// Quit anyway once all exit events have been running this long
+    #[serde(default = "ConfigExit::default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Non-critical exit events that run concurrently alongside `exit_events`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parallel_events: Vec<ConfigEvent>,
+}
+
+impl ConfigExit {
+    fn default_event_timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(15)
+    }
+}
+
+impl Default for ConfigExit {
+    fn default() -> Self {
+        ConfigExit {
+            event_timeout: Self::default_event_timeout(),
+            timeout: Self::default_timeout(),
+            parallel_events: Default::default(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -36,6 +335,12 @@ pub struct ConfigScreen {
     pub guest_source: ConfigSource,
     #[serde(default)]
     pub host_source: ConfigSource,
+    /// Named inputs that `cycle_source` steps through, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty", with = "ordered_map")]
+    pub sources: Vec<(String, ConfigSource)>,
+    /// Other monitors switched along with `monitor`, using the same `ddc` methods
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub monitors: Vec<ConfigScreenMonitor>,
 
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub ddc: Option<ConfigDdc>,
@@ -44,18 +349,263 @@ pub struct ConfigScreen {
     pub x_instance: Option<String>,
 }
 
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigScreenMonitor {
+    #[serde(default)]
+    pub monitor: ConfigMonitor,
+    #[serde(default)]
+    pub guest_source: ConfigSource,
+    #[serde(default)]
+    pub host_source: ConfigSource,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigGuestNotify {
+    /// A built-in guest command line, see `exec_template`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// The guest command line to run, with `{message}` replaced
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exec: Vec<String>,
+    #[serde(default = "ConfigGuestNotify::default_show_guest", skip_serializing_if = "Option::is_none")]
+    pub show_guest: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub show_host: Option<String>,
+}
+
+impl ConfigGuestNotify {
+    /// Built-in guest command lines for showing a message, per guest OS.
+    ///
+    /// The linux one needs the guest agent to be able to reach the desktop session.
+    pub fn exec_template(name: &str) -> Option<&'static [&'static str]> {
+        Some(match name {
+            "windows" => &["msg.exe", "*", "/time:3", "{message}"],
+            "linux" => &["notify-send", "--expire-time=3000", "screenstub", "{message}"],
+            _ => return None,
+        })
+    }
+
+    fn default_show_guest() -> Option<String> {
+        Some("Keyboard and mouse are now attached to this machine".into())
+    }
+
+    /// The guest command line that shows `message`
+    pub fn command(&self, message: &str) -> Option<Vec<String>> {
+        let args: Vec<&str> = match self.template {
+            Some(ref template) => Self::exec_template(template)?.to_vec(),
+            None if self.exec.is_empty() => return None,
+            None => self.exec.iter().map(|s| &s[..]).collect(),
+        };
+        Some(args.into_iter().map(|arg| arg.replace("{message}", message)).collect())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigGuestRequest {
+    /// Unix socket of a QEMU `-chardev socket,server=on` behind a guest
+    /// virtio-serial port, where each line written is a request
+    #[serde(default, deserialize_with = "expand_path_opt", skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    /// A file that the guest creates (e.g. in a shared folder), removed once it is seen
+    #[serde(default, deserialize_with = "expand_path_opt", skip_serializing_if = "Option::is_none")]
+    pub marker: Option<String>,
+    /// How often to look for `marker`
+    #[serde(default = "ConfigGuestRequest::default_poll_interval", with = "humantime_serde")]
+    pub poll_interval: Duration,
+    /// Requests this soon after the last one are ignored
+    #[serde(default = "ConfigGuestRequest::default_min_interval", with = "humantime_serde")]
+    pub min_interval: Duration,
+    /// Run for each request
+    #[serde(default = "ConfigGuestRequest::default_events")]
+    pub events: Vec<ConfigEvent>,
+}
+
+impl ConfigGuestRequest {
+    fn default_poll_interval() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn default_min_interval() -> Duration {
+        Duration::from_secs(2)
+    }
+
+    fn default_events() -> Vec<ConfigEvent> {
+        vec![
+            ConfigEvent::Ungrab(ConfigGrabMode::X),
+            ConfigEvent::Ungrab(ConfigGrabMode::Evdev),
+            ConfigEvent::ShowHost,
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigXwayland {
+    /// Forces the compatibility mode on or off, otherwise it's used whenever Xwayland is detected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compat: Option<bool>,
+    /// Used in place of full X grabs in compatibility mode, since Xwayland
+    /// can't stop the compositor from seeing input. Usually an evdev grab.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grab: Option<ConfigGrab>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigCompositor {
+    /// A built-in pair of command lines, see `exec_template`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// Run when switching to the guest, with `{display}` replaced
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pause: Vec<String>,
+    /// Run when switching back to the host
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub resume: Vec<String>,
+}
+
+impl ConfigCompositor {
+    /// Built-in pause and resume command lines per compositor.
+    ///
+    /// picom needs `--dbus`, and unredirecting the screen stops it from compositing at all.
+    pub fn exec_template(name: &str) -> Option<[&'static [&'static str]; 2]> {
+        Some(match name {
+            "picom" => [
+                &["dbus-send", "--session", "--print-reply", "--dest=com.github.chjj.compton.{display}", "/", "com.github.chjj.compton.opts_set", "string:redirected_force", "uint32:0"],
+                &["dbus-send", "--session", "--print-reply", "--dest=com.github.chjj.compton.{display}", "/", "com.github.chjj.compton.opts_set", "string:redirected_force", "uint32:2"],
+            ],
+            _ => return None,
+        })
+    }
+
+    /// The command line for pausing or resuming, or an empty one if there's nothing to run
+    pub fn command(&self, pause: bool, display: &str) -> Option<Vec<String>> {
+        let args: Vec<&str> = match self.template {
+            Some(ref template) => Self::exec_template(template)?[if pause { 0 } else { 1 }].to_vec(),
+            None if pause => self.pause.iter().map(|s| &s[..]).collect(),
+            None => self.resume.iter().map(|s| &s[..]).collect(),
+        };
+        Some(args.into_iter().map(|arg| arg.replace("{display}", display)).collect())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigVm {
+    pub name: String,
+    #[serde(default, deserialize_with = "expand_path_opt", skip_serializing_if = "Option::is_none")]
+    pub qmp_socket: Option<String>,
+    #[serde(default, deserialize_with = "expand_path_opt", skip_serializing_if = "Option::is_none")]
+    pub ga_socket: Option<String>,
+    /// The monitor input this VM is on, if not the screen's `guest_source`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest_source: Option<ConfigSource>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigGuestReady {
+    /// A guest command line that must exit successfully, instead of just pinging the agent
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exec: Vec<String>,
+    /// Time between checks, and how long each one gets to answer
+    #[serde(default = "ConfigGuestReady::default_interval", with = "humantime_serde")]
+    pub interval: Duration,
+    /// Give up on showing the guest after this long
+    #[serde(default = "ConfigGuestReady::default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl ConfigGuestReady {
+    fn default_interval() -> Duration {
+        Duration::from_secs(2)
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(120)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigQemuLaunch {
+    /// Starts the VM, either daemonizing or running for as long as the VM does
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exec: Vec<String>,
+    /// A systemd unit to start and stop instead of `exec`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub systemd_unit: Option<String>,
+    /// Use the user's service manager for `systemd_unit`
+    #[serde(default)]
+    pub user: bool,
+    /// How long to wait for the QMP socket to come up
+    #[serde(default = "ConfigQemuLaunch::default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl ConfigQemuLaunch {
+    fn default_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn systemctl(&self, verb: &str, unit: &str) -> Vec<String> {
+        let mut args = vec!["systemctl".to_owned()];
+        if self.user {
+            args.push("--user".into());
+        }
+        args.push(verb.into());
+        args.push(unit.into());
+        args
+    }
+
+    /// The command line that boots the VM
+    pub fn start_command(&self) -> Option<Vec<String>> {
+        match self.systemd_unit {
+            Some(ref unit) => Some(self.systemctl("start", unit)),
+            None if self.exec.is_empty() => None,
+            None => Some(self.exec.clone()),
+        }
+    }
+
+    /// The command line that stops the VM, if it isn't left to the guest over QMP
+    pub fn stop_command(&self) -> Option<Vec<String>> {
+        self.systemd_unit.as_ref().map(|unit| self.systemctl("stop", unit))
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum ConfigDdcMethod {
     Ddc,
     Libddcutil,
     Ddcutil,
+    Ddcci,
     Exec(Vec<String>),
+    ExecTemplate(String),
     GuestExec(Vec<String>),
     GuestWait,
+    /// Blanks the host display while the guest is shown, for monitors
+    /// without a spare input
+    Dpms,
 }
 
 impl ConfigDdcMethod {
+    /// Built-in command lines for common host DDC/CI tools, usable via `exec_template`.
+    ///
+    /// `{bus}` is replaced with the monitor's i2c bus number, and `{display}`
+    /// with ddcset's display filter arguments.
+    pub fn exec_template(name: &str) -> Option<&'static [&'static str]> {
+        Some(match name {
+            "ddcutil" => &["ddcutil", "--bus", "{bus}", "setvcp", "0x60", "{}"],
+            "ddccontrol" => &["ddccontrol", "-r", "0x60", "-w", "{}", "dev:/dev/i2c-{bus}"],
+            "ddcset" => &["ddcset", "{display}", "setvcp", "60", "{}"],
+            _ => return None,
+        })
+    }
+
     #[cfg(all(not(feature = "with-ddc"), feature = "with-ddcutil"))]
     fn default_host() -> Vec<Self> {
         vec![ConfigDdcMethod::Libddcutil]
@@ -79,37 +629,151 @@ impl ConfigDdcMethod {
 impl Default for ConfigDdc {
     fn default() -> Self {
         Self {
+            mode: Default::default(),
+            grab: None,
             host: ConfigDdcMethod::default_host(),
             guest: ConfigDdcMethod::default_guest(),
             minimal_delay: Self::default_delay(),
+            timeout: Self::default_timeout(),
+            retries: Self::default_retries(),
+            retry_delay: Self::default_retry_delay(),
+            verify: false,
         }
     }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
+#[serde(deny_unknown_fields, remote = "ConfigDdc")]
 pub struct ConfigDdc {
+    #[serde(default, skip_serializing_if = "ConfigDdcMode::is_switch")]
+    pub mode: ConfigDdcMode,
+    /// Taken when showing the guest and released when showing the host with `mode: none`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grab: Option<ConfigGrab>,
     #[serde(default = "ConfigDdcMethod::default_host")]
     pub host: Vec<ConfigDdcMethod>,
     #[serde(default = "ConfigDdcMethod::default_guest")]
     pub guest: Vec<ConfigDdcMethod>,
     #[serde(default = "ConfigDdc::default_delay", with = "humantime_serde")]
     pub minimal_delay: Duration,
+    #[serde(default = "ConfigDdc::default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Further attempts at a DDC switch that failed
+    #[serde(default = "ConfigDdc::default_retries")]
+    pub retries: u32,
+    /// Wait before the first retry, doubling for each one after
+    #[serde(default = "ConfigDdc::default_retry_delay", with = "humantime_serde")]
+    pub retry_delay: Duration,
+    /// Read the source back after every DDC switch, retrying if it didn't change
+    #[serde(default)]
+    pub verify: bool,
+}
+
+impl<'de> Deserialize<'de> for ConfigDdc {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "lowercase")]
+        enum ConfigDdcDeserializerPlain {
+            None,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum ConfigDdcDeserializer {
+            Struct(
+                #[serde(deserialize_with = "ConfigDdc::deserialize")]
+                ConfigDdc
+            ),
+            Plain(ConfigDdcDeserializerPlain),
+        }
+
+        ConfigDdcDeserializer::deserialize(deserializer)
+            .map(|v| match v {
+                ConfigDdcDeserializer::Struct(v) => v,
+                ConfigDdcDeserializer::Plain(ConfigDdcDeserializerPlain::None) => ConfigDdc::none(),
+            })
+    }
+}
+
+impl Serialize for ConfigDdc {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ConfigDdc::serialize(self, serializer)
+    }
 }
 
 impl ConfigDdc {
+    /// Input routing only, with no monitor to switch
+    pub fn none() -> Self {
+        Self {
+            mode: ConfigDdcMode::None,
+            host: Vec::new(),
+            guest: Vec::new(),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.mode == ConfigDdcMode::None
+    }
+
+    /// The grab that stands in for switching when there is no monitor
+    pub fn show_grab(&self) -> Option<ConfigGrab> {
+        match self.mode {
+            ConfigDdcMode::None => Some(self.grab.clone().unwrap_or_default()),
+            ConfigDdcMode::Switch => None,
+        }
+    }
+
     fn default_delay() -> Duration {
         Duration::from_millis(100)
     }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn default_retries() -> u32 {
+        2
+    }
+
+    fn default_retry_delay() -> Duration {
+        Duration::from_millis(500)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigDdcMode {
+    Switch,
+    /// Show events only grab and ungrab input
+    None,
+}
+
+impl ConfigDdcMode {
+    fn is_switch(&self) -> bool {
+        *self == ConfigDdcMode::Switch
+    }
+}
+
+impl Default for ConfigDdcMode {
+    fn default() -> Self {
+        ConfigDdcMode::Switch
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct ConfigQemu {
+    /// VM name used to find sockets that aren't configured, from a running QEMU's `-name`
     #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "expand_path_opt", skip_serializing_if = "Option::is_none")]
     pub ga_socket: Option<String>,
-    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(default, deserialize_with = "expand_path_opt", skip_serializing_if = "Option::is_none")]
     pub qmp_socket: Option<String>,
+    /// Where `routing: spice` connects to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spice: Option<ConfigSpice>,
 
     #[serde(default)]
     pub driver: Option<ConfigQemuDriver>,
@@ -122,23 +786,96 @@ pub struct ConfigQemu {
 
     #[serde(default = "ConfigQemuRouting::qmp")]
     pub routing: ConfigQemuRouting,
+
+    #[serde(default)]
+    pub uinput: ConfigUInput,
+
+    /// Check that each input route reaches the VM before starting
+    #[serde(default)]
+    pub self_test: bool,
+
+    /// Wake a suspended guest when a key is pressed while it is being shown
+    #[serde(default = "ConfigQemu::default_wake_on_input")]
+    pub wake_on_input: bool,
+
+    /// Pass MSC_SCAN events through to the guest keyboard, unsupported by QMP routing
+    #[serde(default)]
+    pub forward_scancodes: bool,
+
+    /// Stop forwarding input while the host is shown, keeping the guest devices around
+    #[serde(default)]
+    pub pause_on_host: bool,
+
+    /// Stop QMP routing after this many input errors in a row, until the guest resumes or resets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub qmp_error_pause: Option<u32>,
+
+    /// Hold off `show_guest` until the guest answers, rather than switching to a booting VM
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest_ready: Option<ConfigGuestReady>,
+
+    /// Range of the absolute pointer axes, shared by the window and the guest tablet
+    #[serde(default)]
+    pub absolute: ConfigAbsolute,
+
+    /// Scaling of fractional relative motion, like from a touchpad
+    #[serde(default)]
+    pub relative: ConfigRelative,
+
+    /// How `start_vm` boots the guest when it isn't running
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub launch: Option<ConfigQemuLaunch>,
+
+    /// Other VMs sharing this screen, picked with `switch_vm`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vms: Vec<ConfigVm>,
 }
 
 impl Default for ConfigQemu {
     fn default() -> Self {
         ConfigQemu {
+            name: Default::default(),
             ga_socket: Default::default(),
             qmp_socket: Default::default(),
+            spice: Default::default(),
             driver: Default::default(),
             keyboard_driver: Default::default(),
             relative_driver: Default::default(),
             absolute_driver: Default::default(),
             routing: ConfigQemuRouting::Qmp,
+            uinput: Default::default(),
+            self_test: false,
+            wake_on_input: Self::default_wake_on_input(),
+            forward_scancodes: false,
+            pause_on_host: false,
+            qmp_error_pause: None,
+            guest_ready: None,
+            absolute: Default::default(),
+            relative: Default::default(),
+            launch: Default::default(),
+            vms: Default::default(),
         }
     }
 }
 
 impl ConfigQemu {
+    fn default_wake_on_input() -> bool {
+        true
+    }
+
+    /// Every VM that can be switched to, starting with the one given by the
+    /// top level sockets if there are any
+    pub fn vms(&self) -> Vec<ConfigVm> {
+        let main = ConfigVm {
+            name: self.name.clone().unwrap_or_else(|| "default".into()),
+            qmp_socket: self.qmp_socket.clone(),
+            ga_socket: self.ga_socket.clone(),
+            guest_source: None,
+        };
+        let main = Some(main).filter(|vm| vm.qmp_socket.is_some() || vm.ga_socket.is_some());
+        main.into_iter().chain(self.vms.iter().cloned()).collect()
+    }
+
     pub fn keyboard_driver(&self) -> &ConfigQemuDriver {
         self.keyboard_driver
             .as_ref()
@@ -161,6 +898,123 @@ impl ConfigQemu {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigAbsolute {
+    #[serde(default = "ConfigAbsolute::default_maximum")]
+    pub maximum: i32,
+    /// Units per millimeter reported by the uinput tablet
+    #[serde(default = "ConfigAbsolute::default_resolution")]
+    pub resolution: i32,
+}
+
+impl ConfigAbsolute {
+    /// The range QEMU uses for its own absolute input
+    pub const QEMU_MAXIMUM: i32 = 0x7fff;
+
+    fn default_maximum() -> i32 {
+        Self::QEMU_MAXIMUM
+    }
+
+    fn default_resolution() -> i32 {
+        1
+    }
+
+    /// Maps `value` from this range onto `0..=maximum`, passing it through untouched if the ranges match
+    pub fn rescale(&self, value: i32, maximum: i32) -> i32 {
+        if self.maximum == maximum || self.maximum <= 0 {
+            return value
+        }
+        let value = value.max(0).min(self.maximum) as i64;
+        (value * maximum as i64 / self.maximum as i64) as i32
+    }
+}
+
+impl Default for ConfigAbsolute {
+    fn default() -> Self {
+        ConfigAbsolute {
+            maximum: Self::default_maximum(),
+            resolution: Self::default_resolution(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigRelative {
+    /// Host pixels of motion per count sent to the guest
+    #[serde(default = "ConfigRelative::default_resolution")]
+    pub resolution: NonZeroU32,
+}
+
+impl ConfigRelative {
+    fn default_resolution() -> NonZeroU32 {
+        NonZeroU32::new(1).unwrap()
+    }
+}
+
+impl Default for ConfigRelative {
+    fn default() -> Self {
+        ConfigRelative {
+            resolution: Self::default_resolution(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigSpice {
+    /// A unix socket path or `host:port`, the server must have ticketing disabled
+    pub address: String,
+    /// Guest resolution that absolute input is scaled to, which only works with the SPICE agent running
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_size: Option<(u32, u32)>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigUInput {
+    /// Events to buffer for a stalled device before blocking input
+    #[serde(default = "ConfigUInput::default_high_water")]
+    pub high_water: usize,
+    #[serde(default = "ConfigUInput::default_flush_interval", with = "humantime_serde")]
+    pub flush_interval: Duration,
+    /// How long a device may refuse input before it is recreated
+    #[serde(default = "ConfigUInput::default_stall_timeout", with = "humantime_serde")]
+    pub stall_timeout: Duration,
+    #[serde(default = "ConfigUInput::default_recover_attempts")]
+    pub recover_attempts: usize,
+}
+
+impl ConfigUInput {
+    fn default_high_water() -> usize {
+        8
+    }
+
+    fn default_flush_interval() -> Duration {
+        Duration::from_millis(50)
+    }
+
+    fn default_stall_timeout() -> Duration {
+        Duration::from_secs(2)
+    }
+
+    fn default_recover_attempts() -> usize {
+        3
+    }
+}
+
+impl Default for ConfigUInput {
+    fn default() -> Self {
+        ConfigUInput {
+            high_water: Self::default_high_water(),
+            flush_interval: Self::default_flush_interval(),
+            stall_timeout: Self::default_stall_timeout(),
+            recover_attempts: Self::default_recover_attempts(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(deny_unknown_fields, rename_all = "lowercase", remote = "ConfigQemuDriver")]
 pub enum ConfigQemuDriver {
@@ -234,6 +1088,16 @@ pub enum ConfigQemuRouting {
 
 impl ConfigQemuRouting {
     fn qmp() -> Self { ConfigQemuRouting::Qmp }
+
+    /// The name used in config, which input route backends are registered under
+    pub fn key(&self) -> &'static str {
+        match *self {
+            ConfigQemuRouting::InputLinux => "input-linux",
+            ConfigQemuRouting::VirtioHost => "virtio-host",
+            ConfigQemuRouting::Spice => "spice",
+            ConfigQemuRouting::Qmp => "qmp",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -249,6 +1113,50 @@ pub struct ConfigHotkey {
     pub on_release: bool,
     #[serde(default)]
     pub global: bool,
+    /// Keys to press one after another once the hotkey itself is pressed,
+    /// with the events only running after the last of them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub then: Vec<Key>,
+    /// Time allowed before each key of `then`
+    #[serde(default = "ConfigHotkey::default_within", with = "humantime_serde")]
+    pub within: Duration,
+    /// Only fire when the trigger is released after being held this long
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "humantime_serde")]
+    pub hold: Option<Duration>,
+    /// Only fire when the trigger is pressed twice within this long
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "humantime_serde")]
+    pub double_tap: Option<Duration>,
+    /// Don't fire while any keys besides the triggers and modifiers are held
+    #[serde(default)]
+    pub exact: bool,
+    /// Only fire in this state, so the same keys can do something else otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when: Option<ConfigHotkeyCondition>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigHotkeyCondition {
+    GuestShown,
+    HostShown,
+    Grabbed,
+}
+
+impl ConfigHotkey {
+    fn default_within() -> Duration {
+        Duration::from_millis(500)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigSwitchEvent {
+    pub switch: SwitchKind,
+    /// Whether to trigger on the switch turning on (ie. the lid closing) or off
+    #[serde(default = "true_")]
+    pub on: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<ConfigEvent>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -256,6 +1164,8 @@ pub struct ConfigHotkey {
 pub enum ConfigEvent {
     Exec(Vec<String>),
     GuestExec(Vec<String>),
+    /// Starts a guest process without waiting for it to exit
+    GuestExecNowait(Vec<String>),
     GuestWait,
     ShowHost,
     ShowGuest,
@@ -263,13 +1173,87 @@ pub enum ConfigEvent {
     ToggleGrab(ConfigGrab),
     Grab(ConfigGrab),
     Ungrab(ConfigGrabMode),
+    /// An X grab that leaves the pointer on the host
+    GrabKeyboardOnly,
+    /// An X grab that leaves the keyboard on the host
+    GrabPointerOnly,
     UnstickHost,
     UnstickGuest,
+    SetMouseMode(ConfigMouseMode),
+    CycleSource,
+    /// Types into the guest keyboard as-is, without triggering hotkeys or remaps
+    TypeText {
+        text: String,
+        #[serde(default = "ConfigEvent::default_type_delay", with = "humantime_serde")]
+        delay: Duration,
+    },
+    /// Presses the keys in order as a chord in the guest, then releases them,
+    /// without triggering hotkeys or remaps
+    GuestKeys(Vec<Key>),
+    /// Sends Ctrl+Alt+Del straight to QEMU, bypassing grabs and remapping
+    GuestCtrlAltDel,
+    /// Sends a magic SysRq key (Alt+SysRq+key) straight to QEMU
+    GuestSysrq(char),
+    RefreshKeymaps,
+    /// Switches to one of the config's `profiles`
+    Profile(String),
+    /// Plays a sound file with `sounds.player`
+    PlaySound(String),
+    /// Dumps input to a file for `screenstub replay`, for bug reports
+    CaptureInput {
+        path: String,
+        #[serde(default = "ConfigEvent::default_capture_duration", with = "humantime_serde")]
+        duration: Duration,
+    },
+    /// Re-reads hotkeys, remaps, exit events and DDC methods from the config file
+    ReloadConfig,
+    /// Moves input and the guest monitor input over to one of `qemu.vms`
+    SwitchVm(String),
+    /// Boots the VM with `qemu.launch` if it isn't running, then shows the guest
+    StartVm,
+    /// Stops the `qemu.launch` systemd unit, or asks the guest to power down
+    StopVm,
+    /// Wakes (true) or blanks the host display through DPMS
+    DisplayPower(bool),
+    /// Pauses before the next event in the list
+    Wait(#[serde(with = "humantime_serde")] Duration),
+    /// Runs each event after the one before it finishes, rather than all at once
+    Sequence(Vec<ConfigEvent>),
+    /// Runs `then` in order if every given condition holds, otherwise `else`
+    If {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        showing_guest: Option<bool>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        grabbed: Option<bool>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        then: Vec<ConfigEvent>,
+        #[serde(default, rename = "else", skip_serializing_if = "Vec::is_empty")]
+        otherwise: Vec<ConfigEvent>,
+    },
     Shutdown,
     Reboot,
     Exit,
 }
 
+impl ConfigEvent {
+    fn default_type_delay() -> Duration {
+        Duration::from_millis(10)
+    }
+
+    fn default_capture_duration() -> Duration {
+        Duration::from_secs(30)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigMouseMode {
+    /// A relative mouse device, for games and anything else that captures the cursor
+    Relative,
+    /// An absolute tablet device that follows the host cursor
+    Absolute,
+}
+
 #[derive(Debug, Copy, Clone, PartialOrd, Ord, PartialEq, Eq, Hash, Deserialize, Serialize, BitFlags)]
 #[repr(u8)]
 #[serde(rename_all = "snake_case")]
@@ -284,6 +1268,23 @@ pub enum ConfigInputEvent {
     Sound = 0x80,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigUnknownEvent {
+    /// Discard them quietly
+    Drop,
+    /// Pass them through untouched to the keyboard route, which only works for uinput routing
+    Forward,
+    /// Discard them, warning about the first of each kind
+    LogOnce,
+}
+
+impl Default for ConfigUnknownEvent {
+    fn default() -> Self {
+        ConfigUnknownEvent::LogOnce
+    }
+}
+
 impl ConfigInputEvent {
     pub fn from_event(e: &InputEvent) -> Option<Self> {
         match EventRef::new(e) {
@@ -328,10 +1329,127 @@ pub struct ConfigInputDevice {
     // pub kind (keyboard, mouse, tablet, etc)
 }
 
+/// Every field given has to match, and at least one has to be
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigEvdevMatch {
+    /// The device name, where `*` and `?` are wildcards
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vendor_id: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product_id: Option<u16>,
+    /// The unique id, usually a serial number when there is one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uniq: Option<String>,
+}
+
+impl ConfigEvdevMatch {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigWheel {
+    #[serde(default)]
+    pub vertical: ConfigWheelAxis,
+    #[serde(default)]
+    pub horizontal: ConfigWheelAxis,
+}
+
+impl ConfigWheel {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigWheelAxis {
+    /// Scrolls the other way, ie. natural scrolling
+    #[serde(default)]
+    pub invert: bool,
+    /// Multiplies wheel movement, with fractions carried over to the next event.
+    /// Wheels reported as buttons can only be inverted.
+    #[serde(default = "ConfigWheelAxis::default_scale")]
+    pub scale: f32,
+}
+
+impl ConfigWheelAxis {
+    fn default_scale() -> f32 {
+        1.0
+    }
+}
+
+impl Default for ConfigWheelAxis {
+    fn default() -> Self {
+        ConfigWheelAxis {
+            invert: false,
+            scale: Self::default_scale(),
+        }
+    }
+}
+
 fn true_() -> bool {
     true
 }
 
+/// Expands a leading `~` and any `$VAR` or `${VAR}` in a path
+pub fn expand_path(path: &str) -> Result<String, String> {
+    expand_path_with(path, |name| env::var(name).ok())
+}
+
+/// Like `expand_path`, looking variables up with `lookup` instead of the environment
+pub fn expand_path_with<F: Fn(&str) -> Option<String>>(path: &str, lookup: F) -> Result<String, String> {
+    let var = |name: &str| lookup(name)
+        .ok_or_else(|| format!("{} is not set, for {}", name, path));
+
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = if path == "~" || path.starts_with("~/") {
+        expanded.push_str(&var("HOME")?);
+        &path[1..]
+    } else {
+        path
+    };
+    while let Some(i) = rest.find('$') {
+        expanded.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        let (name, next) = if rest.starts_with('{') {
+            let end = rest.find('}')
+                .ok_or_else(|| format!("unterminated ${{ in {}", path))?;
+            (&rest[1..end], &rest[end + 1..])
+        } else {
+            let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+        if name.is_empty() {
+            expanded.push('$');
+        } else {
+            expanded.push_str(&var(name)?);
+        }
+        rest = next;
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+fn expand_path_opt<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|path| expand_path(&path).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn expand_paths<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?.iter()
+        .map(|path| expand_path(path).map_err(serde::de::Error::custom))
+        .collect()
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(deny_unknown_fields, rename_all = "lowercase", remote = "ConfigGrab")]
 pub enum ConfigGrab {
@@ -340,6 +1458,11 @@ pub enum ConfigGrab {
         confine: bool,
         #[serde(default = "true_")]
         mouse: bool,
+        /// Leaving either of these off keeps that device on the host
+        #[serde(default = "true_")]
+        keyboard: bool,
+        #[serde(default = "true_")]
+        pointer: bool,
         #[serde(default = "ConfigGrab::default_ignore", skip_serializing_if = "Vec::is_empty")]
         ignore: Vec<ConfigInputEvent>,
         #[serde(default, skip_serializing_if = "Vec::is_empty")]
@@ -354,7 +1477,18 @@ pub enum ConfigGrab {
         xcore_ignore: Vec<ConfigInputEvent>,
         #[serde(default, skip_serializing_if = "Vec::is_empty")]
         evdev_ignore: Vec<ConfigInputEvent>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        wheel_emulation: Option<ConfigWheelEmulation>,
+        #[serde(default, deserialize_with = "expand_paths")]
         devices: Vec<String>,
+        /// Devices found by what they are rather than where, since event
+        /// numbers change between boots
+        #[serde(default, rename = "match", skip_serializing_if = "Vec::is_empty")]
+        matches: Vec<ConfigEvdevMatch>,
+        /// Adds devices that show up while grabbed, any that fit `match` or
+        /// every keyboard and mouse without it
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        hotplug: bool,
     },
 }
 
@@ -381,12 +1515,7 @@ impl<'de> Deserialize<'de> for ConfigGrab {
                 match v {
                     ConfigGrabDeserializer::Enum(v) => v,
                     ConfigGrabDeserializer::Plain(e) => match e {
-                        ConfigGrabDeserializerPlain::X => ConfigGrab::X {
-                            confine: true,
-                            mouse: true,
-                            ignore: ConfigGrab::default_ignore(),
-                            devices: Default::default(),
-                        },
+                        ConfigGrabDeserializerPlain::X => ConfigGrab::default(),
                     },
                 }
             }
@@ -404,7 +1533,7 @@ impl Serialize for ConfigGrab {
 }
 
 impl ConfigGrab {
-    fn default_ignore() -> Vec<ConfigInputEvent> {
+    pub fn default_ignore() -> Vec<ConfigInputEvent> {
         vec![ConfigInputEvent::Absolute]
     }
 
@@ -414,19 +1543,52 @@ impl ConfigGrab {
             ConfigGrab::Evdev { .. } => ConfigGrabMode::Evdev,
         }
     }
-}
 
-impl Default for ConfigGrab {
-    fn default() -> Self {
+    /// An X grab of just the keyboard, the pointer, or both
+    pub fn x_devices(keyboard: bool, pointer: bool) -> Self {
         ConfigGrab::X {
             confine: true,
-            mouse: true,
+            mouse: pointer,
+            keyboard,
+            pointer,
             ignore: Self::default_ignore(),
             devices: Default::default(),
         }
     }
 }
 
+impl Default for ConfigGrab {
+    fn default() -> Self {
+        Self::x_devices(true, true)
+    }
+}
+
+/// Scrolls with pointer motion while a button is held, like a trackpoint
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigWheelEmulation {
+    /// The grabbed devices to apply this to, or all of them if empty
+    #[serde(default, deserialize_with = "expand_paths", skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<String>,
+    #[serde(default = "ConfigWheelEmulation::default_button")]
+    pub button: Key,
+    /// Pointer motion needed per wheel step
+    #[serde(default = "ConfigWheelEmulation::default_threshold")]
+    pub threshold: u32,
+    #[serde(default = "true_")]
+    pub horizontal: bool,
+}
+
+impl ConfigWheelEmulation {
+    fn default_button() -> Key {
+        Key::ButtonMiddle
+    }
+
+    fn default_threshold() -> u32 {
+        8
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ConfigGrabMode {
@@ -451,18 +1613,80 @@ pub struct ConfigMonitor {
     pub model: Option<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub serial: Option<String>,
-    //#[serde(default, skip_serializing_if = "Option::is_none")]
-    // pub path: Option<DisplayPath>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connector: Option<String>,
+    /// i2c bus number, for telling apart monitors with identical EDIDs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub i2c_bus: Option<u32>,
+    /// Alternative to `i2c_bus`, such as `/dev/i2c-4` or a symlink to it
+    #[serde(default, deserialize_with = "expand_path_opt", skip_serializing_if = "Option::is_none")]
+    pub dev_path: Option<String>,
+    /// RandR output that the X window goes fullscreen on, which also
+    /// identifies the monitor by its EDID if nothing else here does
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub xrandr_name: Option<String>,
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
-pub struct ConfigSource {
+pub struct ConfigMonitorQuirks {
     #[serde(default, skip_serializing_if = "Option::is_none")]
-    pub value: Option<u8>,
+    pub manufacturer: Option<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+
+    /// Time the monitor needs to settle after switching before it responds to DDC again
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "humantime_serde")]
+    pub switch_delay: Option<Duration>,
+    /// Reported source values that don't actually work
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ignore_sources: Vec<u8>,
+    /// Overrides the source values listed in a broken capability string
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<u8>,
+    /// Read the source back after switching to confirm it took effect
+    #[serde(default)]
+    pub verify: bool,
+}
+
+impl ConfigMonitorQuirks {
+    /// Fills in anything not set here from a less specific quirk
+    pub fn merge(&mut self, other: &Self) {
+        if self.switch_delay.is_none() {
+            self.switch_delay = other.switch_delay;
+        }
+        if self.ignore_sources.is_empty() {
+            self.ignore_sources = other.ignore_sources.clone();
+        }
+        if self.sources.is_empty() {
+            self.sources = other.sources.clone();
+        }
+        self.verify |= other.verify;
+    }
+
+    pub fn matches(&self, manufacturer: Option<&str>, model: Option<&str>) -> bool {
+        self.manufacturer.as_ref().map(|m| Some(&m[..]) == manufacturer).unwrap_or(true) &&
+            self.model.as_ref().map(|m| Some(&m[..]) == model).unwrap_or(true)
+    }
+
+    /// Applies the source list override and ignored values to a detected source list
+    pub fn filter_sources(&self, sources: Vec<u8>) -> Vec<u8> {
+        let sources = if self.sources.is_empty() {
+            sources
+        } else {
+            self.sources.clone()
+        };
+        sources.into_iter()
+            .filter(|s| !self.ignore_sources.contains(s))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConfigSource {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<ConfigSourceName>,
 }
 
@@ -472,6 +1696,115 @@ impl ConfigSource {
     }
 }
 
+impl<'de> Deserialize<'de> for ConfigSource {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct ConfigSourceFields {
+            #[serde(default, deserialize_with = "source_value_opt")]
+            value: Option<u8>,
+            #[serde(default)]
+            name: Option<ConfigSourceName>,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum ConfigSourceDeserializer {
+            Name(ConfigSourceName),
+            Value(#[serde(deserialize_with = "source_value")] u8),
+            Fields(ConfigSourceFields),
+        }
+
+        fn source_value_opt<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<u8>, D::Error> {
+            source_value(deserializer).map(Some)
+        }
+
+        impl From<ConfigSourceDeserializer> for ConfigSource {
+            fn from(v: ConfigSourceDeserializer) -> Self {
+                match v {
+                    ConfigSourceDeserializer::Name(name) => ConfigSource {
+                        value: None,
+                        name: Some(name),
+                    },
+                    ConfigSourceDeserializer::Value(value) => ConfigSource {
+                        value: Some(value),
+                        name: None,
+                    },
+                    ConfigSourceDeserializer::Fields(ConfigSourceFields { value, name }) => ConfigSource {
+                        value,
+                        name,
+                    },
+                }
+            }
+        }
+
+        ConfigSourceDeserializer::deserialize(deserializer)
+            .map(From::from)
+    }
+}
+
+/// Accepts either a number or a string, which is only read as hex with a
+/// `0x` prefix like `"0x1b"`
+fn source_value<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum SourceValue {
+        Value(u8),
+        String(String),
+    }
+
+    match SourceValue::deserialize(deserializer)? {
+        SourceValue::Value(value) => Ok(value),
+        SourceValue::String(s) => match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => u8::from_str_radix(hex, 16),
+            None => s.parse(),
+        }.map_err(|_| serde::de::Error::custom(format!("invalid source value {:?}", s))),
+    }
+}
+
+/// Deserializes a map while keeping its entries in the order they were written
+mod ordered_map {
+    use std::fmt;
+    use std::marker::PhantomData;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde::de::{MapAccess, Visitor};
+    use serde::ser::SerializeMap;
+
+    pub fn deserialize<'de, D, K, V>(deserializer: D) -> Result<Vec<(K, V)>, D::Error> where
+        D: Deserializer<'de>,
+        K: Deserialize<'de>,
+        V: Deserialize<'de>,
+    {
+        struct OrderedVisitor<K, V>(PhantomData<(K, V)>);
+
+        impl<'de, K: Deserialize<'de>, V: Deserialize<'de>> Visitor<'de> for OrderedVisitor<K, V> {
+            type Value = Vec<(K, V)>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a map")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut res = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    res.push(entry);
+                }
+                Ok(res)
+            }
+        }
+
+        deserializer.deserialize_map(OrderedVisitor(PhantomData))
+    }
+
+    pub fn serialize<S: Serializer, K: Serialize, V: Serialize>(entries: &[(K, V)], serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(entries.len()))?;
+        for (k, v) in entries {
+            map.serialize_entry(k, v)?;
+        }
+        map.end()
+    }
+}
+
 #[derive(Debug, Copy, Clone, Deserialize, Serialize)]
 #[repr(u8)]
 pub enum ConfigSourceName {
@@ -511,6 +1844,11 @@ pub enum ConfigSourceName {
     HDMI1 = 0x11,
     #[serde(rename = "HDMI-2")]
     HDMI2 = 0x12,
+    // not part of MCCS, but common among monitors with USB-C/Thunderbolt inputs
+    #[serde(rename = "USB-C-1")]
+    USBC1 = 0x1b,
+    #[serde(rename = "USB-C-2")]
+    USBC2 = 0x1c,
 }
 
 impl fmt::Display for ConfigSourceName {
@@ -526,7 +1864,7 @@ impl ConfigSourceName {
 
     pub fn from_value(value: u8) -> Option<Self> {
         match value {
-            1..=0x12 => Some(unsafe { Self::from_value_unchecked(value) }),
+            1..=0x12 | 0x1b | 0x1c => Some(unsafe { Self::from_value_unchecked(value) }),
             _ => None,
         }
     }
@@ -535,3 +1873,53 @@ impl ConfigSourceName {
         core::mem::transmute(value)
     }
 }
+
+#[test]
+fn merge_includes() {
+    let mut base: Value = serde_yaml::from_str("qemu: { name: a, qmp_socket: /a }\nhotkeys: [x]").unwrap();
+    let over: Value = serde_yaml::from_str("qemu: { name: b }\nhotkeys: []").unwrap();
+    merge_value(&mut base, over);
+    let expected: Value = serde_yaml::from_str("qemu: { name: b, qmp_socket: /a }\nhotkeys: []").unwrap();
+    assert_eq!(base, expected);
+}
+
+#[test]
+fn expand_paths_env() {
+    let lookup = |name: &str| match name {
+        "HOME" => Some("/home/user".to_owned()),
+        "VM_DIR" => Some("/run/vm".to_owned()),
+        _ => None,
+    };
+    assert_eq!(expand_path_with("$VM_DIR/qmp", lookup).unwrap(), "/run/vm/qmp");
+    assert_eq!(expand_path_with("${VM_DIR}_ga", lookup).unwrap(), "/run/vm_ga");
+    assert_eq!(expand_path_with("~/vm.sock", lookup).unwrap(), "/home/user/vm.sock");
+    assert_eq!(expand_path_with("/dev/input/~user$", lookup).unwrap(), "/dev/input/~user$");
+    assert!(expand_path_with("$UNSET", lookup).is_err());
+}
+
+#[test]
+fn source_values() {
+    let source: ConfigSource = serde_yaml::from_str("\"27\"").unwrap();
+    assert_eq!(source.value(), Some(27));
+    let source: ConfigSource = serde_yaml::from_str("27").unwrap();
+    assert_eq!(source.value(), Some(27));
+    let source: ConfigSource = serde_yaml::from_str("\"0x1b\"").unwrap();
+    assert_eq!(source.value(), Some(0x1b));
+    let source: ConfigSource = serde_yaml::from_str("{ value: \"0x1B\" }").unwrap();
+    assert_eq!(source.value(), Some(0x1b));
+    assert!(serde_yaml::from_str::<ConfigSource>("\"1b\"").is_err());
+}
+
+#[test]
+fn quirks_merge() {
+    let quirks: Vec<ConfigMonitorQuirks> = serde_yaml::from_str("- { model: U2720Q, verify: true, ignore_sources: [0x1b] }\n- { manufacturer: DEL, switch_delay: 1s, ignore_sources: [0x11] }").unwrap();
+    let quirk = quirks.iter()
+        .filter(|q| q.matches(Some("DEL"), Some("U2720Q")))
+        .fold(ConfigMonitorQuirks::default(), |mut quirk, q| {
+            quirk.merge(q);
+            quirk
+        });
+    assert!(quirk.verify);
+    assert_eq!(quirk.switch_delay, Some(Duration::from_secs(1)));
+    assert_eq!(quirk.ignore_sources, vec![0x1b]);
+}
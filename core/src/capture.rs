@@ -0,0 +1,154 @@
+//! Binary dumps of input as it passes through screenstub, for reproducing
+//! input bugs offline with `screenstub replay`.
+//!
+//! A capture is `MAGIC` followed by fixed size little endian records.
+
+use std::fs::File;
+use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use anyhow::{Error, format_err};
+use input::InputEvent;
+
+pub const MAGIC: &[u8; 8] = b"SSINPUT1";
+
+/// Where in the pipeline an event was seen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CaptureStage {
+    /// Host input, before hotkeys and remapping
+    Host = 0,
+    /// On its way to a guest route
+    Guest = 1,
+}
+
+impl CaptureStage {
+    fn from_u8(stage: u8) -> Option<Self> {
+        match stage {
+            0 => Some(CaptureStage::Host),
+            1 => Some(CaptureStage::Guest),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CaptureRecord {
+    /// Since the capture started
+    pub time: Duration,
+    pub stage: CaptureStage,
+    pub event: InputEvent,
+}
+
+impl CaptureRecord {
+    /// micros u64, stage u8, reserved u8, type u16, code u16, value i32
+    pub const SIZE: usize = 18;
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let raw = self.event.as_raw();
+        let mut buf = [0u8; Self::SIZE];
+        buf[0..8].copy_from_slice(&(self.time.as_micros() as u64).to_le_bytes());
+        buf[8] = self.stage as u8;
+        buf[10..12].copy_from_slice(&raw.type_.to_le_bytes());
+        buf[12..14].copy_from_slice(&raw.code.to_le_bytes());
+        buf[14..18].copy_from_slice(&raw.value.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8; Self::SIZE]) -> Result<Self, Error> {
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        let mut micros = [0u8; 8];
+        micros.copy_from_slice(&buf[0..8]);
+        let mut value = [0u8; 4];
+        value.copy_from_slice(&buf[14..18]);
+
+        let stage = CaptureStage::from_u8(buf[8])
+            .ok_or_else(|| format_err!("unknown capture stage {}", buf[8]))?;
+        // everything but the time is plain data, which starts out zeroed
+        let mut raw: input::sys::input_event = unsafe { std::mem::zeroed() };
+        raw.type_ = u16_at(10);
+        raw.code = u16_at(12);
+        raw.value = i32::from_le_bytes(value);
+        let event = *InputEvent::from_raw(&raw)
+            .map_err(|_| format_err!("unknown event type {}", raw.type_))?;
+
+        Ok(CaptureRecord {
+            time: Duration::from_micros(u64::from_le_bytes(micros)),
+            stage,
+            event,
+        })
+    }
+}
+
+/// A capture being written, which stops taking events once its time is up
+pub struct Capture {
+    file: BufWriter<File>,
+    start: Instant,
+    duration: Duration,
+}
+
+impl Capture {
+    pub fn create<P: AsRef<Path>>(path: P, duration: Duration) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        Ok(Capture {
+            file,
+            start: Instant::now(),
+            duration,
+        })
+    }
+
+    /// Writes `event` out, returning false once the capture is over
+    pub fn record(&mut self, stage: CaptureStage, event: &InputEvent) -> io::Result<bool> {
+        let time = self.start.elapsed();
+        if time > self.duration {
+            self.file.flush()?;
+            return Ok(false)
+        }
+        self.file.write_all(&CaptureRecord { time, stage, event: *event }.encode())?;
+        Ok(true)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Reads every record of a capture file
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Vec<CaptureRecord>, Error> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(format_err!("not a screenstub input capture"))
+    }
+
+    let mut records = Vec::new();
+    let mut buf = [0u8; CaptureRecord::SIZE];
+    loop {
+        match file.read_exact(&mut buf) {
+            Ok(()) => records.push(CaptureRecord::decode(&buf)?),
+            // a capture cut short by a crash still has everything before the last record
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(records)
+}
+
+#[test]
+fn capture_roundtrip() {
+    use input::{Key, KeyEvent, KeyState, RelativeAxis, RelativeEvent};
+
+    let records = [
+        (CaptureStage::Host, KeyEvent::new(Default::default(), Key::A, KeyState::PRESSED).into()),
+        (CaptureStage::Guest, RelativeEvent::new(Default::default(), RelativeAxis::Y, -12345).into()),
+    ];
+    for (i, &(stage, event)) in records.iter().enumerate() {
+        let record = CaptureRecord { time: Duration::from_micros(i as u64 * 1500), stage, event };
+        let decoded = CaptureRecord::decode(&record.encode()).unwrap();
+        assert_eq!(decoded.time, record.time);
+        assert_eq!(decoded.stage, stage);
+        assert_eq!(decoded.event, event);
+    }
+}
@@ -0,0 +1,60 @@
+//! Counts how often the buffers between tasks fill up, which is where a
+//! guest that can't keep up or a very fast mouse shows up first.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use futures::channel::mpsc;
+use futures::SinkExt;
+
+/// Sends that had to wait for room in a channel
+#[derive(Debug, Default)]
+pub struct Saturation(AtomicU64);
+
+impl Saturation {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Sends `item`, counting it if the channel was full
+    pub async fn send<T>(&self, sender: &mut mpsc::Sender<T>, item: T) -> Result<(), mpsc::SendError> {
+        match sender.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(e) if e.is_full() => {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                sender.send(e.into_inner()).await
+            },
+            Err(e) => Err(e.into_send_error()),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ChannelStats {
+    /// Any of the guest routes
+    pub route: Saturation,
+    pub user: Saturation,
+}
+
+impl ChannelStats {
+    pub fn counts(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("route", self.route.get()),
+            ("user", self.user.get()),
+        ]
+    }
+}
+
+#[test]
+fn saturation() {
+    let (mut sender, mut receiver) = mpsc::channel(0);
+    let full = Saturation::default();
+    futures::executor::block_on(async {
+        full.send(&mut sender, 1).await.unwrap();
+        let (sent, received) = futures::join!(full.send(&mut sender, 2), async {
+            use futures::StreamExt;
+            (receiver.next().await, receiver.next().await)
+        });
+        sent.unwrap();
+        assert_eq!(received, (Some(1), Some(2)));
+    });
+    assert_eq!(full.get(), 1);
+}
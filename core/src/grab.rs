@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{fs, io};
+use futures::channel::mpsc as un_mpsc;
+use futures::future::Either;
+use futures::{Sink, SinkExt, Stream, StreamExt, FutureExt, stream, future};
+use tokio::time::sleep;
+use anyhow::{Error, format_err};
+use input::{InputEvent, InputId, Key, RelativeAxis};
+use uinput::{UInputSink, EvdevHandle, Evdev};
+use config::{ConfigInputEvent, ConfigEvdevMatch};
+use log::{debug, info, warn};
+use crate::filter::InputEventFilter;
+use crate::middleware::InputChain;
+use crate::hotplug::Uevents;
+
+/// How long to keep trying a replugged device while udev sets it up
+const HOTPLUG_DELAY: Duration = Duration::from_millis(200);
+const HOTPLUG_ATTEMPTS: usize = 10;
+
+/*pub enum Grab {
+    XCore,
+    Evdev(GrabEvdev),
+}
+
+impl From<GrabEvdev> for Grab {
+    fn from(g: GrabEvdev) -> Self {
+        Grab::Evdev(g)
+    }
+}*/
+
+pub struct GrabEvdev {
+    devices: HashMap<InputId, (PathBuf, UInputSink, InputChain)>,
+    /// What the devices were when the grab started, to recognize them later
+    known: Vec<DeviceInfo>,
+    filter: Arc<InputEventFilter>,
+    grabbed: bool,
+}
+
+/// What a grab needs to pick its devices back up when they're replugged
+pub struct Hotplug {
+    /// Paths as configured, which may be links that udev recreates
+    pub devices: Vec<PathBuf>,
+    pub matches: Vec<ConfigEvdevMatch>,
+    /// Add new devices too, rather than only those that were there to begin with
+    pub new_devices: bool,
+    /// The id of screenstub's own devices, which never match
+    pub own: InputId,
+    pub chain: Box<dyn FnMut(&Path) -> InputChain + Send>,
+}
+
+impl Hotplug {
+    /// The configured path that leads to `node`, or `node` itself if it
+    /// belongs in the grab
+    fn path_for(&self, node: &Path, known: &[DeviceInfo]) -> Option<PathBuf> {
+        if let Some(path) = self.devices.iter().find(|path| fs::canonicalize(path).map(|path| path == node).unwrap_or(false)) {
+            return Some(path.clone())
+        }
+
+        let info = device_info(node).filter(|info| info.id != self.own)?;
+        let wanted = if self.matches.is_empty() {
+            // without any rules every keyboard and mouse is fair game
+            self.new_devices && info.input
+        } else {
+            self.matches.iter().any(|m| fits(m, &info)) && (self.new_devices || known.contains(&info))
+        };
+        Some(node.to_owned()).filter(|_| wanted)
+    }
+}
+
+type Reopened = (PathBuf, UInputSink, InputChain);
+
+impl GrabEvdev {
+    /// `chain` picks the transformations applied to each device's events
+    pub fn new<P, I, F, C>(devices: I, filter: F, mut chain: C) -> Result<Self, Error> where
+        P: AsRef<Path>,
+        I: IntoIterator<Item=P>,
+        F: IntoIterator<Item=ConfigInputEvent>,
+        C: FnMut(&Path) -> InputChain,
+    {
+        let mut known = Vec::new();
+        let devices: io::Result<_> = devices.into_iter().map(|dev| -> io::Result<_> {
+            let chain = chain(dev.as_ref());
+            let path = dev.as_ref().to_owned();
+            let dev = Evdev::open(&dev)?;
+
+            let evdev = dev.evdev();
+
+            let id = evdev.device_id()?;
+            known.extend(evdev_info(&evdev));
+            let stream = dev.to_sink()?;
+
+            Ok((id, (path, stream, chain)))
+        }).collect();
+
+        Ok(GrabEvdev {
+            devices: devices?,
+            known,
+            filter: Arc::new(InputEventFilter::new(filter)),
+            grabbed: false,
+        })
+    }
+
+    pub fn grab(&mut self, grab: bool) -> io::Result<()> {
+        for (_, uinput, _) in self.devices.values() {
+            if let Some(evdev) = uinput.evdev() {
+                evdev.grab(grab)?;
+            }
+        }
+        self.grabbed = grab;
+        Ok(())
+    }
+
+    /// A device's events until it's unplugged
+    fn device_events(path: PathBuf, device: UInputSink, mut chain: InputChain) -> impl Stream<Item=io::Result<InputEvent>> + Send + Unpin {
+        device.take_while(move |e| {
+            let gone = matches!(e, Err(e) if e.raw_os_error() == Some(libc::ENODEV));
+            if gone {
+                warn!("evdev device {} was disconnected", path.display());
+            }
+            future::ready(!gone)
+        }).flat_map(move |e| stream::iter(match e {
+            Ok(e) if chain.is_empty() => vec![Ok(e)],
+            Ok(e) => chain.process(e).into_iter().map(Ok).collect(),
+            Err(e) => vec![Err(e)],
+        }))
+    }
+
+    fn reopen(path: &Path, grab: bool) -> io::Result<UInputSink> {
+        let dev = Evdev::open(&path)?;
+        if grab {
+            dev.evdev().grab(true)?;
+        }
+        dev.to_sink()
+    }
+
+    /// Reopens devices of the grab as they're plugged back in, keeping track
+    /// of the event nodes in `open`
+    async fn hotplug(mut hotplug: Hotplug, known: Vec<DeviceInfo>, mut open: Vec<PathBuf>, grab: bool, mut reopened: un_mpsc::Sender<Reopened>) {
+        let uevents = match Uevents::new() {
+            Ok(uevents) => uevents,
+            Err(e) => {
+                warn!("Failed to watch for evdev devices being replugged: {}", e);
+                return future::pending().await
+            },
+        };
+        loop {
+            let event = match uevents.next().await {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("Failed to watch for evdev devices being replugged: {}", e);
+                    return future::pending().await
+                },
+            };
+            let node = match event.input_device() {
+                Some(node) => PathBuf::from(node),
+                None => continue,
+            };
+            match &event.action[..] {
+                "remove" => open.retain(|path| path != &node),
+                "add" if !open.contains(&node) => {
+                    // udev still has to set permissions and create any links
+                    for _ in 0..HOTPLUG_ATTEMPTS {
+                        sleep(HOTPLUG_DELAY).await;
+                        let path = match hotplug.path_for(&node, &known) {
+                            Some(path) => path,
+                            None => continue,
+                        };
+                        match Self::reopen(&path, grab) {
+                            Ok(device) => {
+                                info!("evdev device {} was added to the grab", path.display());
+                                open.push(node.clone());
+                                let chain = (hotplug.chain)(&path);
+                                if reopened.send((path, device, chain)).await.is_err() {
+                                    return
+                                }
+                                break
+                            },
+                            Err(e) => debug!("evdev device {} isn't ready yet: {}", path.display(), e),
+                        }
+                    }
+                },
+                _ => (),
+            }
+        }
+    }
+
+    /// Forwards events until aborted, picking devices back up through
+    /// `hotplug` when they're replugged
+    pub fn spawn<S>(self, mut sink: S, mut error_sender: un_mpsc::Sender<Error>, hotplug: Option<Hotplug>) -> future::AbortHandle where
+        S: Sink<InputEvent> + Unpin + Clone + Send + 'static,
+        Error: From<S::Error>,
+    {
+        let open = self.devices.values()
+            .map(|(path, ..)| fs::canonicalize(path).unwrap_or_else(|_| path.clone()))
+            .collect();
+        let (watch, mut reopened) = match hotplug {
+            Some(hotplug) => {
+                let (sender, receiver) = un_mpsc::channel(1);
+                (Self::hotplug(hotplug, self.known, open, self.grabbed, sender).boxed(), Some(receiver))
+            },
+            None => (future::pending().boxed(), None),
+        };
+        let filter = self.filter;
+        let mut select = stream::SelectAll::new();
+        for (_, (path, device, chain)) in self.devices {
+            select.push(Self::device_events(path, device, chain));
+        }
+
+        let fut = async move {
+            loop {
+                let woken = {
+                    let event = async {
+                        if select.is_empty() {
+                            future::pending().await
+                        } else {
+                            select.next().await
+                        }
+                    };
+                    let device = async {
+                        match reopened.as_mut() {
+                            Some(reopened) => reopened.next().await,
+                            None => future::pending().await,
+                        }
+                    };
+                    futures::pin_mut!(event, device);
+                    match future::select(event, device).await {
+                        Either::Left((event, _)) => Either::Left(event),
+                        Either::Right((device, _)) => Either::Right(device),
+                    }
+                };
+                match woken {
+                    Either::Left(Some(e)) => {
+                        let e = e?;
+                        if filter.filter_event(&e) && sink.send(e).await.is_err() {
+                            break
+                        }
+                    },
+                    Either::Left(None) => (),
+                    Either::Right(Some((path, device, chain))) =>
+                        select.push(Self::device_events(path, device, chain)),
+                    Either::Right(None) => reopened = None,
+                }
+                if select.is_empty() && reopened.is_none() {
+                    break
+                }
+            }
+
+            Ok(())
+        }.then(move |r: Result<(), Error>| async move { match r {
+            Err(e) => {
+                let _ = error_sender.send(e).await;
+            },
+            _ => (),
+        } });
+        let (fut, handle) = future::abortable(future::select(fut.boxed(), watch).map(drop));
+        tokio::spawn(fut);
+        handle
+    }
+
+    /// Event classes the X window also receives from these devices while they
+    /// aren't grabbed exclusively, which would otherwise reach the guest twice
+    pub fn x_overlap(&self) -> io::Result<Vec<ConfigInputEvent>> {
+        let mut overlap = Vec::new();
+        for evdev in self.evdevs() {
+            let keys = evdev.key_bits()?;
+            if keys.iter().any(|key| key.is_key()) {
+                overlap.push(ConfigInputEvent::Key);
+            }
+            let pointer = keys.iter().any(|key| key.is_button()) ||
+                evdev.relative_bits()?.iter().next().is_some() ||
+                evdev.absolute_bits()?.iter().next().is_some();
+            if pointer {
+                // the window reports motion as absolute and wheels as buttons or relative
+                overlap.extend(&[ConfigInputEvent::Button, ConfigInputEvent::Relative, ConfigInputEvent::Absolute]);
+            }
+        }
+
+        // nothing is duplicated if the grab drops it anyway
+        let ignored = self.filter.filter();
+        overlap.retain(|&class| !ignored.contains(class));
+        overlap.sort();
+        overlap.dedup();
+        Ok(overlap)
+    }
+
+    pub fn evdevs(&self) -> Vec<EvdevHandle> {
+        // TODO: come on
+        self.devices.iter().filter_map(|(_, (_, device, _))| device.evdev()).collect()
+    }
+}
+
+/// Whether `name` fits `pattern`, where `*` is any run of characters and `?` any one
+fn glob(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<_>, Vec<_>) = (pattern.chars().collect(), name.chars().collect());
+    // the last `*` seen and where in `name` it's currently matched up to
+    let (mut p, mut n, mut star) = (0, 0, None);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            },
+            _ => match star {
+                Some((sp, sn)) => {
+                    star = Some((sp, sn + 1));
+                    p = sp + 1;
+                    n = sn + 1;
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct DeviceInfo {
+    name: String,
+    id: InputId,
+    uniq: String,
+    /// Whether it's a keyboard or mouse, rather than a power button or webcam
+    input: bool,
+}
+
+fn fits(m: &ConfigEvdevMatch, info: &DeviceInfo) -> bool {
+    !m.is_empty() &&
+        m.name.as_ref().map(|name| glob(name, &info.name)).unwrap_or(true) &&
+        m.vendor_id.map(|vendor| vendor == info.id.vendor).unwrap_or(true) &&
+        m.product_id.map(|product| product == info.id.product).unwrap_or(true) &&
+        m.uniq.as_ref().map(|uniq| uniq == &info.uniq).unwrap_or(true)
+}
+
+fn evdev_info(evdev: &EvdevHandle) -> Option<DeviceInfo> {
+    let string = |s: io::Result<Vec<u8>>| s.map(|s| String::from_utf8_lossy(&s).trim_end_matches('\0').to_owned()).unwrap_or_default();
+    let keyboard = evdev.key_bits().map(|keys| keys.get(Key::A) && keys.get(Key::Enter)).unwrap_or(false);
+    let mouse = evdev.relative_bits().map(|rel| rel.get(RelativeAxis::X) && rel.get(RelativeAxis::Y)).unwrap_or(false);
+    Some(DeviceInfo {
+        name: string(evdev.device_name()),
+        id: evdev.device_id().ok()?,
+        uniq: string(evdev.unique_id()),
+        input: keyboard || mouse,
+    })
+}
+
+fn device_info(path: &Path) -> Option<DeviceInfo> {
+    Evdev::open(&path).ok().and_then(|evdev| evdev_info(&evdev.evdev()))
+}
+
+/// Finds the `/dev/input` event devices that fit `matches`, skipping any
+/// with the `own` id that screenstub gives the devices it creates. A match
+/// without any devices is an error unless they're `optional`.
+pub fn find_devices(matches: &[ConfigEvdevMatch], own: Option<&InputId>, optional: bool) -> Result<Vec<PathBuf>, Error> {
+    let mut paths: Vec<_> = fs::read_dir("/dev/input")?
+        .filter_map(|e| e.ok()).map(|e| e.path())
+        .filter(|p| p.file_name().and_then(|n| n.to_str()).map(|n| n.starts_with("event")).unwrap_or(false))
+        .collect();
+    paths.sort();
+
+    let devices: Vec<_> = paths.into_iter()
+        .filter_map(|path| device_info(&path).map(|info| (path, info)))
+        .filter(|(_, info)| Some(&info.id) != own)
+        .collect();
+
+    let mut found = Vec::new();
+    for m in matches {
+        let before = found.len();
+        found.extend(devices.iter()
+            .filter(|(path, info)| fits(m, info) && !found.contains(path))
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>()
+        );
+        if found.len() == before && !optional {
+            return Err(format_err!("no evdev device matches {:?}", m))
+        }
+    }
+    Ok(found)
+}
+
+/*impl Drop for GrabEvdev {
+    fn drop(&mut self) {
+        for (_, mut stream) in self.devices.drain() {
+            // TODO: stream.close();
+        }
+    }
+}*/
+
+#[test]
+fn glob_names() {
+    assert!(glob("Logitech*Keyboard", "Logitech USB Keyboard"));
+    assert!(glob("*Mouse", "Razer DeathAdder Mouse"));
+    assert!(glob("event?", "event7"));
+    assert!(glob("*", ""));
+    assert!(!glob("*Mouse", "Razer DeathAdder Mouse Keyboard"));
+    assert!(!glob("event?", "event12"));
+}
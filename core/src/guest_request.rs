@@ -0,0 +1,165 @@
+//! Lets the guest hand control back to the host, by writing a line to a
+//! virtio-serial port or by creating a marker file that screenstub polls for.
+
+use std::fs;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use futures::channel::mpsc;
+use futures::future::{self, AbortHandle};
+use futures::SinkExt;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::time::sleep;
+use config::{ConfigEvent, ConfigGuestRequest};
+use log::{debug, info, warn};
+use crate::util::LogId;
+
+/// Delay before reconnecting to the serial socket, doubling up to `SERIAL_BACKOFF_MAX`
+const SERIAL_BACKOFF: Duration = Duration::from_secs(1);
+const SERIAL_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Drops requests that come in too quickly after the last one
+#[derive(Debug)]
+pub struct RateLimit {
+    min_interval: Duration,
+    last: Option<Instant>,
+}
+
+impl RateLimit {
+    pub fn new(min_interval: Duration) -> Self {
+        RateLimit {
+            min_interval,
+            last: None,
+        }
+    }
+
+    pub fn allow(&mut self, now: Instant) -> bool {
+        match self.last {
+            Some(last) if now.saturating_duration_since(last) < self.min_interval => false,
+            _ => {
+                self.last = Some(now);
+                true
+            },
+        }
+    }
+}
+
+struct Requests {
+    events: Vec<Arc<ConfigEvent>>,
+    limit: RateLimit,
+    user_sender: mpsc::Sender<Arc<ConfigEvent>>,
+    log_id: LogId,
+}
+
+impl Requests {
+    /// Returns false once the session is gone
+    async fn request(&mut self, source: &str) -> bool {
+        if !self.limit.allow(Instant::now()) {
+            debug!("{} Ignoring guest request from {}, too soon after the last one", self.log_id, source);
+            return true
+        }
+
+        info!("{} Guest requested the host from {}", self.log_id, source);
+        for event in &self.events {
+            if self.user_sender.send(event.clone()).await.is_err() {
+                return false
+            }
+        }
+        true
+    }
+}
+
+/// Watches for guest requests in the background until aborted
+pub fn spawn(config: ConfigGuestRequest, user_sender: mpsc::Sender<Arc<ConfigEvent>>, log_id: LogId) -> AbortHandle {
+    let mut requests = Requests {
+        events: crate::process::sequenced(config.events).into_iter().map(Arc::new).collect(),
+        limit: RateLimit::new(config.min_interval),
+        user_sender,
+        log_id,
+    };
+    let (serial, marker, poll_interval) = (config.serial, config.marker, config.poll_interval);
+
+    let (watch, abort) = future::abortable(async move {
+        let mut backoff = SERIAL_BACKOFF;
+        let mut serial_lines = None;
+        loop {
+            if let (Some(path), None) = (&serial, &serial_lines) {
+                match UnixStream::connect(path).await {
+                    Ok(stream) => {
+                        backoff = SERIAL_BACKOFF;
+                        serial_lines = Some(BufReader::new(stream).lines());
+                    },
+                    Err(e) => debug!("{} Guest request serial {} is unavailable: {}", requests.log_id, path, e),
+                }
+            }
+
+            // a line from the serial port, or None when it's time to poll again
+            let woken = {
+                let connected = serial_lines.is_some();
+                let poll = async {
+                    match (connected, &serial, &marker) {
+                        // retrying the serial connection also needs to wake up
+                        (false, Some(_), None) => sleep(backoff).await,
+                        (false, Some(_), Some(_)) => sleep(poll_interval.min(backoff)).await,
+                        (_, _, Some(_)) => sleep(poll_interval).await,
+                        _ => future::pending().await,
+                    }
+                };
+                let line = async {
+                    match &mut serial_lines {
+                        Some(lines) => lines.next_line().await,
+                        None => future::pending().await,
+                    }
+                };
+                futures::pin_mut!(poll, line);
+                match future::select(line, poll).await {
+                    future::Either::Left((line, _)) => Some(line),
+                    future::Either::Right(((), _)) => None,
+                }
+            };
+
+            let alive = match woken {
+                Some(Ok(Some(line))) if line.trim().is_empty() => true,
+                Some(Ok(Some(_))) => requests.request("serial").await,
+                Some(res) => {
+                    if let Err(e) = res {
+                        warn!("{} Guest request serial failed: {}", requests.log_id, e);
+                    }
+                    serial_lines = None;
+                    true
+                },
+                None => {
+                    if serial.is_some() && serial_lines.is_none() {
+                        backoff = (backoff * 2).min(SERIAL_BACKOFF_MAX);
+                    }
+                    match marker.as_ref().map(|path| (path, fs::remove_file(path))) {
+                        Some((_, Ok(()))) => requests.request("marker").await,
+                        Some((path, Err(e))) if e.kind() != io::ErrorKind::NotFound => {
+                            warn!("{} Failed to remove guest request marker {}: {}", requests.log_id, path, e);
+                            true
+                        },
+                        _ => true,
+                    }
+                },
+            };
+            if !alive {
+                break
+            }
+        }
+    });
+    tokio::spawn(watch);
+
+    abort
+}
+
+#[test]
+fn rate_limit() {
+    let start = Instant::now();
+    let mut limit = RateLimit::new(Duration::from_secs(2));
+    assert!(limit.allow(start));
+    assert!(!limit.allow(start + Duration::from_secs(1)));
+    assert!(limit.allow(start + Duration::from_secs(2)));
+    assert!(!limit.allow(start + Duration::from_millis(3999)));
+    assert!(limit.allow(start + Duration::from_secs(5)));
+}
@@ -0,0 +1,142 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use std::fmt;
+use anyhow::Error;
+use tokio::sync::broadcast;
+use config::{ConfigEvent, ConfigGrabMode};
+
+pub const DEFAULT_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone)]
+pub enum HistoryEntry {
+    Event(ConfigEvent),
+    Showing { guest: bool },
+    Grab(ConfigGrabMode),
+    Ungrab(ConfigGrabMode),
+}
+
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    Pending,
+    Ok,
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryRecord {
+    pub id: u64,
+    pub time: SystemTime,
+    pub entry: HistoryEntry,
+    pub outcome: Outcome,
+}
+
+/// A bounded log of recent user events and the state changes they caused
+#[derive(Debug)]
+pub struct History {
+    capacity: usize,
+    records: Mutex<(u64, VecDeque<HistoryRecord>)>,
+    transitions: broadcast::Sender<HistoryEntry>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            records: Mutex::new((0, VecDeque::with_capacity(capacity))),
+            transitions: broadcast::channel(16).0,
+        }
+    }
+
+    fn push(&self, entry: HistoryEntry, outcome: Outcome) -> u64 {
+        let mut records = self.records.lock().unwrap();
+        let (ref mut next_id, ref mut records) = *records;
+        let id = *next_id;
+        *next_id += 1;
+
+        if self.capacity == 0 {
+            return id
+        }
+        if records.len() >= self.capacity {
+            records.pop_front();
+        }
+        records.push_back(HistoryRecord {
+            id,
+            time: SystemTime::now(),
+            entry,
+            outcome,
+        });
+        id
+    }
+
+    /// Records an action whose outcome will be filled in later by `complete`
+    pub fn begin(&self, entry: HistoryEntry) -> u64 {
+        self.push(entry, Outcome::Pending)
+    }
+
+    /// Records a state transition that has already happened
+    pub fn record(&self, entry: HistoryEntry) {
+        let _ = self.transitions.send(entry.clone());
+        self.push(entry, Outcome::Ok);
+    }
+
+    /// Notifies of each transition passed to `record`
+    pub fn subscribe(&self) -> broadcast::Receiver<HistoryEntry> {
+        self.transitions.subscribe()
+    }
+
+    pub fn complete(&self, id: u64, res: &Result<(), Error>) {
+        let mut records = self.records.lock().unwrap();
+        if let Some(record) = records.1.iter_mut().rev().find(|r| r.id == id) {
+            record.outcome = match res {
+                Ok(()) => Outcome::Ok,
+                Err(e) => Outcome::Failed(format!("{:#}", e)),
+            };
+        }
+    }
+
+    /// The most recent `count` records, oldest first
+    pub fn latest(&self, count: usize) -> Vec<HistoryRecord> {
+        let records = self.records.lock().unwrap();
+        let records = &records.1;
+        records.iter().skip(records.len().saturating_sub(count)).cloned().collect()
+    }
+
+    pub fn to_vec(&self) -> Vec<HistoryRecord> {
+        self.latest(self.capacity)
+    }
+}
+
+/// Formats the time of day in UTC, ie. `14:32:07.250`
+pub struct TimeOfDay(pub SystemTime);
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let time = self.0.duration_since(SystemTime::UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        let secs = time.as_secs();
+        write!(f, "{:02}:{:02}:{:02}.{:03}", (secs / 3600) % 24, (secs / 60) % 60, secs % 60, time.subsec_millis())
+    }
+}
+
+impl fmt::Display for HistoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HistoryEntry::Event(event) => write!(f, "event {:?}", event),
+            HistoryEntry::Showing { guest: true } => write!(f, "showing guest"),
+            HistoryEntry::Showing { guest: false } => write!(f, "showing host"),
+            HistoryEntry::Grab(mode) => write!(f, "grabbed {:?}", mode),
+            HistoryEntry::Ungrab(mode) => write!(f, "ungrabbed {:?}", mode),
+        }
+    }
+}
+
+impl fmt::Display for HistoryRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", TimeOfDay(self.time), self.entry)?;
+        match self.outcome {
+            Outcome::Pending => write!(f, " (pending)"),
+            Outcome::Ok => Ok(()),
+            Outcome::Failed(ref e) => write!(f, " (failed: {})", e),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Kernel uevents for input devices coming and going, so that evdev grabs
+//! can pick their devices back up after a USB reset or a wireless keyboard
+//! waking up.
+
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use tokio::io::unix::AsyncFd;
+
+/// The multicast group the kernel itself sends to, rather than udev's
+const KERNEL_GROUP: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Uevent {
+    pub action: String,
+    pub subsystem: Option<String>,
+    /// Relative to `/dev`
+    pub devname: Option<String>,
+}
+
+impl Uevent {
+    /// Parses `action@devpath` followed by `KEY=value` lines, all NUL separated
+    pub fn parse(msg: &[u8]) -> Option<Self> {
+        let mut fields = msg.split(|&b| b == 0)
+            .filter(|f| !f.is_empty())
+            .map(String::from_utf8_lossy);
+        let header = fields.next()?;
+        let (action, _) = header.split_once('@')?;
+        let mut event = Uevent {
+            action: action.into(),
+            subsystem: None,
+            devname: None,
+        };
+        for field in fields {
+            match field.split_once('=') {
+                Some(("SUBSYSTEM", value)) => event.subsystem = Some(value.into()),
+                Some(("DEVNAME", value)) => event.devname = Some(value.into()),
+                _ => (),
+            }
+        }
+        Some(event)
+    }
+
+    /// The `/dev/input/event*` node this is about, if any
+    pub fn input_device(&self) -> Option<String> {
+        match (&self.subsystem, &self.devname) {
+            (Some(subsystem), Some(devname)) if subsystem == "input" && devname.starts_with("input/event") =>
+                Some(format!("/dev/{}", devname)),
+            _ => None,
+        }
+    }
+}
+
+pub struct Uevents {
+    fd: AsyncFd<File>,
+}
+
+impl Uevents {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, libc::NETLINK_KOBJECT_UEVENT) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as _;
+        addr.nl_groups = KERNEL_GROUP;
+        let res = unsafe { libc::bind(fd, &addr as *const _ as *const libc::sockaddr, mem::size_of::<libc::sockaddr_nl>() as _) };
+        if res < 0 {
+            return Err(io::Error::last_os_error())
+        }
+
+        Ok(Uevents {
+            fd: AsyncFd::new(file)?,
+        })
+    }
+
+    pub async fn next(&self) -> io::Result<Uevent> {
+        let mut buf = [0u8; 8192];
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let res = guard.try_io(|fd| {
+                match unsafe { libc::recv(fd.as_raw_fd(), buf.as_mut_ptr() as *mut _, buf.len(), 0) } {
+                    n if n < 0 => Err(io::Error::last_os_error()),
+                    n => Ok(n as usize),
+                }
+            });
+            match res {
+                Ok(Ok(n)) => if let Some(event) = Uevent::parse(&buf[..n]) {
+                    return Ok(event)
+                },
+                Ok(Err(e)) => return Err(e),
+                Err(_) => (), // not actually readable yet
+            }
+        }
+    }
+}
+
+#[test]
+fn parse_uevent() {
+    let msg = b"add@/devices/pci0000:00/usb1/1-2/input/input9/event5\0ACTION=add\0SUBSYSTEM=input\0MAJOR=13\0DEVNAME=input/event5\0";
+    let event = Uevent::parse(msg).unwrap();
+    assert_eq!(event.action, "add");
+    assert_eq!(event.input_device().as_deref(), Some("/dev/input/event5"));
+
+    let msg = b"remove@/devices/pci0000:00/usb1/1-2/input/input9\0ACTION=remove\0SUBSYSTEM=input\0";
+    assert_eq!(Uevent::parse(msg).unwrap().input_device(), None);
+    assert_eq!(Uevent::parse(b"libudev\0"), None);
+}
@@ -0,0 +1,47 @@
+//! The input routing and display switching engine behind the `screenstub` binary.
+//!
+//! A [`SessionBuilder`] sets up the QEMU input devices, routes, and monitor
+//! sources for one configured screen. Host input events sent into the resulting
+//! [`Session`] are forwarded to the guest, and [`ConfigEvent`]s drive switching,
+//! grabs, and everything else a hotkey can do.
+//!
+//! [`ConfigEvent`]: config::ConfigEvent
+
+extern crate input_linux as input;
+extern crate screenstub_uinput as uinput;
+extern crate screenstub_config as config;
+extern crate screenstub_event as event;
+extern crate screenstub_qemu as qemu;
+extern crate screenstub_ddc as ddc;
+extern crate screenstub_x as x;
+
+use std::sync::Arc;
+use config::ConfigEvent;
+
+pub mod session;
+pub mod process;
+pub mod route;
+pub mod spice;
+pub mod sources;
+pub mod filter;
+pub mod middleware;
+pub mod spawner;
+pub mod status;
+pub mod history;
+pub mod sounds;
+pub mod capture;
+pub mod guest_request;
+pub mod channels;
+pub mod unknown;
+pub mod util;
+mod grab;
+mod hotplug;
+mod exec;
+
+pub use session::{Session, SessionBuilder};
+pub use process::Process;
+pub use spawner::Spawner;
+pub use util::LogId;
+pub use grab::find_devices;
+
+pub type Events = event::Events<Arc<ConfigEvent>>;
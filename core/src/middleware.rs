@@ -0,0 +1,139 @@
+use input::{InputEvent, EventRef, EventTime, Key, KeyEvent, KeyState, RelativeAxis, RelativeEvent, SynchronizeEvent};
+use config::ConfigWheelEmulation;
+
+/// One step in transforming input on its way to the guest
+pub trait InputStage: Send {
+    /// Pushes whatever `e` turns into onto `out`, which may be nothing at all
+    fn process(&mut self, e: InputEvent, out: &mut Vec<InputEvent>);
+}
+
+/// Input stages, applied in order
+#[derive(Default)]
+pub struct InputChain {
+    stages: Vec<Box<dyn InputStage>>,
+}
+
+impl InputChain {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn push<S: InputStage + 'static>(&mut self, stage: S) {
+        self.stages.push(Box::new(stage))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    pub fn process(&mut self, e: InputEvent) -> Vec<InputEvent> {
+        let mut events = vec![e];
+        let mut out = Vec::new();
+        for stage in &mut self.stages {
+            for e in events.drain(..) {
+                stage.process(e, &mut out);
+            }
+            std::mem::swap(&mut events, &mut out);
+        }
+        events
+    }
+}
+
+/// Turns pointer motion into wheel events while a button is held.
+///
+/// Clicking the button without moving still sends the click.
+pub struct WheelEmulation {
+    button: Key,
+    threshold: i32,
+    horizontal: bool,
+    held: bool,
+    scrolled: bool,
+    motion: (i32, i32),
+}
+
+impl WheelEmulation {
+    pub fn new(config: &ConfigWheelEmulation) -> Self {
+        WheelEmulation {
+            button: config.button,
+            threshold: config.threshold.max(1) as i32,
+            horizontal: config.horizontal,
+            held: false,
+            scrolled: false,
+            motion: (0, 0),
+        }
+    }
+
+    /// Wheel steps for the motion accumulated along one axis, keeping the remainder
+    fn steps(&mut self, motion: i32, time: EventTime, axis: RelativeAxis, out: &mut Vec<InputEvent>) -> i32 {
+        let steps = motion / self.threshold;
+        if steps != 0 {
+            // moving down or left scrolls the wheel down or left
+            let value = if axis == RelativeAxis::Wheel { -steps } else { steps };
+            out.push(RelativeEvent::new(time, axis, value).into());
+            self.scrolled = true;
+        }
+        motion - steps * self.threshold
+    }
+}
+
+impl InputStage for WheelEmulation {
+    fn process(&mut self, e: InputEvent, out: &mut Vec<InputEvent>) {
+        match EventRef::new(&e) {
+            Ok(EventRef::Key(key)) if key.key == self.button => match key.value {
+                KeyState::PRESSED => {
+                    self.held = true;
+                    self.scrolled = false;
+                    self.motion = (0, 0);
+                },
+                KeyState::RELEASED => {
+                    self.held = false;
+                    if !self.scrolled {
+                        out.push(KeyEvent::new(key.time, key.key, KeyState::PRESSED).into());
+                        out.push(SynchronizeEvent::report(key.time).into());
+                        out.push(e);
+                    }
+                },
+                _ => (),
+            },
+            Ok(EventRef::Relative(rel)) if self.held => match rel.axis {
+                RelativeAxis::X => if self.horizontal {
+                    let motion = self.motion.0 + rel.value;
+                    self.motion.0 = self.steps(motion, rel.time, RelativeAxis::HorizontalWheel, out);
+                },
+                RelativeAxis::Y => {
+                    let motion = self.motion.1 + rel.value;
+                    self.motion.1 = self.steps(motion, rel.time, RelativeAxis::Wheel, out);
+                },
+                _ => out.push(e),
+            },
+            _ => out.push(e),
+        }
+    }
+}
+
+#[test]
+fn wheel_emulation() {
+    let config = ConfigWheelEmulation {
+        devices: Vec::new(),
+        button: Key::ButtonMiddle,
+        threshold: 4,
+        horizontal: false,
+    };
+    let mut chain = InputChain::new();
+    chain.push(WheelEmulation::new(&config));
+    let time = EventTime::default();
+
+    assert!(chain.process(KeyEvent::new(time, Key::ButtonMiddle, KeyState::PRESSED).into()).is_empty());
+    assert!(chain.process(RelativeEvent::new(time, RelativeAxis::Y, 3).into()).is_empty());
+    let wheel = chain.process(RelativeEvent::new(time, RelativeAxis::Y, 6).into());
+    match &wheel.iter().map(EventRef::new).collect::<Vec<_>>()[..] {
+        [Ok(EventRef::Relative(rel))] => assert_eq!((rel.axis, rel.value), (RelativeAxis::Wheel, -2)),
+        events => panic!("expected a wheel event, got {:?}", events),
+    }
+    assert!(chain.process(RelativeEvent::new(time, RelativeAxis::X, 10).into()).is_empty());
+    assert!(chain.process(KeyEvent::new(time, Key::ButtonMiddle, KeyState::RELEASED).into()).is_empty());
+
+    // a plain click still gets through
+    chain.process(KeyEvent::new(time, Key::ButtonMiddle, KeyState::PRESSED).into());
+    assert_eq!(chain.process(KeyEvent::new(time, Key::ButtonMiddle, KeyState::RELEASED).into()).len(), 3);
+}
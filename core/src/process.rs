@@ -0,0 +1,1614 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::pin::Pin;
+use std::path::{Path, PathBuf};
+//use futures::{future, Stream, Future, IntoFuture};
+use futures::{future, FutureExt, SinkExt, StreamExt, TryFutureExt};
+use futures::channel::mpsc as un_mpsc;
+use std::sync::{Mutex, Once};
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use anyhow::{Error, format_err};
+use config::keymap::{self, Keymaps};
+use config::{Config, ConfigEvent, ConfigHotkey, ConfigGrab, ConfigGrabMode, ConfigInputEvent, ConfigQemuRouting, ConfigQemuDriver, ConfigUInput, ConfigAbsolute, ConfigMouseMode, ConfigExit, ConfigUnknownEvent, ConfigGuestNotify, ConfigSounds, ConfigCompositor, ConfigQemuLaunch, ConfigVm, ConfigSwitchEvent, ConfigChannels, ConfigGuestReady, ConfigWheel, ConfigWheelAxis, ConfigHotkeyCondition};
+use qapi::qga::{guest_shutdown, GuestShutdownMode};
+use qapi::qmp::QKeyCode;
+use input::{self, InputEvent, RelativeAxis, InputId, Key, KeyEvent, KeyState, SwitchKind, SynchronizeEvent};
+use qemu::{Qemu, QemuCapabilities};
+use crate::filter::InputEventFilter;
+use crate::sources::{Sources, DdcRetry};
+use crate::route::{Route, RouteRegistry, RouteGate};
+use crate::grab::{GrabEvdev, Hotplug, find_devices};
+use crate::middleware::{InputChain, WheelEmulation};
+use crate::exec::exec;
+use x::{XRequest, XKeymapInfo};
+use event::{Hotkey, Destination, Wheel, WheelAxis, Condition};
+use crate::Events;
+use crate::spawner::Spawner;
+use crate::status::Status;
+use crate::history::{History, HistoryEntry};
+use crate::sounds::{Sounds, SoundCue};
+use crate::capture::{Capture, CaptureStage};
+use crate::unknown::UnknownEvents;
+use crate::util::LogId;
+use crate::channels::ChannelStats;
+use log::{trace, debug, info, warn, error};
+
+/// How often the host screensaver is reset while the guest is shown, well under any sane timeout
+const IDLE_HEARTBEAT: Duration = Duration::from_secs(30);
+
+pub fn convert_hotkey(hotkey: ConfigHotkey) -> (Hotkey<Arc<ConfigEvent>>, bool) {
+    (
+        Hotkey::new(hotkey.triggers, hotkey.modifiers, sequenced(hotkey.events).into_iter().map(Arc::new))
+            .with_sequence(hotkey.then, hotkey.within)
+            .with_hold(hotkey.hold)
+            .with_double_tap(hotkey.double_tap)
+            .with_exact(hotkey.exact)
+            .with_condition(hotkey.when.map(|when| match when {
+                ConfigHotkeyCondition::GuestShown => Condition::GuestShown,
+                ConfigHotkeyCondition::HostShown => Condition::HostShown,
+                ConfigHotkeyCondition::Grabbed => Condition::Grabbed,
+            })),
+        // how long it was held is only known on release
+        !hotkey.on_release && hotkey.hold.is_none(),
+    )
+}
+
+pub fn convert_wheel(wheel: &ConfigWheel) -> Wheel {
+    let axis = |axis: ConfigWheelAxis| WheelAxis { invert: axis.invert, scale: axis.scale };
+    Wheel {
+        vertical: axis(wheel.vertical),
+        horizontal: axis(wheel.horizontal),
+    }
+}
+
+pub fn convert_switch_event(switch: ConfigSwitchEvent) -> (SwitchKind, bool, Vec<Arc<ConfigEvent>>) {
+    (switch.switch, switch.on, sequenced(switch.events).into_iter().map(Arc::new).collect())
+}
+
+/// Events sent separately all run at once, so a list that waits has to stay together
+pub fn sequenced(events: Vec<ConfigEvent>) -> Vec<ConfigEvent> {
+    if events.iter().any(|e| matches!(e, ConfigEvent::Wait(_))) {
+        vec![ConfigEvent::Sequence(events)]
+    } else {
+        events
+    }
+}
+
+pub struct GrabHandle {
+    grab: Option<future::AbortHandle>,
+    x_filter: Vec<ConfigInputEvent>,
+    is_mouse: bool,
+}
+
+impl Drop for GrabHandle {
+    fn drop(&mut self) {
+        if let Some(grab) = self.grab.take() {
+            grab.abort();
+        }
+    }
+}
+
+pub struct Process {
+    routing: ConfigQemuRouting,
+    driver_keyboard: Arc<ConfigQemuDriver>,
+    pointer: Arc<PointerMode>,
+    exit_events: Mutex<Vec<ConfigEvent>>,
+    hotkeys: Mutex<Vec<ConfigHotkey>>,
+    exit: ConfigExit,
+    uinput: ConfigUInput,
+    qemu: Arc<Qemu>,
+    events: Arc<Events>,
+    sources: Arc<Pin<Box<Sources>>>,
+    grabs: Arc<Mutex<HashMap<ConfigGrabMode, GrabHandle>>>,
+    grab_lock: Arc<futures::lock::Mutex<()>>,
+    x_input_filter: Arc<InputEventFilter>,
+    xreq_sender: un_mpsc::Sender<XRequest>,
+    event_sender: un_mpsc::Sender<InputEvent>,
+    /// Where cursor syncs go, skipping the window's input filters
+    absolute_route: Option<un_mpsc::Sender<InputEvent>>,
+    /// Where typed text goes, skipping hotkeys and remapping
+    keyboard_route: Option<un_mpsc::Sender<InputEvent>>,
+    user_sender: un_mpsc::Sender<Arc<ConfigEvent>>,
+    error_sender: un_mpsc::Sender<Error>,
+    uinput_id: Arc<InputId>,
+    spawner: Arc<Spawner>,
+    history: Arc<History>,
+    unknown: UnknownEvents,
+    log_id: LogId,
+    guest_notify: Option<Arc<ConfigGuestNotify>>,
+    routes: Arc<RouteRegistry>,
+    gate: Arc<RouteGate>,
+    pause_on_host: bool,
+    /// Stands in for monitor switching with `ddc: none`
+    show_grab: Option<ConfigGrab>,
+    /// The config file and screen index that `ReloadConfig` reads
+    config_path: Option<(PathBuf, usize)>,
+    profile: Mutex<Option<String>>,
+    sounds: Arc<Sounds>,
+    compositor: Option<Arc<ConfigCompositor>>,
+    /// Whether the host screensaver is held off while the guest is shown
+    inhibit_idle: bool,
+    idle_heartbeat: Arc<AtomicBool>,
+    capture: Mutex<Option<Capture>>,
+    /// Saves taking the lock for every event while nothing is being captured
+    capturing: AtomicBool,
+    launch: Option<Arc<ConfigQemuLaunch>>,
+    vms: Vec<ConfigVm>,
+    current_vm: Arc<Mutex<Option<String>>>,
+    xwayland_compat: bool,
+    /// Stands in for full X grabs under Xwayland
+    xwayland_grab: Option<ConfigGrab>,
+    keymap_info: Option<Arc<Mutex<XKeymapInfo>>>,
+    channels: ConfigChannels,
+    channel_stats: Arc<ChannelStats>,
+    guest_ready: Option<ConfigGuestReady>,
+}
+
+/// Tracks which pointer device is currently plugged into the guest
+struct PointerMode {
+    qemu: Arc<Qemu>,
+    routing: ConfigQemuRouting,
+    driver_relative: ConfigQemuDriver,
+    driver_absolute: ConfigQemuDriver,
+    is_mouse: AtomicBool,
+    lock: futures::lock::Mutex<()>,
+}
+
+impl PointerMode {
+    fn is_mouse(&self) -> bool {
+        self.is_mouse.load(Ordering::Relaxed)
+    }
+
+    /// Swaps the guest device only when the mode changes, unless `force` is set
+    async fn set(&self, is_mouse: bool, force: bool) -> Result<(), Error> {
+        let _lock = self.lock.lock().await;
+        if !force && self.is_mouse() == is_mouse {
+            return Ok(())
+        }
+
+        let (device, driver) = if is_mouse {
+            (InputDevice::Relative, &self.driver_relative)
+        } else {
+            (InputDevice::Absolute, &self.driver_absolute)
+        };
+
+        Process::devices_init_cmd(self.qemu.clone(), self.routing, device, driver).await?;
+        self.is_mouse.store(is_mouse, Ordering::Relaxed);
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum InputDevice {
+    Keyboard,
+    Relative,
+    Absolute,
+}
+
+/// Parsed once rather than for every bit of typed text
+fn keymaps() -> Arc<Keymaps> {
+    unsafe {
+        static mut KEYMAPS: Option<Arc<Keymaps>> = None;
+        static KEYMAPS_ONCE: Once = Once::new();
+
+        KEYMAPS_ONCE.call_once(|| {
+            KEYMAPS = Some(Arc::new(Keymaps::from_csv()));
+        });
+        KEYMAPS.as_ref().unwrap().clone()
+    }
+}
+
+impl Process {
+    pub fn new(routing: ConfigQemuRouting, driver_keyboard: ConfigQemuDriver, driver_relative: ConfigQemuDriver, driver_absolute: ConfigQemuDriver, exit_events: Vec<ConfigEvent>, hotkeys: Vec<ConfigHotkey>, exit: ConfigExit, uinput: ConfigUInput, qemu: Arc<Qemu>, events: Arc<Events>, sources: Sources, xreq_sender: un_mpsc::Sender<XRequest>, event_sender: un_mpsc::Sender<InputEvent>, user_sender: un_mpsc::Sender<Arc<ConfigEvent>>, error_sender: un_mpsc::Sender<Error>, spawner: Arc<Spawner>, history_size: usize, unknown_events: HashMap<ConfigInputEvent, ConfigUnknownEvent>, log_id: LogId, guest_notify: Option<ConfigGuestNotify>, routes: Arc<RouteRegistry>, pause_on_host: bool, show_grab: Option<ConfigGrab>, sounds: Option<ConfigSounds>, compositor: Option<ConfigCompositor>, inhibit_idle: bool, launch: Option<ConfigQemuLaunch>, vms: Vec<ConfigVm>) -> Self {
+        let sources = Arc::new(Box::pin(sources));
+        let grabs: Arc<Mutex<HashMap<ConfigGrabMode, GrabHandle>>> = Default::default();
+        events.set_conditions({
+            let (sources, grabs) = (sources.clone(), grabs.clone());
+            move |when| match when {
+                Condition::GuestShown => sources.showing_guest() == Some(true),
+                Condition::HostShown => sources.showing_guest() == Some(false),
+                Condition::Grabbed => !grabs.lock().unwrap().is_empty(),
+            }
+        });
+
+        Process {
+            routing,
+            driver_keyboard: Arc::new(driver_keyboard),
+            pointer: Arc::new(PointerMode {
+                qemu: qemu.clone(),
+                routing,
+                driver_relative,
+                driver_absolute,
+                is_mouse: AtomicBool::new(false),
+                lock: Default::default(),
+            }),
+            exit_events: Mutex::new(exit_events),
+            hotkeys: Mutex::new(hotkeys),
+            exit,
+            uinput,
+            qemu,
+            events,
+            sources,
+            grabs,
+            grab_lock: Default::default(),
+            x_input_filter: Arc::new(InputEventFilter::empty()),
+            xreq_sender,
+            event_sender,
+            user_sender,
+            error_sender,
+            uinput_id: Arc::new(InputId {
+                bustype: input::sys::BUS_VIRTUAL,
+                vendor: 0x16c0,
+                product: 0x05df,
+                version: 1,
+            }),
+            spawner,
+            history: Arc::new(History::new(history_size)),
+            unknown: UnknownEvents::new(unknown_events),
+            sounds: Arc::new(Sounds::new(sounds, log_id.clone())),
+            log_id,
+            guest_notify: guest_notify.map(Arc::new),
+            routes,
+            gate: Default::default(),
+            pause_on_host,
+            show_grab,
+            config_path: None,
+            xwayland_compat: false,
+            channels: Default::default(),
+            channel_stats: Default::default(),
+            guest_ready: None,
+            xwayland_grab: None,
+            keymap_info: None,
+            absolute_route: None,
+            keyboard_route: None,
+            profile: Default::default(),
+            compositor: compositor.map(Arc::new),
+            inhibit_idle,
+            idle_heartbeat: Default::default(),
+            capture: Default::default(),
+            capturing: Default::default(),
+            launch: launch.map(Arc::new),
+            current_vm: Arc::new(Mutex::new(vms.first().map(|vm| vm.name.clone()))),
+            vms,
+        }
+    }
+
+    /// `profile` is the one the config was loaded with, if any
+    pub fn set_config_path(&mut self, path: PathBuf, screen: usize, profile: Option<String>) {
+        self.config_path = Some((path, screen));
+        *self.profile.get_mut().unwrap() = profile;
+    }
+
+    /// The X window is running under Xwayland, where grabs are unreliable
+    pub fn set_xwayland_compat(&mut self, grab: Option<ConfigGrab>) {
+        self.xwayland_compat = true;
+        self.xwayland_grab = grab;
+    }
+
+    pub fn set_absolute_route(&mut self, route: un_mpsc::Sender<InputEvent>) {
+        self.absolute_route = Some(route);
+    }
+
+    pub fn set_keyboard_route(&mut self, route: un_mpsc::Sender<InputEvent>) {
+        self.keyboard_route = Some(route);
+    }
+
+    pub fn set_keymap_info(&mut self, info: Arc<Mutex<XKeymapInfo>>) {
+        self.keymap_info = Some(info);
+    }
+
+    /// Buffer sizes for the routes and hotkey taps that evdev grabs start
+    pub fn set_channels(&mut self, channels: ConfigChannels) {
+        self.channels = channels;
+    }
+
+    /// Makes `show_guest` wait for the guest to pass `ready`'s check first
+    pub fn set_guest_ready(&mut self, ready: Option<ConfigGuestReady>) {
+        self.guest_ready = ready;
+    }
+
+    /// Counts of full channels, shared with the session's event loop
+    pub fn channel_stats(&self) -> &Arc<ChannelStats> {
+        &self.channel_stats
+    }
+
+    /// Swaps full X grabs for the configured Xwayland grab
+    fn compat_grab<'a>(&'a self, grab: &'a ConfigGrab) -> &'a ConfigGrab {
+        match (grab, &self.xwayland_grab) {
+            (&ConfigGrab::X { keyboard: true, pointer: true, .. }, Some(compat)) if self.xwayland_compat => compat,
+            _ => grab,
+        }
+    }
+
+    fn compat_grab_mode(&self, mode: ConfigGrabMode) -> ConfigGrabMode {
+        match &self.xwayland_grab {
+            Some(compat) if self.xwayland_compat && mode == ConfigGrabMode::X => compat.mode(),
+            _ => mode,
+        }
+    }
+
+    pub fn profile(&self) -> Option<String> {
+        self.profile.lock().unwrap().clone()
+    }
+
+    pub fn x_filter(&self) -> Arc<InputEventFilter> {
+        self.x_input_filter.clone()
+    }
+
+    /// Shared by every route, see `ConfigQemu::pause_on_host`
+    pub fn route_gate(&self) -> Arc<RouteGate> {
+        self.gate.clone()
+    }
+
+    pub fn history(&self) -> Arc<History> {
+        self.history.clone()
+    }
+
+    fn device_id(device: InputDevice) -> &'static str {
+        match device {
+            InputDevice::Keyboard => "screenstub-dev-kbd",
+            InputDevice::Relative => "screenstub-dev-mouse",
+            InputDevice::Absolute => "screenstub-dev-mouse",
+        }
+    }
+
+    /// Seen by the guest as the device's serial number (`uniq` on Linux), which
+    /// unlike enumeration order stays the same across reboots and mode switches
+    fn device_serial(device: InputDevice) -> &'static str {
+        match device {
+            InputDevice::Keyboard => "screenstub-kbd",
+            InputDevice::Relative => "screenstub-mouse",
+            InputDevice::Absolute => "screenstub-tablet",
+        }
+    }
+
+    fn add_device_cmd(device: InputDevice, driver: &ConfigQemuDriver) -> Option<qapi::qmp::device_add> {
+        let driver = match (device, driver) {
+            (InputDevice::Absolute, ConfigQemuDriver::Ps2) => panic!("PS/2 tablet not possible"),
+            (_, ConfigQemuDriver::Ps2) => return None,
+            (InputDevice::Keyboard, ConfigQemuDriver::Usb) => "usb-kbd",
+            (InputDevice::Relative, ConfigQemuDriver::Usb) => "usb-mouse",
+            (InputDevice::Absolute, ConfigQemuDriver::Usb) => "usb-tablet",
+            (InputDevice::Keyboard, ConfigQemuDriver::Virtio { .. }) => "virtio-keyboard-pci",
+            (InputDevice::Relative, ConfigQemuDriver::Virtio { .. }) => "virtio-mouse-pci",
+            (InputDevice::Absolute, ConfigQemuDriver::Virtio { .. }) => "virtio-tablet-pci",
+        };
+
+        let id = Self::device_id(device);
+        let serial = ("serial".into(), qapi::Any::String(Self::device_serial(device).into()));
+        Some(qapi::qmp::device_add::new(driver, Some(id.into()), None, vec![serial]))
+    }
+
+    async fn devices_init_cmd(qemu: Arc<Qemu>, routing: ConfigQemuRouting, device: InputDevice, driver: &ConfigQemuDriver) -> Result<(), Error> {
+        match routing {
+            ConfigQemuRouting::VirtioHost => return Ok(()),
+            _ => (),
+        };
+
+        if let Some(cmd) = Self::add_device_cmd(device, driver) {
+            qemu.device_add(cmd, tokio::time::Instant::now()).await
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Explains which parts of the config the running QEMU can't do
+    pub fn check_capabilities(&self, capabilities: &QemuCapabilities) -> Result<(), Error> {
+        let routing = match self.routing {
+            ConfigQemuRouting::InputLinux => Some("input-linux"),
+            ConfigQemuRouting::VirtioHost => Some("virtio-input-host-pci"),
+            _ => None,
+        };
+        if let Some(name) = routing {
+            if !capabilities.has_type(name) {
+                return Err(format_err!("routing: {} needs {}, which QEMU {} doesn't have", self.routing.key(), name, capabilities.version))
+            }
+        }
+
+        if !matches!(self.routing, ConfigQemuRouting::VirtioHost) {
+            let devices = [
+                (InputDevice::Keyboard, &*self.driver_keyboard),
+                (InputDevice::Relative, &self.pointer.driver_relative),
+                (InputDevice::Absolute, &self.pointer.driver_absolute),
+            ];
+            for &(device, driver) in &devices {
+                if let (InputDevice::Absolute, ConfigQemuDriver::Ps2) = (device, driver) {
+                    continue
+                }
+                if let Some(cmd) = Self::add_device_cmd(device, driver) {
+                    if !capabilities.has_type(&cmd.driver) {
+                        return Err(format_err!("{:?} driver {:?} needs the {} device, which QEMU {} doesn't have", device, driver, cmd.driver, capabilities.version))
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn devices_init(&self) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        let (qemu, routing) = (self.qemu.clone(), self.routing);
+        let (driver_keyboard, pointer) = (self.driver_keyboard.clone(), self.pointer.clone());
+        async move {
+            // independent devices, so their commands can be in flight together
+            future::try_join(
+                Self::devices_init_cmd(qemu, routing, InputDevice::Keyboard, &driver_keyboard),
+                pointer.set(false, true), // TODO: config option to start up in relative mode instead
+            ).await?;
+
+            Ok(())
+        }
+    }
+
+    /// Whether the VM could boot if it isn't running, so its devices may be missing at startup
+    pub fn can_launch(&self) -> bool {
+        self.launch.is_some()
+    }
+
+    fn start_vm(&self) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let (qemu, launch, log_id) = (self.qemu.clone(), self.launch.clone(), self.log_id.clone());
+        let devices = self.devices_init();
+        let show = self.show(false);
+        async move {
+            if qemu.connect_qmp().await.is_ok() {
+                info!("{} VM is already running", log_id);
+                return show.await
+            }
+
+            let launch = launch.ok_or_else(|| format_err!("start_vm needs qemu.launch to be configured"))?;
+            let args = launch.start_command()
+                .ok_or_else(|| format_err!("qemu.launch needs either exec or systemd_unit"))?;
+            info!("{} Starting VM with {:?}", log_id, args);
+            let run = exec(&args).into_future().boxed();
+            let wait = qemu.wait_qmp(launch.timeout).boxed();
+            match future::select(run, wait).await {
+                future::Either::Left((Err(e), _)) => return Err(e.context("failed to start the VM")),
+                future::Either::Left((Ok(()), wait)) => {
+                    wait.await?;
+                },
+                future::Either::Right((res, run)) => {
+                    // a launcher that runs for as long as the VM does is left to it
+                    tokio::spawn(async move {
+                        if let Err(e) = run.await {
+                            warn!("{} VM launcher failed: {:#}", log_id, e);
+                        }
+                    });
+                    res?;
+                },
+            }
+
+            devices.await?;
+            show.await
+        }.boxed()
+    }
+
+    /// The name of the VM that input goes to, out of `qemu.vms`
+    pub fn current_vm(&self) -> Option<String> {
+        self.current_vm.lock().unwrap().clone()
+    }
+
+    fn switch_vm(&self, name: &str) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let vm = match self.vms.iter().find(|vm| vm.name == name) {
+            Some(vm) => vm.clone(),
+            None => return future::err(format_err!("switch_vm: no VM named {}", name)).boxed(),
+        };
+        if self.current_vm().as_ref() == Some(&vm.name) {
+            return self.show(false)
+        }
+
+        // before `show` picks up the source to switch to
+        if let Some(source) = vm.guest_source.as_ref().and_then(|source| source.value()) {
+            self.sources.set_guest_source(Some(source));
+        }
+        let (qemu, current_vm, log_id) = (self.qemu.clone(), self.current_vm.clone(), self.log_id.clone());
+        let unstick = self.unstick_guest();
+        let devices = self.devices_init();
+        let show = self.show(false);
+        async move {
+            // the old VM shouldn't be left holding anything down
+            unstick.await?;
+            if let Err(e) = qemu.cleanup().await {
+                warn!("{} Failed to remove input devices from the previous VM: {:#}", log_id, e);
+            }
+            info!("{} Switching to VM {}", log_id, vm.name);
+            qemu.retarget(vm.qmp_socket, vm.ga_socket);
+            *current_vm.lock().unwrap() = Some(vm.name);
+
+            devices.await?;
+            show.await
+        }.boxed()
+    }
+
+    fn stop_vm(&self) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let show = self.show(true);
+        let stop = match self.launch.as_ref().and_then(|launch| launch.stop_command()) {
+            Some(args) => exec(args).into_future().boxed(),
+            None => {
+                let qemu = self.qemu.clone();
+                async move {
+                    qemu.execute_qmp(qapi::qmp::system_powerdown { }).await.map(drop)
+                }.boxed()
+            },
+        };
+        async move {
+            show.await?;
+            stop.await
+        }.boxed()
+    }
+
+    pub fn set_is_mouse(&self, is_mouse: bool) -> impl Future<Output=Result<(), Error>> {
+        let pointer = self.pointer.clone();
+        async move {
+            pointer.set(is_mouse, false).await
+        }
+    }
+
+    fn grab(&self, grab: &ConfigGrab) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let grab = self.compat_grab(grab);
+        let mode = grab.mode();
+        let grab = self.grab_(grab);
+        let (grab_lock, grabs) = (self.grab_lock.clone(), self.grabs.clone());
+        let log_id = self.log_id.clone();
+        async move {
+            let _lock = grab_lock.lock().await;
+            if grabs.lock().unwrap().contains_key(&mode) {
+                info!("{} {:?} is already grabbed", log_id, mode);
+                Ok(())
+            } else {
+                grab.await
+            }
+        }.boxed()
+    }
+
+    fn ungrab(&self, mode: ConfigGrabMode) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let ungrab = self.ungrab_(self.compat_grab_mode(mode));
+        let grab_lock = self.grab_lock.clone();
+        async move {
+            let _lock = grab_lock.lock().await;
+            ungrab.await
+        }.boxed()
+    }
+
+    fn toggle_grab(&self, grab: &ConfigGrab) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let grab = self.compat_grab(grab);
+        let mode = grab.mode();
+        let (grab, ungrab) = (self.grab_(grab), self.ungrab_(mode));
+        let (grab_lock, grabs) = (self.grab_lock.clone(), self.grabs.clone());
+        async move {
+            // decide only once any in-flight transition has settled
+            let _lock = grab_lock.lock().await;
+            let grabbed = grabs.lock().unwrap().contains_key(&mode);
+            if grabbed {
+                ungrab.await
+            } else {
+                grab.await
+            }
+        }.boxed()
+    }
+
+    /// Grab and ungrab futures have no effect until polled, and must only be
+    /// polled while holding `grab_lock`
+    fn grab_(&self, grab: &ConfigGrab) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let mode = grab.mode();
+
+        match *grab {
+            ConfigGrab::X { confine, mouse, keyboard, pointer: grab_pointer, ref ignore, ref devices } => {
+                let grabs = self.grabs.clone();
+                let pointer = self.pointer.clone();
+                // Xwayland can't reliably confine the cursor for relative
+                // motion, so the guest gets an absolute pointer that follows it
+                let mouse = mouse && grab_pointer && !self.xwayland_compat;
+                // whatever stays on the host mustn't reach the guest through the window either
+                let mut ignore = ignore.clone();
+                if !keyboard {
+                    ignore.push(ConfigInputEvent::Key);
+                }
+                if !grab_pointer {
+                    ignore.extend(&[ConfigInputEvent::Button, ConfigInputEvent::Relative, ConfigInputEvent::Absolute]);
+                }
+                let x_filter = self.x_input_filter.clone();
+                let history = self.history.clone();
+                let sounds = self.sounds.clone();
+                let update_title = self.title_updater();
+
+                let grab = self.xreq(XRequest::Grab {
+                    xcore: confine,
+                    confine,
+                    motion: mouse,
+                    keyboard,
+                    pointer: grab_pointer,
+                    devices: devices.iter().map(|_| unimplemented!()).collect(),
+                });
+                let sync_cursor = self.sync_cursor();
+                async move {
+                    grab.await?;
+
+                    x_filter.set_filter(ignore.iter().cloned());
+
+                    grabs.lock().unwrap().insert(mode, GrabHandle {
+                        grab: None,
+                        x_filter: ignore,
+                        is_mouse: mouse,
+                    });
+                    history.record(HistoryEntry::Grab(mode));
+                    sounds.cue(SoundCue::Grab);
+                    update_title();
+
+                    if mouse && !pointer.is_mouse() {
+                        sync_cursor.await?;
+                        pointer.set(true, false).await?;
+                    }
+
+                    Ok(())
+                }.boxed()
+            },
+            ConfigGrab::Evdev { exclusive, ref new_device_name, ref xcore_ignore, ref evdev_ignore, ref wheel_emulation, ref devices, ref matches, hotplug } => {
+                let qemu = self.qemu.clone();
+                let grabs = self.grabs.clone();
+                let x_filter = self.x_input_filter.clone();
+                let mut xcore_ignore = xcore_ignore.clone();
+                let devname = new_device_name.clone();
+                let error_sender = self.error_sender.clone();
+                let event_sender = if new_device_name.is_some() { None } else { Some(self.event_sender.clone()) };
+                let routing = self.routing;
+                let uinput_id = self.uinput_id.clone();
+                let pointer = self.pointer.clone();
+                let sync_cursor = self.sync_cursor();
+                let spawner = self.spawner.clone();
+                let history = self.history.clone();
+                let uinput_config = self.uinput.clone();
+                let (devices, matches) = (devices.clone(), matches.clone());
+                let evdev_ignore = evdev_ignore.clone();
+                let wheel_emulation = wheel_emulation.clone();
+                let routes = self.routes.clone();
+                let gate = self.gate.clone();
+                let channels = self.channels;
+                let log_id = self.log_id.clone();
+                let (events, user_sender) = (self.events.clone(), self.user_sender.clone());
+                let sounds = self.sounds.clone();
+                let update_title = self.title_updater();
+
+                async move {
+                    let configured: Vec<PathBuf> = devices.into_iter().map(PathBuf::from).collect();
+                    let mut devices = configured.clone();
+                    if !matches.is_empty() {
+                        for path in find_devices(&matches, Some(&*uinput_id), hotplug)? {
+                            if !devices.contains(&path) {
+                                devices.push(path);
+                            }
+                        }
+                    }
+                    let mut chain = move |path: &Path| {
+                        let mut chain = InputChain::new();
+                        match wheel_emulation {
+                            Some(ref wheel) if wheel.devices.is_empty() || wheel.devices.iter().any(|dev| Path::new(dev) == path) =>
+                                chain.push(WheelEmulation::new(wheel)),
+                            _ => (),
+                        }
+                        chain
+                    };
+                    let mut grab = GrabEvdev::new(&devices, evdev_ignore, &mut chain)?;
+                    if !exclusive {
+                        // the window would pass the same input along a second time
+                        let overlap = grab.x_overlap()?;
+                        for class in overlap.iter().filter(|class| !xcore_ignore.contains(class)) {
+                            debug!("{} evdev grab owns {:?} events, ignoring them from the window", log_id, class);
+                        }
+                        xcore_ignore.extend(overlap);
+                        xcore_ignore.sort();
+                        xcore_ignore.dedup();
+                    }
+                    let event_sender = if let Some(devname) = devname {
+                        let id = format!("screenstub-uinput-{}", devname);
+                        let repeat = false;
+                        let bus = None;
+                        let qemu = qemu.clone();
+                        let mut uinput = Route::new(&routes, routing, qemu, id, bus, repeat, uinput_config, ConfigAbsolute::default(), None, &log_id)?;
+
+                        let mut builder = uinput.builder();
+
+                        if let Some(builder) = builder.as_mut() {
+                            builder.name(&devname);
+                            builder.id(&uinput_id);
+                        }
+
+                        for evdev in grab.evdevs() {
+                            if let Some(builder) = builder.as_mut() {
+                                builder.from_evdev(&evdev)?;
+                            }
+                        }
+
+                        if hotplug {
+                            if let Some(builder) = builder.as_mut() {
+                                // devices plugged in later can't add capabilities after the fact
+                                builder.x_config_key(false).x_config_rel();
+                            }
+                        }
+
+                        if exclusive {
+                            grab.grab(true)?;
+                        }
+
+                        let uinput = uinput.spawn(&spawner, gate, channels.route, error_sender.clone());
+                        if exclusive || !xcore_ignore.is_empty() {
+                            Self::hotkey_tap(events, user_sender, uinput, exclusive, &xcore_ignore, channels.input)
+                        } else {
+                            uinput
+                        }
+                    } else {
+                        event_sender.unwrap()
+                    };
+
+                    let mut is_mouse = false;
+                    for evdev in grab.evdevs() {
+                        let rel = evdev.relative_bits()?;
+                        if rel.get(RelativeAxis::X) || rel.get(RelativeAxis::Y) {
+                            is_mouse = true;
+                            break
+                        }
+                    }
+
+                    let hotplug = Hotplug {
+                        devices: configured,
+                        matches,
+                        new_devices: hotplug,
+                        own: *uinput_id,
+                        chain: Box::new(chain),
+                    };
+                    let grab = grab.spawn(event_sender, error_sender, Some(hotplug));
+
+                    x_filter.set_filter(xcore_ignore.iter().cloned());
+
+                    grabs.lock().unwrap().insert(mode, GrabHandle {
+                        grab: Some(grab),
+                        x_filter: xcore_ignore,
+                        is_mouse,
+                    });
+                    history.record(HistoryEntry::Grab(mode));
+                    sounds.cue(SoundCue::Grab);
+                    update_title();
+
+                    if is_mouse && !pointer.is_mouse() {
+                        sync_cursor.await?;
+                        pointer.set(true, false).await?;
+                    }
+                    Ok(())
+                }.boxed()
+            },
+            _ => future::err(format_err!("grab {:?} unimplemented", mode)).boxed(),
+        }
+    }
+
+    /// Runs hotkeys on grabbed input that goes straight to its own device, for
+    /// anything the X window won't see. Otherwise nothing would notice the
+    /// hotkey that ungrabs, or the release of keys pressed before grabbing.
+    fn hotkey_tap(events: Arc<Events>, mut user_sender: un_mpsc::Sender<Arc<ConfigEvent>>, mut sink: un_mpsc::Sender<InputEvent>, exclusive: bool, xcore_ignore: &[ConfigInputEvent], capacity: usize) -> un_mpsc::Sender<InputEvent> {
+        let ignored = InputEventFilter::new(xcore_ignore.iter().cloned());
+        let (sender, mut receiver) = un_mpsc::channel(capacity);
+        tokio::spawn(async move {
+            while let Some(e) = receiver.next().await {
+                if exclusive || !ignored.filter_event(&e) {
+                    for event in events.process_input_event(&e) {
+                        let _ = user_sender.send(event).await;
+                    }
+                }
+                if sink.send(e).await.is_err() {
+                    break
+                }
+            }
+        });
+        sender
+    }
+
+    pub fn is_mouse(&self) -> bool {
+        self.pointer.is_mouse()
+    }
+
+    pub fn showing_guest(&self) -> Option<bool> {
+        self.sources.showing_guest()
+    }
+
+    /// Adds `event` to the input capture, if one is running
+    pub fn capture(&self, stage: CaptureStage, event: &InputEvent) {
+        if !self.capturing.load(Ordering::Relaxed) {
+            return
+        }
+
+        let mut capture = self.capture.lock().unwrap();
+        let res = match *capture {
+            Some(ref mut c) => c.record(stage, event),
+            None => return,
+        };
+        let res = match res {
+            Ok(true) => return,
+            Ok(false) => capture.take().map(Capture::finish).unwrap_or(Ok(())),
+            Err(e) => {
+                capture.take();
+                Err(e)
+            },
+        };
+        self.capturing.store(false, Ordering::Relaxed);
+        match res {
+            Ok(()) => info!("{} Input capture finished", self.log_id),
+            Err(e) => warn!("{} Input capture failed: {}", self.log_id, e),
+        }
+    }
+
+    fn grabs_mouse(grabs: &Mutex<HashMap<ConfigGrabMode, GrabHandle>>) -> bool {
+        grabs.lock().unwrap().iter().any(|(_, g)| g.is_mouse)
+    }
+
+    pub fn status(&self) -> Status {
+        let mut grabs: Vec<_> = self.grabs.lock().unwrap().keys().cloned().collect();
+        grabs.sort();
+        Status {
+            showing_guest: self.sources.showing_guest(),
+            source: self.sources.current_source(),
+            source_name: self.sources.current_source()
+                .and_then(|s| self.sources.source_name(s))
+                .map(ToOwned::to_owned),
+            grabs,
+            profile: self.profile(),
+            is_mouse: self.is_mouse(),
+            routing: self.routing,
+            routes_paused: self.gate.is_paused(),
+            vm: self.current_vm(),
+            qmp_connected: self.qemu.qmp_connected(),
+            qemu_capabilities: self.qemu.probed_capabilities(),
+            recent_events: self.history.latest(8),
+            unknown_events: self.unknown.counts(),
+            switches: self.events.switch_states(),
+            saturated: self.channel_stats.counts(),
+            keymap: self.keymap_info.as_ref().map(|info| *info.lock().unwrap()),
+        }
+    }
+
+    pub fn hotkeys(&self) -> Vec<ConfigHotkey> {
+        self.hotkeys.lock().unwrap().clone()
+    }
+
+    pub fn add_hotkey(&self, hotkey: ConfigHotkey) {
+        let mut hotkeys = self.hotkeys.lock().unwrap();
+        hotkeys.push(hotkey);
+        self.events.set_hotkeys(hotkeys.iter().cloned().map(convert_hotkey));
+    }
+
+    pub fn remove_hotkey(&self, index: usize) -> Result<ConfigHotkey, Error> {
+        let mut hotkeys = self.hotkeys.lock().unwrap();
+        if index >= hotkeys.len() {
+            return Err(format_err!("no hotkey {}, there are {}", index, hotkeys.len()))
+        }
+        let hotkey = hotkeys.remove(index);
+        self.events.set_hotkeys(hotkeys.iter().cloned().map(convert_hotkey));
+        Ok(hotkey)
+    }
+
+    /// Applies hotkeys, remaps, exit events and DDC methods from a new config,
+    /// leaving devices, connections and windows alone
+    pub fn reload(&self, mut config: Config, screen: usize) -> Result<(), Error> {
+        let screen = config.screens.drain(..).nth(screen)
+            .ok_or_else(|| format_err!("no screen {} in the config", screen))?;
+
+        {
+            let mut hotkeys = self.hotkeys.lock().unwrap();
+            *hotkeys = config.hotkeys;
+            self.events.set_hotkeys(hotkeys.iter().cloned().map(convert_hotkey));
+        }
+        self.events.set_remaps(
+            config.key_remap.into_iter().map(|(from, to)| (Destination::Guest, from, to))
+            .chain(config.host_key_remap.into_iter().map(|(from, to)| (Destination::Host, from, to)))
+        );
+        self.events.set_wheel(Destination::Guest, convert_wheel(&config.wheel));
+        self.events.set_wheel(Destination::Host, convert_wheel(&config.host_wheel));
+        self.events.set_sticky_keys(config.sticky_keys);
+        self.events.set_switch_events(config.switch_events.into_iter().map(convert_switch_event));
+        *self.exit_events.lock().unwrap() = config.exit_events;
+        self.sounds.set(config.sounds);
+
+        if config.qemu.routing.key() != self.routing.key() {
+            warn!("{} Changing qemu.routing needs a restart", self.log_id);
+        }
+
+        let ddc = screen.ddc.unwrap_or_default();
+        if ddc.is_none() != self.show_grab.is_some() {
+            warn!("{} Changing to or from ddc: none needs a restart", self.log_id);
+        } else if !ddc.is_none() {
+            self.sources.set_retry(DdcRetry::from_config(&ddc));
+            self.sources.set_methods(ddc.host, ddc.guest);
+        }
+
+        info!("{} Reloaded config", self.log_id);
+        Ok(())
+    }
+
+    /// Reads the config file again, with `profile` applied, returning the
+    /// profile's events
+    fn reload_config(&self, profile: Option<String>) -> Result<Vec<ConfigEvent>, Error> {
+        let (path, screen) = self.config_path.as_ref()
+            .ok_or_else(|| format_err!("no config file to reload"))?;
+        let mut config = Config::load_profile(path, profile.as_ref().map(|s| &s[..]))
+            .map_err(|e| e.context(format!("failed to read {}", path.display())))?;
+        let events = profile.as_ref()
+            .and_then(|name| config.profiles.remove(name))
+            .map(|profile| profile.events)
+            .unwrap_or_default();
+        self.reload(config, *screen)?;
+        *self.profile.lock().unwrap() = profile;
+        Ok(events)
+    }
+
+    fn switch_profile(&self, name: &str) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let events = match self.reload_config(Some(name.into())) {
+            Ok(events) => events,
+            Err(e) => return future::err(e).boxed(),
+        };
+        info!("{} Switched to profile {}", self.log_id, name);
+        let events: Vec<_> = events.iter()
+            .map(|e| self.process_user_event(e))
+            .collect();
+        async move {
+            for event in events {
+                event.await?;
+            }
+            Ok(())
+        }.boxed()
+    }
+
+    /// Runs `events` in order. Anything after a wait is sent back through the
+    /// user events once it's over, so that it sees the state as it is by then.
+    fn sequence(&self, events: &[ConfigEvent]) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let wait = events.iter().position(|e| matches!(e, ConfigEvent::Wait(_)));
+        let (now, rest) = events.split_at(wait.unwrap_or(events.len()));
+        let now: Vec<_> = now.iter()
+            .map(|e| self.process_user_event(e))
+            .collect();
+        let rest = match rest.split_first() {
+            Some((&ConfigEvent::Wait(duration), rest)) => Some((duration, rest.to_vec())),
+            _ => None,
+        };
+        let mut user_sender = self.user_sender.clone();
+        async move {
+            for event in now {
+                event.await?;
+            }
+            if let Some((duration, rest)) = rest {
+                tokio::time::sleep(duration).await;
+                if !rest.is_empty() {
+                    user_sender.send(Arc::new(ConfigEvent::Sequence(rest))).await?;
+                }
+            }
+            Ok(())
+        }.boxed()
+    }
+
+    pub fn exit_events(&self) -> Vec<ConfigEvent> {
+        self.exit_events.lock().unwrap().clone()
+    }
+
+    pub fn add_exit_event(&self, event: ConfigEvent) {
+        self.exit_events.lock().unwrap().push(event);
+    }
+
+    pub fn remove_exit_event(&self, index: usize) -> Result<ConfigEvent, Error> {
+        let mut events = self.exit_events.lock().unwrap();
+        if index >= events.len() {
+            return Err(format_err!("no exit event {}, there are {}", index, events.len()))
+        }
+        Ok(events.remove(index))
+    }
+
+    pub fn unknown_events(&self) -> &UnknownEvents {
+        &self.unknown
+    }
+
+    fn ungrab_(&self, mode: ConfigGrabMode) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let grabs = self.grabs.clone();
+        let x_filter = self.x_input_filter.clone();
+        let history = self.history.clone();
+        let set_is_mouse = self.set_is_mouse(false);
+        let ungrab = match mode {
+            ConfigGrabMode::X => Some(self.xreq(XRequest::Ungrab)),
+            ConfigGrabMode::Evdev => None,
+        };
+        // releases for anything held now will go to the host instead
+        let unstick = self.unstick_guest();
+        let log_id = self.log_id.clone();
+        let sounds = self.sounds.clone();
+        let update_title = self.title_updater();
+        async move {
+            let grab = grabs.lock().unwrap().remove(&mode);
+            if let Some(mut grab) = grab {
+                history.record(HistoryEntry::Ungrab(mode));
+                sounds.cue(SoundCue::Ungrab);
+                update_title();
+                x_filter.unset_filter(grab.x_filter.drain(..));
+                if grab.is_mouse && !Self::grabs_mouse(&grabs) {
+                    set_is_mouse.await?;
+                }
+            } else if ungrab.is_none() {
+                info!("{} requested non-existent grab", log_id);
+            }
+
+            unstick.await?;
+
+            match ungrab {
+                Some(ungrab) => ungrab.await,
+                None => Ok(()),
+            }
+        }.boxed()
+    }
+
+    /// Releases every key the guest is believed to be holding
+    fn unstick_guest(&self) -> impl Future<Output=Result<(), Error>> {
+        let mut event_sender = self.event_sender.clone();
+        let events = self.events.clone();
+        async move {
+            for e in events.unstick_guest() {
+                let _ = event_sender.send(e).await;
+            }
+
+            Ok(())
+        }
+    }
+
+    /// Moves the guest cursor to where the host cursor is, before the tablet
+    /// is swapped out for a relative mouse and the guest cursor stops following along
+    fn sync_cursor(&self) -> impl Future<Output=Result<(), Error>> {
+        let (reply, mut position) = un_mpsc::unbounded();
+        let sync = self.xreq(XRequest::SyncCursor(reply));
+        let route = self.absolute_route.clone();
+        async move {
+            sync.await?;
+            let events = position.next().await.unwrap_or_default();
+            if let Some(mut route) = route {
+                for e in events {
+                    route.send(e).await?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn text_keys(text: &str) -> Result<Vec<(Key, bool)>, Error> {
+        let keymaps = keymaps();
+        text.chars().map(|c| keymaps.char_key(c)
+            .ok_or_else(|| format_err!("no key to type {:?}", c))
+        ).collect()
+    }
+
+    fn type_key_events(key: Key, shift: bool) -> impl Iterator<Item=InputEvent> {
+        let time = Default::default();
+        let sync = || SynchronizeEvent::report(time).into();
+        let mut events = Vec::with_capacity(6);
+        if shift {
+            events.push(KeyEvent::new(time, Key::LeftShift, KeyState::PRESSED).into());
+        }
+        events.push(KeyEvent::new(time, key, KeyState::PRESSED).into());
+        events.push(sync());
+        events.push(KeyEvent::new(time, key, KeyState::RELEASED).into());
+        if shift {
+            events.push(KeyEvent::new(time, Key::LeftShift, KeyState::RELEASED).into());
+        }
+        events.push(sync());
+        events.into_iter()
+    }
+
+    /// Presses `keys` in order and releases them in reverse, so modifiers
+    /// surround the key they modify
+    fn chord_events(keys: &[Key]) -> Vec<InputEvent> {
+        let time = Default::default();
+        let sync = || SynchronizeEvent::report(time).into();
+        keys.iter().map(|&key| KeyEvent::new(time, key, KeyState::PRESSED).into())
+            .chain(Some(sync()))
+            .chain(keys.iter().rev().map(|&key| KeyEvent::new(time, key, KeyState::RELEASED).into()))
+            .chain(Some(sync()))
+            .collect()
+    }
+
+    async fn send_events(mut sender: un_mpsc::Sender<InputEvent>, events: Vec<InputEvent>) -> Result<(), Error> {
+        for e in events {
+            sender.send(e).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Alt+SysRq+`key`, for a Linux guest's magic SysRq
+    fn sysrq_keys(key: char) -> Result<Vec<QKeyCode>, Error> {
+        let key = keymap::qkeycode(&key.to_string().to_ascii_lowercase())
+            .filter(|_| key.is_ascii_alphanumeric())
+            .ok_or_else(|| format_err!("no SysRq key for {:?}", key))?;
+        Ok(vec![QKeyCode::alt, QKeyCode::sysrq, key])
+    }
+
+    /// Presses `keys` together with QMP `send-key`, which reaches the guest
+    /// whatever input route, grab, or remapping is in effect
+    fn send_keys_qmp(&self, keys: Result<Vec<QKeyCode>, Error>) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let qemu = self.qemu.clone();
+        async move {
+            let cmd = qapi::qmp::send_key {
+                keys: keys?.into_iter().map(|key| qapi::qmp::KeyValue::qcode(key.into())).collect(),
+                hold_time: None,
+            };
+            qemu.execute_qmp(cmd).await.map(drop)
+        }.boxed()
+    }
+
+    /// What's shown and whether anything is grabbed, for panels to display
+    fn title(showing_guest: Option<bool>, grabbed: bool) -> String {
+        let showing = match showing_guest {
+            Some(true) => "guest",
+            Some(false) => "host",
+            None => "unknown",
+        };
+        format!("screenstub — {}{}", showing, if grabbed { ", grabbed" } else { "" })
+    }
+
+    /// Returns a callback that updates the window title from the current state
+    fn title_updater(&self) -> impl Fn() + Send + 'static {
+        let (xreq_sender, sources, grabs) = (self.xreq_sender.clone(), self.sources.clone(), self.grabs.clone());
+        move || {
+            let title = Self::title(sources.showing_guest(), !grabs.lock().unwrap().is_empty());
+            // only cosmetic, so skip it rather than wait on a backed up window
+            let _ = xreq_sender.clone().try_send(XRequest::SetTitle(title));
+        }
+    }
+
+    fn xreq(&self, req: XRequest) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let mut xreq_sender = self.xreq_sender.clone();
+        async move {
+            xreq_sender.send(req)
+                .map_err(From::from).await
+        }.boxed()
+    }
+
+    /// Fills in `{SHOWING}`, `{GUEST_SOURCE}`, `{HOST_SOURCE}` and `{GRABBED}` from the current state
+    fn map_exec_arg<S: AsRef<str>>(&self, s: S) -> Result<String, Error> {
+        let s = s.as_ref();
+        if !s.contains('{') {
+            return Ok(s.into())
+        }
+
+        let showing = match self.sources.showing_guest() {
+            Some(true) => Some("guest"),
+            Some(false) => Some("host"),
+            None => None,
+        };
+        let vars = [
+            ("SHOWING", showing.map(ToOwned::to_owned)),
+            ("GUEST_SOURCE", self.sources.guest_source().map(|s| s.to_string())),
+            ("HOST_SOURCE", self.sources.host_source().map(|s| s.to_string())),
+            ("GRABBED", Some((!self.grabs.lock().unwrap().is_empty()).to_string())),
+        ];
+        substitute(s, &vars)
+    }
+
+    pub fn process_user_event(&self, event: &ConfigEvent) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let id = self.history.begin(HistoryEntry::Event(event.clone()));
+        let history = self.history.clone();
+        let res = self.process_user_event_(event);
+        async move {
+            let res = res.await;
+            history.complete(id, &res);
+            res
+        }.boxed()
+    }
+
+    fn show(&self, host: bool) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let sources = self.sources.clone();
+        let history = self.history.clone();
+        let prev = sources.showing_guest();
+        let ready = match &self.guest_ready {
+            Some(ready) if !host && prev != Some(true) =>
+                Some(Self::wait_guest_ready(self.qemu.clone(), ready.clone(), self.xreq_sender.clone(), self.log_id.clone())),
+            _ => None,
+        };
+        let show = if host {
+            sources.show_host().boxed()
+        } else {
+            sources.show_guest().boxed()
+        };
+        let (qemu, notify, log_id) = (self.qemu.clone(), self.guest_notify.clone(), self.log_id.clone());
+        let gate = if self.pause_on_host { Some(self.gate.clone()) } else { None };
+        let sounds = self.sounds.clone();
+        let update_title = self.title_updater();
+        let compositor = self.compositor.clone();
+        let inhibit_idle = if self.inhibit_idle {
+            Some((self.xreq_sender.clone(), self.idle_heartbeat.clone()))
+        } else {
+            None
+        };
+        let grab = self.show_grab.as_ref().map(|grab| if host {
+            self.ungrab(grab.mode())
+        } else {
+            self.grab(grab)
+        });
+        async move {
+            if let Some(ready) = ready {
+                if let Err(e) = ready.await {
+                    update_title();
+                    return Err(e)
+                }
+            }
+            show.await?;
+            if let Some(grab) = grab {
+                grab.await?;
+            }
+            match (sources.showing_guest(), gate) {
+                (Some(true), Some(gate)) => gate.resume(),
+                (Some(false), Some(gate)) => gate.pause(),
+                _ => (),
+            }
+            match sources.showing_guest() {
+                Some(guest) if Some(guest) != prev => {
+                    history.record(HistoryEntry::Showing { guest });
+                    sounds.cue(if guest { SoundCue::ShowGuest } else { SoundCue::ShowHost });
+                    update_title();
+                    if let Some(compositor) = compositor {
+                        Self::pause_compositor(&compositor, guest, log_id.clone());
+                    }
+                    if let Some((xreq_sender, heartbeat)) = inhibit_idle {
+                        Self::inhibit_idle(xreq_sender, sources.clone(), heartbeat, guest);
+                    }
+                    if let Some(notify) = notify {
+                        Self::notify_guest(qemu, &notify, guest, log_id);
+                    }
+                },
+                _ => (),
+            }
+            Ok(())
+        }.boxed()
+    }
+
+    /// Checks on the guest until it's ready to be shown, counting the wait in the window title
+    async fn wait_guest_ready(qemu: Arc<Qemu>, ready: ConfigGuestReady, mut xreq_sender: un_mpsc::Sender<XRequest>, log_id: LogId) -> Result<(), Error> {
+        let start = Instant::now();
+        loop {
+            let attempt = Instant::now();
+            let check = async {
+                if ready.exec.is_empty() {
+                    return qemu.guest_wait().await
+                }
+                let status = qemu.guest_exec(ready.exec.iter().cloned()).into_future().await?;
+                match status.exitcode {
+                    Some(0) => Ok(()),
+                    code => Err(format_err!("{:?} exited with {:?}", ready.exec, code)),
+                }
+            };
+            let res = match tokio::time::timeout(ready.interval, check).await {
+                Ok(res) => res,
+                Err(_) => Err(format_err!("no answer within {:?}", ready.interval)),
+            };
+
+            let waited = start.elapsed();
+            match res {
+                Ok(()) => {
+                    if waited > ready.interval {
+                        info!("{} Guest ready after {}s", log_id, waited.as_secs());
+                    }
+                    return Ok(())
+                },
+                Err(e) if waited >= ready.timeout =>
+                    return Err(e.context(format!("guest still not ready after {:?}", ready.timeout))),
+                Err(e) => {
+                    debug!("{} Guest not ready yet: {:#}", log_id, e);
+                    // only cosmetic, like the usual title
+                    let _ = xreq_sender.try_send(XRequest::SetTitle(format!("screenstub — waiting for guest ({}s)", waited.as_secs())));
+                },
+            }
+            tokio::time::sleep_until((attempt + ready.interval).into()).await;
+        }
+    }
+
+    /// Shows a message in the guest in the background, ignoring failures
+    fn notify_guest(qemu: Arc<Qemu>, notify: &ConfigGuestNotify, guest: bool, log_id: LogId) {
+        let message = match if guest { &notify.show_guest } else { &notify.show_host } {
+            Some(message) => message,
+            None => return,
+        };
+        let args = match notify.command(message) {
+            Some(args) => args,
+            None => {
+                warn!("{} guest_notify needs a known template or an exec command line", log_id);
+                return
+            },
+        };
+        tokio::spawn(async move {
+            if let Err(e) = qemu.guest_exec(args).into_future().await {
+                warn!("{} Failed to notify guest: {:#}", log_id, e);
+            }
+        });
+    }
+
+    /// Keeps the host screensaver away for as long as the guest stays shown
+    fn inhibit_idle(mut xreq_sender: un_mpsc::Sender<XRequest>, sources: Arc<Pin<Box<Sources>>>, heartbeat: Arc<AtomicBool>, guest: bool) {
+        if !guest {
+            let _ = xreq_sender.try_send(XRequest::InhibitIdle(false));
+            return
+        }
+        if heartbeat.swap(true, Ordering::SeqCst) {
+            // still running from the last time the guest was shown
+            return
+        }
+        tokio::spawn(async move {
+            while sources.showing_guest() == Some(true) {
+                if xreq_sender.send(XRequest::InhibitIdle(true)).await.is_err() {
+                    break
+                }
+                tokio::time::sleep(IDLE_HEARTBEAT).await;
+            }
+            heartbeat.store(false, Ordering::SeqCst);
+        });
+    }
+
+    /// Pauses the host compositor while the guest is shown, resuming it after
+    fn pause_compositor(compositor: &ConfigCompositor, guest: bool, log_id: LogId) {
+        // picom names its bus after the display, with anything but letters and digits replaced
+        let display: String = env::var("DISPLAY").unwrap_or_default().chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let args = match compositor.command(guest, &display) {
+            Some(args) if args.is_empty() => return,
+            Some(args) => args,
+            None => {
+                warn!("{} compositor has an unknown template", log_id);
+                return
+            },
+        };
+        tokio::spawn(async move {
+            if let Err(e) = exec(args).into_future().await {
+                warn!("{} Failed to {} the compositor: {:#}", log_id, if guest { "pause" } else { "resume" }, e);
+            }
+        });
+    }
+
+    async fn exit_event(log_id: LogId, name: String, event: Pin<Box<dyn Future<Output=Result<(), Error>> + Send>>, timeout: Duration) -> Option<String> {
+        match tokio::time::timeout(timeout, event).await {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => {
+                error!("{} Failed to run exit event: {} {:?}", log_id, e, e);
+                Some(format!("{} failed: {}", name, e))
+            },
+            Err(_) => Some(format!("{} timed out after {:?}", name, timeout)),
+        }
+    }
+
+    fn process_user_event_(&self, event: &ConfigEvent) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        trace!("{} process_user_event({:?})", self.log_id, event);
+        info!("{} User event {:?}", self.log_id, event);
+        match event {
+            ConfigEvent::Exec(args) => {
+                let args = args.iter()
+                    .map(|i| self.map_exec_arg(i))
+                    .collect::<Result<Vec<_>, Error>>();
+                match args {
+                    Err(e) => future::ready(Err(e)).boxed(),
+                    Ok(args) => exec(args).into_future().boxed(),
+                }
+            },
+            ConfigEvent::GuestExec(args) => {
+                let args = args.iter()
+                    .map(|i| self.map_exec_arg(i))
+                    .collect::<Result<Vec<_>, Error>>();
+                match args {
+                    Err(e) => future::ready(Err(e)).boxed(),
+                    Ok(args) => self.qemu.guest_exec(args).into_future().map_ok(drop).boxed(),
+                }
+            },
+            ConfigEvent::GuestExecNowait(args) => {
+                let args = args.iter()
+                    .map(|i| self.map_exec_arg(i))
+                    .collect::<Result<Vec<_>, Error>>();
+                let log_id = self.log_id.clone();
+                match args {
+                    Err(e) => future::ready(Err(e)).boxed(),
+                    Ok(args) => self.qemu.guest_exec(args).detached()
+                        .map_ok(move |pid| debug!("{} Started guest process {}", log_id, pid))
+                        .boxed(),
+                }
+            },
+            ConfigEvent::GuestWait =>
+                self.qemu.guest_wait().boxed(),
+            ConfigEvent::ShowHost => {
+                self.show(true)
+            },
+            ConfigEvent::ShowGuest => {
+                self.show(false)
+            },
+            ConfigEvent::CycleSource => {
+                self.sources.cycle()
+            },
+            ConfigEvent::ToggleShow => {
+                self.show(self.sources.showing_guest().unwrap_or_default())
+            },
+            ConfigEvent::ToggleGrab(ref grab) => self.toggle_grab(grab),
+            ConfigEvent::Grab(grab) => self.grab(grab),
+            ConfigEvent::Ungrab(grab) => self.ungrab(*grab),
+            ConfigEvent::GrabKeyboardOnly => self.grab(&ConfigGrab::x_devices(true, false)),
+            ConfigEvent::GrabPointerOnly => self.grab(&ConfigGrab::x_devices(false, true)),
+            ConfigEvent::UnstickGuest => {
+                self.unstick_guest().boxed()
+            },
+            ConfigEvent::UnstickHost => {
+                self.xreq(XRequest::UnstickHost)
+            },
+            ConfigEvent::DisplayPower(on) => {
+                self.xreq(XRequest::DisplayPower(*on))
+            },
+            ConfigEvent::Wait(duration) => {
+                tokio::time::sleep(*duration).map(Ok).boxed()
+            },
+            ConfigEvent::Sequence(events) => self.sequence(events),
+            ConfigEvent::If { showing_guest, grabbed, then, otherwise } => {
+                let holds = showing_guest.map(|guest| self.sources.showing_guest() == Some(guest)).unwrap_or(true) &&
+                    grabbed.map(|grabbed| self.grabs.lock().unwrap().is_empty() != grabbed).unwrap_or(true);
+                self.sequence(if holds { then } else { otherwise })
+            },
+            ConfigEvent::SetMouseMode(mode) => {
+                self.set_is_mouse(match mode {
+                    ConfigMouseMode::Relative => true,
+                    ConfigMouseMode::Absolute => false,
+                }).boxed()
+            },
+            ConfigEvent::TypeText { ref text, delay } => {
+                let (keys, delay) = (Self::text_keys(text), *delay);
+                let mut event_sender = self.keyboard_route.clone()
+                    .unwrap_or_else(|| self.event_sender.clone());
+                async move {
+                    for (key, shift) in keys? {
+                        for e in Self::type_key_events(key, shift) {
+                            event_sender.send(e).await?;
+                        }
+                        tokio::time::sleep(delay).await;
+                    }
+
+                    Ok(())
+                }.boxed()
+            },
+            ConfigEvent::GuestKeys(ref keys) => {
+                let events = Self::chord_events(keys);
+                let event_sender = self.keyboard_route.clone()
+                    .unwrap_or_else(|| self.event_sender.clone());
+                Self::send_events(event_sender, events).boxed()
+            },
+            ConfigEvent::GuestCtrlAltDel => {
+                self.send_keys_qmp(Ok(vec![QKeyCode::ctrl, QKeyCode::alt, QKeyCode::delete]))
+            },
+            ConfigEvent::GuestSysrq(key) => {
+                self.send_keys_qmp(Self::sysrq_keys(*key))
+            },
+            ConfigEvent::RefreshKeymaps => {
+                self.xreq(XRequest::RefreshKeymaps)
+            },
+            ConfigEvent::ReloadConfig => {
+                future::ready(self.reload_config(self.profile()).map(drop)).boxed()
+            },
+            ConfigEvent::Profile(ref name) => {
+                self.switch_profile(name)
+            },
+            ConfigEvent::PlaySound(ref path) => {
+                self.sounds.play_file(path);
+                future::ok(()).boxed()
+            },
+            ConfigEvent::CaptureInput { ref path, duration } => {
+                let res = config::expand_path(path)
+                    .map_err(|e| format_err!("{}", e))
+                    .and_then(|path| Capture::create(&path, *duration)
+                        .map_err(|e| Error::from(e).context(format!("failed to create {}", path)))
+                        .map(|capture| (path, capture))
+                    ).map(|(path, capture)| {
+                        info!("{} Capturing input to {} for {:?}", self.log_id, path, duration);
+                        *self.capture.lock().unwrap() = Some(capture);
+                        self.capturing.store(true, Ordering::Relaxed);
+                    });
+                future::ready(res).boxed()
+            },
+            ConfigEvent::SwitchVm(ref name) => self.switch_vm(name),
+            ConfigEvent::StartVm => self.start_vm(),
+            ConfigEvent::StopVm => self.stop_vm(),
+            ConfigEvent::Shutdown => {
+                self.qemu.guest_shutdown(guest_shutdown { mode: Some(GuestShutdownMode::Powerdown) }).boxed()
+            },
+            ConfigEvent::Reboot => {
+                self.qemu.guest_shutdown(guest_shutdown { mode: Some(GuestShutdownMode::Reboot) }).boxed()
+            },
+            ConfigEvent::Exit => {
+                let exit_events = |events: &[ConfigEvent]| -> Vec<_> {
+                    events.iter()
+                        .filter_map(|e| match e {
+                            ConfigEvent::Exit => None,
+                            e => Some(e),
+                        }).map(|e| (format!("{:?}", e), self.process_user_event(e)))
+                        .collect()
+                };
+                let sequential = exit_events(&self.exit_events());
+                let parallel = exit_events(&self.exit.parallel_events);
+                let (event_timeout, exit_timeout) = (self.exit.event_timeout, self.exit.timeout);
+                let quit = self.xreq(XRequest::Quit);
+                let log_id = self.log_id.clone();
+                async move {
+                    let sequential = {
+                        let log_id = log_id.clone();
+                        async move {
+                            let mut failures = Vec::new();
+                            for (name, e) in sequential {
+                                failures.extend(Self::exit_event(log_id.clone(), name, e, event_timeout).await);
+                            }
+                            failures
+                        }
+                    };
+                    let parallel = future::join_all(parallel.into_iter()
+                        .map(|(name, e)| Self::exit_event(log_id.clone(), name, e, event_timeout))
+                    );
+                    let failures: Vec<_> = match tokio::time::timeout(exit_timeout, future::join(sequential, parallel)).await {
+                        Ok((sequential, parallel)) => sequential.into_iter()
+                            .chain(parallel.into_iter().flatten())
+                            .collect(),
+                        Err(_) => vec![format!("exit events still running after {:?}", exit_timeout)],
+                    };
+                    if !failures.is_empty() {
+                        error!("{} Quitting despite failed exit events: {}", log_id, failures.join("; "));
+                    }
+                    quit.await
+                }.boxed()
+            }
+        }
+    }
+}
+
+fn substitute(s: &str, vars: &[(&str, Option<String>)]) -> Result<String, Error> {
+    let mut s = s.to_owned();
+    for (name, value) in vars {
+        let key = format!("{{{}}}", name);
+        if s.contains(&key) {
+            let value = value.as_ref()
+                .ok_or_else(|| format_err!("{{{}}} is not known yet", name))?;
+            s = s.replace(&key, value);
+        }
+    }
+    Ok(s)
+}
+
+#[test]
+fn exec_substitution() {
+    let vars = [("SHOWING", Some("guest".to_owned())), ("HOST_SOURCE", None)];
+    assert_eq!(substitute("--{SHOWING}={SHOWING}", &vars).unwrap(), "--guest=guest");
+    assert_eq!(substitute("{}", &vars).unwrap(), "{}");
+    assert!(substitute("{HOST_SOURCE}", &vars).is_err());
+}
+
+#[test]
+fn chord_order() {
+    let keys: Vec<_> = Process::chord_events(&[Key::LeftCtrl, Key::LeftAlt, Key::Delete]).into_iter()
+        .filter_map(|e| match input::EventRef::new(&e) {
+            Ok(input::EventRef::Key(key)) => Some((key.key, key.value)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(keys, [
+        (Key::LeftCtrl, KeyState::PRESSED), (Key::LeftAlt, KeyState::PRESSED), (Key::Delete, KeyState::PRESSED),
+        (Key::Delete, KeyState::RELEASED), (Key::LeftAlt, KeyState::RELEASED), (Key::LeftCtrl, KeyState::RELEASED),
+    ]);
+}
+
+#[test]
+fn chord_route_order() {
+    let events = Process::chord_events(&[Key::LeftCtrl, Key::LeftAlt, Key::Delete]);
+    let (sender, receiver) = un_mpsc::channel(0);
+    let (sent, routed) = futures::executor::block_on(future::join(
+        Process::send_events(sender, events.clone()),
+        receiver.collect::<Vec<_>>(),
+    ));
+    sent.unwrap();
+    assert_eq!(routed, events);
+}
+
+#[test]
+fn sysrq_keys() {
+    assert_eq!(Process::sysrq_keys('S').unwrap(), [QKeyCode::alt, QKeyCode::sysrq, QKeyCode::s]);
+    assert!(Process::sysrq_keys('!').is_err());
+}
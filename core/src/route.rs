@@ -0,0 +1,916 @@
+use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::task::Poll;
+use std::sync::Once;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::pin::Pin;
+use std::iter;
+use std::mem;
+use std::collections::{HashMap, HashSet};
+use tokio::sync::broadcast;
+use tokio::time::{Duration, Instant, interval, timeout};
+use input::{InputEvent, EventRef, EventKind, KeyEvent, KeyState, Key, RelativeAxis, AbsoluteAxis};
+use futures::channel::mpsc;
+use futures::stream::FusedStream;
+use futures::{future, StreamExt, SinkExt, Future, FutureExt, TryFutureExt};
+use anyhow::{Error, format_err};
+use config::{ConfigAbsolute, ConfigQemuRouting, ConfigSpice, ConfigUInput};
+use config::keymap::Keymaps;
+use qapi::{qmp, Any};
+use qemu::{Qemu, QemuEvent, QemuResource};
+use uinput;
+use event::KeyStateTracker;
+use log::{info, warn};
+use crate::spawner::Spawner;
+use crate::spice::{self, SpiceInputs, SpiceInput};
+use crate::util::LogId;
+
+pub type RouteEvents = Pin<Box<dyn FusedStream<Item=InputEvent> + Send>>;
+
+/// Holds back input from every route it is given to, without removing any devices.
+///
+/// Key releases still get through, so nothing is left held down in the guest.
+#[derive(Debug, Default)]
+pub struct RouteGate {
+    paused: AtomicBool,
+}
+
+impl RouteGate {
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed)
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    fn allows(&self, e: &InputEvent) -> bool {
+        !self.is_paused() || match EventRef::new(e) {
+            Ok(EventRef::Key(key)) => key.value == KeyState::RELEASED,
+            Ok(EventRef::Synchronize(..)) => true,
+            _ => false,
+        }
+    }
+}
+
+/// A way of getting input events into the VM
+pub trait InputRoute: Send + Sync {
+    /// The uinput device that events are written to, for routes that have one
+    fn builder(&mut self) -> Option<&mut uinput::Builder> {
+        None
+    }
+
+    /// Checks that input sent through this route will reach the VM,
+    /// to be awaited once the route has been spawned
+    fn self_test(&self) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>>;
+
+    /// Forwards `events` until the channel closes, reporting a fatal failure to `error_sender`
+    fn spawn(&self, spawner: &Spawner, events: RouteEvents, error_sender: mpsc::Sender<Error>);
+}
+
+pub struct RouteQmp {
+    qemu: Arc<Qemu>,
+    qkeycodes: Arc<[u8]>,
+    keys: Arc<KeyStateTracker>,
+    absolute: ConfigAbsolute,
+    error_pause: Option<u32>,
+    log_id: LogId,
+}
+
+/// Collapses runs of the same error into a count, so that a guest without a
+/// display doesn't flood the log
+#[derive(Debug, Default)]
+struct ErrorRun {
+    message: Option<String>,
+    repeats: u64,
+    /// Errors since the last success
+    consecutive: u32,
+    total: u64,
+}
+
+impl ErrorRun {
+    /// Returns the run that `message` ends, and whether `message` is new and worth logging
+    fn error(&mut self, message: String) -> (Option<(String, u64)>, bool) {
+        self.consecutive = self.consecutive.saturating_add(1);
+        self.total += 1;
+        if self.message.as_ref() == Some(&message) {
+            self.repeats += 1;
+            (None, false)
+        } else {
+            (self.end_run(Some(message)), true)
+        }
+    }
+
+    fn success(&mut self) -> Option<(String, u64)> {
+        self.consecutive = 0;
+        self.end_run(None)
+    }
+
+    fn end_run(&mut self, next: Option<String>) -> Option<(String, u64)> {
+        let repeats = mem::replace(&mut self.repeats, 0);
+        mem::replace(&mut self.message, next)
+            .filter(|_| repeats > 0)
+            .map(|message| (message, repeats))
+    }
+}
+
+/// QEMU qnum keycodes, indexed by linux keycode
+fn qkeycodes() -> Arc<[u8]> {
+    unsafe {
+        static mut QKEYCODES: Option<Arc<[u8]>> = None;
+        static QKEYCODES_ONCE: Once = Once::new();
+
+        QKEYCODES_ONCE.call_once(|| {
+            QKEYCODES = Some(Keymaps::from_csv().qnum_keycodes().into());
+        });
+        QKEYCODES.as_ref().unwrap().clone()
+    }
+}
+
+impl RouteQmp {
+    /// `error_pause` stops sending input after that many errors in a row,
+    /// until QEMU reports that the guest resumed or reset
+    pub fn new(qemu: Arc<Qemu>, absolute: ConfigAbsolute, error_pause: Option<u32>, log_id: LogId) -> Self {
+        RouteQmp {
+            qemu,
+            qkeycodes: qkeycodes(),
+            keys: Default::default(),
+            absolute,
+            error_pause,
+            log_id,
+        }
+    }
+
+    fn convert_key(key: Key, down: bool, qkeycodes: &[u8]) -> Option<qmp::InputEvent> {
+        qkeycodes.get(key as usize).map(|&qnum| qmp::InputEvent::key(qmp::InputKeyEvent {
+            down,
+            key: qmp::KeyValue::number(qnum.into()),
+        }.into()))
+    }
+
+    /// Browser navigation keys to stand in for side buttons on QEMU versions without them
+    fn navigation_key(button: Key) -> Option<Key> {
+        match button {
+            Key::ButtonSide | Key::ButtonBack => Some(Key::Back),
+            Key::ButtonExtra | Key::ButtonForward => Some(Key::Forward),
+            _ => None,
+        }
+    }
+
+    fn convert_event(e: &InputEvent, qkeycodes: &[u8], side_buttons: bool) -> Option<qmp::InputEvent> {
+        Some(match EventRef::new(e) {
+            Ok(EventRef::Key(ref key)) if !side_buttons && Self::navigation_key(key.key).is_some() =>
+                return Self::convert_key(Self::navigation_key(key.key)?, key.value.is_pressed(), qkeycodes),
+            Ok(EventRef::Key(ref key)) if key.key.is_button() => qmp::InputEvent::btn(qmp::InputBtnEvent {
+                down: key.value.is_pressed(),
+                button: match key.key {
+                    Key::ButtonLeft => qmp::InputButton::left,
+                    Key::ButtonMiddle => qmp::InputButton::middle,
+                    Key::ButtonRight => qmp::InputButton::right,
+                    Key::ButtonWheel => qmp::InputButton::wheel_down,
+                    Key::ButtonGearUp => qmp::InputButton::wheel_up,
+                    Key::ButtonSide | Key::ButtonBack => qmp::InputButton::side,
+                    Key::ButtonExtra | Key::ButtonForward => qmp::InputButton::extra,
+                    // BTN_TASK has no QMP button, and nothing on the keyboard to stand in for it
+                    _ => return None, // TODO: warn/error/etc
+                },
+            }.into()),
+            Ok(EventRef::Key(KeyEvent { key: Key::Reserved, .. })) =>
+                return None, // ignore key 0 events
+            Ok(EventRef::Key(ref key)) =>
+                return Self::convert_key(key.key, key.value.is_pressed(), qkeycodes),
+            Ok(EventRef::Relative(rel)) => qmp::InputEvent::rel(qmp::InputMoveEvent {
+                axis: match rel.axis {
+                    RelativeAxis::X => qmp::InputAxis::x,
+                    RelativeAxis::Y => qmp::InputAxis::y,
+                    _ => return None, // TODO: warn/error/etc
+                },
+                value: rel.value as _,
+            }.into()),
+            Ok(EventRef::Absolute(abs)) => qmp::InputEvent::abs(qmp::InputMoveEvent {
+                axis: match abs.axis {
+                    AbsoluteAxis::X => qmp::InputAxis::x,
+                    AbsoluteAxis::Y => qmp::InputAxis::y,
+                    _ => return None, // TODO: warn/error/etc
+                },
+                value: abs.value as _,
+            }.into()),
+            _ => return None, // TODO: warn/error/etc
+        })
+    }
+
+    /// QMP has no horizontal wheel axis, only a button to click for each step
+    fn hwheel_clicks(e: &InputEvent) -> Option<(qmp::InputButton, usize)> {
+        match EventRef::new(e) {
+            Ok(EventRef::Relative(rel)) if rel.axis == RelativeAxis::HorizontalWheel && rel.value != 0 => Some((
+                if rel.value > 0 { qmp::InputButton::wheel_right } else { qmp::InputButton::wheel_left },
+                rel.value.unsigned_abs() as usize,
+            )),
+            _ => None,
+        }
+    }
+
+    fn convert_events<'a, I: IntoIterator<Item=InputEvent> + 'a>(e: I, qkeycodes: &'a [u8], side_buttons: bool, hwheel: bool) -> impl Iterator<Item=qmp::InputEvent> + 'a {
+        e.into_iter().flat_map(move |ref e| {
+            let (event, (button, steps)) = match Self::hwheel_clicks(e) {
+                Some(clicks) if hwheel => (None, clicks),
+                _ => (Self::convert_event(e, qkeycodes, side_buttons), (qmp::InputButton::wheel_right, 0)),
+            };
+            event.into_iter().chain((0..steps * 2).map(move |i| qmp::InputEvent::btn(qmp::InputBtnEvent {
+                down: i % 2 == 0,
+                button,
+            }.into())))
+        })
+    }
+
+    /// Whether QEMU knows `button`, assuming it does if it can't tell
+    async fn has_button(qemu: &Qemu, log_id: &LogId, button: &str) -> bool {
+        match qemu.capabilities().await {
+            Ok(capabilities) => capabilities.has_input_button(button),
+            Err(e) => {
+                warn!("{} QEMU capability probing failed: {:?}", log_id, e);
+                true
+            },
+        }
+    }
+
+    /// Whether any of the QMP events received since last time show the guest is running again
+    fn guest_restarted(events: &mut broadcast::Receiver<QemuEvent>) -> bool {
+        let mut restarted = false;
+        loop {
+            match events.try_recv() {
+                Ok(QemuEvent::Qmp(qmp::Event::RESUME { .. })) | Ok(QemuEvent::Qmp(qmp::Event::RESET { .. })) | Ok(QemuEvent::Connected) =>
+                    restarted = true,
+                Ok(_) | Err(broadcast::error::TryRecvError::Lagged(_)) => (),
+                Err(_) => break restarted,
+            }
+        }
+    }
+
+    /// Keeps `keys` up to date, and notes every key in `touched`
+    fn track_key(keys: &KeyStateTracker, touched: &mut HashSet<Key>, e: &InputEvent) {
+        if keys.track(e) {
+            if let Ok(EventRef::Key(key)) = EventRef::new(e) {
+                touched.insert(key.key);
+            }
+        }
+    }
+}
+
+impl InputRoute for RouteQmp {
+    /// Sends a zero-length relative move and a release of the reserved key,
+    /// which the guest shouldn't notice
+    fn self_test(&self) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let qemu = self.qemu.clone();
+        let cmd = qmp::input_send_event {
+            device: Default::default(),
+            head: Default::default(),
+            events: vec![
+                qmp::InputEvent::rel(qmp::InputMoveEvent {
+                    axis: qmp::InputAxis::x,
+                    value: 0,
+                }.into()),
+                qmp::InputEvent::key(qmp::InputKeyEvent {
+                    down: false,
+                    key: qmp::KeyValue::number(0.into()),
+                }.into()),
+            ],
+        };
+        async move {
+            qemu.execute_qmp(cmd).await
+                .map(drop)
+        }.boxed()
+    }
+
+    fn spawn(&self, spawner: &Spawner, events: RouteEvents, mut error_sender: mpsc::Sender<Error>) {
+        let qemu = self.qemu.clone();
+        let qkeycodes = self.qkeycodes.clone();
+        let keys = self.keys.clone();
+        let absolute = self.absolute;
+        let error_pause = self.error_pause;
+        let mut qemu_events = qemu.qmp_events();
+        // QEMU always expects its own range for absolute axes
+        let mut events = events.map(move |mut e| {
+            if e.kind == EventKind::Absolute {
+                e.value = absolute.rescale(e.value, ConfigAbsolute::QEMU_MAXIMUM);
+            }
+            e
+        });
+        let (log_id, error_log_id) = (self.log_id.clone(), self.log_id.clone());
+        spawner.spawn(async move {
+            let mut qmp = qemu.connect_qmp().await?;
+            let side_buttons = RouteQmp::has_button(&qemu, &log_id, "side").await;
+            let hwheel = RouteQmp::has_button(&qemu, &log_id, "wheel-left").await;
+            if !hwheel {
+                warn!("{} QEMU has no horizontal wheel buttons, horizontal scrolling won't reach the guest", log_id);
+            }
+            let mut cmd = qmp::input_send_event {
+                device: Default::default(),
+                head: Default::default(),
+                events: Default::default(),
+            };
+            let mut touched = HashSet::new();
+            let mut reconnected = false;
+            let mut errors = ErrorRun::default();
+            let mut paused = false;
+            'outer: while let Some(event) = events.next().await {
+                const THRESHOLD: usize = 0x20;
+                if paused {
+                    if !RouteQmp::guest_restarted(&mut qemu_events) {
+                        continue
+                    }
+                    info!("{} Guest restarted, resuming QMP input", log_id);
+                    paused = false;
+                    errors.consecutive = 0;
+                }
+                cmd.events.clear();
+                touched.clear();
+                RouteQmp::track_key(&keys, &mut touched, &event);
+                cmd.events.extend(RouteQmp::convert_events(iter::once(event), &qkeycodes, side_buttons, hwheel));
+                while let Poll::Ready(event) = futures::poll!(events.next()) {
+                    match event {
+                        Some(event) => {
+                            RouteQmp::track_key(&keys, &mut touched, &event);
+                            cmd.events.extend(RouteQmp::convert_events(iter::once(event), &qkeycodes, side_buttons, hwheel))
+                        },
+                        None => break 'outer,
+                    }
+                    if cmd.events.len() > THRESHOLD {
+                        break
+                    }
+                }
+                if !cmd.events.is_empty() {
+                    match qmp.execute(&cmd).await {
+                        Ok(_) => {
+                            reconnected = false;
+                            if let Some((message, repeats)) = errors.success() {
+                                warn!("{} QMP input routing error repeated {} more times: {}", log_id, repeats, message);
+                            }
+                        },
+                        Err(qapi::ExecuteError::Qapi(e @ qapi::Error { class: qapi::ErrorClass::GenericError, .. })) => {
+                            let (ended, new) = errors.error(e.desc.clone());
+                            if let Some((message, repeats)) = ended {
+                                warn!("{} QMP input routing error repeated {} more times: {}", log_id, repeats, message);
+                            }
+                            if new {
+                                warn!("{} QMP input routing error: {:?}", log_id, e);
+                            }
+                            if error_pause.map(|limit| errors.consecutive >= limit).unwrap_or(false) {
+                                warn!("{} Pausing QMP input after {} errors in a row ({} in total), until the guest resumes or resets",
+                                    log_id, errors.consecutive, errors.total);
+                                // only events from here on count
+                                RouteQmp::guest_restarted(&mut qemu_events);
+                                paused = true;
+                            }
+                        },
+                        // only try once per failure, a second error in a row is fatal
+                        Err(e) if reconnected => return Err(Error::from(e)),
+                        Err(e) => {
+                            warn!("{} QMP input routing failed, reconnecting: {:?}", log_id, e);
+                            reconnected = true;
+
+                            // anything that queued up in the meantime is stale by now
+                            while let Poll::Ready(Some(event)) = futures::poll!(events.next()) {
+                                RouteQmp::track_key(&keys, &mut touched, &event);
+                            }
+                            // the failed batch may or may not have made it through
+                            for key in touched.drain() {
+                                keys.track_key(key, KeyState::PRESSED);
+                            }
+
+                            drop(qmp);
+                            qmp = qemu.connect_qmp().await?;
+
+                            // release anything the guest may still think is held, which also resets modifiers
+                            cmd.events.clear();
+                            cmd.events.extend(RouteQmp::convert_events(keys.release_all(), &qkeycodes, side_buttons, hwheel));
+                            if !cmd.events.is_empty() {
+                                qmp.execute(&cmd).await?;
+                            }
+                        },
+                    }
+                }
+            }
+            Ok(())
+        }.then(move |r| async move { match r {
+            Err(e) => {
+                let _ = error_sender.send(e.context(format!("{} input route failed", error_log_id))).await;
+            },
+            _ => (),
+        } }));
+    }
+}
+
+pub struct RouteUInput<U> {
+    qemu: Arc<Qemu>,
+    builder: uinput::Builder,
+    commands: Arc<U>,
+    config: Arc<ConfigUInput>,
+    /// The uinput device currently handed to the VM
+    device: Arc<Mutex<Option<PathBuf>>>,
+    log_id: LogId,
+}
+
+impl RouteUInput<RouteUInputInputLinux> {
+    pub fn new_input_linux(qemu: Arc<Qemu>, id: String, repeat: bool, config: ConfigUInput, log_id: LogId) -> Self {
+        Self::new(qemu, uinput::Builder::new(), config, RouteUInputInputLinux {
+            id,
+            repeat,
+        }, log_id)
+    }
+}
+
+impl RouteUInput<RouteUInputVirtio> {
+    pub fn new_virtio_host(qemu: Arc<Qemu>, id: String, bus: Option<String>, config: ConfigUInput, log_id: LogId) -> Self {
+        Self::new(qemu, uinput::Builder::new(), config, RouteUInputVirtio {
+            id,
+            bus,
+        }, log_id)
+    }
+}
+
+pub trait UInputCommands: Send + Sync + 'static {
+    fn command_create(&self, qemu: &Arc<Qemu>, path: &Path) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>>;
+    fn command_delete(&self, qemu: &Arc<Qemu>) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>>;
+    /// QOM path of whatever `command_create` adds to the VM, with an `evdev` property
+    fn qom_path(&self) -> String;
+}
+
+pub struct RouteUInputVirtio {
+    id: String,
+    bus: Option<String>,
+}
+
+impl UInputCommands for RouteUInputVirtio {
+    fn command_create(&self, qemu: &Arc<Qemu>, path: &Path) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let command = qmp::device_add::new("virtio-input-host-pci", Some(self.id.clone()), self.bus.clone(), vec![
+            ("evdev".into(), Any::String(path.display().to_string())),
+            ("multifunction".into(), Any::Bool(true)),
+            // stays put for guest udev rules, unlike the event device numbering
+            ("serial".into(), Any::String(self.id.clone())),
+        ]);
+        let deadline = Instant::now() + Duration::from_millis(512); // HACK: wait for udev to see device and change permissions
+        let qemu = qemu.clone();
+        async move {
+            qemu.device_add(command, deadline).await
+        }.boxed()
+    }
+
+    fn qom_path(&self) -> String {
+        format!("/machine/peripheral/{}", self.id)
+    }
+
+    fn command_delete(&self, qemu: &Arc<Qemu>) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let command = qmp::device_del {
+            id: self.id.clone(),
+        };
+        let qemu = qemu.clone();
+        async move {
+            qemu.unregister(&QemuResource::Device(command.id.clone()));
+            qemu.execute_qmp(command).map_ok(drop).await
+        }.boxed()
+    }
+}
+
+pub struct RouteUInputInputLinux {
+    id: String,
+    repeat: bool,
+}
+
+impl UInputCommands for RouteUInputInputLinux {
+    fn command_create(&self, qemu: &Arc<Qemu>, path: &Path) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let path = path.display();
+        let command = qmp::object_add::from(qmp::ObjectOptions::input_linux {
+            id: self.id.clone(),
+            input_linux: qmp::InputLinuxProperties {
+                evdev: path.to_string(),
+                repeat: Some(self.repeat),
+                grab_all: None,
+                grab_toggle: None,
+            },
+        });
+        let delete_command = qmp::object_del {
+            id: self.id.clone(),
+        };
+        let id = self.id.clone();
+        let qemu = qemu.clone();
+        async move {
+            if qemu.execute_qmp(delete_command).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(512)).await;
+            }
+            qemu.execute_qmp(command).await?;
+            qemu.register(QemuResource::Object(id));
+            Ok(())
+        }.boxed()
+    }
+
+    fn qom_path(&self) -> String {
+        format!("/objects/{}", self.id)
+    }
+
+    fn command_delete(&self, qemu: &Arc<Qemu>) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let command = qmp::object_del {
+            id: self.id.clone(),
+        };
+        let qemu = qemu.clone();
+        async move {
+            qemu.unregister(&QemuResource::Object(command.id.clone()));
+            qemu.execute_qmp(command).map_ok(drop).await
+        }.boxed()
+    }
+}
+
+impl<U> RouteUInput<U> {
+    fn new(qemu: Arc<Qemu>, builder: uinput::Builder, config: ConfigUInput, commands: U, log_id: LogId) -> Self {
+        RouteUInput {
+            qemu,
+            builder,
+            commands: Arc::new(commands),
+            config: Arc::new(config),
+            device: Default::default(),
+            log_id,
+        }
+    }
+}
+
+impl<U: UInputCommands> RouteUInput<U> {
+    async fn open(builder: &uinput::Builder, commands: &U, qemu: &Arc<Qemu>, config: &ConfigUInput, device: &Mutex<Option<PathBuf>>) -> Result<uinput::UInputSink, Error> {
+        let uinput = builder.create()?;
+        let path = uinput.path().to_owned();
+        let mut uinput = uinput.to_sink()?;
+        uinput.set_high_water(config.high_water);
+        commands.command_create(qemu, &path).await?;
+        *device.lock().unwrap() = Some(path);
+        Ok(uinput)
+    }
+}
+
+impl<U: UInputCommands> InputRoute for RouteUInput<U> {
+    fn builder(&mut self) -> Option<&mut uinput::Builder> {
+        Some(&mut self.builder)
+    }
+
+    /// Waits for the spawned device to show up in the VM, reading from the
+    /// uinput device that input is written to
+    fn self_test(&self) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let qemu = self.qemu.clone();
+        let path = self.commands.qom_path();
+        let device = self.device.clone();
+        let deadline = Instant::now() + Duration::from_secs(3);
+        async move {
+            loop {
+                let expected = device.lock().unwrap().clone();
+                let res = match expected {
+                    Some(expected) => qemu.execute_qmp(qmp::qom_get { path: path.clone(), property: "evdev".into() }).await
+                        .map_err(|e| e.context(format!("{} was not added to the VM", path)))
+                        .and_then(|evdev| match evdev {
+                            Any::String(ref evdev) if Path::new(evdev) == expected => Ok(()),
+                            evdev => Err(format_err!("{} reads from {} instead of {}", path, evdev, expected.display())),
+                        }),
+                    None => Err(format_err!("uinput device for {} was not created", path)),
+                };
+                match res {
+                    Ok(()) => break Ok(()),
+                    Err(e) if Instant::now() >= deadline => break Err(e),
+                    Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+                }
+            }
+        }.boxed()
+    }
+
+    fn spawn(&self, spawner: &Spawner, mut events: RouteEvents, mut error_sender: mpsc::Sender<Error>) {
+        let qemu = self.qemu.clone();
+        let builder = self.builder.clone();
+        let commands = self.commands.clone();
+        let config = self.config.clone();
+        let device = self.device.clone();
+        let (log_id, error_log_id) = (self.log_id.clone(), self.log_id.clone());
+        spawner.spawn(async move {
+            let mut uinput = Self::open(&builder, &commands, &qemu, &config, &device).await?;
+            let mut flush = interval(config.flush_interval);
+            let mut recoveries = 0;
+            let res = loop {
+                let (res, flushing) = futures::select! {
+                    e = events.next() => match e {
+                        Some(e) => (timeout(config.stall_timeout, uinput.feed(e)).await, false),
+                        None => break Ok(()),
+                    },
+                    _ = Box::pin(flush.tick()).fuse() =>
+                        (timeout(config.stall_timeout, uinput.flush()).await, true),
+                };
+                let e = match res {
+                    Ok(Ok(())) => {
+                        if flushing {
+                            // the device has caught up
+                            recoveries = 0;
+                        }
+                        continue
+                    },
+                    Ok(Err(e)) => Error::from(e).context("uinput write failed"),
+                    Err(_) => format_err!("uinput device stalled with {} events pending", uinput.pending_events()),
+                };
+                if recoveries >= config.recover_attempts {
+                    break Err(e)
+                }
+                recoveries += 1;
+                warn!("{} {}, recreating device", log_id, e);
+
+                drop(uinput);
+                let _ = commands.command_delete(&qemu).await;
+                uinput = Self::open(&builder, &commands, &qemu, &config, &device).await?;
+            };
+            let qres = commands.command_delete(&qemu).await
+                .map_err(From::from);
+            res.and_then(move |()| qres)
+        }.then(move |r: Result<(), Error>| async move { match r {
+            Err(e) => {
+                let _ = error_sender.send(e.context(format!("{} input route failed", error_log_id))).await;
+            },
+            _ => (),
+        } }));
+    }
+}
+
+/// What a route backend is constructed from
+pub struct RouteParams {
+    pub qemu: Arc<Qemu>,
+    pub id: String,
+    pub bus: Option<String>,
+    pub repeat: bool,
+    pub uinput: ConfigUInput,
+    pub absolute: ConfigAbsolute,
+    /// See [`RouteQmp::new`]
+    pub error_pause: Option<u32>,
+    pub log_id: LogId,
+}
+
+pub type RouteConstructor = Box<dyn Fn(RouteParams) -> Result<Box<dyn InputRoute>, Error> + Send + Sync>;
+
+/// Route backends, by the name their routing has in config
+pub struct RouteRegistry {
+    routes: HashMap<String, RouteConstructor>,
+}
+
+impl RouteRegistry {
+    /// A registry without any backends, see `Default` for the built-in ones
+    pub fn new() -> Self {
+        RouteRegistry {
+            routes: Default::default(),
+        }
+    }
+
+    /// Adds or replaces the backend for `key`
+    pub fn register<K: Into<String>, F>(&mut self, key: K, constructor: F) where
+        F: Fn(RouteParams) -> Result<Box<dyn InputRoute>, Error> + Send + Sync + 'static,
+    {
+        self.routes.insert(key.into(), Box::new(constructor));
+    }
+
+    pub fn create(&self, key: &str, params: RouteParams) -> Result<Box<dyn InputRoute>, Error> {
+        let constructor = self.routes.get(key)
+            .ok_or_else(|| format_err!("{} routing is not available", key))?;
+        constructor(params)
+    }
+
+    /// Adds SPICE routing, with every route sharing one connection to `config.address`
+    pub fn register_spice(&mut self, config: ConfigSpice) {
+        let spice = Arc::new(SpiceInputs::new(config.address));
+        let display_size = config.display_size;
+        self.register(ConfigQemuRouting::Spice.key(), move |p: RouteParams|
+            Ok(Box::new(RouteInputSpice::new(spice.clone(), display_size, p.absolute, p.log_id)))
+        );
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.routes.contains_key(key)
+    }
+}
+
+impl Default for RouteRegistry {
+    fn default() -> Self {
+        let mut routes = Self::new();
+        routes.register(ConfigQemuRouting::InputLinux.key(), |p: RouteParams|
+            Ok(Box::new(RouteUInput::new_input_linux(p.qemu, p.id, p.repeat, p.uinput, p.log_id)))
+        );
+        routes.register(ConfigQemuRouting::VirtioHost.key(), |p: RouteParams|
+            Ok(Box::new(RouteUInput::new_virtio_host(p.qemu, p.id, p.bus, p.uinput, p.log_id)))
+        );
+        routes.register(ConfigQemuRouting::Qmp.key(), |p: RouteParams|
+            Ok(Box::new(RouteQmp::new(p.qemu, p.absolute, p.error_pause, p.log_id)))
+        );
+        routes
+    }
+}
+
+pub struct Route {
+    route: Box<dyn InputRoute>,
+}
+
+impl Route {
+    pub fn new(routes: &RouteRegistry, routing: ConfigQemuRouting, qemu: Arc<Qemu>, id: String, bus: Option<String>, repeat: bool, uinput: ConfigUInput, absolute: ConfigAbsolute, error_pause: Option<u32>, log_id: &LogId) -> Result<Self, Error> {
+        let log_id = log_id.route(&id);
+        routes.create(routing.key(), RouteParams {
+            qemu,
+            id,
+            bus,
+            repeat,
+            uinput,
+            absolute,
+            error_pause,
+            log_id,
+        }).map(|route| Route { route })
+    }
+
+    pub fn builder(&mut self) -> Option<&mut uinput::Builder> {
+        self.route.builder()
+    }
+
+    pub fn self_test(&self) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        self.route.self_test()
+    }
+
+    /// Returns a sender that buffers up to `capacity` events for the route
+    pub fn spawn(self, spawner: &Spawner, gate: Arc<RouteGate>, capacity: usize, error_sender: mpsc::Sender<Error>) -> mpsc::Sender<InputEvent> {
+        let (sender, events) = mpsc::channel(capacity);
+        let events = events.filter(move |e| future::ready(gate.allows(e)));
+        self.route.spawn(spawner, Box::pin(events), error_sender);
+        sender
+    }
+}
+
+pub struct RouteInputSpice {
+    spice: Arc<SpiceInputs>,
+    qkeycodes: Arc<[u8]>,
+    display_size: Option<(u32, u32)>,
+    absolute: ConfigAbsolute,
+    log_id: LogId,
+}
+
+impl RouteInputSpice {
+    pub fn new(spice: Arc<SpiceInputs>, display_size: Option<(u32, u32)>, absolute: ConfigAbsolute, log_id: LogId) -> Self {
+        RouteInputSpice {
+            spice,
+            qkeycodes: qkeycodes(),
+            display_size,
+            absolute,
+            log_id,
+        }
+    }
+
+    fn button(key: Key) -> Option<u8> {
+        Some(match key {
+            Key::ButtonLeft => spice::BUTTON_LEFT,
+            Key::ButtonMiddle => spice::BUTTON_MIDDLE,
+            Key::ButtonRight => spice::BUTTON_RIGHT,
+            Key::ButtonGearUp => spice::BUTTON_UP,
+            Key::ButtonWheel => spice::BUTTON_DOWN,
+            Key::ButtonSide | Key::ButtonBack => spice::BUTTON_SIDE,
+            Key::ButtonExtra | Key::ButtonForward => spice::BUTTON_EXTRA,
+            _ => return None,
+        })
+    }
+
+    /// Scales an absolute axis onto the guest display
+    fn scale(value: i32, size: u32, maximum: i32) -> u32 {
+        let maximum = maximum.max(1);
+        let value = value.max(0).min(maximum) as u64;
+        (value * size.saturating_sub(1) as u64 / maximum as u64) as u32
+    }
+}
+
+impl InputRoute for RouteInputSpice {
+    /// Links the inputs channel
+    fn self_test(&self) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let spice = self.spice.clone();
+        async move {
+            spice.check().await
+        }.boxed()
+    }
+
+    fn spawn(&self, spawner: &Spawner, mut events: RouteEvents, mut error_sender: mpsc::Sender<Error>) {
+        let spice = self.spice.clone();
+        let qkeycodes = self.qkeycodes.clone();
+        let display_size = self.display_size;
+        let maximum = self.absolute.maximum;
+        let (log_id, error_log_id) = (self.log_id.clone(), self.log_id.clone());
+        spawner.spawn(async move {
+            let mut inputs = Vec::new();
+            let mut motion = (0, 0);
+            let mut position = (0, 0);
+            let mut moved = false;
+            let mut warned = false;
+            let mut warned_hwheel = false;
+            while let Some(e) = events.next().await {
+                match EventRef::new(&e) {
+                    Ok(EventRef::Key(key)) if key.key.is_button() => if let Some(button) = Self::button(key.key) {
+                        let pressed = key.value.is_pressed();
+                        let buttons = spice.set_button(button, pressed);
+                        inputs.push(if pressed {
+                            SpiceInput::Press { button, buttons }
+                        } else {
+                            SpiceInput::Release { button, buttons }
+                        });
+                    },
+                    Ok(EventRef::Key(KeyEvent { key: Key::Reserved, .. })) => (),
+                    Ok(EventRef::Key(key)) => if let Some(&qnum) = qkeycodes.get(key.key as usize) {
+                        let (down, up) = SpiceInput::scancodes(qnum);
+                        inputs.push(if key.value.is_pressed() {
+                            SpiceInput::KeyDown(down)
+                        } else {
+                            SpiceInput::KeyUp(up)
+                        });
+                    },
+                    Ok(EventRef::Relative(rel)) => match rel.axis {
+                        RelativeAxis::X => motion.0 += rel.value,
+                        RelativeAxis::Y => motion.1 += rel.value,
+                        RelativeAxis::Wheel if rel.value != 0 => {
+                            let button = if rel.value > 0 { spice::BUTTON_UP } else { spice::BUTTON_DOWN };
+                            let buttons = spice.buttons();
+                            for _ in 0..rel.value.abs() {
+                                inputs.push(SpiceInput::Press { button, buttons: buttons | 1 << (button - 1) });
+                                inputs.push(SpiceInput::Release { button, buttons });
+                            }
+                        },
+                        RelativeAxis::HorizontalWheel if !warned_hwheel => {
+                            warn!("{} SPICE has no horizontal wheel, horizontal scrolling won't reach the guest", log_id);
+                            warned_hwheel = true;
+                        },
+                        _ => (),
+                    },
+                    Ok(EventRef::Absolute(abs)) => match display_size {
+                        Some((width, height)) => match abs.axis {
+                            AbsoluteAxis::X => {
+                                position.0 = Self::scale(abs.value, width, maximum);
+                                moved = true;
+                            },
+                            AbsoluteAxis::Y => {
+                                position.1 = Self::scale(abs.value, height, maximum);
+                                moved = true;
+                            },
+                            _ => (),
+                        },
+                        None if !warned => {
+                            warn!("{} SPICE needs display_size configured to route absolute input", log_id);
+                            warned = true;
+                        },
+                        None => (),
+                    },
+                    Ok(EventRef::Synchronize(..)) => {
+                        let buttons = spice.buttons();
+                        if motion != (0, 0) {
+                            inputs.push(SpiceInput::Motion { dx: motion.0, dy: motion.1, buttons });
+                            motion = (0, 0);
+                        }
+                        if moved {
+                            inputs.push(SpiceInput::Position { x: position.0, y: position.1, buttons });
+                            moved = false;
+                        }
+                    },
+                    _ => (),
+                }
+                if !inputs.is_empty() {
+                    spice.send(&inputs).await?;
+                    inputs.clear();
+                }
+            }
+            Ok(())
+        }.then(move |r: Result<(), Error>| async move { match r {
+            Err(e) => {
+                let _ = error_sender.send(e.context(format!("{} input route failed", error_log_id))).await;
+            },
+            _ => (),
+        } }));
+    }
+}
+
+#[test]
+fn error_runs() {
+    let mut errors = ErrorRun::default();
+    assert_eq!(errors.error("no display".into()), (None, true));
+    assert_eq!(errors.error("no display".into()), (None, false));
+    assert_eq!(errors.error("no display".into()), (None, false));
+    assert_eq!(errors.consecutive, 3);
+    assert_eq!(errors.error("bad axis".into()), (Some(("no display".into(), 2)), true));
+    assert_eq!(errors.success(), None);
+    assert_eq!((errors.consecutive, errors.total), (0, 4));
+}
+
+#[test]
+fn qmp_hwheel() {
+    let e: InputEvent = input::RelativeEvent::new(Default::default(), RelativeAxis::HorizontalWheel, -2).into();
+    let clicks: Vec<_> = RouteQmp::convert_events(iter::once(e), &[], true, true)
+        .map(|e| match e {
+            qmp::InputEvent::btn(btn) => (btn.data.button, btn.data.down),
+            e => panic!("unexpected {:?}", e),
+        })
+        .collect();
+    assert_eq!(clicks, [
+        (qmp::InputButton::wheel_left, true), (qmp::InputButton::wheel_left, false),
+        (qmp::InputButton::wheel_left, true), (qmp::InputButton::wheel_left, false),
+    ]);
+    assert_eq!(RouteQmp::convert_events(iter::once(e), &[], true, false).count(), 0);
+}
@@ -0,0 +1,511 @@
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures::channel::mpsc;
+use futures::future::{self, AbortHandle};
+use futures::{FutureExt, TryFutureExt, StreamExt};
+use tokio::task::JoinHandle;
+use anyhow::{Error, format_err};
+use config::{Config, ConfigEvent, ConfigQemuRouting, ConfigScreen};
+use input::{self, InputId, Key, RelativeAxis, AbsoluteAxis, MiscKind, InputEvent, EventKind, EventRef};
+use qemu::Qemu;
+use x::{XRequest, XKeymapInfo};
+use event::Destination;
+use log::{warn, info};
+use crate::process::{self, Process};
+use crate::route::{Route, RouteRegistry};
+use crate::sources::{Sources, DdcRetry};
+use crate::capture::CaptureStage;
+use crate::guest_request;
+use crate::spawner::Spawner;
+use crate::history;
+use crate::util::LogId;
+use crate::Events;
+
+const WAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Configures a [`Session`] for one screen
+pub struct SessionBuilder {
+    config: Config,
+    screen: ConfigScreen,
+    log_id: Option<LogId>,
+    spawner: Option<Arc<Spawner>>,
+    xreq_sender: Option<mpsc::Sender<XRequest>>,
+    routes: Option<RouteRegistry>,
+    config_path: Option<(PathBuf, usize)>,
+    profile: Option<String>,
+    xwayland: bool,
+    source_cache: bool,
+    keymap_info: Option<Arc<Mutex<XKeymapInfo>>>,
+}
+
+impl SessionBuilder {
+    /// `config.screens` is ignored, `screen` is the one being driven
+    pub fn new(config: Config, screen: ConfigScreen) -> Self {
+        Self {
+            config,
+            screen,
+            log_id: None,
+            spawner: None,
+            xreq_sender: None,
+            routes: None,
+            config_path: None,
+            profile: None,
+            xwayland: false,
+            source_cache: true,
+            keymap_info: None,
+        }
+    }
+
+    /// Prefix for logs and errors, defaults to screen 0 and the configured VM name
+    pub fn log_id(mut self, log_id: LogId) -> Self {
+        self.log_id = Some(log_id);
+        self
+    }
+
+    /// Tracks background tasks (like exec events) that should finish before exiting
+    pub fn spawner(mut self, spawner: Arc<Spawner>) -> Self {
+        self.spawner = Some(spawner);
+        self
+    }
+
+    /// Where grab and cursor requests for the X window go.
+    ///
+    /// They are discarded when no X window is attached.
+    pub fn x_requests(mut self, sender: mpsc::Sender<XRequest>) -> Self {
+        self.xreq_sender = Some(sender);
+        self
+    }
+
+    /// Input route backends to pick from with `qemu.routing`, defaults to the built-in ones
+    pub fn routes(mut self, routes: RouteRegistry) -> Self {
+        self.routes = Some(routes);
+        self
+    }
+
+    /// The file `ReloadConfig` re-reads, and which of its screens this is
+    pub fn config_path(mut self, path: PathBuf, screen: usize) -> Self {
+        self.config_path = Some((path, screen));
+        self
+    }
+
+    /// The profile that `config` was loaded with, reapplied on reload
+    pub fn profile(mut self, profile: String) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    /// Whether the X window runs in Xwayland compatibility mode
+    pub fn xwayland(mut self, compat: bool) -> Self {
+        self.xwayland = compat;
+        self
+    }
+
+    /// Whether monitor input sources are cached between runs, on by default
+    pub fn source_cache(mut self, enabled: bool) -> Self {
+        self.source_cache = enabled;
+        self
+    }
+
+    /// The X keyboard mapping, shown in the status
+    pub fn keymap_info(mut self, info: Arc<Mutex<XKeymapInfo>>) -> Self {
+        self.keymap_info = Some(info);
+        self
+    }
+
+    /// Connects to QEMU and the monitor, adds the guest input devices, and
+    /// starts forwarding input
+    pub async fn build(self) -> Result<Session, Error> {
+        let SessionBuilder { config, screen, log_id, spawner, xreq_sender, routes, config_path, profile, xwayland, source_cache, keymap_info } = self;
+        let log_id = log_id.unwrap_or_else(|| LogId::new(0, config.qemu.name.as_ref().map(|s| &s[..])));
+        let spawner = spawner.unwrap_or_else(|| Arc::new(Spawner::new()));
+        let mut routes = routes.unwrap_or_default();
+        if let Some(spice) = config.qemu.spice.clone() {
+            if !routes.contains(ConfigQemuRouting::Spice.key()) {
+                routes.register_spice(spice);
+            }
+        }
+        let routes = Arc::new(routes);
+        let xreq_sender = xreq_sender.unwrap_or_else(|| {
+            let (sender, receiver) = mpsc::channel(0x08);
+            tokio::spawn(receiver.for_each(|_| future::ready(())));
+            sender
+        });
+
+        let (keyboard_driver, relative_driver, absolute_driver) =
+            (config.qemu.keyboard_driver().clone(), config.qemu.relative_driver().clone(), config.qemu.absolute_driver().clone());
+
+        let events = Arc::new(events_from_config(&config));
+
+        let vms = config.qemu.vms();
+        let vm = vms.first().cloned();
+        let qemu = match vm {
+            Some(ref vm) => Qemu::new(vm.qmp_socket.clone(), vm.ga_socket.clone()),
+            None => Qemu::new(None, None),
+        };
+        let qemu = Arc::new(qemu);
+        qemu.supervise();
+        let guest_source = vm.and_then(|vm| vm.guest_source).unwrap_or(screen.guest_source);
+
+        let ddc = screen.ddc.unwrap_or_default();
+        let show_grab = ddc.show_grab();
+        let retry = DdcRetry::from_config(&ddc);
+        let cache = ddc::cache::default_path().filter(|_| source_cache)
+            .map(|path| Arc::new(std::sync::Mutex::new(ddc::cache::SourceCache::load(path))));
+        let new_sources = |monitor, host_source, guest_source, named, log_id: LogId| {
+            let mut sources = if ddc.is_none() {
+                // still tracks what's showing, without any monitor to switch
+                Sources::new(qemu.clone(), monitor, Default::default(), Default::default(), Vec::new(), Vec::new(), Vec::new(), ddc.minimal_delay, ddc.timeout, config.quirks.clone(), log_id)
+            } else {
+                Sources::new(qemu.clone(), monitor, host_source, guest_source, named, ddc.host.clone(), ddc.guest.clone(), ddc.minimal_delay, ddc.timeout, config.quirks.clone(), log_id)
+            };
+            sources.set_retry(retry);
+            sources.set_x_requests(xreq_sender.clone());
+            if let Some(cache) = &cache {
+                sources.set_cache(cache.clone());
+            }
+            sources
+        };
+        let mut sources = new_sources(screen.monitor, screen.host_source, guest_source, screen.sources, log_id.clone());
+        for (i, monitor) in screen.monitors.into_iter().enumerate() {
+            sources.add_monitor(new_sources(monitor.monitor, monitor.host_source, monitor.guest_source, Vec::new(), log_id.monitor(i + 1)));
+        }
+        sources.fill().await?;
+
+        let channels = config.channels;
+        let (event_sender, mut event_recv) = mpsc::channel(channels.input);
+        let (error_sender, error_recv) = mpsc::channel(1);
+        let (user_sender, user_receiver) = mpsc::channel::<Arc<ConfigEvent>>(channels.user);
+
+        let mut process = Process::new(
+            config.qemu.routing, keyboard_driver.clone(), relative_driver.clone(), absolute_driver.clone(), config.exit_events, config.hotkeys, config.exit,
+            config.qemu.uinput.clone(), qemu.clone(), events.clone(), sources, xreq_sender, event_sender.clone(), user_sender.clone(), error_sender.clone(),
+            spawner.clone(), config.history_size.unwrap_or(history::DEFAULT_CAPACITY), config.unknown_events,
+            log_id.clone(), config.guest_notify, routes.clone(), config.qemu.pause_on_host, show_grab, config.sounds,
+            config.compositor, !config.allow_host_idle, config.qemu.launch.clone(), vms,
+        );
+
+        if let Some((path, screen)) = config_path {
+            process.set_config_path(path, screen, profile);
+        }
+        if xwayland {
+            process.set_xwayland_compat(config.xwayland.grab.clone());
+        }
+        if let Some(info) = keymap_info {
+            process.set_keymap_info(info);
+        }
+        process.set_channels(channels);
+        process.set_guest_ready(config.qemu.guest_ready.clone());
+
+        match qemu.capabilities().await {
+            Ok(capabilities) => {
+                info!("{} {}", log_id, capabilities);
+                process.check_capabilities(&capabilities)?;
+            },
+            Err(e) => warn!("{} QEMU capability probing failed: {:#}", log_id, e),
+        }
+
+        match process.devices_init().await {
+            Err(e) if process.can_launch() =>
+                warn!("{} Guest input devices will be added once the VM is started: {:#}", log_id, e),
+            res => res?,
+        }
+
+        let uinput_id = InputId {
+            bustype: input::sys::BUS_VIRTUAL,
+            vendor: 0x16c0,
+            product: 0x05df,
+            version: 1,
+        };
+
+        let repeat = false;
+        let mut route_keyboard = Route::new(&routes, config.qemu.routing, qemu.clone(), "screenstub-route-kbd".into(), keyboard_driver.bus().cloned(), repeat, config.qemu.uinput.clone(), config.qemu.absolute, config.qemu.qmp_error_pause, &log_id)?;
+        let mut forward_scancodes = false;
+        if let Some(builder) = route_keyboard.builder() {
+            builder
+                .name("screenstub-kbd")
+                .x_config_key(repeat)
+                .id(&uinput_id);
+            if config.qemu.forward_scancodes {
+                builder.x_config_scancode();
+                forward_scancodes = true;
+            }
+        } else if config.qemu.forward_scancodes {
+            warn!("forward_scancodes requires uinput routing, ignoring");
+        }
+        let test_keyboard = route_keyboard.self_test();
+        let mut events_keyboard = route_keyboard.spawn(&spawner, process.route_gate(), channels.route, error_sender.clone());
+
+        let mut route_relative = Route::new(&routes, config.qemu.routing, qemu.clone(), "screenstub-route-mouse".into(), relative_driver.bus().cloned(), repeat, config.qemu.uinput.clone(), config.qemu.absolute, config.qemu.qmp_error_pause, &log_id)?;
+        if let Some(builder) = route_relative.builder() {
+            builder
+                .name("screenstub-mouse")
+                .x_config_rel()
+                .id(&uinput_id);
+        }
+        let test_relative = route_relative.self_test();
+        let mut events_relative = route_relative.spawn(&spawner, process.route_gate(), channels.route, error_sender.clone());
+
+        let mut route_absolute = Route::new(&routes, config.qemu.routing, qemu.clone(), "screenstub-route-tablet".into(), absolute_driver.bus().cloned(), repeat, config.qemu.uinput.clone(), config.qemu.absolute, config.qemu.qmp_error_pause, &log_id)?;
+        if let Some(builder) = route_absolute.builder() {
+            builder
+                .name("screenstub-tablet")
+                .x_config_abs(config.qemu.absolute.maximum, config.qemu.absolute.resolution)
+                .id(&uinput_id);
+        }
+        let test_absolute = route_absolute.self_test();
+        let mut events_absolute = route_absolute.spawn(&spawner, process.route_gate(), channels.route, error_sender.clone());
+
+        process.set_absolute_route(events_absolute.clone());
+        process.set_keyboard_route(events_keyboard.clone());
+
+        if config.qemu.self_test {
+            let (keyboard, relative, absolute) = future::join3(test_keyboard, test_relative, test_absolute).await;
+            let failures: Vec<_> = vec![("keyboard", keyboard), ("mouse", relative), ("tablet", absolute)].into_iter()
+                .filter_map(|(name, res)| res.err().map(|e| format!("{} route: {:#}", name, e)))
+                .collect();
+            if !failures.is_empty() {
+                return Err(format_err!("input routing self-test failed: {}", failures.join("; ")))
+            }
+            info!("Input routing self-test passed");
+        }
+
+        let process = Arc::new(process);
+
+        let (event_loop, event_loop_abort) = future::abortable({
+            let events = events.clone();
+            let process = process.clone();
+            let qemu = qemu.clone();
+            let wake_on_input = config.qemu.wake_on_input;
+            let log_id = log_id.clone();
+            let mut user_sender = user_sender.clone();
+            let stats = process.channel_stats().clone();
+            async move {
+                while let Some(event) = event_recv.next().await {
+                    process.capture(CaptureStage::Host, &event);
+                    if wake_on_input && qemu.guest_suspended() && is_key_press(&event) && process.showing_guest() == Some(true) {
+                        // input queues up in event_recv while the guest resumes
+                        info!("{} Waking suspended guest", log_id);
+                        if let Err(e) = qemu.wakeup(WAKE_TIMEOUT).await {
+                            warn!("{} Failed to wake guest: {:?}", log_id, e);
+                        }
+                    }
+                    let user_events = events.process_input_event(&event);
+                    let inputevent = events.map_input_event(event, Destination::Guest);
+                    let user_sender = &mut user_sender;
+                    let stats = &stats;
+                    let f1 = async move {
+                        for e in user_events {
+                            let _ = stats.user.send(user_sender, e).await;
+                        }
+                    };
+                    process.capture(CaptureStage::Guest, &inputevent);
+                    let is_mouse = process.is_mouse();
+                    let kind = map_event_kind(&inputevent, is_mouse, forward_scancodes);
+                    let forward_unknown = match kind {
+                        EventKind::Key | EventKind::Relative | EventKind::Absolute | EventKind::Synchronize => false,
+                        _ => process.unknown_events().handle(&inputevent),
+                    };
+
+                    let events_keyboard = &mut events_keyboard;
+                    let events_relative = &mut events_relative;
+                    let events_absolute = &mut events_absolute;
+                    let route = &stats.route;
+                    let f2 = async move {
+                        match kind {
+                            EventKind::Key => {
+                                let _ = route.send(events_keyboard, inputevent).await;
+                            },
+                            EventKind::Relative => {
+                                let _ = route.send(events_relative, inputevent).await;
+                            },
+                            EventKind::Absolute => {
+                                let _ = route.send(events_absolute, inputevent).await;
+                            },
+                            EventKind::Synchronize => {
+                                let _ = future::try_join3(
+                                    route.send(events_keyboard, inputevent),
+                                    route.send(events_relative, inputevent),
+                                    route.send(events_absolute, inputevent)
+                                ).await;
+                            },
+                            _ => if forward_unknown {
+                                let _ = route.send(events_keyboard, inputevent).await;
+                            },
+                        }
+                    };
+                    let _ = future::join(f1, f2).await;
+                }
+            }
+        });
+        let event_loop = tokio::spawn(event_loop.map(drop));
+
+        let guest_request_abort = config.guest_request
+            .map(|guest_request| guest_request::spawn(guest_request, user_sender.clone(), log_id.clone()));
+
+        Ok(Session {
+            process,
+            events,
+            qemu,
+            log_id,
+            event_sender,
+            user_sender,
+            user_receiver,
+            error_recv,
+            event_loop,
+            event_loop_abort,
+            guest_request_abort,
+        })
+    }
+}
+
+/// Hotkeys and remapping from `config`, the part of a session that input goes through first
+pub fn events_from_config(config: &Config) -> Events {
+    let mut events = Events::new();
+    config.hotkeys.iter().cloned()
+        .map(process::convert_hotkey)
+        .for_each(|(hotkey, on_press)| events.add_hotkey(hotkey, on_press));
+    config.key_remap.iter().for_each(|(&from, &to)| events.add_remap(Destination::Guest, from, to));
+    config.host_key_remap.iter().for_each(|(&from, &to)| events.add_remap(Destination::Host, from, to));
+    events.set_wheel(Destination::Guest, process::convert_wheel(&config.wheel));
+    events.set_wheel(Destination::Host, process::convert_wheel(&config.host_wheel));
+    events.set_sticky_keys(config.sticky_keys);
+    config.switch_events.iter().cloned()
+        .map(process::convert_switch_event)
+        .for_each(|(switch, on, e)| events.add_switch_event(switch, on, e));
+    events
+}
+
+/// A running screen: input routes to the guest along with the switching and
+/// grab state driven by config events
+pub struct Session {
+    process: Arc<Process>,
+    events: Arc<Events>,
+    qemu: Arc<Qemu>,
+    log_id: LogId,
+    event_sender: mpsc::Sender<InputEvent>,
+    user_sender: mpsc::Sender<Arc<ConfigEvent>>,
+    user_receiver: mpsc::Receiver<Arc<ConfigEvent>>,
+    error_recv: mpsc::Receiver<Error>,
+    event_loop: JoinHandle<()>,
+    event_loop_abort: AbortHandle,
+    guest_request_abort: Option<AbortHandle>,
+}
+
+impl Session {
+    pub fn process(&self) -> &Arc<Process> {
+        &self.process
+    }
+
+    /// Hotkey and remap state, for turning X events into input
+    pub fn events(&self) -> &Arc<Events> {
+        &self.events
+    }
+
+    pub fn qemu(&self) -> &Arc<Qemu> {
+        &self.qemu
+    }
+
+    /// Host input to be checked for hotkeys and forwarded to the guest
+    pub fn input_sender(&self) -> mpsc::Sender<InputEvent> {
+        self.event_sender.clone()
+    }
+
+    /// Events to run, in the same way as a hotkey would
+    pub fn user_sender(&self) -> mpsc::Sender<Arc<ConfigEvent>> {
+        self.user_sender.clone()
+    }
+
+    /// Runs user events until `stop` completes or a route fails
+    pub async fn run<F: Future<Output=()>>(&mut self, stop: F) -> Result<(), Error> {
+        let stop = stop.fuse();
+        futures::pin_mut!(stop);
+        loop {
+            futures::select! {
+                _ = stop => break Ok(()),
+                error = self.error_recv.next() => if let Some(error) = error {
+                    break Err(error)
+                },
+                event = self.user_receiver.next() => if let Some(event) = event {
+                    let event = self.process.process_user_event(&event);
+                    let log_id = self.log_id.clone();
+                    tokio::spawn(async move {
+                        match event.await {
+                            Err(e) =>
+                                warn!("{} User event failed {:#} {:?}", log_id, e, e),
+                            Ok(()) => (),
+                        }
+                    });
+                },
+            }
+        }
+    }
+
+    /// Removes the guest input devices and stops forwarding input
+    pub async fn shutdown(self) -> Result<(), Error> {
+        match tokio::time::timeout(Duration::from_secs(2), self.qemu.cleanup()).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => warn!("Failed to remove QEMU devices: {} {:?}", e, e),
+            Err(_) => warn!("Timed out removing QEMU devices"),
+        }
+
+        // seal off senders
+        if let Some(abort) = self.guest_request_abort {
+            abort.abort();
+        }
+        self.event_loop_abort.abort();
+        self.event_loop.map_err(Error::from).await
+    }
+}
+
+fn axis_is_relative(axis: RelativeAxis) -> bool {
+    match axis {
+        RelativeAxis::X | RelativeAxis::Y => true,
+        _ => false,
+    }
+}
+
+fn axis_is_absolute(axis: AbsoluteAxis) -> bool {
+    match axis {
+        AbsoluteAxis::X | AbsoluteAxis::Y => true,
+        _ => false,
+    }
+}
+
+fn is_key_press(inputevent: &InputEvent) -> bool {
+    match EventRef::new(inputevent) {
+        Ok(EventRef::Key(key)) => key.value.is_pressed(),
+        _ => false,
+    }
+}
+
+fn map_event_kind(inputevent: &InputEvent, is_mouse: bool, forward_scancodes: bool) -> EventKind {
+    match inputevent.kind {
+        EventKind::Key if Key::from_code(inputevent.code).map(|k| k.is_button()).unwrap_or(false) =>
+            if is_mouse {
+                EventKind::Relative
+            } else {
+                EventKind::Absolute
+            },
+        EventKind::Key =>
+            EventKind::Key,
+        EventKind::Absolute if inputevent.code == AbsoluteAxis::Volume as u16 =>
+            EventKind::Key, // is this right?
+        EventKind::Relative if RelativeAxis::from_code(inputevent.code).map(|a| axis_is_relative(a)).unwrap_or(false) =>
+            EventKind::Relative,
+        EventKind::Absolute if AbsoluteAxis::from_code(inputevent.code).map(|a| axis_is_absolute(a)).unwrap_or(false) =>
+            EventKind::Absolute,
+        EventKind::Relative | EventKind::Absolute =>
+            if is_mouse {
+                EventKind::Relative
+            } else {
+                EventKind::Absolute
+            },
+        EventKind::Synchronize =>
+            EventKind::Synchronize,
+        EventKind::Misc if forward_scancodes && inputevent.code == MiscKind::Scancode as u16 =>
+            EventKind::Key,
+        kind => kind,
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::{Arc, RwLock};
+use log::warn;
+use config::ConfigSounds;
+use crate::exec::exec;
+use crate::util::LogId;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SoundCue {
+    Grab,
+    Ungrab,
+    ShowGuest,
+    ShowHost,
+}
+
+/// Plays the configured `sounds` in the background, ignoring failures
+pub struct Sounds {
+    config: RwLock<Option<Arc<ConfigSounds>>>,
+    log_id: LogId,
+}
+
+impl Sounds {
+    pub fn new(config: Option<ConfigSounds>, log_id: LogId) -> Self {
+        Sounds {
+            config: RwLock::new(config.map(Arc::new)),
+            log_id,
+        }
+    }
+
+    pub fn set(&self, config: Option<ConfigSounds>) {
+        *self.config.write().unwrap() = config.map(Arc::new);
+    }
+
+    pub fn cue(&self, cue: SoundCue) {
+        let config = match &*self.config.read().unwrap() {
+            Some(config) => config.clone(),
+            None => return,
+        };
+        let path = match cue {
+            SoundCue::Grab => &config.grab,
+            SoundCue::Ungrab => &config.ungrab,
+            SoundCue::ShowGuest => &config.show_guest,
+            SoundCue::ShowHost => &config.show_host,
+        };
+        if let Some(path) = path {
+            self.play(&config, path);
+        }
+    }
+
+    /// Plays any sound file, with the configured player if there is one
+    pub fn play_file(&self, path: &str) {
+        let path = match config::expand_path(path) {
+            Ok(path) => path,
+            Err(e) => return warn!("{} Failed to play sound: {}", self.log_id, e),
+        };
+        let config = self.config.read().unwrap().clone()
+            .unwrap_or_else(|| Arc::new(Default::default()));
+        self.play(&config, &path);
+    }
+
+    fn play(&self, config: &ConfigSounds, path: &str) {
+        let args: Vec<_> = config.player.iter()
+            .map(|arg| arg.replace("{}", path))
+            .collect();
+        let log_id = self.log_id.clone();
+        tokio::spawn(async move {
+            if let Err(e) = exec(args).into_future().await {
+                warn!("{} Failed to play sound: {:#}", log_id, e);
+            }
+        });
+    }
+}
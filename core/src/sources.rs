@@ -0,0 +1,572 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU16, Ordering};
+use std::sync::{Arc, RwLock};
+use futures::channel::mpsc;
+use futures::lock::Mutex;
+use futures::{future, FutureExt, SinkExt};
+use log::warn;
+use tokio::time::{Duration, Instant, sleep, sleep_until};
+use anyhow::{Error, format_err};
+use qemu::Qemu;
+use config::{ConfigSource, ConfigMonitor, ConfigMonitorQuirks, ConfigDdc, ConfigDdcMethod};
+use x::XRequest;
+use crate::exec::exec;
+use crate::util::LogId;
+use ddc::{SearchDisplay, DdcError, AsyncMonitor, DynMonitor, Backend};
+use ddc::cache::SourceCache;
+
+pub struct Sources {
+    qemu: Arc<Qemu>,
+    /// Changes along with the VM being switched to
+    source_guest: AtomicU16,
+    source_host: Option<u8>,
+    target_showing: Arc<AtomicBool>,
+    showing_guest: Arc<AtomicU8>,
+    current_source: Arc<AtomicU16>,
+    named: Arc<Vec<(String, u8)>>,
+    host: RwLock<Vec<Arc<ConfigDdcMethod>>>,
+    guest: RwLock<Vec<Arc<ConfigDdcMethod>>>,
+    monitor: Arc<SearchDisplay>,
+    ddc: AsyncMonitor,
+    quirks: Arc<Vec<ConfigMonitorQuirks>>,
+    throttle: Arc<Mutex<Instant>>,
+    throttle_duration: Duration,
+    retry: RwLock<DdcRetry>,
+    cache: Option<Arc<std::sync::Mutex<SourceCache>>>,
+    /// Window requests for the `dpms` method
+    xreq_sender: Option<mpsc::Sender<XRequest>>,
+    /// Other monitors that switch along with this one
+    monitors: Vec<Sources>,
+    log_id: LogId,
+}
+
+/// How DDC switches that fail are retried
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DdcRetry {
+    pub retries: u32,
+    pub delay: Duration,
+    /// Reads the source back to make sure the switch took
+    pub verify: bool,
+}
+
+impl DdcRetry {
+    pub fn from_config(ddc: &ConfigDdc) -> Self {
+        DdcRetry {
+            retries: ddc.retries,
+            delay: ddc.retry_delay,
+            verify: ddc.verify,
+        }
+    }
+}
+
+const NO_SOURCE: u16 = u16::MAX;
+
+fn source_u16(source: Option<u8>) -> u16 {
+    source.map(|s| s as u16).unwrap_or(NO_SOURCE)
+}
+
+/// The DDC implementation that a switching method uses, if any
+pub fn ddc_backend(method: &ConfigDdcMethod) -> Option<Backend> {
+    Some(match method {
+        ConfigDdcMethod::Ddc => Backend::DdcHi,
+        ConfigDdcMethod::Libddcutil => Backend::Libddcutil,
+        ConfigDdcMethod::Ddcutil => Backend::DdcutilCli,
+        ConfigDdcMethod::Ddcci => Backend::Ddcci,
+        _ => return None,
+    })
+}
+
+fn convert_display(monitor: ConfigMonitor, log_id: &LogId) -> SearchDisplay {
+    let i2c_bus = monitor.i2c_bus.or_else(|| monitor.dev_path.as_ref().and_then(|path| {
+        let bus = ddc::sysfs::i2c_bus_from_path(path);
+        if bus.is_none() {
+            warn!("{} monitor dev_path {} is not an i2c device", log_id, path);
+        }
+        bus
+    }));
+    SearchDisplay {
+        backend_id: monitor.id,
+        manufacturer_id: monitor.manufacturer,
+        model_name: monitor.model,
+        serial_number: monitor.serial,
+        connector: monitor.connector,
+        i2c_bus,
+    }
+}
+
+impl Sources {
+    pub fn new(qemu: Arc<Qemu>, display: ConfigMonitor, source_host: ConfigSource, source_guest: ConfigSource, named: Vec<(String, ConfigSource)>, host: Vec<ConfigDdcMethod>, guest: Vec<ConfigDdcMethod>, throttle_duration: Duration, timeout: Duration, quirks: Vec<ConfigMonitorQuirks>, log_id: LogId) -> Self {
+        let named = named.into_iter()
+            .filter_map(|(name, source)| match source.value() {
+                Some(value) => Some((name, value)),
+                None => {
+                    warn!("{} source {} has no value", log_id, name);
+                    None
+                },
+            }).collect();
+        Sources {
+            qemu,
+            source_guest: AtomicU16::new(source_u16(source_guest.value())),
+            source_host: source_host.value(),
+            target_showing: Arc::new(AtomicBool::new(false)),
+            showing_guest: Arc::new(AtomicU8::new(2)),
+            current_source: Arc::new(AtomicU16::new(NO_SOURCE)),
+            named: Arc::new(named),
+            host: RwLock::new(host.into_iter().map(Arc::new).collect()),
+            guest: RwLock::new(guest.into_iter().map(Arc::new).collect()),
+            monitor: Arc::new(convert_display(display, &log_id)),
+            ddc: AsyncMonitor::new(timeout),
+            quirks: Arc::new(quirks),
+            throttle: Arc::new(Mutex::new(Instant::now() - throttle_duration)),
+            throttle_duration,
+            retry: Default::default(),
+            cache: None,
+            xreq_sender: None,
+            monitors: Vec::new(),
+            log_id,
+        }
+    }
+
+    /// Switches `monitor` in parallel with this one whenever the host or guest is shown.
+    ///
+    /// Each monitor keeps its own sources and throttling.
+    pub fn add_monitor(&mut self, monitor: Sources) {
+        self.monitors.push(monitor);
+    }
+
+    /// Replaces how inputs are switched, taking effect from the next switch
+    pub fn set_methods(&self, host: Vec<ConfigDdcMethod>, guest: Vec<ConfigDdcMethod>) {
+        for monitor in &self.monitors {
+            monitor.set_methods(host.clone(), guest.clone());
+        }
+        *self.host.write().unwrap() = host.into_iter().map(Arc::new).collect();
+        *self.guest.write().unwrap() = guest.into_iter().map(Arc::new).collect();
+    }
+
+    /// Takes effect from the next switch
+    pub fn set_retry(&self, retry: DdcRetry) {
+        for monitor in &self.monitors {
+            monitor.set_retry(retry);
+        }
+        *self.retry.write().unwrap() = retry;
+    }
+
+    /// Remembers the monitor's sources in `cache` rather than asking it every run
+    pub fn set_cache(&mut self, cache: Arc<std::sync::Mutex<SourceCache>>) {
+        self.cache = Some(cache);
+    }
+
+    /// Where the `dpms` method sends its requests
+    pub fn set_x_requests(&mut self, sender: mpsc::Sender<XRequest>) {
+        self.xreq_sender = Some(sender);
+    }
+
+    pub async fn fill(&mut self) -> Result<(), Error> {
+        self.fill_monitor().await?;
+        for monitor in &mut self.monitors {
+            monitor.fill_monitor().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn fill_monitor(&mut self) -> Result<(), Error> {
+        let methods = self.host.read().unwrap().clone();
+        for method in methods {
+            if self.source_host.is_some() && self.guest_source().is_some() {
+                break
+            }
+            let monitor = self.monitor.clone();
+            let quirks = self.quirks.clone();
+            let cache = self.cache.clone();
+            let (source_host, source_guest) = (self.source_host, self.guest_source());
+            let (source_host, source_guest) = self.ddc.run(move |ddc| {
+                let ddc = Self::ddc_connect(ddc, &method, &monitor)?;
+                let quirk = Self::quirk(&quirks, &**ddc);
+                let source_host = match source_host {
+                    Some(source) => source,
+                    None => ddc.get_source()?,
+                };
+                let source_guest = match source_guest {
+                    Some(source) => Some(source),
+                    None => {
+                        let sources = match cache {
+                            Some(cache) => cache.lock().unwrap().sources(&mut **ddc)?,
+                            None => ddc.sources()?,
+                        };
+                        ddc::find_guest_source(&quirk.filter_sources(sources), source_host)
+                    },
+                };
+                Ok((source_host, source_guest))
+            }).await?;
+            self.source_host = Some(source_host);
+            self.source_guest.store(source_u16(source_guest), Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    fn map_source_arg<S: AsRef<str>>(s: S, source: Option<u8>, host: bool, bus: Option<u32>) -> Result<String, Error> {
+        let source = source
+            .ok_or_else(|| format_err!("DDC {} source not found",
+                if host { "host" } else { "guest" }
+            ));
+        let s = s.as_ref();
+        Ok(if s == "{}" {
+            format!("{}", source?)
+        } else if s == "{:x}" {
+            format!("{:02x}", source?)
+        } else if s == "0x{:x}" {
+            format!("0x{:02x}", source?)
+        } else if s.contains("{bus}") {
+            let bus = bus.ok_or_else(|| format_err!("DDC monitor i2c bus not found"))?;
+            s.replace("{bus}", &bus.to_string())
+        } else {
+            s.to_owned()
+        })
+    }
+
+    /// ddcset filters for the configured monitor, so that only it is switched
+    fn display_args(monitor: &SearchDisplay) -> Result<Vec<String>, Error> {
+        let args: Vec<String> = [
+            ("-i", &monitor.backend_id),
+            ("-m", &monitor.manufacturer_id),
+            ("-n", &monitor.model_name),
+            ("-s", &monitor.serial_number),
+        ].iter()
+            .filter_map(|&(flag, value)| value.as_ref().map(|value| (flag, value)))
+            .flat_map(|(flag, value)| vec![flag.to_owned(), value.clone()])
+            .collect();
+        if args.is_empty() {
+            Err(format_err!("DDC monitor needs an id, manufacturer, model or serial to select it"))
+        } else {
+            Ok(args)
+        }
+    }
+
+    fn quirk(quirks: &[ConfigMonitorQuirks], ddc: &DynMonitor) -> ConfigMonitorQuirks {
+        let (manufacturer, model) = (ddc.manufacturer_id(), ddc.model_name());
+        quirks.iter()
+            .filter(|q| q.matches(manufacturer.as_ref().map(|s| &s[..]), model.as_ref().map(|s| &s[..])))
+            .fold(ConfigMonitorQuirks::default(), |mut quirk, q| {
+                quirk.merge(q);
+                quirk
+            })
+    }
+
+    fn i2c_bus(monitor: &SearchDisplay) -> Option<u32> {
+        if monitor.i2c_bus.is_some() {
+            return monitor.i2c_bus
+        }
+
+        ddc::sysfs::search(monitor).ok()?.into_iter()
+            .filter_map(|(connector, _)| connector.i2c_buses().ok())
+            .flat_map(|buses| buses.into_iter())
+            .next()
+    }
+
+    // TODO: detect current showing state via ddc when unknown?
+
+    pub fn showing_guest(&self) -> Option<bool> {
+        Self::showing_guest_(&self.showing_guest)
+    }
+
+    fn showing_guest_(showing_guest: &AtomicU8) -> Option<bool> {
+        match showing_guest.load(Ordering::Relaxed) {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+
+    pub fn host_source(&self) -> Option<u8> {
+        self.source_host
+    }
+
+    pub fn guest_source(&self) -> Option<u8> {
+        Self::current_source_(&self.source_guest)
+    }
+
+    /// Points the guest at another input, such as for a different VM.
+    ///
+    /// What's showing becomes unknown if the old guest input was, so that the next
+    /// `show_guest` switches over.
+    pub fn set_guest_source(&self, source: Option<u8>) {
+        let previous = self.source_guest.swap(source_u16(source), Ordering::Relaxed);
+        if previous != source_u16(source) && self.showing_guest() == Some(true) {
+            self.showing_guest.store(2, Ordering::Relaxed);
+        }
+    }
+
+    /// The input last switched to, if known
+    pub fn current_source(&self) -> Option<u8> {
+        Self::current_source_(&self.current_source)
+    }
+
+    fn current_source_(current_source: &AtomicU16) -> Option<u8> {
+        match current_source.load(Ordering::Relaxed) {
+            NO_SOURCE => None,
+            source => Some(source as u8),
+        }
+    }
+
+    pub fn source_name(&self, source: u8) -> Option<&str> {
+        self.named.iter()
+            .find(|&&(_, value)| value == source)
+            .map(|(name, _)| &name[..])
+    }
+
+    /// Switches to the named source after the current one
+    pub fn cycle(&self) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        let current = self.current_source();
+        let next = match self.named.iter().position(|&(_, value)| Some(value) == current) {
+            Some(i) => self.named.get(i + 1).or(self.named.first()),
+            None => self.named.first(),
+        };
+        match next {
+            Some(&(_, source)) => self.show_source(source),
+            None => future::err(format_err!("no named sources configured")).boxed(),
+        }
+    }
+
+    pub fn show_source(&self, source: u8) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        if Some(source) == self.source_host {
+            return self.show(true, false).boxed()
+        } else if Some(source) == self.guest_source() {
+            return self.show(false, false).boxed()
+        }
+
+        // neither host nor guest, so switch it from the host like we would the guest
+        let methods: Vec<_> = self.guest.read().unwrap().iter().cloned()
+            .map(|method| self.show_(false, Some(source), method))
+            .collect();
+        let showing_guest = self.showing_guest.clone();
+        let current_source = self.current_source.clone();
+        let throttle = self.throttle.clone();
+        let throttle_duration = self.throttle_duration;
+        async move {
+            let mut throttle = throttle.lock().await;
+            sleep_until(*throttle).await;
+
+            for method in methods {
+                method.await?;
+            }
+
+            showing_guest.store(2, Ordering::Relaxed);
+            current_source.store(source as u16, Ordering::Relaxed);
+            *throttle = Instant::now() + throttle_duration;
+
+            Ok(())
+        }.boxed()
+    }
+
+    pub fn show_guest(&self) -> impl Future<Output=Result<(), Error>> {
+        self.show(false, false)
+    }
+
+    pub fn show_host(&self) -> impl Future<Output=Result<(), Error>> {
+        self.show(true, false)
+    }
+
+    fn ddc_connect<'a>(ddc: &'a mut Option<Box<DynMonitor>>, method: &ConfigDdcMethod, monitor: &SearchDisplay) -> Result<&'a mut Box<DynMonitor>, Error> {
+        if ddc.is_some() {
+            // mean workaround for lifetime issues
+            match ddc.as_mut() {
+                Some(ddc) => Ok(ddc),
+                None => unsafe { core::hint::unreachable_unchecked() },
+            }
+        } else {
+            // other methods still read the host source over DDC when they can
+            let backend = ddc_backend(method).or_else(Backend::preferred)
+                .ok_or_else(|| format_err!("{:?} is not a DDC backend", method))?;
+            match backend.search(monitor)? {
+                Some(res) =>
+                    Ok(ddc.get_or_insert(res)),
+                None =>
+                    Err(format_err!("DDC monitor not found")),
+            }
+        }
+    }
+
+    pub fn show(&self, host: bool, force: bool) -> impl Future<Output=Result<(), Error>> {
+        let monitors = future::try_join_all(self.monitors.iter().map(|monitor| monitor.show_monitor(host, force)));
+        future::try_join(self.show_monitor(host, force), monitors)
+            .map(|res| res.map(drop))
+    }
+
+    fn show_monitor(&self, host: bool, force: bool) -> impl Future<Output=Result<(), Error>> {
+        let show_host = self.show_commands(true);
+        let show_guest = self.show_commands(false);
+
+        self.target_showing.store(host, Ordering::Relaxed);
+
+        let target_showing = self.target_showing.clone();
+        let showing_guest = self.showing_guest.clone();
+        let current_source = self.current_source.clone();
+        let (source_host, source_guest) = (self.source_host, self.guest_source());
+        let throttle = self.throttle.clone();
+        let throttle_duration = self.throttle_duration;
+        async move {
+            let mut throttle = throttle.lock().await;
+
+            let throttle_until = *throttle;
+            let now = Instant::now();
+            let throttle_until = if throttle_until <= now {
+                // a short delay gives the event loop a chance to change its mind
+                now + Duration::from_millis(48)
+            } else {
+                throttle_until
+            };
+            sleep_until(throttle_until).await;
+
+            let host = target_showing.load(Ordering::Relaxed);
+            let guest = !host;
+
+            if force || Self::showing_guest_(&showing_guest) != Some(guest) {
+                let methods = if host {
+                    show_host
+                } else {
+                    show_guest
+                };
+
+                for method in methods {
+                    method.await?;
+                }
+
+                showing_guest.store(guest as u8, Ordering::Relaxed);
+                let source = if host { source_host } else { source_guest };
+                current_source.store(source_u16(source), Ordering::Relaxed);
+                *throttle = Instant::now() + throttle_duration;
+            }
+
+            Ok(())
+        }
+    }
+
+    fn show_commands(&self, host: bool) -> Vec<impl Future<Output=Result<(), Error>>> {
+        let methods = if host {
+            &self.host
+        } else {
+            &self.guest
+        }.read().unwrap();
+        let source = if host {
+            self.source_host
+        } else {
+            self.guest_source()
+        };
+        methods.iter().cloned()
+            .map(|method|
+                self.show_(host, source, method)
+            ).collect()
+    }
+
+    /// A single attempt at switching over DDC, which only supersedes queued
+    /// operations on the first try
+    async fn ddc_switch(ddc: AsyncMonitor, method: Arc<ConfigDdcMethod>, monitor: Arc<SearchDisplay>, quirks: Arc<Vec<ConfigMonitorQuirks>>, source: u8, verify: bool, first: bool) -> Result<(), Error> {
+        let set_source = {
+            let (method, monitor) = (method.clone(), monitor.clone());
+            move |ddc: &mut Option<Box<DynMonitor>>| {
+                let res = Self::ddc_connect(ddc, &method, &monitor).and_then(|monitor| {
+                    monitor.set_source(source)?;
+                    Ok(Self::quirk(&quirks, &**monitor))
+                });
+                if res.is_err() {
+                    // reconnect for the next attempt
+                    *ddc = None;
+                }
+                res
+            }
+        };
+        let quirk = if first {
+            ddc.run_latest(set_source).await?
+        } else {
+            ddc.run(set_source).await?
+        };
+
+        if let Some(delay) = quirk.switch_delay {
+            sleep(delay).await;
+        }
+
+        if verify || quirk.verify {
+            let current = ddc.run(move |ddc|
+                Self::ddc_connect(ddc, &method, &monitor)?.get_source()
+            ).await?;
+            if current != source {
+                return Err(format_err!("DDC source verification failed: expected 0x{:02x} but monitor reports 0x{:02x}", source, current))
+            }
+        }
+
+        Ok(())
+    }
+
+    fn show_(&self, host: bool, source: Option<u8>, method: Arc<ConfigDdcMethod>) -> impl Future<Output=Result<(), Error>> {
+        let monitor = self.monitor.clone();
+        let quirks = self.quirks.clone();
+        let (ddc, qemu) = (
+            self.ddc.clone(),
+            self.qemu.clone(),
+        );
+        let retry = *self.retry.read().unwrap();
+        let xreq_sender = self.xreq_sender.clone();
+        let log_id = self.log_id.clone();
+        let context = format!("{} switching to {} with {:?}", self.log_id, if host { "host" } else { "guest" }, method);
+        async move { match &*method {
+            ConfigDdcMethod::GuestWait => qemu.guest_wait().await,
+            ConfigDdcMethod::Ddc | ConfigDdcMethod::Libddcutil | ConfigDdcMethod::Ddcutil | ConfigDdcMethod::Ddcci => {
+                let source = source
+                    .ok_or_else(|| format_err!("DDC {} source not found",
+                        if host { "host" } else { "guest" }
+                    ))?;
+                let (mut attempt, mut delay) = (0, retry.delay);
+                loop {
+                    let res = Self::ddc_switch(ddc.clone(), method.clone(), monitor.clone(), quirks.clone(), source, retry.verify, attempt == 0).await;
+                    match res {
+                        // cancelled means a newer switch took over, and this one no longer matters
+                        Err(e) if attempt < retry.retries && !matches!(e.downcast_ref::<DdcError>(), Some(DdcError::Cancelled)) => {
+                            attempt += 1;
+                            warn!("{} DDC switch attempt {} failed, retrying in {:?}: {:#}", log_id, attempt, delay, e);
+                            sleep(delay).await;
+                            delay *= 2;
+                        },
+                        res => break res,
+                    }
+                }
+            },
+            ConfigDdcMethod::Exec(args) => {
+                let bus = Self::i2c_bus(&monitor);
+                let res = exec(args.iter()
+                    .map(|i| Self::map_source_arg(i, source, host, bus))
+                    .collect::<Result<Vec<_>, Error>>()?
+                ).into_future().await;
+                res
+            },
+            ConfigDdcMethod::ExecTemplate(name) => {
+                let args = ConfigDdcMethod::exec_template(name)
+                    .ok_or_else(|| format_err!("unknown DDC exec template {}", name))?;
+                let bus = Self::i2c_bus(&monitor);
+                let mut cmd = Vec::new();
+                for &arg in args {
+                    match arg {
+                        "{display}" => cmd.extend(Self::display_args(&monitor)?),
+                        arg => cmd.push(Self::map_source_arg(arg, source, host, bus)?),
+                    }
+                }
+                let res = exec(cmd).into_future().await;
+                res
+            },
+            ConfigDdcMethod::GuestExec(args) => {
+                let res = qemu.guest_exec(args.iter()
+                    .map(|i| Self::map_source_arg(i, source, host, None))
+                    .collect::<Result<Vec<_>, Error>>()?
+                ).into_future().await;
+                res.map(drop)
+            },
+            ConfigDdcMethod::Dpms => match xreq_sender {
+                Some(mut sender) => sender.send(XRequest::DisplayPower(host)).await
+                    .map_err(From::from),
+                None => Err(format_err!("DPMS needs a window to send requests to")),
+            },
+        } }.map(move |res: Result<(), Error>| res.map_err(|e| e.context(context)))
+    }
+}
@@ -0,0 +1,412 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::task::Poll;
+use futures::channel::mpsc;
+use futures::lock::Mutex;
+use futures::{future, stream, Stream, StreamExt};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use anyhow::{Error, format_err};
+use log::{trace, warn};
+
+const LINK_MAGIC: &[u8; 4] = b"REDQ";
+const VERSION_MAJOR: u32 = 2;
+const VERSION_MINOR: u32 = 2;
+const LINK_MESS_SIZE: u32 = 18;
+const TICKET_SIZE: usize = 128;
+const MAX_MESSAGE_SIZE: usize = 0x100_0000;
+
+const CHANNEL_MAIN: u8 = 1;
+const CHANNEL_INPUTS: u8 = 3;
+
+const MSG_SET_ACK: u16 = 3;
+const MSG_PING: u16 = 4;
+const MSG_DISCONNECTING: u16 = 6;
+const MSG_MAIN_INIT: u16 = 103;
+
+const MSGC_ACK_SYNC: u16 = 1;
+const MSGC_ACK: u16 = 2;
+const MSGC_PONG: u16 = 3;
+const MSGC_MAIN_ATTACH_CHANNELS: u16 = 104;
+const MSGC_INPUTS_KEY_DOWN: u16 = 101;
+const MSGC_INPUTS_KEY_UP: u16 = 102;
+const MSGC_INPUTS_MOUSE_MOTION: u16 = 111;
+const MSGC_INPUTS_MOUSE_POSITION: u16 = 112;
+const MSGC_INPUTS_MOUSE_PRESS: u16 = 113;
+const MSGC_INPUTS_MOUSE_RELEASE: u16 = 114;
+
+pub const BUTTON_LEFT: u8 = 1;
+pub const BUTTON_MIDDLE: u8 = 2;
+pub const BUTTON_RIGHT: u8 = 3;
+pub const BUTTON_UP: u8 = 4;
+pub const BUTTON_DOWN: u8 = 5;
+pub const BUTTON_SIDE: u8 = 6;
+pub const BUTTON_EXTRA: u8 = 7;
+
+trait SpiceIo: AsyncRead + AsyncWrite + Unpin + Send { }
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> SpiceIo for S { }
+
+type SpiceSocket = Box<dyn SpiceIo>;
+type Message = (u16, Vec<u8>);
+
+/// A message for the inputs channel
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SpiceInput {
+    /// PC/XT scancode, with extended keys prefixed by 0xe0 in the low byte
+    KeyDown(u32),
+    KeyUp(u32),
+    Motion { dx: i32, dy: i32, buttons: u16 },
+    /// Only used by the guest when the SPICE agent puts it in client mouse mode
+    Position { x: u32, y: u32, buttons: u16 },
+    Press { button: u8, buttons: u16 },
+    Release { button: u8, buttons: u16 },
+}
+
+impl SpiceInput {
+    /// The scancodes for pressing and releasing a key, from its QEMU qnum
+    pub fn scancodes(qnum: u8) -> (u32, u32) {
+        if qnum & 0x80 != 0 {
+            let code = (qnum & 0x7f) as u32;
+            (0xe0 | code << 8, 0xe0 | (code | 0x80) << 8)
+        } else {
+            (qnum as u32, (qnum | 0x80) as u32)
+        }
+    }
+
+    fn message(&self) -> Message {
+        let mut body = Vec::with_capacity(11);
+        let kind = match *self {
+            SpiceInput::KeyDown(code) => {
+                body.extend_from_slice(&code.to_le_bytes());
+                MSGC_INPUTS_KEY_DOWN
+            },
+            SpiceInput::KeyUp(code) => {
+                body.extend_from_slice(&code.to_le_bytes());
+                MSGC_INPUTS_KEY_UP
+            },
+            SpiceInput::Motion { dx, dy, buttons } => {
+                body.extend_from_slice(&dx.to_le_bytes());
+                body.extend_from_slice(&dy.to_le_bytes());
+                body.extend_from_slice(&buttons.to_le_bytes());
+                MSGC_INPUTS_MOUSE_MOTION
+            },
+            SpiceInput::Position { x, y, buttons } => {
+                body.extend_from_slice(&x.to_le_bytes());
+                body.extend_from_slice(&y.to_le_bytes());
+                body.extend_from_slice(&buttons.to_le_bytes());
+                body.push(0); // display id
+                MSGC_INPUTS_MOUSE_POSITION
+            },
+            SpiceInput::Press { button, buttons } | SpiceInput::Release { button, buttons } => {
+                body.push(button);
+                body.extend_from_slice(&buttons.to_le_bytes());
+                match *self {
+                    SpiceInput::Press { .. } => MSGC_INPUTS_MOUSE_PRESS,
+                    _ => MSGC_INPUTS_MOUSE_RELEASE,
+                }
+            },
+        };
+        (kind, body)
+    }
+}
+
+fn le_u32(bytes: &[u8], offset: usize) -> Result<u32, Error> {
+    bytes.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| format_err!("SPICE message too short"))
+}
+
+fn encode(buf: &mut Vec<u8>, serial: u64, (kind, body): &Message) {
+    buf.extend_from_slice(&serial.to_le_bytes());
+    buf.extend_from_slice(&kind.to_le_bytes());
+    buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&0u32.to_le_bytes()); // sub_list
+    buf.extend_from_slice(body);
+}
+
+async fn read_message<R: AsyncRead + Unpin>(read: &mut R) -> Result<Message, Error> {
+    let mut header = [0u8; 18];
+    read.read_exact(&mut header).await?;
+    let kind = u16::from_le_bytes([header[8], header[9]]);
+    let size = le_u32(&header, 10)? as usize;
+    if size > MAX_MESSAGE_SIZE {
+        return Err(format_err!("SPICE message {} is too large at {} bytes", kind, size))
+    }
+    let mut body = vec![0; size];
+    read.read_exact(&mut body).await?;
+    Ok((kind, body))
+}
+
+/// Replies that keep the server happy, if any are needed
+fn control_reply(kind: u16, body: &[u8]) -> Result<Option<Message>, Error> {
+    Ok(match kind {
+        MSG_SET_ACK => Some((MSGC_ACK_SYNC, le_u32(body, 0)?.to_le_bytes().to_vec())),
+        MSG_PING => Some((MSGC_PONG, body.get(..12).unwrap_or(body).to_vec())),
+        _ => None,
+    })
+}
+
+/// A linked channel, with tasks reading from and writing to it
+struct Channel {
+    sender: mpsc::UnboundedSender<Message>,
+    alive: Arc<AtomicBool>,
+    tasks: [future::AbortHandle; 2],
+}
+
+impl Channel {
+    fn spawn(socket: SpiceSocket, serial: u64, name: &'static str) -> Self {
+        let (read, write) = io::split(socket);
+        let (sender, outgoing) = mpsc::unbounded();
+        let (control_sender, control) = mpsc::unbounded();
+        let alive = Arc::new(AtomicBool::new(true));
+
+        let (reader, reader_abort) = future::abortable({
+            let alive = alive.clone();
+            async move {
+                if let Err(e) = Self::read_loop(read, control_sender).await {
+                    warn!("SPICE {} channel closed: {:#}", name, e);
+                }
+                alive.store(false, Ordering::Relaxed);
+            }
+        });
+        let (writer, writer_abort) = future::abortable({
+            let alive = alive.clone();
+            async move {
+                if let Err(e) = Self::write_loop(write, stream::select(control, outgoing), serial).await {
+                    warn!("SPICE {} channel write failed: {:#}", name, e);
+                }
+                alive.store(false, Ordering::Relaxed);
+            }
+        });
+        tokio::spawn(reader);
+        tokio::spawn(writer);
+
+        Channel {
+            sender,
+            alive,
+            tasks: [reader_abort, writer_abort],
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Relaxed)
+    }
+
+    fn send(&self, message: Message) -> Result<(), Error> {
+        if !self.is_alive() {
+            return Err(format_err!("SPICE channel closed"))
+        }
+        self.sender.unbounded_send(message)
+            .map_err(|_| format_err!("SPICE channel closed"))
+    }
+
+    async fn read_loop<R: AsyncRead + Unpin>(mut read: R, control: mpsc::UnboundedSender<Message>) -> Result<(), Error> {
+        let (mut window, mut received) = (0, 0);
+        loop {
+            let (kind, body) = read_message(&mut read).await?;
+            match kind {
+                MSG_SET_ACK => {
+                    window = le_u32(&body, 4)?;
+                    received = 0;
+                },
+                MSG_DISCONNECTING => return Ok(()),
+                _ => trace!("SPICE message {} ignored", kind),
+            }
+            if let Some(reply) = control_reply(kind, &body)? {
+                let _ = control.unbounded_send(reply);
+            }
+            if window > 0 && kind != MSG_SET_ACK {
+                received += 1;
+                if received >= window {
+                    received = 0;
+                    let _ = control.unbounded_send((MSGC_ACK, Vec::new()));
+                }
+            }
+        }
+    }
+
+    async fn write_loop<W: AsyncWrite + Unpin, S: Stream<Item=Message> + Unpin>(mut write: W, mut messages: S, mut serial: u64) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        while let Some(message) = messages.next().await {
+            buf.clear();
+            serial += 1;
+            encode(&mut buf, serial, &message);
+            // batch up anything else that's already waiting
+            while let Poll::Ready(Some(message)) = futures::poll!(messages.next()) {
+                serial += 1;
+                encode(&mut buf, serial, &message);
+            }
+            write.write_all(&buf).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+struct Connection {
+    // only kept open, the session ends along with it
+    main: Channel,
+    inputs: Channel,
+}
+
+impl Connection {
+    fn is_alive(&self) -> bool {
+        self.main.is_alive() && self.inputs.is_alive()
+    }
+}
+
+/// A SPICE client that only sends input.
+///
+/// Only servers with `disable-ticketing` are supported, since a password
+/// would need to be encrypted with the server's RSA key.
+pub struct SpiceInputs {
+    address: String,
+    connection: Mutex<Option<Connection>>,
+    buttons: AtomicU16,
+}
+
+impl SpiceInputs {
+    /// `address` is either a unix socket path or `host:port`
+    pub fn new(address: String) -> Self {
+        SpiceInputs {
+            address,
+            connection: Mutex::new(None),
+            buttons: AtomicU16::new(0),
+        }
+    }
+
+    async fn connect_socket(&self) -> Result<SpiceSocket, Error> {
+        Ok(if self.address.contains('/') {
+            Box::new(UnixStream::connect(&self.address).await?)
+        } else {
+            let socket = TcpStream::connect(&self.address).await?;
+            socket.set_nodelay(true)?;
+            Box::new(socket)
+        })
+    }
+
+    async fn link(&self, connection_id: u32, channel_type: u8) -> Result<SpiceSocket, Error> {
+        let mut socket = self.connect_socket().await?;
+
+        let mut link = Vec::with_capacity(16 + LINK_MESS_SIZE as usize);
+        link.extend_from_slice(LINK_MAGIC);
+        for value in &[VERSION_MAJOR, VERSION_MINOR, LINK_MESS_SIZE, connection_id] {
+            link.extend_from_slice(&value.to_le_bytes());
+        }
+        link.push(channel_type);
+        link.push(0); // channel id
+        for value in &[0, 0, LINK_MESS_SIZE] {
+            // no common or channel capabilities, so no mini headers either
+            link.extend_from_slice(&value.to_le_bytes());
+        }
+        socket.write_all(&link).await?;
+
+        let mut header = [0u8; 16];
+        socket.read_exact(&mut header).await?;
+        if &header[..4] != LINK_MAGIC {
+            return Err(format_err!("{} is not a SPICE server", self.address))
+        }
+        match le_u32(&header, 4)? {
+            VERSION_MAJOR => (),
+            major => return Err(format_err!("unsupported SPICE protocol version {}", major)),
+        }
+        let size = le_u32(&header, 12)? as usize;
+        if size > MAX_MESSAGE_SIZE {
+            return Err(format_err!("SPICE link reply is too large at {} bytes", size))
+        }
+        let mut reply = vec![0; size];
+        socket.read_exact(&mut reply).await?;
+        match le_u32(&reply, 0)? {
+            0 => (),
+            error => return Err(format_err!("SPICE link failed with error {}", error)),
+        }
+
+        // the password would go here encrypted with the key from the reply,
+        // but it isn't checked when ticketing is disabled
+        socket.write_all(&[0; TICKET_SIZE]).await?;
+        let mut result = [0u8; 4];
+        socket.read_exact(&mut result).await?;
+        match u32::from_le_bytes(result) {
+            0 => Ok(socket),
+            error => Err(format_err!("SPICE authentication failed with error {}, is disable-ticketing set?", error)),
+        }
+    }
+
+    async fn connect(&self) -> Result<Connection, Error> {
+        let mut main = self.link(0, CHANNEL_MAIN).await?;
+        let mut serial = 0;
+        let session_id = loop {
+            let (kind, body) = read_message(&mut main).await?;
+            match kind {
+                MSG_MAIN_INIT => break le_u32(&body, 0)?,
+                MSG_DISCONNECTING => return Err(format_err!("SPICE server disconnected")),
+                _ => if let Some(reply) = control_reply(kind, &body)? {
+                    let mut buf = Vec::new();
+                    serial += 1;
+                    encode(&mut buf, serial, &reply);
+                    main.write_all(&buf).await?;
+                },
+            }
+        };
+        let main = Channel::spawn(main, serial, "main");
+        main.send((MSGC_MAIN_ATTACH_CHANNELS, Vec::new()))?;
+
+        let inputs = self.link(session_id, CHANNEL_INPUTS).await?;
+        let inputs = Channel::spawn(inputs, 0, "inputs");
+
+        Ok(Connection {
+            main,
+            inputs,
+        })
+    }
+
+    /// Connects unless already connected
+    pub async fn check(&self) -> Result<(), Error> {
+        let mut connection = self.connection.lock().await;
+        match *connection {
+            Some(ref c) if c.is_alive() => (),
+            _ => *connection = Some(self.connect().await?),
+        }
+        Ok(())
+    }
+
+    /// Sends input to the guest, reconnecting once if the connection was lost
+    pub async fn send(&self, inputs: &[SpiceInput]) -> Result<(), Error> {
+        self.check().await?;
+        let connection = self.connection.lock().await;
+        let connection = connection.as_ref()
+            .ok_or_else(|| format_err!("SPICE connection lost"))?;
+        for input in inputs {
+            connection.inputs.send(input.message())?;
+        }
+        Ok(())
+    }
+
+    /// Updates the buttons held down across every route, returning the new mask
+    pub fn set_button(&self, button: u8, pressed: bool) -> u16 {
+        let mask = 1 << (button - 1);
+        if pressed {
+            self.buttons.fetch_or(mask, Ordering::Relaxed) | mask
+        } else {
+            self.buttons.fetch_and(!mask, Ordering::Relaxed) & !mask
+        }
+    }
+
+    pub fn buttons(&self) -> u16 {
+        self.buttons.load(Ordering::Relaxed)
+    }
+}
+
+#[test]
+fn spice_scancodes() {
+    assert_eq!(SpiceInput::scancodes(0x1e), (0x1e, 0x9e));
+    // right control
+    assert_eq!(SpiceInput::scancodes(0x9d), (0x1de0, 0x9de0));
+}
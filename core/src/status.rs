@@ -0,0 +1,94 @@
+use std::fmt;
+use std::sync::Arc;
+use config::{ConfigGrabMode, ConfigQemuRouting};
+use qemu::QemuCapabilities;
+use x::XKeymapInfo;
+use input::{EventKind, SwitchKind};
+use crate::history::HistoryRecord;
+
+/// A point-in-time view of the running KVM
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub showing_guest: Option<bool>,
+    pub source: Option<u8>,
+    pub source_name: Option<String>,
+    pub grabs: Vec<ConfigGrabMode>,
+    pub profile: Option<String>,
+    pub is_mouse: bool,
+    pub routing: ConfigQemuRouting,
+    pub routes_paused: bool,
+    pub vm: Option<String>,
+    pub qmp_connected: bool,
+    pub qemu_capabilities: Option<Arc<QemuCapabilities>>,
+    pub recent_events: Vec<HistoryRecord>,
+    pub unknown_events: Vec<(EventKind, u64)>,
+    pub switches: Vec<(SwitchKind, bool)>,
+    /// How many times each channel was full
+    pub saturated: Vec<(&'static str, u64)>,
+    pub keymap: Option<XKeymapInfo>,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Showing: {}", match self.showing_guest {
+            Some(true) => "guest",
+            Some(false) => "host",
+            None => "unknown",
+        })?;
+        match (self.source, &self.source_name) {
+            (Some(source), Some(name)) => writeln!(f, "Source: {} (0x{:02x})", name, source)?,
+            (Some(source), None) => writeln!(f, "Source: 0x{:02x}", source)?,
+            (None, _) => (),
+        }
+        if let Some(ref profile) = self.profile {
+            writeln!(f, "Profile: {}", profile)?;
+        }
+        write!(f, "Grabs:")?;
+        if self.grabs.is_empty() {
+            write!(f, " none")?;
+        }
+        for grab in &self.grabs {
+            write!(f, " {:?}", grab)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "Pointer: {}", if self.is_mouse { "relative" } else { "absolute" })?;
+        writeln!(f, "Routing: {:?}{}", self.routing, if self.routes_paused { " (paused)" } else { "" })?;
+        if let Some(ref vm) = self.vm {
+            writeln!(f, "VM: {}", vm)?;
+        }
+        writeln!(f, "QMP: {}", if self.qmp_connected { "connected" } else { "disconnected" })?;
+        if let Some(ref capabilities) = self.qemu_capabilities {
+            writeln!(f, "{}", capabilities)?;
+        }
+        if let Some(ref keymap) = self.keymap {
+            writeln!(f, "Keymap: {}", keymap)?;
+        }
+        if !self.unknown_events.is_empty() {
+            write!(f, "Unforwarded:")?;
+            for (kind, count) in &self.unknown_events {
+                write!(f, " {:?}={}", kind, count)?;
+            }
+            writeln!(f)?;
+        }
+        if !self.switches.is_empty() {
+            write!(f, "Switches:")?;
+            for (switch, on) in &self.switches {
+                write!(f, " {:?}={}", switch, if *on { "on" } else { "off" })?;
+            }
+            writeln!(f)?;
+        }
+        if self.saturated.iter().any(|&(_, count)| count > 0) {
+            write!(f, "Channels full:")?;
+            for (channel, count) in &self.saturated {
+                write!(f, " {}={}", channel, count)?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "Recent events:")?;
+        for record in self.recent_events.iter().rev() {
+            writeln!(f, "  {}", record)?;
+        }
+
+        Ok(())
+    }
+}
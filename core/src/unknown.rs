@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use config::{ConfigInputEvent, ConfigUnknownEvent};
+use input::{InputEvent, EventKind};
+use log::warn;
+
+/// Decides what happens to input events that don't belong to any route,
+/// and keeps count of them
+pub struct UnknownEvents {
+    policies: HashMap<ConfigInputEvent, ConfigUnknownEvent>,
+    counts: Mutex<Vec<(EventKind, u64)>>,
+}
+
+impl UnknownEvents {
+    pub fn new(policies: HashMap<ConfigInputEvent, ConfigUnknownEvent>) -> Self {
+        Self {
+            policies,
+            counts: Default::default(),
+        }
+    }
+
+    fn policy(&self, e: &InputEvent) -> ConfigUnknownEvent {
+        ConfigInputEvent::from_event(e)
+            .and_then(|kind| self.policies.get(&kind))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Counts the event, returning whether it should be forwarded as-is
+    pub fn handle(&self, e: &InputEvent) -> bool {
+        let first = {
+            let mut counts = self.counts.lock().unwrap();
+            match counts.iter_mut().find(|(kind, _)| *kind == e.kind) {
+                Some((_, count)) => {
+                    *count += 1;
+                    false
+                },
+                None => {
+                    counts.push((e.kind, 1));
+                    true
+                },
+            }
+        };
+
+        match self.policy(e) {
+            ConfigUnknownEvent::Drop => false,
+            ConfigUnknownEvent::Forward => true,
+            ConfigUnknownEvent::LogOnce => {
+                if first {
+                    warn!("unforwarded event {:?}, ignoring any more like it", e.kind);
+                }
+                false
+            },
+        }
+    }
+
+    pub fn counts(&self) -> Vec<(EventKind, u64)> {
+        self.counts.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,54 @@
+use std::time::Duration;
+use std::future::Future;
+use std::hint;
+use std::sync::Arc;
+use std::fmt;
+use futures::{future, TryFutureExt};
+use anyhow::Error;
+use tokio::time;
+
+pub fn retry<R, E: Into<Error>, F: Future<Output=Result<R, E>>, FF: FnMut() -> F>(mut f: FF, retries: usize, timeout: Duration) -> impl Future<Output=Result<R, Error>> {
+    time::timeout(timeout, async move {
+        let mut err = None;
+        for _ in 0..=retries {
+            match f().await {
+                Ok(res) => return Ok(res),
+                Err(e) => err = Some(e),
+            }
+        }
+        match err {
+            Some(err) => Err(err.into()),
+            None => unsafe { hint::unreachable_unchecked() },
+        }
+    }).map_err(Error::from)
+    .and_then(|r| future::ready(r))
+}
+
+/// Identifies the screen, VM, and route that logs and errors come from,
+/// so that concurrent sessions can be told apart
+#[derive(Debug, Clone)]
+pub struct LogId(Arc<str>);
+
+impl LogId {
+    pub fn new(screen: usize, vm: Option<&str>) -> Self {
+        LogId(match vm {
+            Some(vm) => format!("screen {} vm {}", screen, vm),
+            None => format!("screen {}", screen),
+        }.into())
+    }
+
+    pub fn route(&self, route: &str) -> Self {
+        LogId(format!("{} route {}", self.0, route).into())
+    }
+
+    /// One of a screen's additional `monitors`
+    pub fn monitor(&self, index: usize) -> Self {
+        LogId(format!("{} monitor {}", self.0, index).into())
+    }
+}
+
+impl fmt::Display for LogId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}]", self.0)
+    }
+}
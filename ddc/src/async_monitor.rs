@@ -0,0 +1,111 @@
+use std::sync::{Arc, Mutex, TryLockError};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use std::future::Future;
+use anyhow::Error;
+use crate::{DdcMonitor, DdcError};
+
+pub type DynMonitor = dyn DdcMonitor<Error=Error> + Send;
+
+/// Runs blocking `DdcMonitor` operations off of the async runtime.
+///
+/// Each operation is bounded by a timeout so that a hung I2C transaction can't
+/// stall its caller, and operations started with `run_latest` are skipped if a
+/// newer `run_latest` was queued behind them in the meantime.
+///
+/// A timed out operation keeps its blocking thread, so until it returns,
+/// anything else fails with `DdcError::Hung` rather than queueing up behind
+/// it. The monitor is reopened once it does.
+#[derive(Clone)]
+pub struct AsyncMonitor {
+    monitor: Arc<Mutex<Option<Box<DynMonitor>>>>,
+    generation: Arc<AtomicUsize>,
+    latest: Arc<AtomicUsize>,
+    hung: Arc<AtomicBool>,
+    timeout: Duration,
+}
+
+impl AsyncMonitor {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            monitor: Arc::new(Mutex::new(None)),
+            generation: Arc::new(AtomicUsize::new(0)),
+            latest: Arc::new(AtomicUsize::new(0)),
+            hung: Arc::new(AtomicBool::new(false)),
+            timeout,
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Drops any operations that haven't yet started running
+    pub fn cancel(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn run<R, F>(&self, f: F) -> impl Future<Output=Result<R, Error>> where
+        R: Send + 'static,
+        F: FnOnce(&mut Option<Box<DynMonitor>>) -> Result<R, Error> + Send + 'static,
+    {
+        self.run_(None, f)
+    }
+
+    /// Like `run`, but cancelled if another `run_latest` call supersedes it
+    /// before it gets a chance to start
+    pub fn run_latest<R, F>(&self, f: F) -> impl Future<Output=Result<R, Error>> where
+        R: Send + 'static,
+        F: FnOnce(&mut Option<Box<DynMonitor>>) -> Result<R, Error> + Send + 'static,
+    {
+        let latest = self.latest.fetch_add(1, Ordering::SeqCst) + 1;
+        self.run_(Some(latest), f)
+    }
+
+    fn run_<R, F>(&self, latest: Option<usize>, f: F) -> impl Future<Output=Result<R, Error>> where
+        R: Send + 'static,
+        F: FnOnce(&mut Option<Box<DynMonitor>>) -> Result<R, Error> + Send + 'static,
+    {
+        let monitor = self.monitor.clone();
+        let generation = self.generation.load(Ordering::SeqCst);
+        let current = self.generation.clone();
+        let current_latest = self.latest.clone();
+        let hung = self.hung.clone();
+        let timeout = self.timeout;
+        let task = tokio::task::spawn_blocking({
+            let hung = hung.clone();
+            move || {
+                let mut monitor = if hung.load(Ordering::SeqCst) {
+                    match monitor.try_lock() {
+                        Ok(mut monitor) => {
+                            // the operation that timed out has finished, so don't trust the old handle
+                            hung.store(false, Ordering::SeqCst);
+                            *monitor = None;
+                            monitor
+                        },
+                        Err(TryLockError::WouldBlock) => return Err(DdcError::Hung.into()),
+                        Err(TryLockError::Poisoned(e)) => e.into_inner(),
+                    }
+                } else {
+                    monitor.lock().unwrap_or_else(|e| e.into_inner())
+                };
+                let superseded = current.load(Ordering::SeqCst) != generation
+                    || latest.map(|latest| current_latest.load(Ordering::SeqCst) != latest).unwrap_or(false);
+                if superseded {
+                    return Err(DdcError::Cancelled.into())
+                }
+                f(&mut monitor)
+            }
+        });
+
+        async move {
+            match tokio::time::timeout(timeout, task).await {
+                Ok(res) => res.map_err(Error::from).and_then(|r| r),
+                Err(_) => {
+                    hung.store(true, Ordering::SeqCst);
+                    Err(DdcError::Timeout.into())
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Remembers the input sources monitors report between runs, since reading a
+//! capability string can take seconds.
+//!
+//! The file has a line per monitor: its identity, a tab, and hex source values.
+
+use std::collections::HashMap;
+use std::env::var_os;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use anyhow::Error;
+use crate::DynMonitor;
+
+pub struct SourceCache {
+    path: PathBuf,
+    entries: HashMap<String, Vec<u8>>,
+}
+
+/// `$XDG_CACHE_HOME/screenstub/sources`, or under `~/.cache`
+pub fn default_path() -> Option<PathBuf> {
+    let cache = match var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(&var_os("HOME")?).join(".cache"),
+    };
+    Some(cache.join("screenstub").join("sources"))
+}
+
+/// Monitors are only cached when they can be told apart from other models
+pub fn monitor_key(monitor: &DynMonitor) -> Option<String> {
+    Some(format!("{}:{}:{}",
+        monitor.manufacturer_id()?,
+        monitor.model_name()?,
+        monitor.serial_number().unwrap_or_default(),
+    ))
+}
+
+pub fn parse(data: &str) -> HashMap<String, Vec<u8>> {
+    data.lines().filter_map(|line| {
+        let mut parts = line.splitn(2, '\t');
+        let key = parts.next()?;
+        let sources: Option<Vec<u8>> = parts.next()?.split_whitespace()
+            .map(|v| u8::from_str_radix(v, 16).ok())
+            .collect();
+        Some((key.to_owned(), sources?))
+    }).collect()
+}
+
+pub fn format(entries: &HashMap<String, Vec<u8>>) -> String {
+    let mut lines: Vec<_> = entries.iter().map(|(key, sources)| {
+        let sources: Vec<_> = sources.iter().map(|v| format!("{:02x}", v)).collect();
+        format!("{}\t{}\n", key, sources.join(" "))
+    }).collect();
+    lines.sort();
+    lines.concat()
+}
+
+impl SourceCache {
+    /// Starts out empty if the file is missing or unreadable
+    pub fn load(path: PathBuf) -> Self {
+        let entries = fs::read_to_string(&path)
+            .map(|data| parse(&data))
+            .unwrap_or_default();
+        SourceCache {
+            path,
+            entries,
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, format(&self.entries))
+    }
+
+    /// Asks the monitor only if it hasn't been seen before
+    pub fn sources(&mut self, monitor: &mut DynMonitor) -> Result<Vec<u8>, Error> {
+        let key = monitor_key(monitor);
+        if let Some(sources) = key.as_ref().and_then(|key| self.entries.get(key)) {
+            return Ok(sources.clone())
+        }
+
+        let sources = monitor.sources()?;
+        if let (Some(key), false) = (key, sources.is_empty()) {
+            self.entries.insert(key, sources.clone());
+            // only a missed chance to save time next run
+            let _ = self.save();
+        }
+        Ok(sources)
+    }
+}
+
+#[test]
+fn cache_format() {
+    let entries: HashMap<_, _> = vec![
+        ("GSM:LG Ultra HD:".to_owned(), vec![0x0f, 0x11, 0x12]),
+        ("DEL:DELL U2415:7MT0167B2YNL".to_owned(), vec![0x0f]),
+    ].into_iter().collect();
+    let data = format(&entries);
+    assert_eq!(data, "DEL:DELL U2415:7MT0167B2YNL\t0f\nGSM:LG Ultra HD:\t0f 11 12\n");
+    assert_eq!(parse(&data), entries);
+    assert!(parse("garbage\nGSM:x:\tzz\n").is_empty());
+}
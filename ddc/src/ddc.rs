@@ -1,7 +1,9 @@
 use std::fmt;
-use ddc_hi::{Display, Query, Ddc};
-use crate::{SearchDisplay, DdcMonitor, FEATURE_CODE_INPUT};
-use anyhow::Error;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use ddc_hi::{Display, DisplayInfo, Handle, Backend, Query, Ddc};
+use crate::{SearchDisplay, DdcMonitor, FEATURE_CODE_INPUT, sysfs};
+use anyhow::{Error, format_err};
 
 pub struct Monitor {
     display: Display,
@@ -17,6 +19,37 @@ impl From<Display> for Monitor {
     }
 }
 
+impl Monitor {
+    /// Opens a display on a known i2c bus, using an EDID that was already
+    /// read elsewhere (such as from sysfs) rather than probing the bus.
+    pub fn open_i2c_bus(bus: u32, edid: Vec<u8>) -> Result<Self, Error> {
+        let path = format!("/dev/i2c-{}", bus);
+        // ddc-hi identifies i2c displays by their device number
+        let id = fs::metadata(&path)?.rdev();
+        let ddc = ddc_i2c::from_i2c_device(&path)?;
+        let info = DisplayInfo::from_edid(Backend::I2cDevice, id.to_string(), edid)
+            .map_err(|e| format_err!("failed to parse EDID for {}: {}", path, e))?;
+        Ok(Display::new(Handle::I2cDevice(ddc), info).into())
+    }
+
+    fn search_sysfs(search: &SearchDisplay) -> Option<Self> {
+        if search.backend_id.is_some() {
+            // backend ids can only be checked by a full enumeration
+            return None
+        }
+
+        let mut found = sysfs::search(search).ok()?;
+        if found.len() != 1 {
+            return None
+        }
+        let (connector, edid) = found.pop()?;
+        connector.i2c_buses().ok()?.into_iter()
+            .filter(|&bus| search.matches_i2c_bus(Some(bus)))
+            .filter_map(|bus| Self::open_i2c_bus(bus, edid.clone()).ok())
+            .next()
+    }
+}
+
 fn query(search: &SearchDisplay) -> Query {
     let mut query = Query::Any;
 
@@ -54,13 +87,36 @@ fn query(search: &SearchDisplay) -> Query {
 impl DdcMonitor for Monitor {
     type Error = Error;
 
+    fn search(search: &SearchDisplay) -> Result<Option<Self>, Self::Error> {
+        if let Some(monitor) = Self::search_sysfs(search) {
+            return Ok(Some(monitor))
+        }
+
+        Self::enumerate().map(|displays|
+            displays.into_iter().find(|d| d.matches(search))
+        )
+    }
+
     fn enumerate() -> Result<Vec<Self>, Self::Error> where Self: Sized {
         Ok(Display::enumerate().into_iter().map(From::from).collect())
     }
 
     fn matches(&self, search: &SearchDisplay) -> bool {
         let query = query(search);
-        query.matches(&self.display.info)
+        query.matches(&self.display.info) &&
+            search.matches_i2c_bus(sysfs::i2c_bus_from_id(&self.display.info.id))
+    }
+
+    fn manufacturer_id(&self) -> Option<String> {
+        self.display.info.manufacturer_id.clone()
+    }
+
+    fn model_name(&self) -> Option<String> {
+        self.display.info.model_name.clone()
+    }
+
+    fn serial_number(&self) -> Option<String> {
+        self.display.info.serial_number.clone()
     }
 
     fn sources(&mut self) -> Result<Vec<u8>, Self::Error> {
@@ -87,6 +143,9 @@ impl DdcMonitor for Monitor {
 impl fmt::Display for Monitor {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "ID: {}", self.display.info.id)?;
+        if let Some(connector) = sysfs::i2c_bus_from_id(&self.display.info.id).and_then(sysfs::i2c_bus_connector) {
+            writeln!(f, "Connector: {}", connector)?
+        }
         if let Some(mf) = self.display.info.manufacturer_id.as_ref() {
             writeln!(f, "Manufacturer: {}", mf)?
         }
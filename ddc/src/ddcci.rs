@@ -0,0 +1,198 @@
+use std::path::{Path, PathBuf};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::fmt;
+use anyhow::{Error, format_err};
+use crate::{DdcMonitor, DdcError, SearchDisplay, FEATURE_CODE_INPUT, sysfs};
+use crate::edid::Edid;
+
+const DEV_DDCCI: &'static str = "/dev/bus/ddcci";
+const SYS_DDCCI: &'static str = "/sys/bus/ddcci/devices";
+
+const OP_GET_VCP: u8 = 0x01;
+const OP_GET_VCP_REPLY: u8 = 0x02;
+const OP_SET_VCP: u8 = 0x03;
+
+/// A display driven through the `ddcci` kernel driver's character device,
+/// which handles DDC/CI framing and checksums on our behalf.
+pub struct Monitor {
+    bus: u32,
+    edid: Option<Edid>,
+    device: File,
+    sources: Vec<u8>,
+}
+
+impl Monitor {
+    pub fn device_path(bus: u32) -> PathBuf {
+        Path::new(DEV_DDCCI).join(bus.to_string()).join("display")
+    }
+
+    pub fn open(bus: u32, edid: Option<Edid>) -> io::Result<Self> {
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(Self::device_path(bus))?;
+
+        Ok(Self {
+            bus,
+            edid,
+            device,
+            sources: Default::default(),
+        })
+    }
+
+    pub fn bus(&self) -> u32 {
+        self.bus
+    }
+
+    /// Buses the driver has bound a display on, including any that no DRM
+    /// connector claims (such as with the proprietary nvidia driver)
+    pub fn buses() -> io::Result<Vec<u32>> {
+        let mut buses = Vec::new();
+        for entry in fs::read_dir(SYS_DDCCI)? {
+            let name = entry?.file_name();
+            // devices behind the display are named like ddcci4e50
+            if let Some(bus) = name.to_str().and_then(|n| n.strip_prefix("ddcci")).and_then(|n| n.parse().ok()) {
+                buses.push(bus);
+            }
+        }
+        buses.sort();
+        Ok(buses)
+    }
+
+    fn capabilities(&self) -> io::Result<String> {
+        fs::read_to_string(Path::new(SYS_DDCCI).join(format!("ddcci{}", self.bus)).join("capabilities"))
+    }
+}
+
+/// Pulls the values listed for a VCP feature out of a capability string,
+/// ie. `60(0F 11 12)` inside of `vcp(...)`
+pub fn parse_capability_values(caps: &str, feature: u8) -> Vec<u8> {
+    let caps = caps.to_ascii_uppercase();
+    let vcp = match caps.find("VCP(") {
+        Some(i) => &caps[i + 4..],
+        None => return Default::default(),
+    };
+    let needle = format!("{:02X}(", feature);
+    let bytes = vcp.as_bytes();
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < bytes.len() {
+        if depth == 0 && bytes[i..].starts_with(needle.as_bytes()) && (i == 0 || !bytes[i - 1].is_ascii_hexdigit()) {
+            let values = &vcp[i + needle.len()..];
+            let end = values.find(')').unwrap_or(values.len());
+            return values[..end].split_whitespace()
+                .filter_map(|v| u8::from_str_radix(v, 16).ok())
+                .collect()
+        }
+        match bytes[i] {
+            b'(' => depth += 1,
+            b')' if depth == 0 => break,
+            b')' => depth -= 1,
+            _ => (),
+        }
+        i += 1;
+    }
+
+    Default::default()
+}
+
+impl DdcMonitor for Monitor {
+    type Error = Error;
+
+    fn search(search: &SearchDisplay) -> Result<Option<Self>, Self::Error> {
+        for (connector, edid) in sysfs::search(search)? {
+            for bus in connector.i2c_buses()?.into_iter().filter(|&bus| search.matches_i2c_bus(Some(bus))) {
+                if Self::device_path(bus).exists() {
+                    return Self::open(bus, Edid::parse(&edid)).map(Some).map_err(From::from)
+                }
+            }
+        }
+
+        match search.i2c_bus {
+            Some(bus) if Self::device_path(bus).exists() =>
+                Ok(Some(Self::open(bus, None)?).filter(|monitor| monitor.matches(search))),
+            _ => Ok(None),
+        }
+    }
+
+    fn matches(&self, search: &SearchDisplay) -> bool {
+        let edid_matches = match self.edid.as_ref() {
+            Some(edid) => edid.matches(search),
+            // only the bus or connector can pick out a display without one
+            None => search.manufacturer_id.is_none() && search.model_name.is_none() && search.serial_number.is_none(),
+        };
+        edid_matches && search.matches_i2c_bus(Some(self.bus))
+    }
+
+    fn manufacturer_id(&self) -> Option<String> {
+        self.edid.as_ref().map(|edid| edid.manufacturer_id.clone())
+    }
+
+    fn model_name(&self) -> Option<String> {
+        self.edid.as_ref().and_then(|edid| edid.model_name.clone())
+    }
+
+    fn serial_number(&self) -> Option<String> {
+        self.edid.as_ref().map(|edid| edid.serial_number())
+    }
+
+    fn enumerate() -> Result<Vec<Self>, Self::Error> {
+        let mut res: Vec<Self> = Vec::new();
+        for connector in sysfs::connectors()? {
+            let edid = connector.edid().ok().and_then(|edid| edid);
+            for bus in connector.i2c_buses()? {
+                if Self::device_path(bus).exists() {
+                    res.push(Self::open(bus, edid.clone())?);
+                }
+            }
+        }
+        for bus in Self::buses().unwrap_or_default() {
+            if !res.iter().any(|monitor| monitor.bus == bus) && Self::device_path(bus).exists() {
+                res.push(Self::open(bus, None)?);
+            }
+        }
+        Ok(res)
+    }
+
+    fn sources(&mut self) -> Result<Vec<u8>, Self::Error> {
+        if self.sources.is_empty() {
+            self.sources = parse_capability_values(&self.capabilities()?, FEATURE_CODE_INPUT);
+        }
+        Ok(self.sources.clone())
+    }
+
+    fn get_source(&mut self) -> Result<u8, Self::Error> {
+        self.device.write_all(&[OP_GET_VCP, FEATURE_CODE_INPUT])?;
+        let mut reply = [0u8; 8];
+        let n = self.device.read(&mut reply)?;
+        match &reply[..n] {
+            &[OP_GET_VCP_REPLY, 0, FEATURE_CODE_INPUT, _, _, _, _, value] => Ok(value),
+            &[OP_GET_VCP_REPLY, _, FEATURE_CODE_INPUT, ..] => Err(DdcError::FeatureCodeNotFound.into()),
+            reply => Err(format_err!("unexpected ddcci reply {:02x?}", reply)),
+        }
+    }
+
+    fn set_source(&mut self, value: u8) -> Result<(), Self::Error> {
+        self.device.write_all(&[OP_SET_VCP, FEATURE_CODE_INPUT, 0, value])
+            .map_err(From::from)
+    }
+}
+
+impl fmt::Display for Monitor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "ID: ddcci{}", self.bus)?;
+        if let Some(connector) = sysfs::i2c_bus_connector(self.bus) {
+            writeln!(f, "Connector: {}", connector)?
+        }
+        if let Some(edid) = self.edid.as_ref() {
+            writeln!(f, "Manufacturer: {}", edid.manufacturer_id)?;
+            if let Some(model) = edid.model_name.as_ref() {
+                writeln!(f, "Model: {}", model)?
+            }
+            writeln!(f, "Serial: {}", edid.serial_number())?;
+        }
+
+        Ok(())
+    }
+}
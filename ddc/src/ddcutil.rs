@@ -2,7 +2,7 @@ use std::collections::HashMap;
 use std::mem::replace;
 use std::fmt;
 use anyhow::Error;
-use ddcutil::{DisplayInfo, Display, FeatureInfo};
+use ddcutil::{DisplayInfo, DisplayPath, Display, FeatureInfo};
 use crate::{DdcError, DdcMonitor, SearchDisplay, FEATURE_CODE_INPUT};
 
 impl SearchDisplay {
@@ -14,11 +14,11 @@ impl SearchDisplay {
         ].iter().filter_map(|&(i, m)| m.as_ref().map(|m| (i, m)))
             .all(|(i, m)| i == m);
 
-        if let Some(ref path) = self.path {
-            matches && path == &info.path()
-        } else {
-            matches
-        }
+        let bus = match info.path() {
+            DisplayPath::I2c { bus_number } => Some(bus_number as u32),
+            _ => None,
+        };
+        matches && self.matches_i2c_bus(bus)
     }
 }
 
@@ -163,6 +163,18 @@ impl DdcMonitor for Monitor {
         }
     }
 
+    fn manufacturer_id(&self) -> Option<String> {
+        self.info().map(|info| info.manufacturer_id().to_string())
+    }
+
+    fn model_name(&self) -> Option<String> {
+        self.info().map(|info| info.model_name().to_string())
+    }
+
+    fn serial_number(&self) -> Option<String> {
+        self.info().map(|info| info.serial_number().to_string())
+    }
+
     fn enumerate() -> Result<Vec<Self>, Self::Error> where Self: Sized {
         DisplayInfo::enumerate()?.into_iter().map(|i|
             Self::from_display_info(i, None)
@@ -0,0 +1,196 @@
+use std::process::Command;
+use std::fmt;
+use anyhow::{Error, format_err};
+use crate::{DdcMonitor, SearchDisplay, FEATURE_CODE_INPUT, ddcci, sysfs};
+
+const DDCUTIL: &'static str = "ddcutil";
+
+/// A display switched by running the `ddcutil` command line tool, for when
+/// neither library backend is built in.
+///
+/// Like the other backends it blocks, so it's only used through `AsyncMonitor`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Monitor {
+    bus: u32,
+    manufacturer_id: Option<String>,
+    model_name: Option<String>,
+    serial_number: Option<String>,
+    sources: Vec<u8>,
+}
+
+impl Monitor {
+    pub fn bus(&self) -> u32 {
+        self.bus
+    }
+
+    fn ddcutil(&self, args: &[&str]) -> Result<String, Error> {
+        let bus = self.bus.to_string();
+        run(&[&["--bus", bus.as_str()][..], args].concat())
+    }
+}
+
+fn run(args: &[&str]) -> Result<String, Error> {
+    let output = Command::new(DDCUTIL)
+        .args(args)
+        .output()
+        .map_err(|e| Error::from(e).context(format!("failed to run {}", DDCUTIL)))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        // ddcutil reports most failures on stdout
+        let message = Some(stderr.trim()).filter(|s| !s.is_empty()).unwrap_or(stdout.trim());
+        return Err(format_err!("{} {} failed: {}", DDCUTIL, args.join(" "), message))
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parses `ddcutil detect --terse`, skipping invalid displays
+pub fn parse_detect(output: &str) -> Vec<Monitor> {
+    let mut monitors = Vec::new();
+    let mut current: Option<Monitor> = None;
+    for line in output.lines() {
+        let line = line.trim();
+        if line.starts_with("Display ") || line.starts_with("Invalid display") {
+            monitors.extend(current.take().filter(|m: &Monitor| m.bus != u32::MAX));
+            if line.starts_with("Display ") {
+                current = Some(Monitor { bus: u32::MAX, ..Default::default() });
+            }
+            continue
+        }
+        let monitor = match current.as_mut() {
+            Some(monitor) => monitor,
+            None => continue,
+        };
+        if let Some(bus) = line.strip_prefix("I2C bus:") {
+            if let Some(bus) = bus.trim().strip_prefix("/dev/i2c-").and_then(|bus| bus.parse().ok()) {
+                monitor.bus = bus;
+            }
+        } else if let Some(id) = line.strip_prefix("Monitor:") {
+            // manufacturer:model:serial, any of which may be empty
+            let mut id = id.trim().splitn(3, ':')
+                .map(|s| Some(s.trim().to_owned()).filter(|s| !s.is_empty()));
+            monitor.manufacturer_id = id.next().and_then(|s| s);
+            monitor.model_name = id.next().and_then(|s| s);
+            monitor.serial_number = id.next().and_then(|s| s);
+        }
+    }
+    monitors.extend(current.filter(|m| m.bus != u32::MAX));
+    monitors
+}
+
+/// Parses `ddcutil getvcp --terse`, ie. `VCP 60 SNC x0f`
+pub fn parse_getvcp(output: &str) -> Option<u8> {
+    output.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|words| words.len() >= 4 && words[0] == "VCP")
+        .and_then(|words| u8::from_str_radix(words[3].trim_start_matches('x'), 16).ok())
+}
+
+/// Parses the values listed for a feature by `ddcutil capabilities`, or the
+/// raw capability string if it's there
+pub fn parse_capabilities(output: &str, feature: u8) -> Vec<u8> {
+    if let Some(raw) = output.lines().find_map(|line| line.trim().strip_prefix("Unparsed capabilities string:")) {
+        return ddcci::parse_capability_values(raw, feature)
+    }
+
+    let header = format!("Feature: {:02X}", feature);
+    let mut values = Vec::new();
+    let mut in_feature = false;
+    for line in output.lines() {
+        let line = line.trim();
+        if line.starts_with("Feature:") {
+            if in_feature {
+                break
+            }
+            in_feature = line.to_ascii_uppercase().starts_with(&header.to_ascii_uppercase());
+        } else if in_feature {
+            let value = line.split(':').next()
+                .filter(|v| v.len() == 2)
+                .and_then(|v| u8::from_str_radix(v, 16).ok());
+            values.extend(value);
+        }
+    }
+    values
+}
+
+impl DdcMonitor for Monitor {
+    type Error = Error;
+
+    fn matches(&self, search: &SearchDisplay) -> bool {
+        search.manufacturer_id.as_ref().map(|m| Some(m) == self.manufacturer_id.as_ref()).unwrap_or(true) &&
+            search.model_name.as_ref().map(|m| Some(m) == self.model_name.as_ref()).unwrap_or(true) &&
+            search.serial_number.as_ref().map(|s| Some(s) == self.serial_number.as_ref()).unwrap_or(true) &&
+            search.matches_i2c_bus(Some(self.bus))
+    }
+
+    fn manufacturer_id(&self) -> Option<String> {
+        self.manufacturer_id.clone()
+    }
+
+    fn model_name(&self) -> Option<String> {
+        self.model_name.clone()
+    }
+
+    fn serial_number(&self) -> Option<String> {
+        self.serial_number.clone()
+    }
+
+    fn enumerate() -> Result<Vec<Self>, Self::Error> {
+        run(&["detect", "--terse"]).map(|output| parse_detect(&output))
+    }
+
+    fn sources(&mut self) -> Result<Vec<u8>, Self::Error> {
+        if self.sources.is_empty() {
+            self.sources = parse_capabilities(&self.ddcutil(&["capabilities"])?, FEATURE_CODE_INPUT);
+        }
+        Ok(self.sources.clone())
+    }
+
+    fn get_source(&mut self) -> Result<u8, Self::Error> {
+        let output = self.ddcutil(&["getvcp", "--terse", "0x60"])?;
+        parse_getvcp(&output)
+            .ok_or_else(|| format_err!("unexpected ddcutil getvcp output: {}", output.trim()))
+    }
+
+    fn set_source(&mut self, value: u8) -> Result<(), Self::Error> {
+        self.ddcutil(&["setvcp", "0x60", &format!("0x{:02x}", value)])
+            .map(drop)
+    }
+}
+
+impl fmt::Display for Monitor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "ID: ddcutil /dev/i2c-{}", self.bus)?;
+        if let Some(connector) = sysfs::i2c_bus_connector(self.bus) {
+            writeln!(f, "Connector: {}", connector)?
+        }
+        if let Some(manufacturer) = self.manufacturer_id.as_ref() {
+            writeln!(f, "Manufacturer: {}", manufacturer)?;
+        }
+        if let Some(model) = self.model_name.as_ref() {
+            writeln!(f, "Model: {}", model)?;
+        }
+        if let Some(serial) = self.serial_number.as_ref() {
+            writeln!(f, "Serial: {}", serial)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn ddcutil_output() {
+    let detect = "Display 1\n   I2C bus:  /dev/i2c-4\n   Monitor:  DEL:DELL U2415:7MT0167B2YNL\n\n\
+        Invalid display\n   I2C bus:  /dev/i2c-6\n   Monitor:  GSM::\n\n\
+        Display 2\n   I2C bus:  /dev/i2c-7\n   Monitor:  GSM:LG Ultra HD:\n";
+    let monitors = parse_detect(detect);
+    assert_eq!(monitors.iter().map(|m| m.bus).collect::<Vec<_>>(), [4, 7]);
+    assert_eq!(monitors[0].model_name.as_ref().map(|s| &s[..]), Some("DELL U2415"));
+    assert_eq!(monitors[1].serial_number, None);
+
+    assert_eq!(parse_getvcp("VCP 60 SNC x0f\n"), Some(0x0f));
+    assert_eq!(parse_getvcp("VCP 60 ERR\n"), None);
+
+    let caps = "Model: U2415\nVCP Features:\n   Feature: 10 (Brightness)\n   Feature: 60 (Input Source)\n      Values:\n         0f: DisplayPort-1\n         11: HDMI-1\n   Feature: 62 (Audio speaker volume)\n";
+    assert_eq!(parse_capabilities(caps, 0x60), [0x0f, 0x11]);
+}
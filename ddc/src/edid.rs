@@ -0,0 +1,68 @@
+use crate::SearchDisplay;
+
+const EDID_HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+const DESCRIPTOR_SERIAL: u8 = 0xff;
+const DESCRIPTOR_NAME: u8 = 0xfc;
+
+/// The identifying bits of an EDID base block
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Edid {
+    pub manufacturer_id: String,
+    pub product_code: u16,
+    pub serial: u32,
+    pub model_name: Option<String>,
+    pub serial_number: Option<String>,
+}
+
+impl Edid {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 0x80 || data[..8] != EDID_HEADER {
+            return None
+        }
+
+        let manufacturer = u16::from_be_bytes([data[8], data[9]]);
+        let manufacturer_id = [10, 5, 0].iter()
+            .map(|shift| (((manufacturer >> shift) & 0x1f) as u8 + b'A' - 1) as char)
+            .collect();
+
+        let mut edid = Edid {
+            manufacturer_id,
+            product_code: u16::from_le_bytes([data[10], data[11]]),
+            serial: u32::from_le_bytes([data[12], data[13], data[14], data[15]]),
+            model_name: None,
+            serial_number: None,
+        };
+
+        for descriptor in data[0x36..0x7e].chunks(18) {
+            if descriptor[..3] != [0, 0, 0] {
+                // detailed timing descriptor
+                continue
+            }
+            let text = || String::from_utf8_lossy(&descriptor[5..])
+                .split('\n').next().unwrap_or_default()
+                .trim_end().to_owned();
+            match descriptor[3] {
+                DESCRIPTOR_NAME => edid.model_name = Some(text()),
+                DESCRIPTOR_SERIAL => edid.serial_number = Some(text()),
+                _ => (),
+            }
+        }
+
+        Some(edid)
+    }
+
+    pub fn serial_number(&self) -> String {
+        match self.serial_number {
+            Some(ref serial) => serial.clone(),
+            None => self.serial.to_string(),
+        }
+    }
+
+    /// Compares against the search fields that an EDID can answer for,
+    /// backend ids must be checked separately.
+    pub fn matches(&self, search: &SearchDisplay) -> bool {
+        search.manufacturer_id.as_ref().map(|m| m == &self.manufacturer_id).unwrap_or(true) &&
+            search.model_name.as_ref().map(|m| Some(m) == self.model_name.as_ref()).unwrap_or(true) &&
+            search.serial_number.as_ref().map(|s| s == &self.serial_number()).unwrap_or(true)
+    }
+}
@@ -7,12 +7,24 @@ pub mod ddcutil;
 #[cfg(feature = "ddc-hi")]
 pub mod ddc;
 
+pub mod sysfs;
+pub mod edid;
+pub mod cache;
+pub mod ddcci;
+pub mod ddcutil_cli;
+
+mod async_monitor;
+pub use async_monitor::{AsyncMonitor, DynMonitor};
+
 pub const FEATURE_CODE_INPUT: u8 = 0x60;
 
 #[derive(Debug)]
 pub enum DdcError {
     DisplayNotFound,
     FeatureCodeNotFound,
+    Timeout,
+    Cancelled,
+    Hung,
 }
 
 impl fmt::Display for DdcError {
@@ -20,6 +32,9 @@ impl fmt::Display for DdcError {
         let msg = match self {
             DdcError::DisplayNotFound => "Display not found",
             DdcError::FeatureCodeNotFound => "Feature code not found",
+            DdcError::Timeout => "DDC operation timed out",
+            DdcError::Cancelled => "DDC operation cancelled",
+            DdcError::Hung => "DDC monitor is still stuck in an operation that timed out",
         };
         f.write_str(msg)
     }
@@ -33,10 +48,27 @@ pub struct SearchDisplay {
     pub manufacturer_id: Option<String>,
     pub model_name: Option<String>,
     pub serial_number: Option<String>,
-    #[cfg(feature = "ddcutil")]
-    pub path: Option<::ddcutil::DisplayPath>,
-    #[cfg(not(feature = "ddcutil"))]
-    pub path: Option<()>,
+    /// DRM connector name, ie. `DP-3`
+    pub connector: Option<String>,
+    /// i2c bus number, ie. 4 for `/dev/i2c-4`
+    pub i2c_bus: Option<u32>,
+}
+
+impl SearchDisplay {
+    /// Checks an i2c bus against the requested bus and DRM connector, if any
+    pub fn matches_i2c_bus(&self, bus: Option<u32>) -> bool {
+        if self.i2c_bus.is_some() && self.i2c_bus != bus {
+            return false
+        }
+
+        match (&self.connector, bus) {
+            (None, _) => true,
+            (Some(connector), Some(bus)) => sysfs::connector_i2c_buses(connector)
+                .map(|buses| buses.contains(&bus))
+                .unwrap_or(false),
+            (Some(..), None) => false,
+        }
+    }
 }
 
 pub trait DdcMonitor: fmt::Display {
@@ -50,6 +82,18 @@ pub trait DdcMonitor: fmt::Display {
 
     fn matches(&self, search: &SearchDisplay) -> bool;
 
+    fn manufacturer_id(&self) -> Option<String> {
+        None
+    }
+
+    fn model_name(&self) -> Option<String> {
+        None
+    }
+
+    fn serial_number(&self) -> Option<String> {
+        None
+    }
+
     fn enumerate() -> Result<Vec<Self>, Self::Error> where Self: Sized;
 
     fn sources(&mut self) -> Result<Vec<u8>, Self::Error>;
@@ -69,57 +113,82 @@ pub fn find_guest_source(sources: &[u8], our_source: u8) -> Option<u8> {
         .next()
 }
 
-pub struct DummyMonitor;
-
-impl DummyMonitor {
-    fn error() -> Error {
-        format_err!("ddc disabled")
-    }
+/// A DDC/CI implementation, picked at runtime so that a screen can use
+/// whichever ones were compiled in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// ddc-hi, with the `with-ddc` feature
+    DdcHi,
+    /// libddcutil, with the `with-ddcutil` feature
+    Libddcutil,
+    /// The `ddcutil` command line tool
+    DdcutilCli,
+    /// The ddcci kernel driver
+    Ddcci,
 }
 
-impl DdcMonitor for DummyMonitor {
-    type Error = Error;
-
-    fn search(_search: &SearchDisplay) -> Result<Option<Self>, Self::Error> {
-        Err(Self::error())
-    }
+fn boxed<M: DdcMonitor<Error=Error> + Send + 'static>(monitor: M) -> Box<DynMonitor> {
+    Box::new(monitor)
+}
 
-    fn matches(&self, _search: &SearchDisplay) -> bool {
-        false
+impl Backend {
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::DdcHi => "ddc-hi",
+            Backend::Libddcutil => "libddcutil",
+            Backend::DdcutilCli => "ddcutil",
+            Backend::Ddcci => "ddcci",
+        }
     }
 
-    fn enumerate() -> Result<Vec<Self>, Self::Error> {
-        Err(Self::error())
+    /// Whether support for the backend was compiled in
+    pub fn is_available(self) -> bool {
+        match self {
+            Backend::DdcHi => cfg!(feature = "ddc-hi"),
+            Backend::Libddcutil => cfg!(feature = "ddcutil"),
+            Backend::DdcutilCli | Backend::Ddcci => true,
+        }
     }
 
-    fn sources(&mut self) -> Result<Vec<u8>, Self::Error> {
-        Err(Self::error())
+    /// The library backend to use when none is configured
+    pub fn preferred() -> Option<Self> {
+        [Backend::DdcHi, Backend::Libddcutil].iter().copied()
+            .find(|backend| backend.is_available())
     }
 
-    fn get_source(&mut self) -> Result<u8, Self::Error> {
-        Err(Self::error())
+    fn unavailable(self) -> Error {
+        format_err!("screenstub was built without {} support", self.name())
     }
 
-    fn set_source(&mut self, _value: u8) -> Result<(), Self::Error> {
-        Err(Self::error())
+    pub fn enumerate(self) -> Result<Vec<Box<DynMonitor>>, Error> {
+        match self {
+            #[cfg(feature = "ddc-hi")]
+            Backend::DdcHi => ddc::Monitor::enumerate().map(|m| m.into_iter().map(boxed).collect()),
+            #[cfg(feature = "ddcutil")]
+            Backend::Libddcutil => ddcutil::Monitor::enumerate().map(|m| m.into_iter().map(boxed).collect()),
+            Backend::DdcutilCli => ddcutil_cli::Monitor::enumerate().map(|m| m.into_iter().map(boxed).collect()),
+            Backend::Ddcci => ddcci::Monitor::enumerate().map(|m| m.into_iter().map(boxed).collect()),
+            #[allow(unreachable_patterns)]
+            backend => Err(backend.unavailable()),
+        }
     }
 
-    fn find_guest_source(&mut self, _our_source: u8) -> Result<Option<u8>, Self::Error> {
-        Err(Self::error())
+    pub fn search(self, search: &SearchDisplay) -> Result<Option<Box<DynMonitor>>, Error> {
+        match self {
+            #[cfg(feature = "ddc-hi")]
+            Backend::DdcHi => ddc::Monitor::search(search).map(|m| m.map(boxed)),
+            #[cfg(feature = "ddcutil")]
+            Backend::Libddcutil => <ddcutil::Monitor as DdcMonitor>::search(search).map(|m| m.map(boxed)),
+            Backend::DdcutilCli => ddcutil_cli::Monitor::search(search).map(|m| m.map(boxed)),
+            Backend::Ddcci => ddcci::Monitor::search(search).map(|m| m.map(boxed)),
+            #[allow(unreachable_patterns)]
+            backend => Err(backend.unavailable()),
+        }
     }
 }
 
-impl fmt::Display for DummyMonitor {
-    fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
-        panic!("{}", Self::error())
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
     }
 }
-
-#[cfg(feature = "ddc-hi")]
-pub type Monitor = ddc::Monitor;
-
-#[cfg(all(not(feature = "ddc-hi"), feature = "ddcutil"))]
-pub type Monitor = ddcutil::Monitor;
-
-#[cfg(not(any(feature = "ddcutil", feature = "ddc-hi")))]
-pub type Monitor = DummyMonitor;
@@ -0,0 +1,147 @@
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::io;
+use crate::SearchDisplay;
+use crate::edid::Edid;
+
+const DRM_CLASS: &'static str = "/sys/class/drm";
+
+#[derive(Debug, Clone)]
+pub struct DrmConnector {
+    /// Connector name without the card prefix, ie. `DP-3`
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl DrmConnector {
+    /// i2c buses that belong to this connector.
+    ///
+    /// DisplayPort MST ports register their own i2c adapter underneath the
+    /// connector, so this can differ from the bus that the parent port uses.
+    pub fn i2c_buses(&self) -> io::Result<Vec<u32>> {
+        let mut buses = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            if let Some(bus) = entry.file_name().to_str().and_then(parse_i2c_name) {
+                buses.push(bus);
+            }
+        }
+
+        if let Ok(ddc) = fs::read_link(self.path.join("ddc")) {
+            if let Some(bus) = ddc.file_name().and_then(|n| n.to_str()).and_then(parse_i2c_name) {
+                buses.push(bus);
+            }
+        }
+
+        buses.sort();
+        buses.dedup();
+        Ok(buses)
+    }
+
+    /// The raw EDID of the attached display, as cached by the kernel
+    pub fn edid_data(&self) -> io::Result<Option<Vec<u8>>> {
+        let data = fs::read(self.path.join("edid"))?;
+        Ok(if data.is_empty() {
+            None
+        } else {
+            Some(data)
+        })
+    }
+
+    pub fn edid(&self) -> io::Result<Option<Edid>> {
+        self.edid_data().map(|data| data.as_ref().and_then(|data| Edid::parse(data)))
+    }
+}
+
+fn parse_i2c_name(name: &str) -> Option<u32> {
+    name.strip_prefix("i2c-")?.parse().ok()
+}
+
+const I2C_MAJOR: u64 = 89;
+
+/// Extracts the bus number out of backend ids like `/dev/i2c-4` or `4`,
+/// or the device number of `/dev/i2c-4`
+pub fn i2c_bus_from_id(id: &str) -> Option<u32> {
+    let digits = id.len() - id.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+    let value: u64 = match digits {
+        0 => return None,
+        digits => id[id.len() - digits..].parse().ok()?,
+    };
+    let (major, minor) = ((value >> 8) & 0xfff, (value & 0xff) | ((value >> 12) & 0xfff00));
+    Some(if major == I2C_MAJOR {
+        minor as u32
+    } else {
+        value as u32
+    })
+}
+
+/// Resolves an i2c device like `/dev/i2c-4`, or a symlink to one, to its bus number
+pub fn i2c_bus_from_path<P: AsRef<Path>>(path: P) -> Option<u32> {
+    let path = path.as_ref();
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+    path.file_name()?.to_str().and_then(parse_i2c_name)
+}
+
+pub fn connectors() -> io::Result<Vec<DrmConnector>> {
+    connectors_in(Path::new(DRM_CLASS))
+}
+
+fn connectors_in(class: &Path) -> io::Result<Vec<DrmConnector>> {
+    let mut res = Vec::new();
+    for entry in fs::read_dir(class)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = match file_name.to_str() {
+            Some(name) if name.starts_with("card") => name,
+            _ => continue,
+        };
+        // connectors look like card0-DP-3, whereas card0 is the device itself
+        if let Some(i) = file_name.find('-') {
+            res.push(DrmConnector {
+                name: file_name[i + 1..].into(),
+                path: entry.path(),
+            });
+        }
+    }
+
+    res.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(res)
+}
+
+pub fn connector_i2c_buses(connector: &str) -> io::Result<Vec<u32>> {
+    let mut buses = Vec::new();
+    for c in connectors()?.into_iter().filter(|c| c.name == connector) {
+        buses.extend(c.i2c_buses()?);
+    }
+    Ok(buses)
+}
+
+/// Finds connected displays by the EDID the kernel has already read, without
+/// issuing any DDC transactions.
+pub fn search(search: &SearchDisplay) -> io::Result<Vec<(DrmConnector, Vec<u8>)>> {
+    let mut res = Vec::new();
+    for connector in connectors()? {
+        if search.connector.as_ref().map(|c| c != &connector.name).unwrap_or(false) {
+            continue
+        }
+        if let Some(bus) = search.i2c_bus {
+            if !connector.i2c_buses().map(|buses| buses.contains(&bus)).unwrap_or(false) {
+                continue
+            }
+        }
+        let data = match connector.edid_data() {
+            Ok(Some(data)) => data,
+            _ => continue,
+        };
+        if Edid::parse(&data).map(|edid| edid.matches(search)).unwrap_or(false) {
+            res.push((connector, data));
+        }
+    }
+    Ok(res)
+}
+
+pub fn i2c_bus_connector(bus: u32) -> Option<String> {
+    connectors().ok()?.into_iter()
+        .find(|c| c.i2c_buses().map(|b| b.contains(&bus)).unwrap_or(false))
+        .map(|c| c.name)
+}
@@ -0,0 +1,121 @@
+use std::sync::Mutex;
+use std::iter;
+use smallvec::SmallVec;
+use input_linux::{
+    EventRef, InputEvent, SynchronizeEvent,
+    KeyEvent, Key, KeyState,
+    Bitmask,
+};
+
+/// The keys that a destination is believed to be holding down,
+/// based on the events that have been sent its way
+#[derive(Debug, Default)]
+pub struct KeyStateTracker {
+    keys: Mutex<Bitmask<Key>>,
+}
+
+impl KeyStateTracker {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Updates the state from an event headed to the destination,
+    /// returning whether it was a key event
+    pub fn track(&self, e: &InputEvent) -> bool {
+        match EventRef::new(e) {
+            Ok(EventRef::Key(key)) => {
+                self.track_key(key.key, key.value);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    pub fn track_key(&self, key: Key, state: KeyState) {
+        let mut keys = self.keys.lock().unwrap();
+        match state {
+            KeyState::PRESSED => keys.insert(key),
+            KeyState::RELEASED => keys.remove(key),
+            _ => (), // autorepeat doesn't change anything
+        }
+    }
+
+    pub fn is_pressed(&self, key: Key) -> bool {
+        self.keys.lock().unwrap().get(key)
+    }
+
+    pub fn pressed(&self) -> Vec<Key> {
+        self.keys.lock().unwrap().iter().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.lock().unwrap().iter().next().is_none()
+    }
+
+    /// Forgets every held key, returning the events that release them
+    pub fn release_all(&self) -> SmallVec<[InputEvent; 4]> {
+        let mut keys = self.keys.lock().unwrap();
+        let res = keys.iter().map(|key|
+            KeyEvent::new(Default::default(), key, KeyState::RELEASED).into()
+        ).chain(iter::once(SynchronizeEvent::report(Default::default()).into()))
+            .collect();
+        keys.clear();
+        res
+    }
+}
+
+#[cfg(test)]
+fn key(key: Key, state: KeyState) -> InputEvent {
+    KeyEvent::new(Default::default(), key, state).into()
+}
+
+#[cfg(test)]
+fn released(events: &[InputEvent]) -> Vec<Key> {
+    events.iter().filter_map(|e| match EventRef::new(e) {
+        Ok(EventRef::Key(e)) if e.value == KeyState::RELEASED => Some(e.key),
+        _ => None,
+    }).collect()
+}
+
+#[test]
+fn keystate_press_release() {
+    let keys = KeyStateTracker::new();
+    assert!(keys.track(&key(Key::A, KeyState::PRESSED)));
+    assert!(keys.track(&key(Key::LeftShift, KeyState::PRESSED)));
+    assert!(keys.is_pressed(Key::A));
+    assert!(keys.track(&key(Key::A, KeyState::RELEASED)));
+    assert!(!keys.is_pressed(Key::A));
+    assert_eq!(keys.pressed(), vec![Key::LeftShift]);
+    assert!(!keys.track(&SynchronizeEvent::report(Default::default()).into()));
+}
+
+#[test]
+fn keystate_repeat_and_duplicates() {
+    let keys = KeyStateTracker::new();
+    keys.track_key(Key::A, KeyState::PRESSED);
+    keys.track_key(Key::A, KeyState::PRESSED);
+    keys.track_key(Key::A, KeyState::AUTOREPEAT);
+    assert_eq!(keys.pressed(), vec![Key::A]);
+    keys.track_key(Key::A, KeyState::RELEASED);
+    keys.track_key(Key::A, KeyState::RELEASED);
+    assert!(keys.is_empty());
+}
+
+#[test]
+fn keystate_held_across_switch() {
+    // a key is pressed while shown, the destination is switched away,
+    // and the release goes somewhere else
+    let keys = KeyStateTracker::new();
+    keys.track_key(Key::LeftCtrl, KeyState::PRESSED);
+    keys.track_key(Key::C, KeyState::PRESSED);
+
+    let events = keys.release_all();
+    assert_eq!(released(&events), vec![Key::LeftCtrl, Key::C]);
+    assert_eq!(events.len(), 3);
+    assert!(keys.is_empty());
+
+    // the physical release arriving later must not confuse anything
+    keys.track_key(Key::C, KeyState::RELEASED);
+    assert!(keys.is_empty());
+    assert_eq!(keys.release_all().len(), 1);
+}
@@ -1,15 +1,18 @@
 use std::collections::HashMap;
-use std::sync::Mutex;
-use std::{slice, iter};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use std::{fmt, slice, iter, mem};
 use smallvec::{SmallVec, smallvec};
 use input_linux::{
-    EventRef, EventMut, InputEvent, SynchronizeEvent,
-    KeyEvent, Key, KeyState,
-    Bitmask,
+    EventRef, EventMut, InputEvent,
+    Key, KeyState, SwitchKind, RelativeAxis,
 };
 use log::warn;
 use screenstub_x::XEvent;
 
+mod keystate;
+pub use keystate::KeyStateTracker;
+
 #[derive(Debug)]
 pub enum UserEvent {
     Quit,
@@ -19,11 +22,65 @@ pub enum UserEvent {
     UnstickHost,
 }
 
+/// Where input is headed, for picking a remap table
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Destination {
+    /// Hotkey matching and anything else screenstub does with input itself
+    Host,
+    /// The VM, through any of the routes
+    Guest,
+}
+
+/// How one wheel axis is adjusted on its way to a destination
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WheelAxis {
+    pub invert: bool,
+    pub scale: f32,
+}
+
+impl Default for WheelAxis {
+    fn default() -> Self {
+        WheelAxis {
+            invert: false,
+            scale: 1.0,
+        }
+    }
+}
+
+/// State that a hotkey can be limited to
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Condition {
+    GuestShown,
+    HostShown,
+    Grabbed,
+}
+
+/// Answers whether a condition currently holds
+struct Conditions(Box<dyn Fn(Condition) -> bool + Send + Sync>);
+
+impl fmt::Debug for Conditions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Conditions")
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Wheel {
+    pub vertical: WheelAxis,
+    pub horizontal: WheelAxis,
+}
+
 #[derive(Debug, Clone)]
 pub struct Hotkey<U> {
     triggers: Vec<Key>,
     modifiers: Vec<Key>,
     events: Vec<U>,
+    sequence: Vec<Key>,
+    sequence_timeout: Duration,
+    hold: Option<Duration>,
+    double_tap: Option<Duration>,
+    exact: bool,
+    when: Option<Condition>,
 }
 
 impl<U> Hotkey<U> {
@@ -32,20 +89,109 @@ impl<U> Hotkey<U> {
             triggers: triggers.into_iter().collect(),
             modifiers: modifiers.into_iter().collect(),
             events: events.into_iter().collect(),
+            sequence: Vec::new(),
+            sequence_timeout: Duration::default(),
+            hold: None,
+            double_tap: None,
+            exact: false,
+            when: None,
         }
     }
 
+    /// Only fires while `when` holds, leaving the key alone otherwise
+    pub fn with_condition(mut self, when: Option<Condition>) -> Self {
+        self.when = when;
+        self
+    }
+
+    /// Only fires when nothing but the triggers and modifiers is held
+    pub fn with_exact(mut self, exact: bool) -> Self {
+        self.exact = exact;
+        self
+    }
+
+    /// Only fires on release of a trigger held at least this long, so a
+    /// quick tap can still go to the guest alone
+    pub fn with_hold(mut self, hold: Option<Duration>) -> Self {
+        self.hold = hold;
+        self
+    }
+
+    /// Only fires on the second press of a trigger within this long of the first
+    pub fn with_double_tap(mut self, window: Option<Duration>) -> Self {
+        self.double_tap = window;
+        self
+    }
+
+    /// Holds off the events until each of `keys` is pressed in turn, each
+    /// within `timeout` of the last
+    pub fn with_sequence<I: IntoIterator<Item=Key>>(mut self, keys: I, timeout: Duration) -> Self {
+        self.sequence = keys.into_iter().collect();
+        self.sequence_timeout = timeout;
+        self
+    }
+
     pub fn keys(&self) -> iter::Cloned<iter::Chain<slice::Iter<Key>, slice::Iter<Key>>> {
         self.triggers.iter().chain(self.modifiers.iter()).cloned()
     }
 }
 
+#[derive(Debug)]
+struct Triggers<U> {
+    press: HashMap<Key, Vec<Hotkey<U>>>,
+    release: HashMap<Key, Vec<Hotkey<U>>>,
+}
+
+impl<U> Default for Triggers<U> {
+    fn default() -> Self {
+        Triggers {
+            press: Default::default(),
+            release: Default::default(),
+        }
+    }
+}
+
+impl<U: Clone> Triggers<U> {
+    fn add(&mut self, hotkey: Hotkey<U>, on_press: bool) {
+        for &key in &hotkey.triggers {
+            if on_press {
+                &mut self.press
+            } else {
+                &mut self.release
+            }.entry(key).or_insert(Default::default()).push(hotkey.clone())
+        }
+    }
+}
+
+/// A hotkey that has been pressed, waiting on the rest of its sequence
+#[derive(Debug)]
+struct PendingSequence<U> {
+    hotkey: Hotkey<U>,
+    next: usize,
+    deadline: Instant,
+}
+
 #[derive(Debug)]
 pub struct Events<U> {
-    triggers_press: HashMap<Key, Vec<Hotkey<U>>>,
-    triggers_release: HashMap<Key, Vec<Hotkey<U>>>,
-    remap: HashMap<Key, Key>,
-    keys: Mutex<Bitmask<Key>>,
+    triggers: RwLock<Triggers<U>>,
+    sequences: Mutex<Vec<PendingSequence<U>>>,
+    remap: RwLock<HashMap<(Destination, Key), Key>>,
+    wheel: RwLock<HashMap<Destination, Wheel>>,
+    /// Fractional wheel movement left over from scaling, per destination and
+    /// whether it's the horizontal wheel
+    wheel_remainder: Mutex<HashMap<(Destination, bool), f32>>,
+    keys: KeyStateTracker,
+    sticky: RwLock<Option<Duration>>,
+    /// When each key was last released, while `sticky` is set
+    latched: Mutex<HashMap<Key, Instant>>,
+    /// When each key was last pressed, for `hold`
+    pressed_at: Mutex<HashMap<Key, Instant>>,
+    /// Presses that could be the first half of a double tap
+    tapped_at: Mutex<HashMap<Key, Instant>>,
+    switch_events: RwLock<HashMap<(SwitchKind, bool), Vec<U>>>,
+    /// Last reported position of every switch seen so far
+    switches: Mutex<HashMap<SwitchKind, bool>>,
+    conditions: RwLock<Option<Conditions>>,
 }
 
 #[derive(Debug)]
@@ -69,31 +215,142 @@ impl<I: Into<InputEvent>> From<I> for ProcessedXEvent {
 impl<U> Events<U> {
     pub fn new() -> Self {
         Events {
-            triggers_press: Default::default(),
-            triggers_release: Default::default(),
+            triggers: Default::default(),
+            sequences: Default::default(),
             remap: Default::default(),
+            wheel: Default::default(),
+            wheel_remainder: Default::default(),
             keys: Default::default(),
+            sticky: Default::default(),
+            latched: Default::default(),
+            pressed_at: Default::default(),
+            tapped_at: Default::default(),
+            switch_events: Default::default(),
+            switches: Default::default(),
+            conditions: Default::default(),
         }
     }
 
     pub fn add_hotkey(&mut self, hotkey: Hotkey<U>, on_press: bool) where U: Clone {
-        for &key in &hotkey.triggers {
-            if on_press {
-                &mut self.triggers_press
-            } else {
-                &mut self.triggers_release
-            }.entry(key).or_insert(Default::default()).push(hotkey.clone())
+        self.triggers.get_mut().unwrap().add(hotkey, on_press)
+    }
+
+    /// Replaces every hotkey while running
+    pub fn set_hotkeys<I: IntoIterator<Item=(Hotkey<U>, bool)>>(&self, hotkeys: I) where U: Clone {
+        let mut triggers = Triggers::default();
+        for (hotkey, on_press) in hotkeys {
+            triggers.add(hotkey, on_press);
+        }
+        *self.triggers.write().unwrap() = triggers;
+        self.sequences.lock().unwrap().clear();
+    }
+
+    /// Decides whether conditional hotkeys apply, without which they never do
+    pub fn set_conditions<F: Fn(Condition) -> bool + Send + Sync + 'static>(&self, conditions: F) {
+        *self.conditions.write().unwrap() = Some(Conditions(Box::new(conditions)));
+    }
+
+    fn condition(&self, when: Option<Condition>) -> bool {
+        match when {
+            Some(when) => self.conditions.read().unwrap().as_ref().map(|c| (c.0)(when)).unwrap_or(false),
+            None => true,
         }
     }
 
-    pub fn add_remap(&mut self, from: Key, to: Key) {
-        self.remap.insert(from, to);
+    /// Runs `events` when `switch` turns on, or off
+    pub fn add_switch_event(&mut self, switch: SwitchKind, on: bool, events: Vec<U>) {
+        self.switch_events.get_mut().unwrap().entry((switch, on)).or_default().extend(events);
+    }
+
+    /// Replaces every switch event while running
+    pub fn set_switch_events<I: IntoIterator<Item=(SwitchKind, bool, Vec<U>)>>(&self, switch_events: I) {
+        let mut map: HashMap<_, Vec<U>> = HashMap::new();
+        for (switch, on, events) in switch_events {
+            map.entry((switch, on)).or_default().extend(events);
+        }
+        *self.switch_events.write().unwrap() = map;
+    }
+
+    pub fn switch_states(&self) -> Vec<(SwitchKind, bool)> {
+        let mut states: Vec<_> = self.switches.lock().unwrap().iter().map(|(&switch, &on)| (switch, on)).collect();
+        states.sort_by_key(|&(switch, _)| switch as u16);
+        states
+    }
+
+    /// Hotkey modifiers released within `window` still count as held, for
+    /// sticky keys users who press them one after another
+    pub fn set_sticky_keys(&self, window: Option<Duration>) {
+        *self.sticky.write().unwrap() = window;
+        self.latched.lock().unwrap().clear();
     }
 
-    pub fn map_input_event(&self, mut e: InputEvent) -> InputEvent {
+    fn is_latched(&self, key: Key, now: Instant) -> bool {
+        let window = match *self.sticky.read().unwrap() {
+            Some(window) => window,
+            None => return false,
+        };
+        match self.latched.lock().unwrap().get(&key) {
+            Some(&released) => now.duration_since(released) <= window,
+            None => false,
+        }
+    }
+
+    /// Keys and mouse buttons can be remapped to each other
+    pub fn add_remap(&mut self, destination: Destination, from: Key, to: Key) {
+        self.remap.get_mut().unwrap().insert((destination, from), to);
+    }
+
+    /// Replaces every remapping while running
+    pub fn set_remaps<I: IntoIterator<Item=(Destination, Key, Key)>>(&self, remaps: I) {
+        *self.remap.write().unwrap() = remaps.into_iter()
+            .map(|(destination, from, to)| ((destination, from), to))
+            .collect();
+    }
+
+    /// Inverts or scales wheel events, which applies before any remapping
+    pub fn set_wheel(&self, destination: Destination, wheel: Wheel) {
+        self.wheel.write().unwrap().insert(destination, wheel);
+        self.wheel_remainder.lock().unwrap().retain(|&(d, _), _| d != destination);
+    }
+
+    fn scale_wheel(&self, destination: Destination, horizontal: bool, axis: WheelAxis, value: i32) -> i32 {
+        let value = if axis.invert { -value } else { value };
+        if axis.scale == 1.0 {
+            return value
+        }
+
+        let mut remainders = self.wheel_remainder.lock().unwrap();
+        let remainder = remainders.entry((destination, horizontal)).or_default();
+        let scaled = value as f32 * axis.scale + *remainder;
+        *remainder = scaled.fract();
+        scaled.trunc() as i32
+    }
+
+    pub fn map_input_event(&self, mut e: InputEvent, destination: Destination) -> InputEvent {
+        let wheel = self.wheel.read().unwrap().get(&destination).copied().unwrap_or_default();
         match EventMut::new(&mut e) {
-            Ok(EventMut::Key(key)) => if let Some(remap) = self.remap.get(&key.key) {
-                key.key = *remap;
+            Ok(EventMut::Key(key)) => {
+                // wheels reported as buttons can only be inverted
+                if wheel.vertical.invert {
+                    key.key = match key.key {
+                        Key::ButtonGearUp => Key::ButtonWheel,
+                        Key::ButtonWheel => Key::ButtonGearUp,
+                        k => k,
+                    };
+                }
+                if let Some(remap) = self.remap.read().unwrap().get(&(destination, key.key)) {
+                    key.key = *remap;
+                }
+            },
+            Ok(EventMut::Relative(rel)) => {
+                let axis = match rel.axis {
+                    RelativeAxis::Wheel => Some((false, wheel.vertical)),
+                    RelativeAxis::HorizontalWheel => Some((true, wheel.horizontal)),
+                    _ => None,
+                };
+                if let Some((horizontal, axis)) = axis {
+                    rel.value = self.scale_wheel(destination, horizontal, axis, rel.value);
+                }
             },
             _ => (),
         }
@@ -101,7 +358,9 @@ impl<U> Events<U> {
         e
     }
 
-    pub fn process_input_event<'a>(&'a self, e: &InputEvent) -> Vec<&'a U> {
+    /// Matches hotkeys against the event, after any host remapping
+    pub fn process_input_event(&self, e: &InputEvent) -> Vec<U> where U: Clone {
+        let e = &self.map_input_event(*e, Destination::Host);
         match EventRef::new(e) {
             Ok(e) => self.process_input_event_(e),
             Err(err) => {
@@ -111,41 +370,115 @@ impl<U> Events<U> {
         }
     }
 
-    fn process_input_event_<'a>(&'a self, e: EventRef) -> Vec<&'a U> {
+    /// Feeds a key press to hotkeys partway through their sequence, returning
+    /// None if none of them were waiting on it. Any other key cancels them.
+    fn advance_sequences(&self, key: Key, now: Instant) -> Option<Vec<U>> where U: Clone {
+        let mut sequences = self.sequences.lock().unwrap();
+        if sequences.is_empty() {
+            return None
+        }
+
+        let mut events = None;
+        for mut pending in mem::take(&mut *sequences) {
+            if now > pending.deadline || pending.hotkey.sequence[pending.next] != key {
+                continue
+            }
+            let events = events.get_or_insert_with(Vec::new);
+            pending.next += 1;
+            if pending.next == pending.hotkey.sequence.len() {
+                events.extend(pending.hotkey.events.iter().cloned());
+            } else {
+                pending.deadline = now + pending.hotkey.sequence_timeout;
+                sequences.push(pending);
+            }
+        }
+        events
+    }
+
+    fn process_input_event_(&self, e: EventRef) -> Vec<U> where U: Clone {
         match e {
             EventRef::Key(key) => {
                 let state = key.value;
 
+                let triggers = self.triggers.read().unwrap();
                 let hotkeys = match state {
-                    KeyState::PRESSED => self.triggers_press.get(&key.key),
-                    KeyState::RELEASED => self.triggers_release.get(&key.key),
+                    KeyState::PRESSED => triggers.press.get(&key.key),
+                    KeyState::RELEASED => triggers.release.get(&key.key),
                     _ => None,
                 };
 
-                let mut keys = self.keys.lock().unwrap();
-                match state {
-                    KeyState::PRESSED => keys.insert(key.key),
-                    _ => (),
+                let now = Instant::now();
+                let (held, tapped) = match state {
+                    KeyState::PRESSED => {
+                        self.pressed_at.lock().unwrap().insert(key.key, now);
+                        (None, self.tapped_at.lock().unwrap().insert(key.key, now))
+                    },
+                    KeyState::RELEASED => (self.pressed_at.lock().unwrap().get(&key.key).map(|&at| now.duration_since(at)), None),
+                    _ => (None, None),
+                };
+                if state == KeyState::PRESSED {
+                    self.keys.track_key(key.key, state);
+                    if let Some(events) = self.advance_sequences(key.key, now) {
+                        return events
+                    }
                 }
 
-                let events = if let Some(hotkeys) = hotkeys {
-                    hotkeys.iter()
-                        .filter(|h| h.keys().all(|k| keys.get(k)))
+                let matched: Vec<_> = match hotkeys {
+                    Some(hotkeys) => hotkeys.iter()
+                        .filter(|h| h.triggers.iter().all(|&k| self.keys.is_pressed(k)))
+                        .filter(|h| h.modifiers.iter().all(|&k| self.keys.is_pressed(k) || self.is_latched(k, now)))
                         .filter(|h| h.triggers.contains(&key.key))
-                        .flat_map(|h| h.events.iter())
-                        .collect()
-                } else {
-                    Default::default()
+                        .filter(|h| self.condition(h.when))
+                        .filter(|h| !h.exact || self.keys.pressed().iter().all(|k| h.triggers.contains(k) || h.modifiers.contains(k)))
+                        .filter(|h| h.hold.map(|hold| held.map(|held| held >= hold).unwrap_or(false)).unwrap_or(true))
+                        .filter(|h| h.double_tap.map(|window| tapped.map(|at| now.duration_since(at) <= window).unwrap_or(false)).unwrap_or(true))
+                        .collect(),
+                    None => Default::default(),
                 };
+                if matched.iter().any(|h| h.double_tap.is_some()) {
+                    // a third press starts over instead of firing again
+                    self.tapped_at.lock().unwrap().remove(&key.key);
+                }
+                let mut events = Vec::new();
+                for hotkey in &matched {
+                    if hotkey.sequence.is_empty() {
+                        events.extend(hotkey.events.iter().cloned());
+                    } else {
+                        self.sequences.lock().unwrap().push(PendingSequence {
+                            hotkey: (*hotkey).clone(),
+                            next: 0,
+                            deadline: now + hotkey.sequence_timeout,
+                        });
+                    }
+                }
+                if !matched.is_empty() {
+                    // latched modifiers only apply to one hotkey
+                    self.latched.lock().unwrap().clear();
+                }
 
                 match state {
                     KeyState::PRESSED => (),
-                    KeyState::RELEASED => keys.remove(key.key),
+                    KeyState::RELEASED => {
+                        self.keys.track_key(key.key, state);
+                        if self.sticky.read().unwrap().is_some() {
+                            self.latched.lock().unwrap().insert(key.key, now);
+                        }
+                    },
                     state => warn!("Unknown key state {:?}", state),
                 }
 
                 events
             },
+            EventRef::Switch(switch) => {
+                let on = switch.value != 0;
+                // devices repeat their current state when opened
+                if self.switches.lock().unwrap().insert(switch.switch, on) == Some(on) {
+                    return Default::default()
+                }
+
+                self.switch_events.read().unwrap().get(&(switch.switch, on))
+                    .cloned().unwrap_or_default()
+            },
             _ => Default::default(),
         }
     }
@@ -170,24 +503,163 @@ impl<U> Events<U> {
         }.into_iter()
     }
 
-    fn unstick_events_<'a>(keys: &'a Bitmask<Key>) -> impl Iterator<Item=InputEvent> + 'a {
-        keys.iter().map(|key|
-            KeyEvent::new(Default::default(), key, KeyState::RELEASED).into()
-        ).chain(iter::once(SynchronizeEvent::report(Default::default()).into()))
+    /// Keys currently held down on the input side
+    pub fn key_state(&self) -> &KeyStateTracker {
+        &self.keys
     }
 
     pub fn unstick_guest(&self) -> impl Iterator<Item=InputEvent> + Send {
-        let mut keys = self.keys.lock().unwrap();
-        let res: SmallVec<[InputEvent; 4]> = Self::unstick_events_(&keys).collect();
-        keys.clear();
-        res.into_iter()
+        self.keys.release_all().into_iter()
     }
 
     fn unstick_guest_(&self) -> SmallVec<[ProcessedXEvent; 4]> {
-        // sad duplicate because it seems slightly more efficient :(
-        let mut keys = self.keys.lock().unwrap();
-        let res = Self::unstick_events_(&keys).map(From::from).collect();
-        keys.clear();
-        res
+        self.keys.release_all().into_iter().map(From::from).collect()
     }
 }
+
+#[test]
+fn sticky_modifiers() {
+    use input_linux::KeyEvent;
+
+    let key = |key, state| -> InputEvent { KeyEvent::new(Default::default(), key, state).into() };
+    let mut events = Events::new();
+    events.add_hotkey(Hotkey::new(vec![Key::G], vec![Key::LeftMeta], vec![1u32]), true);
+
+    let latched = |events: &Events<u32>| {
+        events.process_input_event(&key(Key::LeftMeta, KeyState::PRESSED));
+        events.process_input_event(&key(Key::LeftMeta, KeyState::RELEASED));
+        let fired = events.process_input_event(&key(Key::G, KeyState::PRESSED));
+        events.process_input_event(&key(Key::G, KeyState::RELEASED));
+        fired
+    };
+    assert!(latched(&events).is_empty());
+
+    events.set_sticky_keys(Some(Duration::from_secs(5)));
+    assert_eq!(latched(&events), vec![1]);
+    // the latch is used up
+    assert!(events.process_input_event(&key(Key::G, KeyState::PRESSED)).is_empty());
+}
+
+#[test]
+fn wheel_adjustment() {
+    use input_linux::{KeyEvent, RelativeEvent};
+
+    let events: Events<u32> = Events::new();
+    let invert = WheelAxis { invert: true, scale: 1.0 };
+    events.set_wheel(Destination::Guest, Wheel { vertical: invert, horizontal: WheelAxis { invert: false, scale: 0.5 } });
+
+    let wheel = |axis, value| -> InputEvent { RelativeEvent::new(Default::default(), axis, value).into() };
+    let value = |e: InputEvent| match EventRef::new(&e) {
+        Ok(EventRef::Relative(rel)) => rel.value,
+        e => panic!("expected a relative event, got {:?}", e),
+    };
+    assert_eq!(value(events.map_input_event(wheel(RelativeAxis::Wheel, 2), Destination::Guest)), -2);
+    assert_eq!(value(events.map_input_event(wheel(RelativeAxis::Wheel, 2), Destination::Host)), 2);
+    // half steps carry over
+    assert_eq!(value(events.map_input_event(wheel(RelativeAxis::HorizontalWheel, 1), Destination::Guest)), 0);
+    assert_eq!(value(events.map_input_event(wheel(RelativeAxis::HorizontalWheel, 1), Destination::Guest)), 1);
+
+    let button = |key| -> InputEvent { KeyEvent::new(Default::default(), key, KeyState::PRESSED).into() };
+    assert_eq!(events.map_input_event(button(Key::ButtonWheel), Destination::Guest), button(Key::ButtonGearUp));
+}
+
+#[test]
+fn hotkey_sequence() {
+    use input_linux::KeyEvent;
+
+    let key = |key, state| -> InputEvent { KeyEvent::new(Default::default(), key, state).into() };
+    let tap = |events: &Events<u32>, k| {
+        let fired = events.process_input_event(&key(k, KeyState::PRESSED));
+        events.process_input_event(&key(k, KeyState::RELEASED));
+        fired
+    };
+    let mut events = Events::new();
+    events.add_hotkey(Hotkey::new(vec![Key::LeftAlt], vec![Key::LeftCtrl], vec![1u32])
+        .with_sequence(vec![Key::G, Key::H], Duration::from_secs(5)), true);
+
+    events.process_input_event(&key(Key::LeftCtrl, KeyState::PRESSED));
+    assert!(tap(&events, Key::LeftAlt).is_empty());
+    events.process_input_event(&key(Key::LeftCtrl, KeyState::RELEASED));
+    assert!(tap(&events, Key::G).is_empty());
+    assert_eq!(tap(&events, Key::H), vec![1]);
+
+    // anything else pressed in between cancels the sequence
+    events.process_input_event(&key(Key::LeftCtrl, KeyState::PRESSED));
+    tap(&events, Key::LeftAlt);
+    events.process_input_event(&key(Key::LeftCtrl, KeyState::RELEASED));
+    tap(&events, Key::J);
+    tap(&events, Key::G);
+    assert!(tap(&events, Key::H).is_empty());
+}
+
+#[test]
+fn hold_and_double_tap() {
+    use input_linux::KeyEvent;
+
+    let key = |key, state| -> InputEvent { KeyEvent::new(Default::default(), key, state).into() };
+    let mut events = Events::new();
+    events.add_hotkey(Hotkey::new(vec![Key::CapsLock], vec![], vec![1u32]).with_hold(Some(Duration::from_millis(20))), false);
+    events.add_hotkey(Hotkey::new(vec![Key::ScrollLock], vec![], vec![2u32]).with_double_tap(Some(Duration::from_secs(5))), true);
+
+    // a tap is too quick, holding it fires on release
+    events.process_input_event(&key(Key::CapsLock, KeyState::PRESSED));
+    assert!(events.process_input_event(&key(Key::CapsLock, KeyState::RELEASED)).is_empty());
+    assert!(events.process_input_event(&key(Key::CapsLock, KeyState::PRESSED)).is_empty());
+    std::thread::sleep(Duration::from_millis(30));
+    assert_eq!(events.process_input_event(&key(Key::CapsLock, KeyState::RELEASED)), vec![1]);
+
+    let tap = |events: &Events<u32>| {
+        let fired = events.process_input_event(&key(Key::ScrollLock, KeyState::PRESSED));
+        events.process_input_event(&key(Key::ScrollLock, KeyState::RELEASED));
+        fired
+    };
+    assert!(tap(&events).is_empty());
+    assert_eq!(tap(&events), vec![2]);
+    assert!(tap(&events).is_empty());
+}
+
+#[test]
+fn exact_modifiers() {
+    use input_linux::KeyEvent;
+
+    let key = |key, state| -> InputEvent { KeyEvent::new(Default::default(), key, state).into() };
+    let mut events = Events::new();
+    events.add_hotkey(Hotkey::new(vec![Key::G], vec![Key::LeftMeta], vec![1u32]).with_exact(true), true);
+
+    events.process_input_event(&key(Key::LeftMeta, KeyState::PRESSED));
+    events.process_input_event(&key(Key::W, KeyState::PRESSED));
+    assert!(events.process_input_event(&key(Key::G, KeyState::PRESSED)).is_empty());
+    events.process_input_event(&key(Key::G, KeyState::RELEASED));
+    events.process_input_event(&key(Key::W, KeyState::RELEASED));
+    assert_eq!(events.process_input_event(&key(Key::G, KeyState::PRESSED)), vec![1]);
+}
+
+#[test]
+fn conditional_hotkeys() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use input_linux::KeyEvent;
+
+    let key = |key, state| -> InputEvent { KeyEvent::new(Default::default(), key, state).into() };
+    let mut events = Events::new();
+    events.add_hotkey(Hotkey::new(vec![Key::F12], vec![], vec![1u32]).with_condition(Some(Condition::GuestShown)), true);
+    events.add_hotkey(Hotkey::new(vec![Key::F12], vec![], vec![2u32]).with_condition(Some(Condition::HostShown)), true);
+    let guest = Arc::new(AtomicBool::new(false));
+    events.set_conditions({
+        let guest = guest.clone();
+        move |when| match when {
+            Condition::GuestShown => guest.load(Ordering::Relaxed),
+            Condition::HostShown => !guest.load(Ordering::Relaxed),
+            Condition::Grabbed => false,
+        }
+    });
+
+    let tap = |events: &Events<u32>| {
+        let fired = events.process_input_event(&key(Key::F12, KeyState::PRESSED));
+        events.process_input_event(&key(Key::F12, KeyState::RELEASED));
+        fired
+    };
+    assert_eq!(tap(&events), vec![2]);
+    guest.store(true, Ordering::Relaxed);
+    assert_eq!(tap(&events), vec![1]);
+}
@@ -1,5 +1,7 @@
 use std::io;
+use std::fmt;
 use std::sync::{Mutex, Arc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::future::Future;
 use anyhow::{Error, format_err};
 use futures::{TryFutureExt, StreamExt};
@@ -11,11 +13,84 @@ use tokio::sync::broadcast;
 use log::{trace, warn, info};
 
 pub struct Qemu {
-    socket_qmp: Option<String>,
-    socket_qga: Option<String>,
-    qmp: Mutex<Weak<QmpService>>,
-    event_send: broadcast::Sender<qapi::qmp::Event>,
+    socket_qmp: Mutex<Option<String>>,
+    qga: QgaConnection,
+    qmp: Arc<Mutex<Weak<QmpService>>>,
+    event_send: broadcast::Sender<QemuEvent>,
     connection_lock: futures::lock::Mutex<()>,
+    resources: Mutex<Vec<QemuResource>>,
+    suspended: Arc<AtomicBool>,
+    capabilities: Mutex<Option<Arc<QemuCapabilities>>>,
+}
+
+/// QMP events, along with changes to the connection itself
+#[derive(Debug, Clone)]
+pub enum QemuEvent {
+    Qmp(qapi::qmp::Event),
+    /// A QMP connection was opened
+    Connected,
+    /// The QMP connection closed, such as when the VM shut down
+    Disconnected,
+}
+
+/// Something screenstub has added to the VM and should remove again on exit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QemuResource {
+    Device(String),
+    Object(String),
+}
+
+/// What the running QEMU supports, as far as screenstub cares
+#[derive(Debug, Clone, Default)]
+pub struct QemuCapabilities {
+    pub version: String,
+    /// Values of `InputButton`, empty if the schema didn't say
+    pub input_buttons: Vec<String>,
+    /// Whether input-linux objects accept `grab_toggle`
+    pub grab_toggle: bool,
+    /// QOM types, which includes every device and object that can be added
+    pub types: Vec<String>,
+}
+
+impl QemuCapabilities {
+    pub fn has_type(&self, name: &str) -> bool {
+        self.types.iter().any(|t| t == name)
+    }
+
+    /// Assumes the button exists when the schema didn't list them
+    pub fn has_input_button(&self, button: &str) -> bool {
+        self.input_buttons.is_empty() || self.input_buttons.iter().any(|b| b == button)
+    }
+}
+
+impl fmt::Display for QemuCapabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "QEMU {}, input buttons: {}, input-linux: {}{}, virtio-input-host: {}",
+            self.version,
+            if self.input_buttons.is_empty() { "unknown".into() } else { self.input_buttons.join(" ") },
+            if self.has_type("input-linux") { "yes" } else { "no" },
+            if self.grab_toggle { " (with grab_toggle)" } else { "" },
+            if self.has_type("virtio-input-host-pci") { "yes" } else { "no" },
+        )
+    }
+}
+
+/// Values of the first enum in a serialized QMP schema that `value` belongs to.
+///
+/// Introspection hides type names, so a value only the wanted enum has stands in for its name.
+fn schema_enum(schema: &serde_json::Value, value: &str) -> Option<Vec<String>> {
+    schema.as_array().into_iter().flatten()
+        .filter(|info| info["meta-type"] == "enum")
+        .map(|info| match info["members"].as_array() {
+            Some(members) => members.iter()
+                .filter_map(|member| member["name"].as_str())
+                .map(From::from).collect::<Vec<String>>(),
+            // older QEMU only lists the names
+            None => info["values"].as_array().into_iter().flatten()
+                .filter_map(|value| value.as_str())
+                .map(From::from).collect(),
+        })
+        .find(|values| values.iter().any(|v| v == value))
 }
 
 type QgaWrite = qapi::futures::QgaStreamTokio<WriteHalf<UnixStream>>;
@@ -26,22 +101,192 @@ pub type QmpService = qapi::futures::QapiService<QmpWrite>;
 pub type QmpStream = qapi::futures::QapiStream<QmpRead, QmpWrite>;
 pub type QmpEvents = qapi::futures::QapiEvents<QmpRead>;
 
+/// Delay before the first QMP reconnection attempt, doubling up to `QMP_BACKOFF_MAX`
+const QMP_BACKOFF: Duration = Duration::from_millis(500);
+const QMP_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// Tries at a QGA command before giving up, waiting twice as long after each failure
+const QGA_ATTEMPTS: u32 = 5;
+const QGA_BACKOFF: Duration = Duration::from_millis(250);
+
+/// A guest agent connection that stays open between commands.
+///
+/// Unlike QMP nothing else holds on to it between hotkeys, so the cache keeps it alive.
+#[derive(Clone)]
+struct QgaConnection {
+    socket: Arc<Mutex<Option<String>>>,
+    service: Arc<Mutex<Option<Arc<QgaService>>>>,
+}
+
+impl QgaConnection {
+    async fn connect(self) -> Result<Arc<QgaService>, io::Error> {
+        if let Some(qga) = self.service.lock().unwrap().clone() {
+            return Ok(qga)
+        }
+
+        let socket = self.socket.lock().unwrap().clone()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "QGA socket not configured"))?;
+        let stream = qapi::futures::QgaStreamTokio::open_uds(socket).await?;
+        let (service, _) = stream.spawn_tokio();
+        let mut cached = self.service.lock().unwrap();
+        Ok(match *cached {
+            // if two tasks fight for this, just ditch this new connection
+            Some(ref qga) => qga.clone(),
+            None => {
+                let qga = Arc::new(service);
+                *cached = Some(qga.clone());
+                qga
+            },
+        })
+    }
+
+    /// Switches to another socket, closing the current connection
+    fn retarget(&self, socket: Option<String>) {
+        *self.socket.lock().unwrap() = socket;
+        *self.service.lock().unwrap() = None;
+    }
+
+    /// Forgets `qga` so the next command opens a new connection
+    fn reset(&self, qga: &Arc<QgaService>) {
+        let mut cached = self.service.lock().unwrap();
+        if cached.as_ref().map(|cached| Arc::ptr_eq(cached, qga)).unwrap_or(false) {
+            *cached = None;
+        }
+    }
+
+    /// Runs `command` once, dropping the connection if it broke
+    async fn execute_once<C: qapi::qga::QgaCommand>(self, command: C) -> qapi::ExecuteResult<C> {
+        let qga = self.clone().connect().await?;
+        let res = qga.execute(command).await;
+        if let Err(qapi::ExecuteError::Io(..)) = res {
+            self.reset(&qga);
+        }
+        res
+    }
+
+    /// Runs `command`, reconnecting with backoff while the agent is unreachable,
+    /// which happens while the guest reboots
+    async fn execute<C: qapi::qga::QgaCommand + Clone>(self, command: C) -> qapi::ExecuteResult<C> {
+        let mut backoff = QGA_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            match self.clone().execute_once(command.clone()).await {
+                Err(qapi::ExecuteError::Io(e)) if attempt < QGA_ATTEMPTS => {
+                    warn!("QGA connection failed, retrying in {:?}: {}", backoff, e);
+                    sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                },
+                res => break res,
+            }
+        }
+    }
+}
+
 impl Qemu {
     pub fn new(socket_qmp: Option<String>, socket_qga: Option<String>) -> Self {
         let (event_send, _event_recv) = broadcast::channel(8);
         Qemu {
-            socket_qmp,
-            socket_qga,
+            socket_qmp: Mutex::new(socket_qmp),
+            qga: QgaConnection {
+                socket: Arc::new(Mutex::new(socket_qga)),
+                service: Default::default(),
+            },
             event_send,
-            qmp: Mutex::new(Weak::new()),
+            qmp: Arc::new(Mutex::new(Weak::new())),
             connection_lock: Default::default(),
+            resources: Default::default(),
+            suspended: Default::default(),
+            capabilities: Default::default(),
         }
     }
 
-    pub fn qmp_events(&self) -> broadcast::Receiver<qapi::qmp::Event> {
+    pub fn qmp_events(&self) -> broadcast::Receiver<QemuEvent> {
         self.event_send.subscribe()
     }
 
+    /// Keeps a QMP connection open in the background, reconnecting with
+    /// backoff whenever it drops, such as across a VM restart.
+    ///
+    /// Stops once the `Qemu` itself goes away.
+    pub fn supervise(self: &Arc<Self>) {
+        if self.socket_qmp.lock().unwrap().is_none() {
+            return
+        }
+
+        let qemu = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut backoff = QMP_BACKOFF;
+            loop {
+                let (mut events, connect) = match qemu.upgrade() {
+                    Some(qemu) => (qemu.qmp_events(), async move { qemu.connect_qmp().await }),
+                    None => break,
+                };
+                match connect.await {
+                    Ok(qmp) => {
+                        backoff = QMP_BACKOFF;
+                        // holding on to the service keeps the connection open until it drops
+                        loop {
+                            match events.recv().await {
+                                Ok(QemuEvent::Disconnected) | Err(broadcast::error::RecvError::Closed) => break,
+                                // the disconnect may have been among the missed events
+                                Err(broadcast::error::RecvError::Lagged(..)) => match qemu.upgrade() {
+                                    Some(qemu) if qemu.is_current_qmp(&qmp) => (),
+                                    _ => break,
+                                },
+                                _ => (),
+                            }
+                        }
+                        drop(qmp);
+                    },
+                    Err(e) => {
+                        trace!("QMP reconnection failed, retrying in {:?}: {:#}", backoff, e);
+                        sleep(backoff).await;
+                        backoff = (backoff * 2).min(QMP_BACKOFF_MAX);
+                    },
+                }
+            }
+        });
+    }
+
+    /// Points at another VM's sockets, dropping the current connections.
+    ///
+    /// Resources registered with the old VM are forgotten, so `cleanup` it first.
+    pub fn retarget(&self, socket_qmp: Option<String>, socket_qga: Option<String>) {
+        *self.socket_qmp.lock().unwrap() = socket_qmp;
+        self.qga.retarget(socket_qga);
+        self.resources.lock().unwrap().clear();
+        *self.capabilities.lock().unwrap() = None;
+        self.suspended.store(false, Ordering::Relaxed);
+        *self.qmp.lock().unwrap() = Weak::new();
+        // lets `supervise` go of the old connection and reconnect
+        let _ = self.event_send.send(QemuEvent::Disconnected);
+    }
+
+    /// Whether a QMP connection is currently open
+    pub fn qmp_connected(&self) -> bool {
+        self.qmp.lock().unwrap().strong_count() > 0
+    }
+
+    /// Keeps trying to connect to QMP until `deadline` passes, for a VM that is still starting up
+    pub async fn wait_qmp(&self, deadline: Duration) -> Result<Arc<QmpService>, Error> {
+        let connect = async {
+            loop {
+                match self.connect_qmp().await {
+                    Ok(qmp) => break qmp,
+                    Err(e) => trace!("QMP is not up yet: {:#}", e),
+                }
+                sleep(QMP_BACKOFF).await;
+            }
+        };
+        timeout(deadline, connect).await
+            .map_err(|_| format_err!("QMP socket did not come up within {:?}", deadline))
+    }
+
+    fn is_current_qmp(&self, qmp: &Arc<QmpService>) -> bool {
+        self.qmp.lock().unwrap().ptr_eq(&Arc::downgrade(qmp))
+    }
+
     pub async fn connect_qmp(&self) -> Result<Arc<QmpService>, Error> {
         let _lock = self.connection_lock.lock().await;
         let qmp = self.qmp.lock().unwrap().upgrade();
@@ -58,11 +303,33 @@ impl Qemu {
                         let res = Arc::new(stream);
                         *qmp = Arc::downgrade(&res);
                         let event_send = self.event_send.clone();
+                        let suspended = self.suspended.clone();
+                        let (cache, cached) = (self.qmp.clone(), Arc::downgrade(&res));
                         let _ = events.release();
+                        let _ = event_send.send(QemuEvent::Connected);
+                        tokio::spawn({
+                            let qmp = res.clone();
+                            let suspended = suspended.clone();
+                            async move {
+                                match qmp.execute(qapi::qmp::query_status { }).await {
+                                    Ok(status) => suspended.store(status.status == qapi::qmp::RunState::suspended, Ordering::Relaxed),
+                                    Err(e) => warn!("QMP failed to query guest status: {:?}", e),
+                                }
+                            }
+                        });
                         tokio::spawn(async move {
                             while let Some(event) = events.next().await {
                                 match event {
-                                    Ok(e) => match event_send.send(e) {
+                                    Ok(qapi::qmp::Event::SUSPEND { .. }) => {
+                                        suspended.store(true, Ordering::Relaxed);
+                                    },
+                                    Ok(qapi::qmp::Event::WAKEUP { .. }) | Ok(qapi::qmp::Event::RESUME { .. }) => {
+                                        suspended.store(false, Ordering::Relaxed);
+                                    },
+                                    _ => (),
+                                }
+                                match event {
+                                    Ok(e) => match event_send.send(QemuEvent::Qmp(e)) {
                                         Err(e) => {
                                             info!("QMP event ignored: {:?}", e.0);
                                         },
@@ -74,6 +341,15 @@ impl Qemu {
                                     },
                                 }
                             }
+                            info!("QMP connection closed");
+                            // anyone still holding the service shouldn't hand it out again
+                            let mut qmp = cache.lock().unwrap();
+                            // unless it was already replaced by `retarget`
+                            if qmp.ptr_eq(&cached) {
+                                *qmp = Weak::new();
+                                drop(qmp);
+                                let _ = event_send.send(QemuEvent::Disconnected);
+                            }
                         });
                         res
                     },
@@ -83,9 +359,8 @@ impl Qemu {
     }
 
     fn connect_qmp_stream(&self) -> impl Future<Output=Result<QmpStream, io::Error>> {
-        let socket_qmp = self.socket_qmp.as_ref()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "QMP socket not configured"))
-            .map(|p| p.to_owned());
+        let socket_qmp = self.socket_qmp.lock().unwrap().clone()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "QMP socket not configured"));
 
         async move {
             let stream = qapi::futures::QmpStreamTokio::open_uds(socket_qmp?).await?;
@@ -93,22 +368,34 @@ impl Qemu {
         }
     }
 
-    pub fn connect_qga(&self) -> impl Future<Output=Result<QgaService, io::Error>> {
-        let socket_qga = self.socket_qga.as_ref()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "QGA socket not configured"))
-            .map(|p| p.to_owned());
+    /// The shared guest agent connection, opening it if there isn't one
+    pub fn connect_qga(&self) -> impl Future<Output=Result<Arc<QgaService>, io::Error>> {
+        self.qga.clone().connect()
+    }
 
-        async move {
-            let stream = qapi::futures::QgaStreamTokio::open_uds(socket_qga?).await?;
-            let (service, _) = stream.spawn_tokio();
+    /// Whether the guest was last seen entering a suspend state
+    pub fn guest_suspended(&self) -> bool {
+        self.suspended.load(Ordering::Relaxed)
+    }
 
-            Ok(service)
-        }
+    /// Issues `system_wakeup` and waits for the guest to report that it woke up
+    pub async fn wakeup(&self, deadline: Duration) -> Result<(), Error> {
+        let mut events = self.qmp_events();
+        self.execute_qmp(qapi::qmp::system_wakeup { }).await?;
+        timeout(deadline, async move {
+            loop {
+                match events.recv().await {
+                    Ok(QemuEvent::Qmp(qapi::qmp::Event::WAKEUP { .. })) | Ok(QemuEvent::Qmp(qapi::qmp::Event::RESUME { .. })) => break Ok(()),
+                    Ok(..) | Err(broadcast::error::RecvError::Lagged(..)) => (),
+                    Err(broadcast::error::RecvError::Closed) => break Err(format_err!("QMP event stream closed")),
+                }
+            }
+        }).await
+            .map_err(|_| format_err!("guest did not wake up within {:?}", deadline))?
     }
 
-    pub async fn execute_qga<C: qapi::qga::QgaCommand>(&self, command: C) -> qapi::ExecuteResult<C> {
-        let qga = self.connect_qga().await?;
-        qga.execute(command).await
+    pub async fn execute_qga<C: qapi::qga::QgaCommand + Clone>(&self, command: C) -> qapi::ExecuteResult<C> {
+        self.qga.clone().execute(command).await
     }
 
     pub async fn execute_qmp<C: qapi::qmp::QmpCommand>(&self, command: C) -> Result<C::Ok, Error> {
@@ -116,54 +403,135 @@ impl Qemu {
             .map_err(From::from)
     }
 
+    /// Sends every command without waiting for the replies in between.
+    ///
+    /// QMP still runs them one at a time in the order given, and each reply is
+    /// matched back up to its command by id. Use separate calls where a command
+    /// depends on the result of an earlier one.
+    pub async fn execute_qmp_pipelined<C: qapi::qmp::QmpCommand, I: IntoIterator<Item=C>>(&self, commands: I) -> Result<Vec<qapi::ExecuteResult<C>>, Error> {
+        let qmp = self.connect_qmp().await?;
+        Ok(future::join_all(commands.into_iter().map(|command| qmp.execute(command))).await)
+    }
+
+    /// What QEMU supports, probed over QMP the first time it's asked for
+    pub async fn capabilities(&self) -> Result<Arc<QemuCapabilities>, Error> {
+        if let Some(capabilities) = self.probed_capabilities() {
+            return Ok(capabilities)
+        }
+
+        let qmp = self.connect_qmp().await?;
+        let (version, schema, types) = future::try_join3(
+            qmp.execute(qapi::qmp::query_version { }),
+            qmp.execute(qapi::qmp::query_qmp_schema { }),
+            qmp.execute(qapi::qmp::qom_list_types { implements: None, abstract_: None }),
+        ).await?;
+        let schema = serde_json::to_value(schema)?;
+        let capabilities = Arc::new(QemuCapabilities {
+            version: format!("{}.{}.{}", version.qemu.major, version.qemu.minor, version.qemu.micro),
+            input_buttons: schema_enum(&schema, "wheel-up").unwrap_or_default(),
+            grab_toggle: schema_enum(&schema, "ctrl-scrolllock").is_some(),
+            types: types.into_iter().map(|info| info.name).collect(),
+        });
+        *self.capabilities.lock().unwrap() = Some(capabilities.clone());
+
+        Ok(capabilities)
+    }
+
+    /// Capabilities from an earlier probe, if there was one
+    pub fn probed_capabilities(&self) -> Option<Arc<QemuCapabilities>> {
+        self.capabilities.lock().unwrap().clone()
+    }
+
     pub async fn device_add(&self, add: qapi::qmp::device_add, deadline: Instant) -> Result<(), Error> {
         let qmp = self.connect_qmp().await?;
         let id = add.id.as_ref()
             .ok_or_else(|| format_err!("device_add id not found"))?
             .to_owned();
-        let path = format!("/machine/peripheral/{}", id);
-        let exists = match qmp.execute(qapi::qmp::qom_list { path }).await {
+        // subscribe first so DEVICE_DELETED can't be missed
+        let mut events = self.qmp_events();
+        // deleting straight away saves a round trip over checking whether it exists first
+        let exists = match qmp.execute(qapi::qmp::device_del { id: id.clone() }).await {
             Ok(_) => true,
             Err(qapi::ExecuteError::Qapi(qapi::Error { class: qapi::ErrorClass::DeviceNotFound, .. })) => false,
             Err(e) => return Err(e.into()),
         };
         if exists {
-            let mut events = self.qmp_events();
-            let delete = qmp.execute(qapi::qmp::device_del { id: id.clone() })
-                .map_err(Error::from)
-                .map_ok(drop);
-            let wait = async move {
-                loop {
-                    match events.recv().await {
-                        Ok(qapi::qmp::Event::DEVICE_DELETED { ref data, .. }) if data.device.as_ref() == Some(&id) => {
-                            // work around qemu bug. without this delay, device_add will work but the new device might be immediately deleted
-                            sleep(Duration::from_millis(128)).await;
-
-                            break Ok(())
-                        },
-                        Err(broadcast::error::RecvError::Closed) => break Err(format_err!("Expected DEVICE_DELETED event")),
-                        _ => (),
-                    }
+            loop {
+                match events.recv().await {
+                    Ok(QemuEvent::Qmp(qapi::qmp::Event::DEVICE_DELETED { ref data, .. })) if data.device.as_ref() == Some(&id) => {
+                        // work around qemu bug. without this delay, device_add will work but the new device might be immediately deleted
+                        sleep(Duration::from_millis(128)).await;
+
+                        break
+                    },
+                    Err(broadcast::error::RecvError::Closed) => return Err(format_err!("Expected DEVICE_DELETED event")),
+                    _ => (),
                 }
-            };
-            future::try_join(wait, delete).await?;
+            }
         }
 
         tokio::time::sleep_until(deadline).await;
         qmp.execute(add).await?;
+        self.register(QemuResource::Device(id.clone()));
 
         Ok(())
     }
 
+    pub fn register(&self, resource: QemuResource) {
+        let mut resources = self.resources.lock().unwrap();
+        if !resources.contains(&resource) {
+            resources.push(resource);
+        }
+    }
+
+    pub fn unregister(&self, resource: &QemuResource) {
+        self.resources.lock().unwrap().retain(|r| r != resource);
+    }
+
+    /// Removes every registered device and object from the VM, newest first.
+    ///
+    /// Anything that has already disappeared is ignored.
+    pub async fn cleanup(&self) -> Result<(), Error> {
+        let resources: Vec<_> = self.resources.lock().unwrap().drain(..).rev().collect();
+        if resources.is_empty() {
+            return Ok(())
+        }
+
+        let qmp = self.connect_qmp().await?;
+        // pipelined, these still run newest first
+        let removals = resources.iter().map(|resource| {
+            info!("Removing {:?}", resource);
+            match *resource {
+                QemuResource::Device(ref id) =>
+                    future::Either::Left(qmp.execute(qapi::qmp::device_del { id: id.clone() }).map_ok(drop)),
+                QemuResource::Object(ref id) =>
+                    future::Either::Right(qmp.execute(qapi::qmp::object_del { id: id.clone() }).map_ok(drop)),
+            }
+        });
+        let results = future::join_all(removals).await;
+        let mut res = Ok(());
+        for (resource, r) in resources.into_iter().zip(results) {
+            match r {
+                Ok(()) | Err(qapi::ExecuteError::Qapi(qapi::Error { class: qapi::ErrorClass::DeviceNotFound, .. })) => (),
+                Err(e) => {
+                    warn!("Failed to remove {:?}: {:?}", resource, e);
+                    res = Err(e.into());
+                },
+            }
+        }
+
+        res
+    }
+
     pub fn guest_exec_(&self, exec: qapi::qga::guest_exec) -> impl Future<Output=Result<qapi::qga::GuestExecStatus, Error>> {
-        let connect = self.connect_qga();
+        let qga = self.qga.clone();
         async move {
             trace!("QEMU GA Exec {:?}", exec);
 
-            let qga = connect.await?;
-            match qga.execute(exec).await {
+            // only the status is retried, a broken exec may or may not have started the process
+            match qga.clone().execute_once(exec).await {
                 Ok(qapi::qga::GuestExec { pid }) => loop {
-                    match qga.execute(qapi::qga::guest_exec_status { pid }).await {
+                    match qga.clone().execute(qapi::qga::guest_exec_status { pid }).await {
                         Ok(r) if !r.exited => sleep(Duration::from_millis(100)).await,
                         res => break res.map_err(From::from),
                     }
@@ -194,10 +562,9 @@ impl Qemu {
     pub fn guest_shutdown(&self, shutdown: qapi::qga::guest_shutdown) -> impl Future<Output=Result<(), Error>> {
         // TODO: a shutdown (but not reboot) can be verified waiting for exit event or socket close or with --no-shutdown, query-status is "shutdown". Ugh!
 
-        let connect = self.connect_qga();
+        let qga = self.qga.clone();
         async move {
-            let qga = connect.await?;
-            match timeout(Duration::from_secs(1), qga.execute(shutdown)).await {
+            match timeout(Duration::from_secs(1), qga.execute_once(shutdown)).await {
                 Ok(res) => res.map(drop).map_err(From::from),
                 Err(_) => {
                     warn!("Shutdown response timed out");
@@ -207,8 +574,9 @@ impl Qemu {
         }
     }
 
+    /// Waits for the agent to answer, retrying for a while if it can't be reached yet
     pub fn guest_wait(&self) -> impl Future<Output=Result<(), Error>> {
-        self.connect_qga()
+        self.qga.clone().execute(qapi::qga::guest_ping { })
             .map_ok(drop).map_err(Error::from)
     }
 }
@@ -222,4 +590,19 @@ impl<'a> GuestExec<'a> {
     pub fn into_future(self) -> impl Future<Output=Result<qapi::qga::GuestExecStatus, Error>> {
         self.qemu.guest_exec_(self.exec)
     }
+
+    /// Starts the process and resolves to its pid, leaving it running in the guest
+    pub fn detached(mut self) -> impl Future<Output=Result<i64, Error>> {
+        // nobody would collect the output
+        self.exec.capture_output = Some(false);
+        let qga = self.qemu.qga.clone();
+        let exec = self.exec;
+        async move {
+            trace!("QEMU GA Exec (detached) {:?}", exec);
+
+            qga.execute_once(exec).await
+                .map(|qapi::qga::GuestExec { pid }| pid)
+                .map_err(From::from)
+        }
+    }
 }
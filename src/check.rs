@@ -0,0 +1,266 @@
+use std::env;
+use std::fs::File;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use config::{Config, ConfigDdcMethod, ConfigEvent, ConfigGrab, ConfigQemuRouting};
+use ddc::Backend;
+
+/// Exit code for a config that checked out, possibly with warnings
+pub const OK: i32 = 0;
+/// Exit code when anything was found that will fail at runtime
+pub const ERRORS: i32 = 1;
+/// Exit code when the config couldn't be read at all
+pub const UNREADABLE: i32 = 2;
+
+#[derive(Default)]
+struct Report {
+    errors: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn ok<S: AsRef<str>>(&mut self, message: S) {
+        println!("ok: {}", message.as_ref());
+    }
+
+    fn warn<S: AsRef<str>>(&mut self, message: S) {
+        self.warnings += 1;
+        println!("warning: {}", message.as_ref());
+    }
+
+    fn error<S: AsRef<str>>(&mut self, message: S) {
+        self.errors += 1;
+        println!("error: {}", message.as_ref());
+    }
+}
+
+/// Validates a config file along with what it refers to on this machine,
+/// printing one line per finding and returning the exit code
+pub fn run(path: Option<&PathBuf>, profile: Option<&str>) -> i32 {
+    let mut report = Report::default();
+
+    let config = match path {
+        Some(path) => match Config::load_profile(path, profile) {
+            Ok(config) => {
+                report.ok(format!("{} parsed", path.display()));
+                config
+            },
+            Err(e) => {
+                println!("error: {}: {:#}", path.display(), e);
+                return UNREADABLE
+            },
+        },
+        None => {
+            report.warn("no config file given, checking the defaults");
+            Config::default()
+        },
+    };
+
+    check_qemu(&mut report, &config);
+    check_screens(&mut report, &config);
+    check_events(&mut report, &config);
+
+    if let Some(ref path) = config.control_socket {
+        match Path::new(path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() =>
+                report.error(format!("control_socket directory {} does not exist", dir.display())),
+            _ => (),
+        }
+    }
+    if let Some(ref notify) = config.guest_notify {
+        if notify.command("").is_none() {
+            report.error("guest_notify needs a known template or an exec command line");
+        }
+    }
+    if let Some(ref request) = config.guest_request {
+        if request.serial.is_none() && request.marker.is_none() {
+            report.error("guest_request needs a serial socket or a marker file");
+        }
+        if let Some(ref serial) = request.serial {
+            check_socket(&mut report, "guest_request serial", Some(serial));
+        }
+    }
+    if let Some(ref compositor) = config.compositor {
+        match (compositor.command(true, ""), compositor.command(false, "")) {
+            (Some(pause), Some(resume)) => for program in pause.first().into_iter().chain(resume.first()) {
+                check_program(&mut report, "compositor", program);
+            },
+            _ => report.error("compositor has an unknown template"),
+        }
+    }
+
+    if let Some(priority) = config.scheduling.as_ref().and_then(|s| s.realtime_priority) {
+        if !crate::sched::realtime_allowed(priority) {
+            report.warn(format!("realtime_priority {} needs CAP_SYS_NICE or an RLIMIT_RTPRIO of at least that much", priority));
+        }
+    }
+
+    println!("{} error(s), {} warning(s)", report.errors, report.warnings);
+    if report.errors > 0 { ERRORS } else { OK }
+}
+
+fn check_socket(report: &mut Report, name: &str, path: Option<&String>) {
+    match path {
+        Some(path) => match UnixStream::connect(path) {
+            Ok(_) => report.ok(format!("{} {} is reachable", name, path)),
+            Err(e) => report.warn(format!("{} {} is not reachable: {}", name, path, e)),
+        },
+        None => report.warn(format!("{} is not configured", name)),
+    }
+}
+
+fn check_qemu(report: &mut Report, config: &Config) {
+    let (mut qmp_socket, mut ga_socket) = (config.qemu.qmp_socket.clone(), config.qemu.ga_socket.clone());
+    if config.qemu.name.is_some() && (qmp_socket.is_none() || ga_socket.is_none()) {
+        match crate::discover::find(config.qemu.name.as_ref().map(|s| &s[..])) {
+            Ok(vm) => {
+                qmp_socket = qmp_socket.or(vm.qmp_socket);
+                ga_socket = ga_socket.or(vm.ga_socket);
+            },
+            Err(e) => report.warn(format!("QEMU socket discovery failed: {}", e)),
+        }
+    }
+    check_socket(report, "qmp_socket", qmp_socket.as_ref());
+    check_socket(report, "ga_socket", ga_socket.as_ref());
+    for vm in &config.qemu.vms {
+        check_socket(report, &format!("vm {} qmp_socket", vm.name), vm.qmp_socket.as_ref());
+        check_socket(report, &format!("vm {} ga_socket", vm.name), vm.ga_socket.as_ref());
+    }
+
+    if let Some(ref launch) = config.qemu.launch {
+        match launch.start_command() {
+            Some(args) => check_program(report, "qemu.launch", &args[0]),
+            None => report.error("qemu.launch needs either exec or systemd_unit"),
+        }
+        if config.qemu.qmp_socket.is_none() {
+            report.error("qemu.launch needs qmp_socket to know when the VM is up");
+        }
+    }
+
+    if matches!(config.qemu.routing, ConfigQemuRouting::Spice) && config.qemu.spice.is_none() {
+        report.error("routing: spice requires qemu.spice to be configured");
+    }
+}
+
+fn check_screens(report: &mut Report, config: &Config) {
+    if config.screens.is_empty() {
+        report.error("no screens are configured");
+    }
+
+    for (i, screen) in config.screens.iter().enumerate() {
+        let ddc = screen.ddc.clone().unwrap_or_default();
+        if ddc.is_none() {
+            report.ok(format!("screen {} has no monitor to switch", i));
+            continue
+        }
+        for method in ddc.host.iter().chain(ddc.guest.iter()) {
+            check_ddc_method(report, i, method);
+        }
+    }
+}
+
+fn check_ddc_method(report: &mut Report, screen: usize, method: &ConfigDdcMethod) {
+    match method {
+        ConfigDdcMethod::Ddc if !Backend::DdcHi.is_available() =>
+            report.error(format!("screen {} uses ddc, but screenstub was built without with-ddc", screen)),
+        ConfigDdcMethod::Libddcutil if !Backend::Libddcutil.is_available() =>
+            report.error(format!("screen {} uses libddcutil, but screenstub was built without with-ddcutil", screen)),
+        ConfigDdcMethod::Ddcutil =>
+            check_program(report, &format!("screen {} ddcutil", screen), "ddcutil"),
+        ConfigDdcMethod::ExecTemplate(name) => match ConfigDdcMethod::exec_template(name) {
+            Some(args) => check_program(report, &format!("screen {} exec_template {}", screen, name), args[0]),
+            None => report.error(format!("screen {} has an unknown exec_template {}", screen, name)),
+        },
+        ConfigDdcMethod::Exec(args) => match args.first() {
+            Some(program) => check_program(report, &format!("screen {} exec", screen), program),
+            None => report.error(format!("screen {} has an empty exec command line", screen)),
+        },
+        _ => (),
+    }
+}
+
+fn check_program(report: &mut Report, context: &str, program: &str) {
+    let found = if program.contains('/') {
+        Path::new(program).is_file()
+    } else {
+        env::var_os("PATH")
+            .map(|paths| env::split_paths(&paths).any(|dir| dir.join(program).is_file()))
+            .unwrap_or(false)
+    };
+    if !found {
+        report.warn(format!("{}: {} was not found", context, program));
+    }
+}
+
+/// `event` along with any it runs itself
+fn nested(event: &ConfigEvent) -> Vec<&ConfigEvent> {
+    let mut events = vec![event];
+    match event {
+        ConfigEvent::Sequence(inner) =>
+            events.extend(inner.iter().flat_map(nested)),
+        ConfigEvent::If { then, otherwise, .. } =>
+            events.extend(then.iter().chain(otherwise).flat_map(nested)),
+        _ => (),
+    }
+    events
+}
+
+/// Every event that might run, with where it came from
+fn events(config: &Config) -> Vec<(String, &ConfigEvent)> {
+    let hotkeys = config.hotkeys.iter().enumerate()
+        .flat_map(|(i, hotkey)| hotkey.events.iter().map(move |e| (format!("hotkey {}", i), e)));
+    let exit = config.exit_events.iter().chain(config.exit.parallel_events.iter())
+        .map(|e| ("exit event".to_owned(), e));
+    let profiles = config.profiles.iter()
+        .flat_map(|(name, profile)| profile.events.iter().map(move |e| (format!("profile {}", name), e)));
+    hotkeys.chain(exit).chain(profiles)
+        .flat_map(|(context, e)| nested(e).into_iter().map(move |e| (context.clone(), e)))
+        .collect()
+}
+
+fn check_events(report: &mut Report, config: &Config) {
+    for (i, hotkey) in config.hotkeys.iter().enumerate() {
+        if hotkey.triggers.is_empty() {
+            report.error(format!("hotkey {} has no triggers", i));
+        }
+        if hotkey.events.is_empty() {
+            report.warn(format!("hotkey {} has no events", i));
+        }
+    }
+
+    for (context, event) in events(config) {
+        match event {
+            ConfigEvent::Grab(ConfigGrab::Evdev { devices, matches, hotplug, .. }) | ConfigEvent::ToggleGrab(ConfigGrab::Evdev { devices, matches, hotplug, .. }) => {
+                if devices.is_empty() && matches.is_empty() && !hotplug {
+                    report.error(format!("{} grabs no evdev devices", context));
+                }
+                if matches.iter().any(|m| m.is_empty()) {
+                    report.error(format!("{} has an evdev match without any fields", context));
+                }
+                if !matches.is_empty() {
+                    match screenstub_core::find_devices(matches, None, *hotplug) {
+                        Ok(found) => report.ok(format!("{} evdev match found {}", context,
+                            found.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(", ")
+                        )),
+                        Err(e) => report.error(format!("{} {}", context, e)),
+                    }
+                }
+                for device in devices {
+                    match File::open(device) {
+                        Ok(_) => report.ok(format!("{} evdev device {} is readable", context, device)),
+                        Err(e) => report.error(format!("{} evdev device {}: {}", context, device, e)),
+                    }
+                }
+            },
+            ConfigEvent::SwitchVm(name) if !config.qemu.vms().iter().any(|vm| &vm.name == name) =>
+                report.error(format!("{} switches to an unknown VM {}", context, name)),
+            ConfigEvent::Profile(name) if !config.profiles.contains_key(name) =>
+                report.error(format!("{} switches to an unknown profile {}", context, name)),
+            ConfigEvent::Exec(args) => match args.first() {
+                Some(program) => check_program(report, &context, program),
+                None => report.error(format!("{} has an empty exec command line", context)),
+            },
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use futures::channel::mpsc;
+use futures::SinkExt;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use anyhow::{Error, format_err};
+use serde_yaml::{Mapping, Value};
+use log::{info, warn};
+use config::{Config, ConfigEvent, ConfigGrab};
+use screenstub_core::Process;
+
+/// Serves commands on a unix socket, one per line. Each reply is followed by
+/// a line that reads either `ok` or `error: <reason>`.
+///
+/// - `show-guest`, `show-host`, `toggle-show`, `exit`
+/// - `grab [<yaml>]` defaults to an X grab, `ungrab [x|evdev]`
+/// - `event <yaml>` runs any event a hotkey could
+/// - `status`, `history`
+/// - `hotkeys`, `hotkeys add <yaml>`, `hotkeys remove <index>`
+/// - `exit-events`, `exit-events add <yaml>`, `exit-events remove <index>`
+/// - `save` writes the current hotkeys and exit events back to the config file
+pub struct Control {
+    process: Arc<Process>,
+    user_sender: mpsc::Sender<Arc<ConfigEvent>>,
+    config_path: Option<PathBuf>,
+}
+
+/// How many history records `history` replies with
+const HISTORY_LINES: usize = 20;
+
+impl Control {
+    pub fn new(process: Arc<Process>, user_sender: mpsc::Sender<Arc<ConfigEvent>>, config_path: Option<PathBuf>) -> Self {
+        Self {
+            process,
+            user_sender,
+            config_path,
+        }
+    }
+
+    pub async fn serve(self: Arc<Self>, path: &Path) -> Result<(), Error> {
+        // clear out a socket left behind by an earlier run
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        info!("Listening for control commands on {}", path.display());
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let control = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = control.client(stream).await {
+                    warn!("Control connection failed: {:?}", e);
+                }
+            });
+        }
+    }
+
+    async fn client(&self, stream: UnixStream) -> Result<(), Error> {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        while let Some(line) = lines.next_line().await? {
+            let line = line.trim();
+            if line.is_empty() {
+                continue
+            }
+            let reply = match self.command(line).await {
+                Ok(reply) if reply.is_empty() => "ok\n".into(),
+                Ok(reply) if reply.ends_with('\n') => format!("{}ok\n", reply),
+                Ok(reply) => format!("{}\nok\n", reply),
+                Err(e) => format!("error: {:#}\n", e),
+            };
+            write.write_all(reply.as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn command(&self, line: &str) -> Result<String, Error> {
+        let mut words = line.splitn(3, char::is_whitespace);
+        let (command, action, arg) = (words.next().unwrap_or_default(), words.next(), words.next());
+        let index = || arg.ok_or_else(|| format_err!("expected an index"))?
+            .trim().parse::<usize>().map_err(Error::from);
+        let yaml = || arg.ok_or_else(|| format_err!("expected a YAML value"));
+        // everything after the command, for the ones that take a single YAML value
+        let rest = Some(line[command.len()..].trim()).filter(|rest| !rest.is_empty());
+
+        let event = match command {
+            "show-guest" => Some(ConfigEvent::ShowGuest),
+            "show-host" => Some(ConfigEvent::ShowHost),
+            "toggle-show" => Some(ConfigEvent::ToggleShow),
+            "exit" => Some(ConfigEvent::Exit),
+            "grab" => Some(ConfigEvent::Grab(match rest {
+                Some(grab) => serde_yaml::from_str(grab)?,
+                None => ConfigGrab::default(),
+            })),
+            "ungrab" => Some(ConfigEvent::Ungrab(match rest {
+                Some(mode) => serde_yaml::from_str(mode)?,
+                None => Default::default(),
+            })),
+            "event" => Some(serde_yaml::from_str(rest.ok_or_else(|| format_err!("expected a YAML value"))?)?),
+            _ => None,
+        };
+        if let Some(event) = event {
+            self.user_sender.clone().send(Arc::new(event)).await
+                .map_err(|_| format_err!("screenstub is shutting down"))?;
+            return Ok(String::new())
+        }
+
+        Ok(match (command, action) {
+            ("status", None) => self.process.status().to_string(),
+            ("history", None) => self.process.history().latest(HISTORY_LINES).iter()
+                .map(|record| format!("{}\n", record))
+                .collect(),
+            ("hotkeys", None) => serde_yaml::to_string(&self.process.hotkeys())?,
+            ("hotkeys", Some("add")) => {
+                self.process.add_hotkey(serde_yaml::from_str(yaml()?)?);
+                String::new()
+            },
+            ("hotkeys", Some("remove")) =>
+                serde_yaml::to_string(&self.process.remove_hotkey(index()?)?)?,
+            ("exit-events", None) => serde_yaml::to_string(&self.process.exit_events())?,
+            ("exit-events", Some("add")) => {
+                self.process.add_exit_event(serde_yaml::from_str(yaml()?)?);
+                String::new()
+            },
+            ("exit-events", Some("remove")) =>
+                serde_yaml::to_string(&self.process.remove_exit_event(index()?)?)?,
+            ("save", None) => {
+                let path = self.config_path.as_ref()
+                    .ok_or_else(|| format_err!("no config file to save to"))?;
+                self.save(path)?;
+                format!("saved to {}", path.display())
+            },
+            _ => return Err(format_err!("unknown command {:?}", line)),
+        })
+    }
+
+    /// Rewrites the bindings in the config file, leaving everything else as it was
+    /// (apart from comments and formatting, which can't be kept)
+    fn save(&self, path: &Path) -> Result<(), Error> {
+        if Config::is_toml(path) {
+            return self.save_toml(path)
+        }
+
+        let mut config = match serde_yaml::from_reader::<_, Value>(File::open(path)?)? {
+            Value::Mapping(config) => config,
+            Value::Null => Mapping::new(),
+            _ => return Err(format_err!("{} is not a YAML mapping", path.display())),
+        };
+        config.insert("hotkeys".into(), serde_yaml::to_value(self.process.hotkeys())?);
+        config.insert("exit_events".into(), serde_yaml::to_value(self.process.exit_events())?);
+
+        let tmp = path.with_extension("tmp");
+        serde_yaml::to_writer(File::create(&tmp)?, &config)?;
+        fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+
+    fn save_toml(&self, path: &Path) -> Result<(), Error> {
+        let mut config = match fs::read_to_string(path)?.parse::<toml::Value>()? {
+            toml::Value::Table(config) => config,
+            _ => return Err(format_err!("{} is not a TOML table", path.display())),
+        };
+        config.insert("hotkeys".into(), toml::Value::try_from(self.process.hotkeys())?);
+        config.insert("exit_events".into(), toml::Value::try_from(self.process.exit_events())?);
+
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, toml::to_string(&config)?)?;
+        fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+}
+
+/// Sends one command to a running instance, printing its reply.
+/// Returns whether the command succeeded.
+pub async fn send(path: &Path, command: &str) -> Result<bool, Error> {
+    let stream = UnixStream::connect(path).await
+        .map_err(|e| format_err!("failed to connect to {}: {}", path.display(), e))?;
+    let (read, mut write) = stream.into_split();
+    write.write_all(format!("{}\n", command).as_bytes()).await?;
+
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line == "ok" {
+            return Ok(true)
+        } else if let Some(e) = line.strip_prefix("error: ") {
+            eprintln!("{}", e);
+            return Ok(false)
+        }
+        println!("{}", line);
+    }
+
+    Err(format_err!("control socket closed without a reply"))
+}
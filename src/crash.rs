@@ -0,0 +1,137 @@
+//! Writes a report when screenstub panics or its session fails, so bug
+//! reports come with the config and the state things were in.
+
+use std::env::{self, var_os};
+use std::fs;
+use std::io;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_yaml::Value;
+use config::Config;
+
+/// How long to wait on the status, in case whatever crashed is holding it up
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(1);
+
+type Snapshot = Arc<dyn Fn() -> Option<String> + Send + Sync>;
+
+struct CrashContext {
+    config: Option<String>,
+    status: Option<Snapshot>,
+}
+
+static CONTEXT: Mutex<CrashContext> = Mutex::new(CrashContext { config: None, status: None });
+/// Only the first panic gets a report, the rest are usually fallout from it
+static REPORTED: AtomicBool = AtomicBool::new(false);
+
+fn context() -> std::sync::MutexGuard<'static, CrashContext> {
+    CONTEXT.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Hides anything that looks like a credential
+fn redact(value: &mut Value) {
+    match value {
+        Value::Mapping(map) => for (key, value) in map.iter_mut() {
+            let secret = key.as_str()
+                .map(|key| ["password", "secret", "token"].iter().any(|s| key.to_ascii_lowercase().contains(s)))
+                .unwrap_or(false);
+            if secret {
+                *value = Value::String("<redacted>".into());
+            } else {
+                redact(value);
+            }
+        },
+        Value::Sequence(seq) => seq.iter_mut().for_each(redact),
+        _ => (),
+    }
+}
+
+/// Writes reports on panic, after the usual message
+pub fn install() {
+    let default = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default(info);
+        if !REPORTED.swap(true, Ordering::SeqCst) {
+            report(&format!("panic: {}", info));
+        }
+    }));
+}
+
+pub fn set_config(config: &Config) {
+    let config = serde_yaml::to_value(config).and_then(|mut config| {
+        redact(&mut config);
+        serde_yaml::to_string(&config)
+    });
+    context().config = Some(config.unwrap_or_else(|e| format!("unserializable: {}", e)));
+}
+
+/// Includes the output of `status` in reports, which is None once it's gone
+pub fn set_status<F: Fn() -> Option<String> + Send + Sync + 'static>(status: F) {
+    context().status = Some(Arc::new(status));
+}
+
+fn report_dir() -> PathBuf {
+    let state = match var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => var_os("HOME").map(|home| Path::new(&home).join(".local").join("state")),
+    };
+    state.map(|dir| dir.join("screenstub")).unwrap_or_else(env::temp_dir)
+}
+
+fn snapshot(status: Option<Snapshot>) -> String {
+    let status = match status {
+        Some(status) => status,
+        None => return "not running\n".into(),
+    };
+    // the status may need locks held by a panicking thread
+    let (send, recv) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = send.send(status());
+    });
+    match recv.recv_timeout(SNAPSHOT_TIMEOUT) {
+        Ok(Some(status)) => status,
+        Ok(None) => "not running\n".into(),
+        Err(_) => "unavailable\n".into(),
+    }
+}
+
+fn write(reason: &str) -> io::Result<PathBuf> {
+    let (config, status) = {
+        let context = context();
+        (context.config.clone(), context.status.clone())
+    };
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    let mut report = format!("screenstub {} ({} {})\n", env!("CARGO_PKG_VERSION"), env::consts::OS, env::consts::ARCH);
+    report.push_str(&format!("Time: {}\n\n", time.as_secs()));
+    report.push_str(&format!("## Error\n\n{}\n\n", reason));
+    report.push_str(&format!("## Status\n\n{}\n", snapshot(status)));
+    report.push_str(&format!("## Config\n\n{}", config.as_deref().unwrap_or("not loaded\n")));
+
+    let dir = report_dir();
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("crash-{}.txt", time.as_secs()));
+    fs::write(&path, report)?;
+    Ok(path)
+}
+
+/// Writes a report for `reason` and tells the user where it went
+pub fn report(reason: &str) {
+    match write(reason) {
+        Ok(path) => eprintln!("A crash report with the config and recent events was written to {}", path.display()),
+        Err(e) => eprintln!("Failed to write a crash report: {}", e),
+    }
+}
+
+#[test]
+fn redact_secrets() {
+    let mut config: Value = serde_yaml::from_str("qemu: { spice: { address: x, Password: hunter2 } }\nhotkeys: [{ token: a }]").unwrap();
+    redact(&mut config);
+    let config = serde_yaml::to_string(&config).unwrap();
+    assert!(config.contains("address: x"));
+    assert!(!config.contains("hunter2"));
+    assert!(!config.contains("token: a"));
+}
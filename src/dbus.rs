@@ -0,0 +1,104 @@
+use std::sync::Arc;
+use futures::channel::mpsc;
+use futures::SinkExt;
+use tokio::sync::broadcast::error::RecvError;
+use anyhow::Error;
+use zbus::{dbus_interface, fdo, ConnectionBuilder, SignalContext};
+use config::{ConfigEvent, ConfigGrab};
+use screenstub_core::Process;
+use screenstub_core::history::HistoryEntry;
+
+const PATH: &str = "/org/arcnmx/screenstub";
+
+/// `org.arcnmx.screenstub1` on the session bus: methods to switch and grab,
+/// and signals when either changes
+struct Interface {
+    process: Arc<Process>,
+    user_sender: mpsc::Sender<Arc<ConfigEvent>>,
+    grab: ConfigGrab,
+}
+
+impl Interface {
+    async fn send(&self, event: ConfigEvent) -> fdo::Result<()> {
+        self.user_sender.clone().send(Arc::new(event)).await
+            .map_err(|_| fdo::Error::Failed("screenstub is shutting down".into()))
+    }
+}
+
+#[dbus_interface(name = "org.arcnmx.screenstub1")]
+impl Interface {
+    async fn show_guest(&self) -> fdo::Result<()> {
+        self.send(ConfigEvent::ShowGuest).await
+    }
+
+    async fn show_host(&self) -> fdo::Result<()> {
+        self.send(ConfigEvent::ShowHost).await
+    }
+
+    async fn toggle_show(&self) -> fdo::Result<()> {
+        self.send(ConfigEvent::ToggleShow).await
+    }
+
+    async fn toggle_grab(&self) -> fdo::Result<()> {
+        self.send(ConfigEvent::ToggleGrab(self.grab.clone())).await
+    }
+
+    /// The same text as the control socket's `status`
+    fn status(&self) -> String {
+        self.process.status().to_string()
+    }
+
+    #[dbus_interface(property)]
+    fn showing_guest(&self) -> bool {
+        self.process.showing_guest().unwrap_or(false)
+    }
+
+    #[dbus_interface(property)]
+    fn grabbed(&self) -> bool {
+        !self.process.status().grabs.is_empty()
+    }
+
+    #[dbus_interface(signal)]
+    async fn visibility_changed(ctxt: &SignalContext<'_>, showing_guest: bool) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn grab_changed(ctxt: &SignalContext<'_>, grabbed: bool) -> zbus::Result<()>;
+}
+
+/// Takes `name` on the session bus and serves until the connection fails.
+///
+/// `grab` is what `ToggleGrab` toggles.
+pub async fn serve(name: &str, process: Arc<Process>, user_sender: mpsc::Sender<Arc<ConfigEvent>>, grab: ConfigGrab) -> Result<(), Error> {
+    let interface = Interface {
+        process: process.clone(),
+        user_sender,
+        grab,
+    };
+    let connection = ConnectionBuilder::session()?
+        .name(name)?
+        .serve_at(PATH, interface)?
+        .build().await?;
+    let interface = connection.object_server().interface::<_, Interface>(PATH).await?;
+    let ctxt = interface.signal_context();
+
+    let mut transitions = process.history().subscribe();
+    loop {
+        let (showing, grab) = match transitions.recv().await {
+            Ok(HistoryEntry::Showing { .. }) => (true, false),
+            Ok(HistoryEntry::Grab(..)) | Ok(HistoryEntry::Ungrab(..)) => (false, true),
+            Ok(HistoryEntry::Event(..)) => continue,
+            // some were missed, so whatever changed is unknown
+            Err(RecvError::Lagged(..)) => (true, true),
+            Err(RecvError::Closed) => break Ok(()),
+        };
+        let iface = interface.get().await;
+        if showing {
+            iface.showing_guest_changed(ctxt).await?;
+            Interface::visibility_changed(ctxt, iface.showing_guest()).await?;
+        }
+        if grab {
+            iface.grabbed_changed(ctxt).await?;
+            Interface::grab_changed(ctxt, iface.grabbed()).await?;
+        }
+    }
+}
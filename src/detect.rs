@@ -0,0 +1,155 @@
+use std::time::Duration;
+use anyhow::{Error, format_err};
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
+use serde_json::{json, Value};
+use config::{ConfigMonitor, ConfigScreen, ConfigSource, ConfigSourceName};
+use ddc::Backend;
+
+/// How `detect` prints the displays it finds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+    Yaml,
+}
+
+/// What a display reported about itself
+struct Probe {
+    id: String,
+    description: String,
+    manufacturer: Option<String>,
+    model: Option<String>,
+    serial: Option<String>,
+    sources: Vec<u8>,
+    current_source: u8,
+}
+
+fn source_name(value: u8) -> String {
+    ConfigSourceName::from_value(value).map(|i| i.to_string()).unwrap_or("Unknown".into())
+}
+
+impl Probe {
+    fn text(&self) -> String {
+        let mut report = self.description.clone();
+        for &i in &self.sources {
+            report.push_str(&format!("  Source: {} = 0x{:02x}{}\n",
+                source_name(i),
+                i,
+                if i == self.current_source { " (Active)" } else { "" }
+            ));
+        }
+        if !self.sources.contains(&self.current_source) {
+            report.push_str(&format!("  Source: {} = 0x{:02x} (Active, not listed in capabilities)\n",
+                source_name(self.current_source),
+                self.current_source,
+            ));
+        }
+        report
+    }
+
+    fn value(&self) -> Value {
+        let mut sources: Vec<_> = self.sources.iter().map(|&value| json!({
+            "value": value,
+            "name": ConfigSourceName::from_value(value).map(|i| i.to_string()),
+            "active": value == self.current_source,
+        })).collect();
+        if !self.sources.contains(&self.current_source) {
+            sources.push(json!({
+                "value": self.current_source,
+                "name": ConfigSourceName::from_value(self.current_source).map(|i| i.to_string()),
+                "active": true,
+                "unlisted": true,
+            }));
+        }
+        json!({
+            "id": self.id,
+            "manufacturer": self.manufacturer,
+            "model": self.model,
+            "serial": self.serial,
+            "current_source": self.current_source,
+            "sources": sources,
+        })
+    }
+
+    /// A `screens` entry for the display, guessing that the guest is on the
+    /// first input that isn't showing right now
+    fn screen(&self) -> ConfigScreen {
+        let source = |value| match ConfigSourceName::from_value(value) {
+            Some(name) => ConfigSource { value: None, name: Some(name) },
+            None => ConfigSource { value: Some(value), name: None },
+        };
+        ConfigScreen {
+            monitor: ConfigMonitor {
+                manufacturer: self.manufacturer.clone(),
+                model: self.model.clone(),
+                serial: self.serial.clone(),
+                ..Default::default()
+            },
+            host_source: source(self.current_source),
+            guest_source: self.sources.iter().copied()
+                .find(|&value| value != self.current_source)
+                .map(source).unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Probes every display at once, printing each as it finishes so one slow
+/// DDC bus doesn't hold up the rest. Structured formats wait for all of them.
+pub async fn run(backend: Backend, timeout: Duration, format: Format) -> Result<i32, Error> {
+    let monitors = tokio::task::spawn_blocking(move || backend.enumerate()).await??;
+    eprintln!("Probing {} displays with {}", monitors.len(), backend);
+
+    let mut probes = monitors.into_iter().map(|mut m| {
+        let name = m.to_string();
+        let probe = tokio::task::spawn_blocking(move || {
+            let description = m.to_string();
+            Ok::<_, Error>(Probe {
+                id: description.lines().next().unwrap_or_default().trim_start_matches("ID: ").into(),
+                manufacturer: m.manufacturer_id(),
+                model: m.model_name(),
+                serial: m.serial_number(),
+                sources: m.sources()?,
+                current_source: m.get_source()?,
+                description: format!("{}\n", description),
+            })
+        });
+        async move {
+            match tokio::time::timeout(timeout, probe).await {
+                Ok(Ok(res)) => res.map_err(|e| e.context(format!("{} failed", name))),
+                Ok(Err(e)) => Err(Error::from(e).context(format!("{} failed", name))),
+                Err(_) => Err(format_err!("{} timed out after {:?}", name, timeout)),
+            }
+        }
+    }).collect::<FuturesUnordered<_>>();
+
+    let mut found = Vec::new();
+    let mut failures = 0;
+    while let Some(res) = probes.next().await {
+        match res {
+            Ok(probe) if format == Format::Text => print!("{}", probe.text()),
+            Ok(probe) => found.push(probe),
+            Err(e) => {
+                failures += 1;
+                eprintln!("{:#}", e);
+            },
+        }
+    }
+
+    if format != Format::Text {
+        found.sort_by(|a, b| a.id.cmp(&b.id));
+        let screens: Vec<_> = found.iter().map(Probe::screen).collect();
+        let report = json!({
+            "backend": backend.name(),
+            "monitors": found.iter().map(Probe::value).collect::<Vec<_>>(),
+            "screens": screens,
+        });
+        match format {
+            Format::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+            _ => print!("{}", serde_yaml::to_string(&report)?),
+        }
+    }
+
+    Ok(if failures > 0 { 1 } else { 0 })
+}
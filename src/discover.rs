@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fmt;
+use anyhow::{Error, format_err};
+
+const GUEST_AGENT_PORT: &'static str = "org.qemu.guest_agent.0";
+
+/// Socket paths found on the command line of a running QEMU process
+#[derive(Debug, Default, Clone)]
+pub struct Discovered {
+    pub pid: u32,
+    pub name: Option<String>,
+    pub qmp_socket: Option<String>,
+    pub ga_socket: Option<String>,
+}
+
+/// Splits a QEMU option string like `socket,id=foo,server=on` into its parts
+fn options(value: &str) -> impl Iterator<Item=(&str, Option<&str>)> {
+    value.split(',').map(|opt| match opt.find('=') {
+        Some(i) => (&opt[..i], Some(&opt[i + 1..])),
+        None => (opt, None),
+    })
+}
+
+fn option<'a>(value: &'a str, key: &str) -> Option<&'a str> {
+    options(value).find(|&(k, _)| k == key).and_then(|(_, v)| v)
+}
+
+impl Discovered {
+    pub fn from_args<S: AsRef<str>>(pid: u32, args: &[S]) -> Self {
+        let mut res = Discovered {
+            pid,
+            .. Default::default()
+        };
+        let mut chardevs = HashMap::new();
+        let mut monitor = None;
+        let mut agent = None;
+
+        let mut args = args.iter().map(AsRef::as_ref);
+        while let Some(arg) = args.next() {
+            let arg = if arg.starts_with("--") { &arg[1..] } else { arg };
+            match arg {
+                "-name" | "-qmp" | "-chardev" | "-mon" | "-device" => (),
+                _ => continue,
+            }
+            let value = match args.next() {
+                Some(value) => value,
+                None => break,
+            };
+            match arg {
+                "-name" => res.name = option(value, "guest")
+                    .or_else(|| options(value).next().filter(|&(_, v)| v.is_none()).map(|(k, _)| k))
+                    .map(ToOwned::to_owned),
+                "-qmp" => if let Some(value) = value.strip_prefix("unix:") {
+                    res.qmp_socket = options(value).next().map(|(path, _)| path.to_owned());
+                },
+                "-chardev" => if value.starts_with("socket,") {
+                    if let (Some(id), Some(path)) = (option(value, "id"), option(value, "path")) {
+                        chardevs.insert(id.to_owned(), path.to_owned());
+                    }
+                },
+                "-mon" => if option(value, "mode") == Some("control") {
+                    monitor = option(value, "chardev").map(ToOwned::to_owned);
+                },
+                "-device" => if option(value, "name") == Some(GUEST_AGENT_PORT) {
+                    agent = option(value, "chardev").map(ToOwned::to_owned);
+                },
+                _ => (),
+            }
+        }
+
+        if res.qmp_socket.is_none() {
+            res.qmp_socket = monitor.and_then(|id| chardevs.get(&id).cloned());
+        }
+        res.ga_socket = agent.and_then(|id| chardevs.get(&id).cloned());
+
+        res
+    }
+}
+
+impl fmt::Display for Discovered {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} (pid {})", self.name.as_ref().map(|s| &s[..]).unwrap_or("unnamed VM"), self.pid)?;
+        writeln!(f, "  qmp_socket: {}", self.qmp_socket.as_ref().map(|s| &s[..]).unwrap_or("not found"))?;
+        write!(f, "  ga_socket: {}", self.ga_socket.as_ref().map(|s| &s[..]).unwrap_or("not found"))
+    }
+}
+
+/// Inspects the command lines of all running `qemu-system-*` processes.
+///
+/// Sockets that were passed in as file descriptors (as libvirt does) can't be found this way.
+pub fn running() -> Result<Vec<Discovered>, Error> {
+    let mut res = Vec::new();
+    for entry in fs::read_dir("/proc")? {
+        let entry = entry?;
+        let pid = match entry.file_name().to_str().and_then(|pid| pid.parse().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        // processes can disappear or be inaccessible, just skip them
+        let cmdline = match fs::read(entry.path().join("cmdline")) {
+            Ok(cmdline) => cmdline,
+            Err(_) => continue,
+        };
+        let args: Vec<_> = cmdline.split(|&b| b == 0)
+            .map(String::from_utf8_lossy)
+            .collect();
+        let is_qemu = args.first()
+            .and_then(|exe| exe.rsplit('/').next())
+            .map(|exe| exe.starts_with("qemu-system"))
+            .unwrap_or(false);
+        if is_qemu {
+            res.push(Discovered::from_args(pid, &args));
+        }
+    }
+    res.sort_by_key(|d| d.pid);
+
+    Ok(res)
+}
+
+/// Finds the VM with the given name, or the only one running if no name is given
+pub fn find(name: Option<&str>) -> Result<Discovered, Error> {
+    let mut vms = running()?;
+    match name {
+        Some(name) => vms.into_iter().find(|vm| vm.name.as_ref().map(|s| &s[..]) == Some(name))
+            .ok_or_else(|| format_err!("no running QEMU process named {}", name)),
+        None if vms.len() == 1 => Ok(vms.pop().unwrap()),
+        None => Err(format_err!("found {} running QEMU processes, configure qemu.name to pick one", vms.len())),
+    }
+}
+
+#[test]
+fn discover_args() {
+    let vm = Discovered::from_args(1, &[
+        "/usr/bin/qemu-system-x86_64",
+        "-name", "guest=win10,debug-threads=on",
+        "-chardev", "socket,id=mon0,path=/run/vfio/qmp,server=on,wait=off",
+        "-mon", "chardev=mon0,mode=control",
+        "-chardev", "socket,path=/run/vfio/qga,server=on,wait=off,id=qga0",
+        "-device", "virtio-serial",
+        "-device", "virtserialport,chardev=qga0,name=org.qemu.guest_agent.0",
+    ]);
+    assert_eq!(vm.name.as_ref().map(|s| &s[..]), Some("win10"));
+    assert_eq!(vm.qmp_socket.as_ref().map(|s| &s[..]), Some("/run/vfio/qmp"));
+    assert_eq!(vm.ga_socket.as_ref().map(|s| &s[..]), Some("/run/vfio/qga"));
+
+    let vm = Discovered::from_args(2, &["qemu-system-x86_64", "-name", "linux", "-qmp", "unix:/tmp/qmp,server,nowait"]);
+    assert_eq!(vm.name.as_ref().map(|s| &s[..]), Some("linux"));
+    assert_eq!(vm.qmp_socket.as_ref().map(|s| &s[..]), Some("/tmp/qmp"));
+    assert!(vm.ga_socket.is_none());
+}
@@ -1,49 +1,48 @@
 #![recursion_limit = "1024"]
 
-extern crate input_linux as input;
-extern crate screenstub_uinput as uinput;
 extern crate screenstub_config as config;
 extern crate screenstub_event as event;
 extern crate screenstub_qemu as qemu;
 extern crate screenstub_ddc as ddc;
 extern crate screenstub_x as x;
+extern crate screenstub_wayland as wayland;
+extern crate screenstub_uinput as uinput;
 
 use std::process::exit;
 use std::time::Duration;
 use std::path::PathBuf;
-use std::pin::Pin;
 use std::sync::Arc;
 use std::io::{self, Write};
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
 use futures::channel::{mpsc, oneshot};
-use futures::{future, TryFutureExt, FutureExt, StreamExt, SinkExt};
+use tokio::signal::unix::{signal, SignalKind};
+use futures::{future, Sink, Stream, TryFutureExt, FutureExt, StreamExt, SinkExt};
 use anyhow::{Error, format_err};
 use log::{warn, error, info};
 use clap::{Arg, Command, value_parser};
-use input::{InputId, Key, RelativeAxis, AbsoluteAxis, InputEvent, EventKind};
-use config::{Config, ConfigEvent, ConfigSourceName};
-use event::{Hotkey, UserEvent, ProcessedXEvent};
+use config::{Config, ConfigEvent, ConfigMonitor};
+use event::{UserEvent, ProcessedXEvent};
 use qemu::Qemu;
-use route::Route;
-use spawner::Spawner;
-use sources::Sources;
-use process::Process;
-use ddc::{Monitor, DdcMonitor};
-use x::XRequest;
-
-mod route;
-mod grab;
-mod filter;
-mod sources;
-mod exec;
-mod process;
-mod util;
-mod spawner;
-
-type Events = event::Events<Arc<ConfigEvent>>;
-
-const EVENT_BUFFER: usize = 8;
+use screenstub_core::{SessionBuilder, Spawner, LogId};
+use screenstub_core::sources::Sources;
+use ddc::Backend;
+use x::{XEvent, XRequest};
+
+mod tui;
+mod discover;
+mod control;
+mod check;
+mod replay;
+mod detect;
+mod sched;
+mod crash;
+#[cfg(feature = "with-dbus")]
+mod dbus;
 
 fn main() {
+    crash::install();
     let runtime = tokio::runtime::Runtime::new().unwrap();
     let spawner = Arc::new(Spawner::new());
 
@@ -75,7 +74,7 @@ async fn main_result(spawner: &Arc<Spawner>) -> Result<i32, Error> {
             .value_name("CONFIG")
             .num_args(1)
             .value_parser(value_parser!(PathBuf))
-            .help("Configuration TOML file")
+            .help("Configuration file, YAML or TOML (by .toml extension)")
         ).arg(Arg::new("screen")
             .short('s')
             .long("screen")
@@ -83,12 +82,62 @@ async fn main_result(spawner: &Arc<Spawner>) -> Result<i32, Error> {
             .num_args(1)
             .value_parser(value_parser!(usize))
             .help("Configuration screen index")
+        ).arg(Arg::new("profile")
+            .short('p')
+            .long("profile")
+            .value_name("PROFILE")
+            .num_args(1)
+            .help("Apply one of the config's named profiles")
+        ).arg(Arg::new("no-cache")
+            .long("no-cache")
+            .action(clap::ArgAction::SetTrue)
+            .help("Ask the monitor for its input sources instead of using the cache")
         ).subcommand(Command::new("x")
             .about("Start the KVM with a fullscreen X window")
+        ).subcommand(Command::new("wayland")
+            .about("Start the KVM with a fullscreen Wayland window")
+        ).subcommand(Command::new("tui")
+            .about("Start the KVM along with a live status dashboard in the terminal")
+        ).subcommand(Command::new("ctl")
+            .about("Send a command to a running instance through its control socket")
+            .arg(Arg::new("command")
+                .value_name("COMMAND")
+                .num_args(1..)
+                .required(true)
+                .help("e.g. show-guest, show-host, grab, ungrab, exit, status")
+            )
         ).subcommand(Command::new("check-config")
             .about("Read config and exit")
+        ).subcommand(Command::new("check")
+            .about("Validate the config against this machine: exit code 1 for problems, 2 if unreadable")
         ).subcommand(Command::new("detect")
             .about("Detect available DDC/CI displays and their video inputs")
+            .arg(Arg::new("json")
+                .long("json")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("yaml")
+                .help("Print the displays as JSON, along with a screens config to start from")
+            ).arg(Arg::new("yaml")
+                .long("yaml")
+                .action(clap::ArgAction::SetTrue)
+                .help("Print the displays as YAML, along with a screens config to start from")
+            )
+        ).subcommand(Command::new("discover")
+            .about("List running QEMU processes and the sockets found for them")
+        ).subcommand(Command::new("list-devices")
+            .about("List evdev input devices, formatted for an evdev grab's devices list")
+        ).subcommand(Command::new("replay")
+            .about("Replay a capture_input file through the config's hotkeys and remapping")
+            .arg(Arg::new("file")
+                .value_name("FILE")
+                .num_args(1)
+                .required(true)
+                .value_parser(value_parser!(PathBuf))
+            ).arg(Arg::new("realtime")
+                .long("realtime")
+                .action(clap::ArgAction::SetTrue)
+                .help("Wait out the captured timing between events")
+            )
         ).subcommand(Command::new("source")
             .about("Change the configured monitor input source")
             .arg(Arg::new("confirm")
@@ -105,123 +154,115 @@ async fn main_result(spawner: &Arc<Spawner>) -> Result<i32, Error> {
         ).subcommand_required(true);
 
     let matches = app.get_matches();
-    let config = if let Some(config) = matches.get_one::<PathBuf>("config") {
-        use std::fs::File;
-
-        let f = File::open(config)?;
-        serde_yaml::from_reader(f)?
+    let profile = matches.get_one::<String>("profile").cloned();
+    match matches.subcommand() {
+        Some(("check", ..)) =>
+            return Ok(check::run(matches.get_one::<PathBuf>("config"), profile.as_ref().map(|s| &s[..]))),
+        Some(("list-devices", ..)) =>
+            return list_devices(),
+        _ => (),
+    }
+    let mut config: Config = if let Some(config) = matches.get_one::<PathBuf>("config") {
+        Config::load_profile(config, profile.as_ref().map(|s| &s[..]))?
+    } else if let Some(ref profile) = profile {
+        return Err(format_err!("profile {} requires a config file", profile))
     } else {
         Config::default()
     };
 
+    if config.qemu.name.is_some() && (config.qemu.qmp_socket.is_none() || config.qemu.ga_socket.is_none()) {
+        match discover::find(config.qemu.name.as_ref().map(|s| &s[..])) {
+            Ok(vm) => {
+                info!("Discovered QEMU sockets for pid {}", vm.pid);
+                if config.qemu.qmp_socket.is_none() {
+                    config.qemu.qmp_socket = vm.qmp_socket;
+                }
+                if config.qemu.ga_socket.is_none() {
+                    config.qemu.ga_socket = vm.ga_socket;
+                }
+            },
+            Err(e) => warn!("QEMU socket discovery failed: {}", e),
+        }
+    }
+
+    crash::set_config(&config);
+
+    if let Some(("replay", matches)) = matches.subcommand() {
+        let path = matches.get_one::<PathBuf>("file").unwrap();
+        return replay::run(path, &config, matches.get_flag("realtime")).await
+    }
+
     let screen_index = matches.get_one("screen").unwrap_or(&0usize);
-    let screen = config.screens.into_iter().nth(*screen_index)
+    let mut screen = mem::take(&mut config.screens).into_iter().nth(*screen_index)
         .ok_or_else(|| format_err!("expected a screen config"))?;
+    let log_id = LogId::new(*screen_index, config.qemu.name.as_ref().map(|s| &s[..]));
 
     match matches.subcommand() {
-        Some(("x", ..)) => {
-            let xinstance = screen.x_instance.unwrap_or("auto".into());
-
-            let (mut x_sender, mut x_receiver) = mpsc::channel(0x20);
-            let (mut xreq_sender, mut xreq_receiver) = mpsc::channel(0x08);
-            let x = x::XContext::xmain("screenstub", &xinstance, "screenstub")?;
-            let xmain = tokio::spawn(async move {
-                let mut x = x.fuse();
-                loop {
-                    futures::select! {
-                        req = xreq_receiver.next() => if let Some(req) = req {
-                            let _ = x.send(req).await;
-                        },
-                        event = x.next() => match event {
-                            Some(Ok(event)) => {
-                                let _ = x_sender.send(event).await;
-                            },
-                            Some(Err(e)) => {
-                                error!("X Error: {}: {:?}", e, e);
-                                break
-                            },
-                            None => {
-                                break
-                            },
-                        },
-                        complete => break,
+        Some(("x", ..)) | Some(("wayland", ..)) | Some(("tui", ..)) => {
+            if let Some(ref scheduling) = config.scheduling {
+                sched::apply(scheduling);
+            }
+            let tui = matches.subcommand_name() == Some("tui");
+            let tui_grabs = tui::grabs(&config.hotkeys);
+            let xinstance = screen.x_instance.clone().unwrap_or("auto".into());
+
+            let (x_sender, mut x_receiver) = mpsc::channel(config.channels.window);
+            let (mut xreq_sender, xreq_receiver) = mpsc::channel(config.channels.window_requests);
+            let mut xwayland = false;
+            let mut keymap_info = None;
+            let xmain = if matches.subcommand_name() == Some("wayland") {
+                let mut wayland = wayland::WaylandContext::connect("screenstub", &xinstance)?;
+                wayland.set_absolute_max(config.qemu.absolute.maximum);
+                wayland.set_relative_resolution(config.qemu.relative.resolution);
+                spawn_window(wayland, xreq_receiver, x_sender)
+            } else {
+                let mut x = x::XContext::xmain("screenstub", &xinstance, "screenstub", screen.monitor.xrandr_name.as_deref())?;
+                x.set_absolute_max(config.qemu.absolute.maximum);
+                if let Some(edid) = x.output().and_then(|output| output.edid.as_ref()) {
+                    monitor_from_edid(&mut screen.monitor, edid);
+                }
+                xwayland = config.xwayland.compat.unwrap_or(x.is_xwayland());
+                if xwayland {
+                    warn!("Running under Xwayland, the guest pointer will follow the host cursor");
+                    if config.xwayland.grab.is_none() {
+                        warn!("X grabs can't keep input from the Wayland compositor, consider an evdev grab for xwayland.grab or the wayland subcommand");
                     }
+                    x.set_xwayland_compat(true);
                 }
-            }).map_err(From::from);
-
-            let (keyboard_driver, relative_driver, absolute_driver) =
-                (config.qemu.keyboard_driver().clone(), config.qemu.relative_driver().clone(), config.qemu.absolute_driver().clone());
-
-            let mut events = event::Events::new();
-            config.hotkeys.into_iter()
-                .map(convert_hotkey)
-                .for_each(|(hotkey, on_press)| events.add_hotkey(hotkey, on_press));
-            config.key_remap.into_iter().for_each(|(from, to)| events.add_remap(from, to));
-
-            let events = Arc::new(events);
-
-            let qemu = Arc::new(Qemu::new(config.qemu.qmp_socket, config.qemu.ga_socket));
-
-            let ddc = screen.ddc.unwrap_or_default();
-            let mut sources = Sources::new(qemu.clone(), screen.monitor, screen.host_source, screen.guest_source, ddc.host, ddc.guest, ddc.minimal_delay);
-            sources.fill().await?;
-
-            let (mut event_sender, mut event_recv) = mpsc::channel(EVENT_BUFFER);
-            let (error_sender, mut error_recv) = mpsc::channel(1);
-
-            let process = Process::new(
-                config.qemu.routing, keyboard_driver.clone(), relative_driver.clone(), absolute_driver.clone(), config.exit_events,
-                qemu.clone(), events.clone(), sources, xreq_sender.clone(), event_sender.clone(), error_sender.clone(),
-                spawner.clone(),
-            );
-
-            process.devices_init().await?;
-
-            let uinput_id = InputId {
-                bustype: input::sys::BUS_VIRTUAL,
-                vendor: 0x16c0,
-                product: 0x05df,
-                version: 1,
-            };
-
-            let repeat = false;
-            let mut route_keyboard = Route::new(config.qemu.routing, qemu.clone(), "screenstub-route-kbd".into(), keyboard_driver.bus().cloned(), repeat);
-            if let Some(builder) = route_keyboard.builder() {
-                builder
-                    .name("screenstub-kbd")
-                    .x_config_key(repeat)
-                    .id(&uinput_id);
+                keymap_info = Some(x.keymap_info_handle());
+                spawn_window(x, xreq_receiver, x_sender)
+            }.map_err(Error::from);
+
+            let control_socket = config.control_socket.take().map(PathBuf::from);
+            let dbus_name = config.dbus_name.take();
+            let mut session = SessionBuilder::new(config, screen)
+                .log_id(log_id)
+                .spawner(spawner.clone())
+                .x_requests(xreq_sender.clone())
+                .xwayland(xwayland)
+                .source_cache(!matches.get_flag("no-cache"));
+            if let Some(path) = matches.get_one::<PathBuf>("config") {
+                session = session.config_path(path.clone(), *screen_index);
             }
-            let mut events_keyboard = route_keyboard.spawn(spawner, error_sender.clone());
-
-            let mut route_relative = Route::new(config.qemu.routing, qemu.clone(), "screenstub-route-mouse".into(), relative_driver.bus().cloned(), repeat);
-            if let Some(builder) = route_relative.builder() {
-                builder
-                    .name("screenstub-mouse")
-                    .x_config_rel()
-                    .id(&uinput_id);
+            if let Some(profile) = profile {
+                session = session.profile(profile);
             }
-            let mut events_relative = route_relative.spawn(spawner, error_sender.clone());
-
-            let mut route_absolute = Route::new(config.qemu.routing, qemu.clone(), "screenstub-route-tablet".into(), absolute_driver.bus().cloned(), repeat);
-            if let Some(builder) = route_absolute.builder() {
-                builder
-                    .name("screenstub-tablet")
-                    .x_config_abs()
-                    .id(&uinput_id);
+            if let Some(info) = keymap_info {
+                session = session.keymap_info(info);
             }
-            let mut events_absolute = route_absolute.spawn(spawner, error_sender.clone());
-
+            let mut session = session.build().await?;
+            let process = session.process().clone();
+            crash::set_status({
+                let process = Arc::downgrade(&process);
+                move || process.upgrade().map(|process| {
+                    let history: Vec<_> = process.history().to_vec().iter().map(ToString::to_string).collect();
+                    format!("{}\nHistory:\n{}\n", process.status(), history.join("\n"))
+                })
+            });
+            let events = session.events().clone();
             let x_filter = process.x_filter();
-
-            let process = Arc::new(process);
-
-            let (mut user_sender, user_receiver) = mpsc::channel::<Arc<ConfigEvent>>(0x08);
-            let mut user_receiver = user_receiver
-                .map({
-                    let process = process.clone();
-                    move |event| process.process_user_event(&event)
-                });
+            let mut event_sender = session.input_sender();
+            let mut user_sender = session.user_sender();
 
             let sigint_handler = ctrlc::set_handler({
                 let mut user_sender = user_sender.clone();
@@ -245,56 +286,79 @@ async fn main_result(spawner: &Arc<Spawner>) -> Result<i32, Error> {
                 Err(e) => warn!("Failed to set up SIGINT handler: {}", e),
             }
 
+            let history_dump = tokio::spawn({
+                let history = process.history();
+                async move {
+                    let mut signal = match signal(SignalKind::user_defined1()) {
+                        Ok(signal) => signal,
+                        Err(e) => {
+                            warn!("Failed to set up SIGUSR1 handler: {}", e);
+                            return
+                        },
+                    };
+                    while let Some(()) = signal.recv().await {
+                        let mut stderr = io::stderr();
+                        for record in history.to_vec() {
+                            let _ = writeln!(stderr, "{}", record);
+                        }
+                    }
+                }
+            });
 
-            let (event_loop, event_loop_abort) = future::abortable({
-                let events = events.clone();
-                let process = process.clone();
+            let reload = tokio::spawn({
                 let mut user_sender = user_sender.clone();
                 async move {
-                    while let Some(event) = event_recv.next().await {
-                        let user_events = events.process_input_event(&event);
-                        let inputevent = events.map_input_event(event);
-                        let user_sender = &mut user_sender;
-                        let f1 = async move {
-                            for e in user_events {
-                                let _ = user_sender.send(e.clone()).await;
-                            }
-                        };
-                        let is_mouse = process.is_mouse();
-
-                        let events_keyboard = &mut events_keyboard;
-                        let events_relative = &mut events_relative;
-                        let events_absolute = &mut events_absolute;
-                        let f2 = async move {
-                            match map_event_kind(&inputevent, is_mouse) {
-                                EventKind::Key => {
-                                    let _ = events_keyboard.send(inputevent).await;
-                                },
-                                EventKind::Relative => {
-                                    let _ = events_relative.send(inputevent).await;
-                                },
-                                EventKind::Absolute => {
-                                    let _ = events_absolute.send(inputevent).await;
-                                },
-                                EventKind::Synchronize => {
-                                    let _ = future::try_join3(
-                                        events_keyboard.send(inputevent),
-                                        events_relative.send(inputevent),
-                                        events_absolute.send(inputevent)
-                                    ).await;
-                                },
-                                _ => (),
-                            }
-                        };
-                        let _ = future::join(f1, f2).await;
+                    let mut signal = match signal(SignalKind::hangup()) {
+                        Ok(signal) => signal,
+                        Err(e) => {
+                            warn!("Failed to set up SIGHUP handler: {}", e);
+                            return
+                        },
+                    };
+                    while let Some(()) = signal.recv().await {
+                        let _ = user_sender.send(Arc::new(ConfigEvent::ReloadConfig)).await;
                     }
                 }
             });
-            let event_loop = tokio::spawn(event_loop.map(drop))
-                .map_err(Error::from);
+
+            let control = control_socket.clone().map(|path| {
+                let control = Arc::new(control::Control::new(process.clone(), session.user_sender(), matches.get_one::<PathBuf>("config").cloned()));
+                tokio::spawn(async move {
+                    if let Err(e) = control.serve(&path).await {
+                        error!("Control socket failed: {} {:?}", e, e);
+                    }
+                })
+            });
+
+            let dbus = dbus_name.map(|name| {
+                let grab = tui_grabs.first().cloned().unwrap_or_default();
+                let (process, user_sender) = (process.clone(), user_sender.clone());
+                tokio::spawn(async move {
+                    #[cfg(feature = "with-dbus")]
+                    let res = dbus::serve(&name, process, user_sender, grab).await;
+                    #[cfg(not(feature = "with-dbus"))]
+                    let res: Result<(), Error> = {
+                        let _ = (process, user_sender, grab);
+                        Err(format_err!("screenstub was built without D-Bus support, for {}", name))
+                    };
+                    if let Err(e) = res {
+                        error!("D-Bus service failed: {} {:?}", e, e);
+                    }
+                })
+            });
+
+            let tui = if tui {
+                let tui = tui::run(process.clone(), user_sender.clone(), tui_grabs);
+                Some(tokio::spawn(async move {
+                    if let Err(e) = tui.await {
+                        error!("Dashboard failed: {} {:?}", e, e);
+                    }
+                }))
+            } else {
+                None
+            };
 
             let (xevent_exit_send, xevent_exit_recv) = oneshot::channel();
-            let mut xevent_exit_recv = xevent_exit_recv.fuse();
             let xevent_loop = tokio::spawn({
                 async move {
                     while let Some(xevent) = x_receiver.next().await {
@@ -315,65 +379,90 @@ async fn main_result(spawner: &Arc<Spawner>) -> Result<i32, Error> {
                 }
             }).map_err(From::from);
 
-            let res = loop {
-                futures::select! {
-                    _ = xevent_exit_recv => break Ok(()),
-                    error = error_recv.next() => if let Some(error) = error {
-                        break Err(error)
-                    },
-                    event = user_receiver.next() => if let Some(event) = event {
-                        tokio::spawn(async move {
-                            match Pin::from(event).await {
-                                Err(e) =>
-                                    warn!("User event failed {} {:?}", e, e),
-                                Ok(()) => (),
-                            }
-                        });
-                    },
-                }
-            };
+            let res = session.run(xevent_exit_recv.map(drop)).await;
+            if let Err(ref e) = res {
+                crash::report(&format!("{:?}", e));
+            }
+
+            history_dump.abort();
+            reload.abort();
+            if let Some(control) = control {
+                control.abort();
+            }
+            if let Some(dbus) = dbus {
+                dbus.abort();
+            }
+            if let Some(path) = control_socket {
+                let _ = std::fs::remove_file(path);
+            }
+
+            if let Some(tui) = tui {
+                // restores the terminal
+                tui.abort();
+                let _ = tui.await;
+            }
+
+            drop(process);
+            let shutdown = session.shutdown().await;
 
             let _ = xreq_sender.send(XRequest::Quit).await; // ensure we kill x
             xreq_sender.close_channel();
             drop(xreq_sender);
-            drop(process);
 
-            // seal off senders
-            event_loop_abort.abort();
-            future::try_join3(
-                event_loop,
+            future::try_join(
                 xevent_loop,
                 xmain,
             ).await?;
+            shutdown?;
 
             res.map(|()| 0)
         },
+        Some(("ctl", ctl)) => {
+            let path = config.control_socket.as_ref().map(PathBuf::from)
+                .ok_or_else(|| format_err!("control_socket is not configured"))?;
+            let command = ctl.get_many::<String>("command").unwrap()
+                .map(|s| &s[..]).collect::<Vec<_>>().join(" ");
+            control::send(&path, &command).await
+                .map(|ok| if ok { 0 } else { 1 })
+        },
         Some(("check-config", ..)) => {
             Ok(0)
         },
-        Some(("detect", ..)) => {
-            Monitor::enumerate()?.into_iter().try_for_each(|mut m| {
-                let sources = m.sources()?;
-                let current_source = m.get_source()?;
-                println!("{}", m);
-                sources.into_iter().for_each(|i|
-                    println!("  Source: {} = 0x{:02x}{}",
-                        ConfigSourceName::from_value(i).map(|i| i.to_string()).unwrap_or("Unknown".into()),
-                        i,
-                        if i == current_source { " (Active)" } else { "" }
-                    )
-                );
-
-                Ok::<_, Error>(())
-            })?;
+        Some(("detect", matches)) => {
+            let ddc = screen.ddc.unwrap_or_default();
+            // probe with the same backend the screen switches with
+            let backend = ddc.host.iter().filter_map(screenstub_core::sources::ddc_backend).next()
+                .or_else(Backend::preferred)
+                .unwrap_or(Backend::DdcutilCli);
+            let format = if matches.get_flag("json") {
+                detect::Format::Json
+            } else if matches.get_flag("yaml") {
+                detect::Format::Yaml
+            } else {
+                detect::Format::Text
+            };
+            detect::run(backend, ddc.timeout, format).await
+        },
+        Some(("discover", ..)) => {
+            let vms = discover::running()?;
+            if vms.is_empty() {
+                println!("No running QEMU processes found");
+            }
+            for vm in vms {
+                let selected = config.qemu.name.is_some() && vm.name == config.qemu.name;
+                println!("{}{}", vm, if selected { "\n  (matches configured qemu.name)" } else { "" });
+            }
 
             Ok(0)
         },
         Some(("source", matches)) => {
             let ddc = screen.ddc.unwrap_or_default();
+            if ddc.is_none() {
+                return Err(format_err!("screen has no monitor to switch (ddc: none)"))
+            }
 
             let qemu = Arc::new(Qemu::new(config.qemu.qmp_socket, config.qemu.ga_socket));
-            let sources = Sources::new(qemu, screen.monitor, screen.host_source, screen.guest_source, ddc.host, ddc.guest, ddc.minimal_delay);
+            let sources = Sources::new(qemu, screen.monitor, screen.host_source, screen.guest_source, screen.sources, ddc.host, ddc.guest, ddc.minimal_delay, ddc.timeout, config.quirks.clone(), log_id.clone());
 
             match matches.get_one::<String>("source").map(|s| &s[..]) {
                 Some("host") => sources.show(true, true).await,
@@ -385,20 +474,112 @@ async fn main_result(spawner: &Arc<Spawner>) -> Result<i32, Error> {
     }
 }
 
-fn axis_is_relative(axis: RelativeAxis) -> bool {
-    match axis {
-        RelativeAxis::X | RelativeAxis::Y => true,
-        _ => false,
+/// Stable symlinks in /dev/input/by-id and by-path, by the device they point to
+fn input_links() -> HashMap<PathBuf, Vec<PathBuf>> {
+    let mut links: HashMap<_, Vec<_>> = HashMap::new();
+    for dir in &["/dev/input/by-id", "/dev/input/by-path"] {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            if let Ok(target) = fs::canonicalize(entry.path()) {
+                links.entry(target).or_default().push(entry.path());
+            }
+        }
+    }
+    links
+}
+
+fn list_devices() -> Result<i32, Error> {
+    let links = input_links();
+    let mut paths: Vec<_> = fs::read_dir("/dev/input")?
+        .filter_map(|e| e.ok()).map(|e| e.path())
+        .filter(|p| p.file_name().and_then(|n| n.to_str()).map(|n| n.starts_with("event")).unwrap_or(false))
+        .collect();
+    paths.sort_by_key(|p| p.file_name().and_then(|n| n.to_str())
+        .and_then(|n| n["event".len()..].parse::<u32>().ok())
+    );
+
+    for path in paths {
+        let mut aliases = links.get(&path).cloned().unwrap_or_default();
+        // by-id names survive replugging, so prefer them
+        aliases.sort_by_key(|p| !p.starts_with("/dev/input/by-id"));
+        let preferred = aliases.first().unwrap_or(&path);
+
+        let evdev = match uinput::Evdev::open(&path) {
+            Ok(evdev) => evdev,
+            Err(e) => {
+                println!("#- {} # {}", preferred.display(), e);
+                continue
+            },
+        };
+        let evdev = evdev.evdev();
+        let name = evdev.device_name().map(|n| String::from_utf8_lossy(&n).into_owned()).unwrap_or_default();
+        let id = evdev.device_id()?;
+        let keys = evdev.key_bits()?;
+        let (buttons, keys) = keys.iter().partition::<Vec<_>, _>(|k| k.is_button());
+        let relative: Vec<_> = evdev.relative_bits()?.iter().map(|a| format!("{:?}", a)).collect();
+        let absolute: Vec<_> = evdev.absolute_bits()?.iter().map(|a| format!("{:?}", a)).collect();
+
+        println!("- {} # {:?}", preferred.display(), name.trim_end_matches('\0'));
+        println!("  #   id: bus 0x{:04x} vendor 0x{:04x} product 0x{:04x} version 0x{:04x}", id.bustype, id.vendor, id.product, id.version);
+        println!("  #   {} keys, {} buttons, relative [{}], absolute [{}]",
+            keys.len(), buttons.len(), relative.join(", "), absolute.join(", ")
+        );
+        for alias in aliases.iter().skip(1).chain(Some(&path).filter(|&p| p != preferred)) {
+            println!("  #   also {}", alias.display());
+        }
     }
+
+    Ok(0)
 }
 
-fn axis_is_absolute(axis: AbsoluteAxis) -> bool {
-    match axis {
-        AbsoluteAxis::X | AbsoluteAxis::Y => true,
-        _ => false,
+/// Identifies an otherwise unspecified DDC monitor by the EDID of its RandR output
+fn monitor_from_edid(monitor: &mut ConfigMonitor, edid: &[u8]) {
+    let unspecified = monitor.id.is_none() && monitor.manufacturer.is_none() && monitor.model.is_none() &&
+        monitor.serial.is_none() && monitor.connector.is_none() && monitor.i2c_bus.is_none() && monitor.dev_path.is_none();
+    if !unspecified {
+        return
+    }
+    if let Some(edid) = ddc::edid::Edid::parse(edid) {
+        info!("Using monitor {} {} from RandR output {}", edid.manufacturer_id, edid.model_name.as_deref().unwrap_or_default(),
+            monitor.xrandr_name.as_deref().unwrap_or_default());
+        monitor.serial = edid.serial_number;
+        monitor.model = edid.model_name;
+        monitor.manufacturer = Some(edid.manufacturer_id);
     }
 }
 
+/// Forwards requests to a window and its events back out until either side closes
+fn spawn_window<W>(window: W, mut xreq_receiver: mpsc::Receiver<XRequest>, mut x_sender: mpsc::Sender<XEvent>) -> tokio::task::JoinHandle<()> where
+    W: Stream<Item=Result<XEvent, Error>> + Sink<XRequest, Error=Error> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut window = Box::pin(window.fuse());
+        loop {
+            futures::select! {
+                req = xreq_receiver.next() => if let Some(req) = req {
+                    let _ = window.send(req).await;
+                },
+                event = window.next() => match event {
+                    Some(Ok(event)) => {
+                        let _ = x_sender.send(event).await;
+                    },
+                    Some(Err(e)) => {
+                        error!("Window Error: {}: {:?}", e, e);
+                        break
+                    },
+                    None => {
+                        break
+                    },
+                },
+                complete => break,
+            }
+        }
+    })
+}
+
 fn convert_user_event(event: UserEvent) -> Arc<ConfigEvent> {
     Arc::new(match event {
         UserEvent::Quit => ConfigEvent::Exit,
@@ -408,41 +589,3 @@ fn convert_user_event(event: UserEvent) -> Arc<ConfigEvent> {
         UserEvent::UnstickHost => ConfigEvent::UnstickHost,
     })
 }
-
-fn convert_hotkey(hotkey: config::ConfigHotkey) -> (Hotkey<Arc<ConfigEvent>>, bool) {
-    (
-        Hotkey::new(hotkey.triggers, hotkey.modifiers, hotkey.events.into_iter().map(Arc::new)),
-        !hotkey.on_release,
-    )
-}
-
-fn map_event_kind(inputevent: &InputEvent, is_mouse: bool) -> EventKind {
-    match inputevent.kind {
-        EventKind::Key if Key::from_code(inputevent.code).map(|k| k.is_button()).unwrap_or(false) =>
-            if is_mouse {
-                EventKind::Relative
-            } else {
-                EventKind::Absolute
-            },
-        EventKind::Key =>
-            EventKind::Key,
-        EventKind::Absolute if inputevent.code == AbsoluteAxis::Volume as u16 =>
-            EventKind::Key, // is this right?
-        EventKind::Relative if RelativeAxis::from_code(inputevent.code).map(|a| axis_is_relative(a)).unwrap_or(false) =>
-            EventKind::Relative,
-        EventKind::Absolute if AbsoluteAxis::from_code(inputevent.code).map(|a| axis_is_absolute(a)).unwrap_or(false) =>
-            EventKind::Absolute,
-        EventKind::Relative | EventKind::Absolute =>
-            if is_mouse {
-                EventKind::Relative
-            } else {
-                EventKind::Absolute
-            },
-        EventKind::Synchronize =>
-            EventKind::Synchronize,
-        kind => {
-            warn!("unforwarded event {:?}", kind);
-            kind
-        },
-    }
-}
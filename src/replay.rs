@@ -0,0 +1,60 @@
+use std::path::Path;
+use std::time::Duration;
+use tokio::time::sleep;
+use anyhow::Error;
+use input_linux::InputEvent;
+use config::Config;
+use event::Destination;
+use screenstub_core::capture::{self, CaptureStage};
+use screenstub_core::session::events_from_config;
+
+/// Feeds the host input of a capture back through the config's hotkeys and
+/// remapping, printing what comes out. Returns 1 if the guest input no
+/// longer matches what was captured.
+pub async fn run(path: &Path, config: &Config, realtime: bool) -> Result<i32, Error> {
+    let records = capture::read(path)?;
+    let events = events_from_config(config);
+
+    let recorded: Vec<InputEvent> = records.iter()
+        .filter(|r| r.stage == CaptureStage::Guest)
+        .map(|r| r.event)
+        .collect();
+    let mut replayed = Vec::with_capacity(recorded.len());
+
+    let mut elapsed = Duration::default();
+    for record in records.iter().filter(|r| r.stage == CaptureStage::Host) {
+        if realtime && record.time > elapsed {
+            sleep(record.time - elapsed).await;
+        }
+        elapsed = record.time;
+
+        let secs = record.time.as_secs_f64();
+        println!("{:10.6} host  {:?}", secs, record.event);
+        for e in events.process_input_event(&record.event) {
+            println!("{:10.6} event {:?}", secs, e);
+        }
+        let e = events.map_input_event(record.event, Destination::Guest);
+        println!("{:10.6} guest {:?}", secs, e);
+        replayed.push(e);
+    }
+
+    if recorded.is_empty() {
+        println!("{} host events replayed, no guest events were captured to compare", replayed.len());
+        return Ok(0)
+    }
+
+    match replayed.iter().zip(&recorded).position(|(replayed, recorded)| replayed != recorded) {
+        Some(i) => {
+            println!("guest event {} differs: replayed {:?}, captured {:?}", i, replayed[i], recorded[i]);
+            Ok(1)
+        },
+        None if replayed.len() != recorded.len() => {
+            println!("replayed {} guest events, captured {}", replayed.len(), recorded.len());
+            Ok(1)
+        },
+        None => {
+            println!("{} guest events match the capture", replayed.len());
+            Ok(0)
+        },
+    }
+}
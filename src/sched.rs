@@ -0,0 +1,126 @@
+//! Scheduling tweaks that keep input responsive while the host is busy
+
+use std::{fs, io, mem};
+use log::{info, warn};
+use config::ConfigScheduling;
+
+const CAP_IPC_LOCK: u32 = 14;
+const CAP_SYS_NICE: u32 = 23;
+
+/// Whether the `CapEff` line of a `/proc/<pid>/status` includes `cap`
+fn has_capability(status: &str, cap: u32) -> bool {
+    status.lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .map(|caps| caps & (1 << cap) != 0)
+        .unwrap_or(false)
+}
+
+fn capability(cap: u32) -> bool {
+    fs::read_to_string("/proc/self/status")
+        .map(|status| has_capability(&status, cap))
+        .unwrap_or(false)
+}
+
+fn rtprio_limit() -> u64 {
+    let mut limit: libc::rlimit = unsafe { mem::zeroed() };
+    match unsafe { libc::getrlimit(libc::RLIMIT_RTPRIO, &mut limit) } {
+        0 => limit.rlim_cur as u64,
+        _ => 0,
+    }
+}
+
+/// Whether SCHED_FIFO at `priority` should be allowed
+pub fn realtime_allowed(priority: i32) -> bool {
+    capability(CAP_SYS_NICE) || rtprio_limit() >= priority.max(0) as u64
+}
+
+fn threads() -> io::Result<Vec<libc::pid_t>> {
+    fs::read_dir("/proc/self/task")?
+        .map(|entry| entry.map(|entry| entry.file_name().to_str().and_then(|tid| tid.parse().ok())))
+        .filter_map(|tid| tid.transpose())
+        .collect()
+}
+
+fn set_realtime(tid: libc::pid_t, priority: i32) -> io::Result<()> {
+    let mut param: libc::sched_param = unsafe { mem::zeroed() };
+    param.sched_priority = priority;
+    match unsafe { libc::sched_setscheduler(tid, libc::SCHED_FIFO, &param) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn set_nice(tid: libc::pid_t, nice: i32) -> io::Result<()> {
+    match unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, nice) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Applies `config` to every thread started so far, which covers the
+/// runtime's workers that read evdev devices and write to uinput and QMP.
+///
+/// Anything that isn't allowed is only a warning, falling back from realtime
+/// to `nice` and from there to the defaults.
+pub fn apply(config: &ConfigScheduling) {
+    if config.lock_memory && unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+        let e = io::Error::last_os_error();
+        if capability(CAP_IPC_LOCK) {
+            warn!("Failed to lock memory: {}", e);
+        } else {
+            warn!("Failed to lock memory, which needs CAP_IPC_LOCK or a larger RLIMIT_MEMLOCK: {}", e);
+        }
+    }
+
+    let threads = match threads() {
+        Ok(threads) => threads,
+        Err(e) => {
+            warn!("Failed to list threads for scheduling: {}", e);
+            return
+        },
+    };
+
+    let mut realtime = config.realtime_priority.map(|priority| {
+        let (min, max) = unsafe { (libc::sched_get_priority_min(libc::SCHED_FIFO), libc::sched_get_priority_max(libc::SCHED_FIFO)) };
+        priority.max(min).min(max)
+    });
+    if let Some(priority) = realtime {
+        if !realtime_allowed(priority) {
+            warn!("Realtime priority {} needs CAP_SYS_NICE or an RLIMIT_RTPRIO of at least that much", priority);
+            realtime = None;
+        }
+    }
+    let mut nice = config.nice;
+    for tid in threads {
+        if let Some(priority) = realtime {
+            match set_realtime(tid, priority) {
+                Ok(()) => continue,
+                Err(e) => {
+                    warn!("Failed to use realtime scheduling: {}", e);
+                    realtime = None;
+                },
+            }
+        }
+        if let Some(value) = nice {
+            if let Err(e) = set_nice(tid, value) {
+                warn!("Failed to set niceness {}: {}", value, e);
+                nice = None;
+            }
+        }
+    }
+
+    match (realtime, nice) {
+        (Some(priority), _) => info!("Input threads running with SCHED_FIFO priority {}", priority),
+        (None, Some(nice)) => info!("Input threads running with niceness {}", nice),
+        (None, None) => (),
+    }
+}
+
+#[test]
+fn capabilities() {
+    let status = "Name:\tscreenstub\nCapInh:\t0000000000000000\nCapEff:\t0000000000800000\n";
+    assert!(has_capability(status, CAP_SYS_NICE));
+    assert!(!has_capability(status, CAP_IPC_LOCK));
+    assert!(!has_capability("Name:\tscreenstub\n", CAP_SYS_NICE));
+}
@@ -0,0 +1,141 @@
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::{mem, thread};
+use futures::channel::mpsc;
+use futures::{FutureExt, SinkExt, StreamExt};
+use tokio::time::{Duration, interval};
+use anyhow::Error;
+use config::{ConfigEvent, ConfigGrab, ConfigGrabMode, ConfigHotkey};
+use screenstub_core::Process;
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Puts the controlling terminal into unbuffered mode on an alternate screen,
+/// restoring it when dropped. Signals are left enabled so ^C still exits.
+struct RawTerminal {
+    termios: libc::termios,
+}
+
+impl RawTerminal {
+    fn new() -> io::Result<Self> {
+        let fd = io::stdin().as_raw_fd();
+        let termios = unsafe {
+            let mut termios = mem::zeroed();
+            if libc::tcgetattr(fd, &mut termios) != 0 {
+                return Err(io::Error::last_os_error())
+            }
+            termios
+        };
+
+        let mut raw = termios;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error())
+        }
+
+        let mut stdout = io::stdout();
+        write!(stdout, "\x1b[?1049h\x1b[?25l")?;
+        stdout.flush()?;
+
+        Ok(Self {
+            termios,
+        })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(io::stdin().as_raw_fd(), libc::TCSANOW, &self.termios);
+        }
+        let mut stdout = io::stdout();
+        let _ = write!(stdout, "\x1b[?25h\x1b[?1049l");
+        let _ = stdout.flush();
+    }
+}
+
+/// The grabs that the dashboard can toggle, taken from the configured hotkeys
+pub fn grabs(hotkeys: &[ConfigHotkey]) -> Vec<ConfigGrab> {
+    let mut grabs: Vec<ConfigGrab> = Vec::new();
+    for event in hotkeys.iter().flat_map(|h| h.events.iter()) {
+        match event {
+            ConfigEvent::ToggleGrab(grab) | ConfigEvent::Grab(grab) if !grabs.iter().any(|g| g.mode() == grab.mode()) =>
+                grabs.push(grab.clone()),
+            _ => (),
+        }
+    }
+    grabs
+}
+
+fn grab_mode(grabs: &[ConfigGrab], mode: ConfigGrabMode) -> Option<&ConfigGrab> {
+    grabs.iter().find(|g| g.mode() == mode)
+}
+
+fn key_event(key: u8, grabs: &[ConfigGrab]) -> Option<ConfigEvent> {
+    Some(match key {
+        b'g' => ConfigEvent::ShowGuest,
+        b'h' => ConfigEvent::ShowHost,
+        b' ' => ConfigEvent::ToggleShow,
+        b'x' => ConfigEvent::ToggleGrab(grab_mode(grabs, ConfigGrabMode::X).cloned()
+            .unwrap_or_default()
+        ),
+        b'e' => ConfigEvent::ToggleGrab(grab_mode(grabs, ConfigGrabMode::Evdev)?.clone()),
+        b'u' => ConfigEvent::UnstickGuest,
+        b'q' => ConfigEvent::Exit,
+        _ => return None,
+    })
+}
+
+fn render(process: &Process, grabs: &[ConfigGrab]) -> io::Result<()> {
+    let status = process.status().to_string();
+    let mut stdout = io::stdout();
+    write!(stdout, "\x1b[H\x1b[2J")?;
+    write!(stdout, "screenstub {}\r\n\r\n", env!("CARGO_PKG_VERSION"))?;
+    for line in status.lines() {
+        write!(stdout, "{}\r\n", line)?;
+    }
+    write!(stdout, "\r\n[g] show guest  [h] show host  [space] toggle  [x] grab X")?;
+    if grab_mode(grabs, ConfigGrabMode::Evdev).is_some() {
+        write!(stdout, "  [e] grab evdev")?;
+    }
+    write!(stdout, "  [u] unstick guest  [q] quit\r\n")?;
+    stdout.flush()
+}
+
+/// Draws a live status view in the terminal and maps keypresses to user events
+/// until the task is dropped.
+pub async fn run(process: Arc<Process>, mut user_sender: mpsc::Sender<Arc<ConfigEvent>>, grabs: Vec<ConfigGrab>) -> Result<(), Error> {
+    let _terminal = RawTerminal::new()?;
+
+    let (key_sender, keys) = mpsc::channel(0x10);
+    thread::spawn(move || {
+        let mut key_sender = key_sender;
+        for key in io::stdin().bytes() {
+            match key {
+                Ok(key) => if futures::executor::block_on(key_sender.send(key)).is_err() {
+                    break
+                },
+                Err(_) => break,
+            }
+        }
+    });
+
+    let mut keys = keys.fuse();
+    let mut refresh = interval(REFRESH_INTERVAL);
+    loop {
+        futures::select! {
+            key = keys.next() => match key {
+                Some(key) => if let Some(event) = key_event(key, &grabs) {
+                    user_sender.send(Arc::new(event)).await?;
+                },
+                None => (),
+            },
+            _ = Box::pin(refresh.tick()).fuse() => (),
+        }
+
+        render(&process, &grabs)?;
+    }
+}
@@ -11,7 +11,7 @@ use input_linux as input;
 use input_linux::{
     UInputHandle, InputId,
     InputEvent, EventKind,
-    AbsoluteAxis, RelativeAxis, Key,
+    AbsoluteAxis, RelativeAxis, Key, MiscKind,
     AbsoluteInfoSetup, AbsoluteInfo, Bitmask,
     EventCodec,
 };
@@ -21,6 +21,9 @@ use futures::{Sink, Stream, ready};
 use bytes::{BytesMut, BufMut};
 use log::{trace, debug};
 
+#[cfg(feature = "with-io-uring")]
+mod uring;
+
 pub type EvdevHandle<'a> = input_linux::EvdevHandle<FdRef<'a, AsyncFd<File>>>;
 
 #[derive(Debug, Default, Clone)]
@@ -86,15 +89,15 @@ impl Builder {
         self
     }
 
-    pub fn x_config_abs(&mut self) -> &mut Self {
+    pub fn x_config_abs(&mut self, maximum: i32, resolution: i32) -> &mut Self {
         self.x_config_button();
         self.bits_events.insert(EventKind::Absolute);
         for &axis in &[AbsoluteAxis::X, AbsoluteAxis::Y] {
             self.absolute_axis(AbsoluteInfoSetup {
                 axis,
                 info: AbsoluteInfo {
-                    maximum: 0x8000,
-                    resolution: 1,
+                    maximum,
+                    resolution,
                     .. Default::default()
                 },
             });
@@ -125,6 +128,14 @@ impl Builder {
         self
     }
 
+    /// Accepts `MSC_SCAN` events alongside keys
+    pub fn x_config_scancode(&mut self) -> &mut Self {
+        self.bits_events.insert(EventKind::Misc);
+        self.bits_misc.insert(MiscKind::Scancode);
+
+        self
+    }
+
     pub fn from_evdev<F: AsRawFd>(&mut self, evdev: &input_linux::EvdevHandle<F>) -> io::Result<&mut Self> {
         evdev.device_properties()?.iter().for_each(|bit| self.bits_props.insert(bit));
         evdev.event_bits()?.iter().for_each(|bit| self.bits_events.insert(bit));
@@ -236,10 +247,13 @@ impl UInput {
         //let uinput_write = FramedWrite::new(uinput_f, input::EventCodec::new());
 
         Ok(UInputSink {
+            #[cfg(feature = "with-io-uring")]
+            uring: uring::Uring::open(&self.fd, mem::size_of::<InputEvent>() * READ_BATCH),
             fd: Some(self.fd),
             buffer_write: BytesMut::with_capacity(mem::size_of::<InputEvent>() * 32),
             buffer_read: Default::default(),
             codec: EventCodec::new(),
+            high_water: DEFAULT_HIGH_WATER,
         })
     }
 }
@@ -270,23 +284,50 @@ impl Evdev {
         //let uinput_write = FramedWrite::new(uinput_f, input::EventCodec::new());
 
         Ok(UInputSink {
+            #[cfg(feature = "with-io-uring")]
+            uring: uring::Uring::open(&self.fd, mem::size_of::<InputEvent>() * READ_BATCH),
             fd: Some(self.fd),
             buffer_write: Default::default(),
-            buffer_read: BytesMut::with_capacity(mem::size_of::<InputEvent>() * 32),
+            buffer_read: BytesMut::with_capacity(mem::size_of::<InputEvent>() * READ_BATCH),
             codec: EventCodec::new(),
+            high_water: DEFAULT_HIGH_WATER,
         })
     }
 }
 
+const DEFAULT_HIGH_WATER: usize = 8;
+
+/// Events to read at once, so a burst from a high polling rate device costs
+/// one syscall rather than one per event
+const READ_BATCH: usize = 64;
+
 //#[derive(Debug)]
 pub struct UInputSink {
+    /// Dropped before `fd`, so nothing is still in flight when it closes
+    #[cfg(feature = "with-io-uring")]
+    uring: Option<uring::Uring>,
     fd: Option<AsyncFd<File>>,
     buffer_write: BytesMut,
     buffer_read: BytesMut,
     codec: EventCodec,
+    high_water: usize,
 }
 
 impl UInputSink {
+    /// Number of buffered events allowed before `poll_ready` waits for them to be written
+    pub fn set_high_water(&mut self, events: usize) {
+        self.high_water = events;
+    }
+
+    /// Number of events written but not yet accepted by the device
+    pub fn pending_events(&self) -> usize {
+        #[cfg(feature = "with-io-uring")]
+        let pending = self.uring.as_ref().map(|uring| uring.write_pending()).unwrap_or_default();
+        #[cfg(not(feature = "with-io-uring"))]
+        let pending = 0;
+        (self.buffer_write.len() + pending) / mem::size_of::<InputEvent>()
+    }
+
     pub fn evdev(&self) -> Option<EvdevHandle> {
         self.fd.as_ref().map(|fd|
             EvdevHandle::new(FdRef::from(fd))
@@ -310,12 +351,14 @@ impl UInputSink {
     }
 
     fn read_events(file: &mut File, buffer_read: &mut BytesMut) -> io::Result<usize> {
-        buffer_read.reserve(mem::size_of::<InputEvent>());
+        // reserving a whole batch also reclaims the space of events already decoded
+        buffer_read.reserve(mem::size_of::<InputEvent>() * READ_BATCH);
         unsafe {
             let n = {
                 let buffer = buffer_read.chunk_mut();
-                debug_assert_eq!(buffer.len() % mem::size_of::<InputEvent>(), 0);
-                let buffer = slice::from_raw_parts_mut(buffer.as_mut_ptr(), buffer.len());
+                // evdev only hands out whole events
+                let len = buffer.len() - buffer.len() % mem::size_of::<InputEvent>();
+                let buffer = slice::from_raw_parts_mut(buffer.as_mut_ptr(), len);
                 file.read(buffer)
             }?;
             buffer_read.advance_mut(n);
@@ -335,6 +378,11 @@ impl Sink<InputEvent> for UInputSink {
         if let Some(fd) = this.fd.as_mut() {
             this.codec.encode(item, &mut this.buffer_write)?;
 
+            #[cfg(feature = "with-io-uring")]
+            if let Some(uring) = this.uring.as_mut() {
+                return uring.start_write(&mut this.buffer_write)
+            }
+
             // attempt a single non-blocking write
             match io_poll(Self::write_events(fd.get_mut(), &mut this.buffer_write)) {
                 Poll::Ready(Err(e)) => return Err(e),
@@ -352,7 +400,7 @@ impl Sink<InputEvent> for UInputSink {
 
         let this = unsafe { self.as_mut().get_unchecked_mut() };
         if this.fd.is_some() {
-            if this.buffer_write.len() > mem::size_of::<InputEvent>() * 8 {
+            if this.buffer_write.len() > mem::size_of::<InputEvent>() * this.high_water {
                 self.poll_flush(cx)
             } else {
                 Poll::Ready(Ok(()))
@@ -369,6 +417,11 @@ impl Sink<InputEvent> for UInputSink {
 
         let this = unsafe { self.get_unchecked_mut() };
         if let Some(fd) = this.fd.as_mut() {
+            #[cfg(feature = "with-io-uring")]
+            if let Some(uring) = this.uring.as_mut() {
+                return uring.poll_flush(cx, &mut this.buffer_write)
+            }
+
             while !this.buffer_write.is_empty() {
                 let mut ready = ready!(fd.poll_write_ready_mut(cx))?;
                 let buffer = &mut this.buffer_write;
@@ -396,6 +449,10 @@ impl Sink<InputEvent> for UInputSink {
         let res = self.as_mut().poll_flush(cx);
 
         if res.is_ready() {
+            #[cfg(feature = "with-io-uring")]
+            {
+                self.uring = None;
+            }
             self.fd = None;
         }
         res
@@ -415,6 +472,15 @@ impl Stream for UInputSink {
                     return Poll::Ready(Some(Ok(frame)))
                 }
 
+                #[cfg(feature = "with-io-uring")]
+                if let Some(uring) = this.uring.as_mut() {
+                    if ready!(uring.poll_read(cx, &mut this.buffer_read))? == 0 {
+                        this.uring = None;
+                        this.fd = None;
+                    }
+                    continue
+                }
+
                 let mut ready = ready!(fd.poll_read_ready_mut(cx))?;
                 let buffer = &mut this.buffer_read;
                 let n = match ready.try_io(|fd| Self::read_events(fd.get_mut(), buffer)) {
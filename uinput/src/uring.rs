@@ -0,0 +1,199 @@
+//! io_uring reads and writes for `UInputSink`, with the `with-io-uring`
+//! feature. Reads are submitted a whole batch at a time, and writes go out
+//! without waiting for the device to be writable first.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::task::{Poll, Context};
+use io_uring::{IoUring, opcode, squeue, types};
+use tokio::io::unix::AsyncFd;
+use bytes::BytesMut;
+use futures::ready;
+use log::debug;
+
+const READ: u64 = 1;
+const WRITE: u64 = 2;
+/// Completions that nothing waits on
+const IGNORED: u64 = 0;
+
+const ENTRIES: u32 = 8;
+
+pub struct Uring {
+    ring: AsyncFd<IoUring>,
+    fd: RawFd,
+    read_buffer: Box<[u8]>,
+    reading: bool,
+    read_result: Option<i32>,
+    /// Owned by the kernel while `writing`
+    write_buffer: BytesMut,
+    writing: bool,
+    write_result: Option<i32>,
+}
+
+impl Uring {
+    /// `None` if the kernel doesn't support io_uring or it isn't permitted,
+    /// in which case the caller sticks to polling the fd
+    pub fn open<F: AsRawFd>(fd: &F, read_batch: usize) -> Option<Self> {
+        match IoUring::new(ENTRIES).and_then(AsyncFd::new) {
+            Ok(ring) => Some(Uring {
+                ring,
+                fd: fd.as_raw_fd(),
+                read_buffer: vec![0u8; read_batch].into_boxed_slice(),
+                reading: false,
+                read_result: None,
+                write_buffer: Default::default(),
+                writing: false,
+                write_result: None,
+            }),
+            Err(e) => {
+                debug!("io_uring unavailable, falling back to polling: {}", e);
+                None
+            },
+        }
+    }
+
+    /// Bytes handed to the kernel but not yet written
+    pub fn write_pending(&self) -> usize {
+        self.write_buffer.len()
+    }
+
+    fn push(&mut self, entries: &[squeue::Entry]) -> io::Result<()> {
+        unsafe {
+            self.ring.get_mut().submission().push_multiple(entries)
+        }.map_err(|_| io::Error::new(io::ErrorKind::Other, "io_uring submission queue is full"))?;
+        self.ring.get_ref().submit()
+            .map(drop)
+    }
+
+    fn result(res: i32) -> io::Result<usize> {
+        if res < 0 {
+            Err(io::Error::from_raw_os_error(-res))
+        } else {
+            Ok(res as usize)
+        }
+    }
+
+    /// Waits for at least one completion to come back
+    fn poll_complete(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.ring.poll_read_ready_mut(cx))?;
+            let mut completed = false;
+            for cqe in guard.get_inner_mut().completion() {
+                match cqe.user_data() {
+                    READ => {
+                        self.reading = false;
+                        self.read_result = Some(cqe.result());
+                    },
+                    WRITE => {
+                        self.writing = false;
+                        self.write_result = Some(cqe.result());
+                    },
+                    _ => continue,
+                }
+                completed = true;
+            }
+            if completed {
+                return Poll::Ready(Ok(()))
+            }
+            guard.clear_ready();
+        }
+    }
+
+    /// Reads up to a batch of events into `buffer`, returning 0 at EOF
+    pub fn poll_read(&mut self, cx: &mut Context, buffer: &mut BytesMut) -> Poll<io::Result<usize>> {
+        loop {
+            match self.read_result.take() {
+                Some(res) if res == -libc::EAGAIN => (),
+                Some(res) => {
+                    let n = Self::result(res)?;
+                    buffer.extend_from_slice(&self.read_buffer[..n]);
+                    return Poll::Ready(Ok(n))
+                },
+                None if self.reading => (),
+                None => {
+                    let fd = types::Fd(self.fd);
+                    // waiting for the fd first means a non-blocking fd won't come back empty
+                    let poll = opcode::PollAdd::new(fd, libc::POLLIN as _).build()
+                        .flags(squeue::Flags::IO_LINK)
+                        .user_data(IGNORED);
+                    let read = opcode::Read::new(fd, self.read_buffer.as_mut_ptr(), self.read_buffer.len() as _).build()
+                        .user_data(READ);
+                    self.push(&[poll, read])?;
+                    self.reading = true;
+                },
+            }
+            if self.reading {
+                ready!(self.poll_complete(cx))?;
+            }
+        }
+    }
+
+    fn submit_write(&mut self) -> io::Result<()> {
+        let write = opcode::Write::new(types::Fd(self.fd), self.write_buffer.as_ptr(), self.write_buffer.len() as _).build()
+            .user_data(WRITE);
+        self.push(&[write])?;
+        self.writing = true;
+        Ok(())
+    }
+
+    /// Takes whatever is in `buffer` unless a write is already underway
+    pub fn start_write(&mut self, buffer: &mut BytesMut) -> io::Result<()> {
+        if self.writing || !self.write_buffer.is_empty() || buffer.is_empty() {
+            return Ok(())
+        }
+        self.write_buffer = buffer.split();
+        self.submit_write()
+    }
+
+    /// Writes out `buffer` and anything already underway
+    pub fn poll_flush(&mut self, cx: &mut Context, buffer: &mut BytesMut) -> Poll<io::Result<()>> {
+        loop {
+            match self.write_result.take() {
+                Some(res) if res == -libc::EAGAIN => (),
+                Some(res) => match Self::result(res)? {
+                    0 => return Poll::Ready(Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write to uinput"))),
+                    n => {
+                        let _ = self.write_buffer.split_to(n);
+                    },
+                },
+                None => (),
+            }
+            if !self.writing {
+                if self.write_buffer.is_empty() {
+                    if buffer.is_empty() {
+                        return Poll::Ready(Ok(()))
+                    }
+                    self.write_buffer = buffer.split();
+                }
+                self.submit_write()?;
+            }
+            ready!(self.poll_complete(cx))?;
+        }
+    }
+}
+
+impl Drop for Uring {
+    fn drop(&mut self) {
+        // the kernel mustn't be left holding buffers that are about to be freed
+        let ring = self.ring.get_mut();
+        for &(busy, user_data) in &[(self.reading, READ), (self.writing, WRITE)] {
+            if busy {
+                let cancel = opcode::AsyncCancel::new(user_data).build()
+                    .user_data(IGNORED);
+                let _ = unsafe { ring.submission().push(&cancel) };
+            }
+        }
+        while self.reading || self.writing {
+            if ring.submit_and_wait(1).is_err() {
+                break
+            }
+            for cqe in ring.completion() {
+                match cqe.user_data() {
+                    READ => self.reading = false,
+                    WRITE => self.writing = false,
+                    _ => (),
+                }
+            }
+        }
+    }
+}
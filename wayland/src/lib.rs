@@ -0,0 +1,570 @@
+//! A fullscreen Wayland window standing in for the X one, for hosts running
+//! sway, Hyprland or another compositor without an X server.
+//!
+//! It speaks the same `XEvent` and `XRequest` as `screenstub-x`, so the rest
+//! of screenstub doesn't need to know which one it's talking to.
+
+use futures::{Sink, Stream, ready};
+use anyhow::{Error, format_err};
+use input_linux::{InputEvent, KeyEvent, KeyState, Key, AbsoluteEvent, AbsoluteAxis, RelativeEvent, RelativeAxis, SynchronizeEvent};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use wayland_client::{Display, EventQueue, GlobalManager, Main, DispatchData};
+use wayland_client::protocol::{wl_compositor, wl_keyboard, wl_pointer, wl_seat, wl_shm, wl_surface, wl_buffer};
+use wayland_protocols::xdg_shell::client::{xdg_wm_base, xdg_surface, xdg_toplevel};
+use wayland_protocols::unstable::pointer_constraints::v1::client::{zwp_pointer_constraints_v1, zwp_locked_pointer_v1, zwp_confined_pointer_v1};
+use wayland_protocols::unstable::relative_pointer::v1::client::{zwp_relative_pointer_manager_v1, zwp_relative_pointer_v1};
+use wayland_protocols::unstable::keyboard_shortcuts_inhibit::v1::client::{zwp_keyboard_shortcuts_inhibit_manager_v1, zwp_keyboard_shortcuts_inhibitor_v1};
+use wayland_protocols::unstable::idle_inhibit::v1::client::{zwp_idle_inhibit_manager_v1, zwp_idle_inhibitor_v1};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::task::{Poll, Context, Waker};
+use std::pin::Pin;
+use std::io;
+use std::num::NonZeroU32;
+use log::{trace, info, warn};
+use screenstub_fd::Fd;
+use screenstub_x::{XEvent, XRequest, ABSOLUTE_MAX, MotionAccumulator, scale_absolute};
+
+/// Scroll distance of one wheel step, which is what most compositors send per notch
+const SCROLL_STEP: f64 = 10.0;
+
+/// Everything the event handlers touch, handed to them through `DispatchData`
+#[derive(Default)]
+struct WaylandState {
+    width: u32,
+    height: u32,
+    running: bool,
+    /// Serial of a configure that still needs acking
+    configure: Option<u32>,
+    /// Outputs the surface is currently shown on
+    outputs: usize,
+    position: (f64, f64),
+    scroll: (f64, f64),
+    relative: (MotionAccumulator, MotionAccumulator),
+    absolute_max: i32,
+    pointer: Option<Main<wl_pointer::WlPointer>>,
+    keyboard: Option<Main<wl_keyboard::WlKeyboard>>,
+    event_queue: VecDeque<XEvent>,
+}
+
+impl WaylandState {
+    fn get<'a>(data: &'a mut DispatchData) -> &'a mut Self {
+        data.get::<WaylandState>().expect("WaylandState dispatch data")
+    }
+
+    fn input<E: Into<InputEvent>>(&mut self, e: E) {
+        self.event_queue.push_back(XEvent::Input(e.into()));
+    }
+
+    fn sync(&mut self) {
+        self.input(SynchronizeEvent::report(Default::default()));
+    }
+
+    fn key(&mut self, code: u32, pressed: bool) {
+        match Key::from_code(code as _) {
+            Ok(key) => {
+                self.input(KeyEvent::new(Default::default(), key, KeyState::pressed(pressed)));
+                self.sync();
+            },
+            Err(..) => warn!("unknown Wayland key or button {}", code),
+        }
+    }
+
+    fn absolute_events(&self, x: f64, y: f64) -> Vec<InputEvent> {
+        [(self.width, x, AbsoluteAxis::X), (self.height, y, AbsoluteAxis::Y)].iter()
+            .filter(|&&(dim, _, _)| dim != 0)
+            .map(|&(dim, value, axis)| AbsoluteEvent::new(Default::default(), axis, scale_absolute(value, dim, self.absolute_max)).into())
+            .collect()
+    }
+
+    fn motion(&mut self, x: f64, y: f64) {
+        self.position = (x, y);
+        for e in self.absolute_events(x, y) {
+            self.input(e);
+        }
+        self.sync();
+    }
+
+    fn relative_motion(&mut self, dx: f64, dy: f64) {
+        // keep the fractions around so slow movement still adds up
+        let (x, y) = (self.relative.0.add(dx), self.relative.1.add(dy));
+        for &(value, axis) in &[(x, RelativeAxis::X), (y, RelativeAxis::Y)] {
+            if value != 0 {
+                self.input(RelativeEvent::new(Default::default(), axis, value));
+            }
+        }
+        self.sync();
+    }
+
+    /// Vertical scrolling becomes wheel buttons and horizontal scrolling
+    /// becomes REL_HWHEEL, the same as X buttons 4-7
+    fn scroll(&mut self, axis: wl_pointer::Axis, value: f64) {
+        let vertical = axis == wl_pointer::Axis::VerticalScroll;
+        let scroll = if vertical { &mut self.scroll.1 } else { &mut self.scroll.0 };
+        *scroll += value;
+        let steps = (*scroll / SCROLL_STEP).trunc();
+        *scroll -= steps * SCROLL_STEP;
+        let steps = steps as i32;
+        if steps == 0 {
+            return
+        }
+
+        if vertical {
+            let button = if steps < 0 { Key::ButtonGearUp } else { Key::ButtonWheel };
+            for _ in 0..steps.abs() {
+                self.input(KeyEvent::new(Default::default(), button, KeyState::PRESSED));
+                self.sync();
+                self.input(KeyEvent::new(Default::default(), button, KeyState::RELEASED));
+                self.sync();
+            }
+        } else {
+            self.input(RelativeEvent::new(Default::default(), RelativeAxis::HorizontalWheel, steps));
+            self.sync();
+        }
+    }
+}
+
+/// Holds the pointer and keyboard shortcuts for as long as it lives
+struct WaylandGrab {
+    inhibitor: Option<Main<zwp_keyboard_shortcuts_inhibitor_v1::ZwpKeyboardShortcutsInhibitorV1>>,
+    locked: Option<Main<zwp_locked_pointer_v1::ZwpLockedPointerV1>>,
+    confined: Option<Main<zwp_confined_pointer_v1::ZwpConfinedPointerV1>>,
+    relative: Option<Main<zwp_relative_pointer_v1::ZwpRelativePointerV1>>,
+}
+
+impl Drop for WaylandGrab {
+    fn drop(&mut self) {
+        if let Some(inhibitor) = self.inhibitor.take() {
+            inhibitor.destroy();
+        }
+        if let Some(locked) = self.locked.take() {
+            locked.destroy();
+        }
+        if let Some(confined) = self.confined.take() {
+            confined.destroy();
+        }
+        if let Some(relative) = self.relative.take() {
+            relative.destroy();
+        }
+    }
+}
+
+pub struct WaylandContext {
+    display: Display,
+    event_queue: EventQueue,
+    fd: AsyncFd<Fd>,
+    state: WaylandState,
+
+    surface: Main<wl_surface::WlSurface>,
+    xdg_surface: Main<xdg_surface::XdgSurface>,
+    toplevel: Main<xdg_toplevel::XdgToplevel>,
+    shm: Main<wl_shm::WlShm>,
+    seat: Main<wl_seat::WlSeat>,
+    /// The buffer currently attached, and the memfd backing it
+    buffer: Option<(Main<wl_buffer::WlBuffer>, File, (u32, u32))>,
+
+    pointer_constraints: Option<Main<zwp_pointer_constraints_v1::ZwpPointerConstraintsV1>>,
+    relative_pointer_manager: Option<Main<zwp_relative_pointer_manager_v1::ZwpRelativePointerManagerV1>>,
+    shortcuts_inhibit_manager: Option<Main<zwp_keyboard_shortcuts_inhibit_manager_v1::ZwpKeyboardShortcutsInhibitManagerV1>>,
+    idle_inhibit_manager: Option<Main<zwp_idle_inhibit_manager_v1::ZwpIdleInhibitManagerV1>>,
+    grab: Option<WaylandGrab>,
+    idle_inhibitor: Option<Main<zwp_idle_inhibitor_v1::ZwpIdleInhibitorV1>>,
+
+    next_request: Option<XRequest>,
+    stop_waker: Option<Waker>,
+}
+
+// the event queue and handlers are only ever touched from whichever task owns the context
+unsafe impl Send for WaylandContext { }
+
+impl WaylandContext {
+    /// Connects to `$WAYLAND_DISPLAY` and maps a fullscreen window
+    pub fn connect(title: &str, app_id: &str) -> Result<Self, Error> {
+        let display = Display::connect_to_env()?;
+        let mut event_queue = display.create_event_queue();
+        let attached = (*display).clone().attach(event_queue.token());
+        let globals = GlobalManager::new(&attached);
+        event_queue.sync_roundtrip(&mut (), |_, _, _| ())?;
+
+        let compositor = globals.instantiate_range::<wl_compositor::WlCompositor>(1, 4)?;
+        let shm = globals.instantiate_exact::<wl_shm::WlShm>(1)?;
+        let wm_base = globals.instantiate_range::<xdg_wm_base::XdgWmBase>(1, 2)?;
+        let seat = globals.instantiate_range::<wl_seat::WlSeat>(1, 5)?;
+        let pointer_constraints = globals.instantiate_exact::<zwp_pointer_constraints_v1::ZwpPointerConstraintsV1>(1).ok();
+        let relative_pointer_manager = globals.instantiate_exact::<zwp_relative_pointer_manager_v1::ZwpRelativePointerManagerV1>(1).ok();
+        let shortcuts_inhibit_manager = globals.instantiate_exact::<zwp_keyboard_shortcuts_inhibit_manager_v1::ZwpKeyboardShortcutsInhibitManagerV1>(1).ok();
+        let idle_inhibit_manager = globals.instantiate_exact::<zwp_idle_inhibit_manager_v1::ZwpIdleInhibitManagerV1>(1).ok();
+        if idle_inhibit_manager.is_none() {
+            info!("Wayland compositor can't inhibit idle, the host may blank while the guest is shown");
+        }
+        if pointer_constraints.is_none() || relative_pointer_manager.is_none() {
+            warn!("Wayland compositor can't lock the pointer, grabs will only take the keyboard");
+        }
+
+        wm_base.quick_assign(|wm_base, event, _| match event {
+            xdg_wm_base::Event::Ping { serial } => wm_base.pong(serial),
+            _ => (),
+        });
+
+        seat.quick_assign(|seat, event, mut data| match event {
+            wl_seat::Event::Capabilities { capabilities } => {
+                let state = WaylandState::get(&mut data);
+                if capabilities.contains(wl_seat::Capability::Pointer) && state.pointer.is_none() {
+                    let pointer = seat.get_pointer();
+                    pointer.quick_assign(Self::handle_pointer);
+                    state.pointer = Some(pointer);
+                }
+                if capabilities.contains(wl_seat::Capability::Keyboard) && state.keyboard.is_none() {
+                    let keyboard = seat.get_keyboard();
+                    keyboard.quick_assign(Self::handle_keyboard);
+                    state.keyboard = Some(keyboard);
+                }
+            },
+            _ => (),
+        });
+
+        let surface = compositor.create_surface();
+        surface.quick_assign(|_, event, mut data| {
+            let state = WaylandState::get(&mut data);
+            match event {
+                wl_surface::Event::Enter { .. } => {
+                    state.outputs += 1;
+                    if state.outputs == 1 {
+                        state.event_queue.push_back(XEvent::Visible(true));
+                    }
+                },
+                wl_surface::Event::Leave { .. } => {
+                    state.outputs = state.outputs.saturating_sub(1);
+                    if state.outputs == 0 {
+                        state.event_queue.push_back(XEvent::Visible(false));
+                    }
+                },
+                _ => (),
+            }
+        });
+
+        let xdg_surface = wm_base.get_xdg_surface(&surface);
+        xdg_surface.quick_assign(|_, event, mut data| match event {
+            xdg_surface::Event::Configure { serial } =>
+                WaylandState::get(&mut data).configure = Some(serial),
+            _ => (),
+        });
+
+        let toplevel = xdg_surface.get_toplevel();
+        toplevel.quick_assign(|_, event, mut data| {
+            let state = WaylandState::get(&mut data);
+            match event {
+                xdg_toplevel::Event::Configure { width, height, .. } => if width > 0 && height > 0 {
+                    state.width = width as u32;
+                    state.height = height as u32;
+                },
+                xdg_toplevel::Event::Close =>
+                    state.event_queue.push_back(XEvent::Close),
+                _ => (),
+            }
+        });
+        toplevel.set_title(title.into());
+        toplevel.set_app_id(app_id.into());
+        toplevel.set_fullscreen(None);
+        surface.commit();
+
+        let fd = AsyncFd::with_interest(display.get_connection_fd().into(), Interest::READABLE)?;
+
+        let mut context = WaylandContext {
+            state: WaylandState {
+                running: true,
+                absolute_max: ABSOLUTE_MAX,
+                ..Default::default()
+            },
+            display,
+            event_queue,
+            fd,
+            surface,
+            xdg_surface,
+            toplevel,
+            shm,
+            seat,
+            buffer: None,
+            pointer_constraints,
+            relative_pointer_manager,
+            shortcuts_inhibit_manager,
+            idle_inhibit_manager,
+            idle_inhibitor: None,
+            grab: None,
+            next_request: None,
+            stop_waker: None,
+        };
+
+        // wait for the first configure so there's a size to draw at
+        while context.state.configure.is_none() {
+            context.event_queue.dispatch(&mut context.state, |_, _, _| ())?;
+        }
+        context.configure()?;
+
+        Ok(context)
+    }
+
+    fn handle_pointer(pointer: Main<wl_pointer::WlPointer>, event: wl_pointer::Event, mut data: DispatchData) {
+        let state = WaylandState::get(&mut data);
+        match event {
+            wl_pointer::Event::Enter { serial, surface_x, surface_y, .. } => {
+                // no cursor over the window
+                pointer.set_cursor(serial, None, 0, 0);
+                state.motion(surface_x, surface_y);
+            },
+            wl_pointer::Event::Motion { surface_x, surface_y, .. } =>
+                state.motion(surface_x, surface_y),
+            wl_pointer::Event::Button { button, state: button_state, .. } =>
+                state.key(button, button_state == wl_pointer::ButtonState::Pressed),
+            wl_pointer::Event::Axis { axis, value, .. } =>
+                state.scroll(axis, value),
+            _ => (),
+        }
+    }
+
+    fn handle_keyboard(_: Main<wl_keyboard::WlKeyboard>, event: wl_keyboard::Event, mut data: DispatchData) {
+        let state = WaylandState::get(&mut data);
+        match event {
+            // keys are already evdev codes, so the keymap isn't needed
+            wl_keyboard::Event::Keymap { fd, .. } => drop(unsafe { File::from_raw_fd(fd) }),
+            wl_keyboard::Event::Enter { .. } =>
+                state.event_queue.push_back(XEvent::Focus(true)),
+            wl_keyboard::Event::Leave { .. } =>
+                state.event_queue.push_back(XEvent::Focus(false)),
+            wl_keyboard::Event::Key { key, state: key_state, .. } =>
+                state.key(key, key_state == wl_keyboard::KeyState::Pressed),
+            _ => (),
+        }
+    }
+
+    /// Acks a pending configure, with a black buffer to match the new size
+    fn configure(&mut self) -> Result<(), Error> {
+        let serial = match self.state.configure.take() {
+            Some(serial) => serial,
+            None => return Ok(()),
+        };
+        self.xdg_surface.ack_configure(serial);
+
+        let size = (self.state.width.max(1), self.state.height.max(1));
+        if self.buffer.as_ref().map(|&(_, _, s)| s) != Some(size) {
+            let (width, height) = size;
+            let stride = width * 4;
+            let len = stride * height;
+            let file = unsafe {
+                let fd = libc::memfd_create(b"screenstub\0".as_ptr() as *const _, libc::MFD_CLOEXEC);
+                if fd < 0 {
+                    return Err(io::Error::last_os_error().into())
+                }
+                File::from_raw_fd(fd)
+            };
+            // zeroes are black in XRGB
+            file.set_len(len as u64)?;
+            let pool = self.shm.create_pool(file.as_raw_fd(), len as i32);
+            let buffer = pool.create_buffer(0, width as i32, height as i32, stride as i32, wl_shm::Format::Xrgb8888);
+            pool.destroy();
+
+            self.surface.attach(Some(&buffer), 0, 0);
+            self.surface.damage(0, 0, i32::MAX, i32::MAX);
+            if let Some((buffer, ..)) = self.buffer.replace((buffer, file, size)) {
+                buffer.destroy();
+            }
+        }
+        self.surface.commit();
+        self.flush()
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        match self.display.flush() {
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Sets the range that pointer motion is scaled to
+    pub fn set_absolute_max(&mut self, maximum: i32) {
+        self.state.absolute_max = maximum;
+    }
+
+    /// Sets how much relative motion makes up one count sent on
+    pub fn set_relative_resolution(&mut self, resolution: NonZeroU32) {
+        self.state.relative.0.set_resolution(resolution);
+        self.state.relative.1.set_resolution(resolution);
+    }
+
+    pub fn stop(&mut self) {
+        trace!("WaylandContext::stop()");
+
+        self.state.running = false;
+        if let Some(waker) = self.stop_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn grab(&mut self, keyboard: bool, confine: bool, motion: bool) -> Result<(), Error> {
+        self.grab = None;
+        let mut grab = WaylandGrab {
+            inhibitor: None,
+            locked: None,
+            confined: None,
+            relative: None,
+        };
+
+        if keyboard {
+            match self.shortcuts_inhibit_manager {
+                Some(ref manager) => grab.inhibitor = Some(manager.inhibit_shortcuts(&self.surface, &self.seat)),
+                None => warn!("Wayland compositor doesn't support inhibiting shortcuts"),
+            }
+        }
+
+        if !confine && !motion {
+            self.grab = Some(grab);
+            return Ok(())
+        }
+
+        let pointer = self.state.pointer.clone()
+            .ok_or_else(|| format_err!("Wayland seat has no pointer to grab"))?;
+        match (&self.pointer_constraints, &self.relative_pointer_manager) {
+            (Some(constraints), Some(relative)) if motion => {
+                grab.locked = Some(constraints.lock_pointer(&self.surface, &pointer, None, zwp_pointer_constraints_v1::Lifetime::Persistent));
+                let relative = relative.get_relative_pointer(&pointer);
+                relative.quick_assign(|_, event, mut data| match event {
+                    zwp_relative_pointer_v1::Event::RelativeMotion { dx_unaccel, dy_unaccel, .. } =>
+                        WaylandState::get(&mut data).relative_motion(dx_unaccel, dy_unaccel),
+                    _ => (),
+                });
+                grab.relative = Some(relative);
+            },
+            (Some(constraints), _) if confine || motion =>
+                grab.confined = Some(constraints.confine_pointer(&self.surface, &pointer, None, zwp_pointer_constraints_v1::Lifetime::Persistent)),
+            _ => (),
+        }
+
+        self.grab = Some(grab);
+        Ok(())
+    }
+
+    pub fn process_request(&mut self, request: &XRequest) -> Result<(), Error> {
+        trace!("processing Wayland request {:?}", request);
+
+        match *request {
+            XRequest::Quit => {
+                self.stop();
+            },
+            // the compositor only sends us keys while we have focus,
+            // so there's nothing held on the host side to release
+            XRequest::UnstickHost => (),
+            XRequest::Grab { xcore, confine, motion, keyboard, pointer, .. } => {
+                self.grab(xcore && keyboard, pointer && confine, pointer && motion)?;
+            },
+            XRequest::Ungrab => {
+                self.grab = None;
+                self.state.relative.0.reset();
+                self.state.relative.1.reset();
+            },
+            // keys arrive as evdev codes, there's no keymap to refresh
+            XRequest::RefreshKeymaps => (),
+            XRequest::SyncCursor(ref reply) => {
+                let (x, y) = self.state.position;
+                let mut events = self.state.absolute_events(x, y);
+                events.push(SynchronizeEvent::report(Default::default()).into());
+                let _ = reply.unbounded_send(events);
+            },
+            XRequest::SetTitle(ref title) => {
+                self.toplevel.set_title(title.clone());
+            },
+            // the inhibitor lasts until it's destroyed, so repeats are no-ops
+            XRequest::InhibitIdle(true) => if self.idle_inhibitor.is_none() {
+                if let Some(ref manager) = self.idle_inhibit_manager {
+                    self.idle_inhibitor = Some(manager.create_inhibitor(&self.surface));
+                }
+            },
+            XRequest::InhibitIdle(false) => if let Some(inhibitor) = self.idle_inhibitor.take() {
+                inhibitor.destroy();
+            },
+            // there's no standard protocol for powering outputs down
+            XRequest::DisplayPower(_) => {
+                warn!("display power can't be changed from a Wayland window");
+            },
+        }
+        self.flush()
+    }
+}
+
+impl Stream for WaylandContext {
+    type Item = Result<XEvent, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if !this.state.running {
+                return Poll::Ready(None)
+            }
+
+            if let Some(event) = this.state.event_queue.pop_front() {
+                return Poll::Ready(Some(Ok(event)))
+            }
+
+            if this.event_queue.dispatch_pending(&mut this.state, |_, _, _| ())? > 0 {
+                this.configure()?;
+                continue
+            }
+            this.flush()?;
+
+            let guard = match this.event_queue.prepare_read() {
+                Some(guard) => guard,
+                // events were queued in the meantime
+                None => continue,
+            };
+            match this.fd.poll_read_ready(cx) {
+                Poll::Pending => {
+                    this.stop_waker = Some(cx.waker().clone());
+                    return Poll::Pending
+                },
+                Poll::Ready(ready) => {
+                    let mut ready = ready?;
+                    match guard.read_events() {
+                        Ok(()) => (),
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => ready.clear_ready(),
+                        Err(e) => return Poll::Ready(Some(Err(e.into()))),
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl Sink<XRequest> for WaylandContext {
+    type Error = Error;
+
+    fn start_send(self: Pin<&mut Self>, item: XRequest) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+
+        this.next_request = Some(item);
+
+        Ok(())
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+
+        if let Some(req) = this.next_request.take() {
+            this.process_request(&req)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_ready(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.state.running {
+            ready!(self.as_mut().poll_flush(cx))?;
+            self.stop();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
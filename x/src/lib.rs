@@ -1,20 +1,90 @@
 pub extern crate xcb;
 
 use futures::{Sink, Stream, ready};
+use futures::channel::mpsc;
 use anyhow::{Error, format_err};
-use input_linux::{InputEvent, EventTime, KeyEvent, KeyState, Key, AbsoluteEvent, AbsoluteAxis, SynchronizeEvent};
+use input_linux::{InputEvent, EventTime, KeyEvent, KeyState, Key, AbsoluteEvent, AbsoluteAxis, RelativeEvent, RelativeAxis, SynchronizeEvent};
 use tokio::io::unix::AsyncFd;
 use tokio::io::Interest;
 use std::task::{Poll, Context, Waker};
 use std::pin::Pin;
+use std::fmt;
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
 use log::{trace, warn, info};
 use screenstub_fd::Fd;
 
+/// Default range of the absolute axes reported for pointer motion, which is what QEMU uses
+pub const ABSOLUTE_MAX: i32 = 0x7fff;
+
+/// Maps a window coordinate onto `0..=maximum`, so the window edges land on the ends of the range
+pub fn scale_absolute(position: f64, size: u32, maximum: i32) -> i32 {
+    let size = size.saturating_sub(1).max(1) as f64;
+    let value = position.max(0.0) * maximum as f64 / size;
+    (value.round() as i32).min(maximum)
+}
+
+/// Fractional bits kept for relative motion, enough for Wayland's 24.8 fixed point deltas
+const MOTION_FRACTION_BITS: u32 = 8;
+
+/// Turns fractional relative motion into whole counts.
+///
+/// Motion is kept in fixed point so the counts handed out plus whatever is
+/// left over always add up to exactly what came in, in either direction.
+#[derive(Debug, Clone, Copy)]
+pub struct MotionAccumulator {
+    remainder: i64,
+    unit: i64,
+}
+
+impl MotionAccumulator {
+    /// `resolution` is how much motion makes up one count
+    pub fn new(resolution: NonZeroU32) -> Self {
+        MotionAccumulator {
+            remainder: 0,
+            unit: (resolution.get() as i64) << MOTION_FRACTION_BITS,
+        }
+    }
+
+    pub fn set_resolution(&mut self, resolution: NonZeroU32) {
+        *self = Self::new(resolution);
+    }
+
+    fn fixed(delta: f64) -> i64 {
+        (delta * (1i64 << MOTION_FRACTION_BITS) as f64).round() as i64
+    }
+
+    /// Adds `delta` and returns the whole counts it completed
+    pub fn add(&mut self, delta: f64) -> i32 {
+        self.remainder += Self::fixed(delta);
+        // division truncates towards zero, so the remainder keeps its sign
+        // and a change of direction has to cancel it out first
+        let counts = self.remainder / self.unit;
+        self.remainder -= counts * self.unit;
+        counts as i32
+    }
+
+    /// Drops any partial count, such as when a grab ends
+    pub fn reset(&mut self) {
+        self.remainder = 0;
+    }
+}
+
+impl Default for MotionAccumulator {
+    fn default() -> Self {
+        Self::new(NonZeroU32::new(1).unwrap())
+    }
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 struct XState {
     pub width: u16,
     pub height: u16,
     pub running: bool,
+    /// What the window last reported, only tracked in Xwayland compatibility mode
+    pub unobscured: bool,
+    pub focused: bool,
+    pub visible: Option<bool>,
 }
 
 #[derive(Debug)]
@@ -58,29 +128,87 @@ pub enum XRequest {
         xcore: bool,
         confine: bool,
         motion: bool,
+        keyboard: bool,
+        pointer: bool,
         devices: Vec<()>,
     },
     Ungrab,
+    RefreshKeymaps,
+    /// Replies with absolute motion events for the current pointer position,
+    /// or none if it's elsewhere
+    SyncCursor(mpsc::UnboundedSender<Vec<InputEvent>>),
+    /// Replaces the window title
+    SetTitle(String),
+    /// Holds off the host screensaver. X only resets its idle timer,
+    /// so this needs repeating for as long as it should last.
+    InhibitIdle(bool),
+    /// Blanks (false) or wakes the host display through DPMS
+    DisplayPower(bool),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XKeymapInfo {
+    pub min_keycode: xcb::Keycode,
+    pub max_keycode: xcb::Keycode,
+    pub keysyms_per_keycode: u8,
+    pub keycodes_per_modifier: u8,
+    pub refresh_count: usize,
+}
+
+impl fmt::Display for XKeymapInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "keycodes {}..={}, {} keysyms per keycode, {} keycodes per modifier (refreshed {} times)",
+            self.min_keycode, self.max_keycode,
+            self.keysyms_per_keycode, self.keycodes_per_modifier,
+            self.refresh_count,
+        )
+    }
+}
+
+/// Where a RandR output sits on the root window
+#[derive(Debug, Clone)]
+pub struct XOutput {
+    pub name: String,
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+    /// The connected monitor's EDID, if the driver exposes it
+    pub edid: Option<Vec<u8>>,
 }
 
 pub struct XContext {
     conn: xcb::Connection,
     fd: AsyncFd<Fd>,
     window: u32,
+    root: xcb::Window,
+    output: Option<XOutput>,
 
     keys: xcb::GetKeyboardMappingReply,
     mods: xcb::GetModifierMappingReply,
+    /// Shared so the status can show it from outside the window task
+    keymap_info: Arc<Mutex<XKeymapInfo>>,
+    keymap_dirty: bool,
     state: XState,
+    absolute_max: i32,
     next_event: Option<xcb::GenericEvent>,
     next_request: Option<XRequest>,
     event_queue: Vec<XEvent>,
     stop_waker: Option<Waker>,
+    /// Host pointer position (root window, x, y) from before the grab started
+    grab_position: Option<(xcb::Window, i16, i16)>,
+    /// The display was blanked by a `DisplayPower` request
+    dpms_off: bool,
+    xwayland: bool,
+    xwayland_compat: bool,
 
     atom_wm_state: xcb::Atom,
     atom_wm_protocols: xcb::Atom,
     atom_wm_delete_window: xcb::Atom,
     atom_net_wm_state: xcb::Atom,
     atom_net_wm_state_fullscreen: xcb::Atom,
+    atom_net_wm_name: xcb::Atom,
+    atom_utf8_string: xcb::Atom,
 }
 
 unsafe impl Send for XContext { }
@@ -93,7 +221,7 @@ impl XContext {
             AsyncFd::with_interest(fd.into(), Interest::READABLE)
         }?;
         let window = conn.generate_id();
-        let (keys, mods) = {
+        let (keys, mods, root) = {
             let setup = conn.get_setup();
             let screen = setup.roots().nth(screen_num as usize).unwrap();
 
@@ -122,8 +250,13 @@ impl XContext {
             (
                 xcb::get_keyboard_mapping(&conn, setup.min_keycode(), setup.max_keycode() - setup.min_keycode()).get_reply()?,
                 xcb::get_modifier_mapping(&conn).get_reply()?,
+                screen.root(),
             )
         };
+        // newer Xwayland advertises itself, older ones only through the environment
+        let xwayland = xcb::query_extension(&conn, "XWAYLAND").get_reply()
+            .map(|ext| ext.present())
+            .unwrap_or(false) || std::env::var_os("WAYLAND_DISPLAY").is_some();
 
         Ok(Self {
             atom_wm_state: xcb::intern_atom(&conn, true, "WM_STATE").get_reply()?.atom(),
@@ -131,25 +264,90 @@ impl XContext {
             atom_wm_delete_window: xcb::intern_atom(&conn, true, "WM_DELETE_WINDOW").get_reply()?.atom(),
             atom_net_wm_state: xcb::intern_atom(&conn, true, "_NET_WM_STATE").get_reply()?.atom(),
             atom_net_wm_state_fullscreen: xcb::intern_atom(&conn, true, "_NET_WM_STATE_FULLSCREEN").get_reply()?.atom(),
+            atom_net_wm_name: xcb::intern_atom(&conn, false, "_NET_WM_NAME").get_reply()?.atom(),
+            atom_utf8_string: xcb::intern_atom(&conn, false, "UTF8_STRING").get_reply()?.atom(),
 
+            keymap_info: Arc::new(Mutex::new(Self::keymap_info_(&conn, &keys, &mods, 0))),
+            keymap_dirty: false,
             keys,
             mods,
             state: Default::default(),
+            absolute_max: ABSOLUTE_MAX,
             next_event: None,
 
             event_queue: Default::default(),
             next_request: None,
             stop_waker: None,
+            grab_position: None,
+            dpms_off: false,
+            xwayland,
+            xwayland_compat: false,
 
             conn,
             fd,
             window,
+            root,
+            output: None,
         })
     }
 
-    pub fn set_wm_name(&self, name: &str) -> Result<(), Error> {
-        // TODO: set _NET_WM_NAME instead? or both?
+    /// Looks up a RandR output by name, like `DP-2` from `xrandr --query`
+    pub fn find_output(&self, name: &str) -> Result<Option<XOutput>, Error> {
+        let resources = xcb::randr::get_screen_resources_current(&self.conn, self.root).get_reply()?;
+        let timestamp = resources.config_timestamp();
+        for &output in resources.outputs() {
+            let info = xcb::randr::get_output_info(&self.conn, output, timestamp).get_reply()?;
+            if info.name() != name.as_bytes() {
+                continue
+            }
+            if info.crtc() == xcb::NONE {
+                return Err(format_err!("RandR output {} is disabled", name))
+            }
+
+            let crtc = xcb::randr::get_crtc_info(&self.conn, info.crtc(), timestamp).get_reply()?;
+            let edid_atom = xcb::intern_atom(&self.conn, true, "EDID").get_reply()?.atom();
+            let edid = if edid_atom == xcb::NONE {
+                None
+            } else {
+                // 128 longs covers the base block and three extensions
+                xcb::randr::get_output_property(&self.conn, output, edid_atom, xcb::ATOM_ANY, 0, 128, false, false).get_reply().ok()
+                    .map(|property| property.data().to_vec())
+                    .filter(|edid| !edid.is_empty())
+            };
+            return Ok(Some(XOutput {
+                name: name.into(),
+                x: crtc.x(),
+                y: crtc.y(),
+                width: crtc.width(),
+                height: crtc.height(),
+                edid,
+            }))
+        }
+
+        Ok(None)
+    }
+
+    /// Moves the window onto `output` so that it goes fullscreen there, which
+    /// only works reliably before the window is mapped
+    pub fn set_output(&mut self, output: XOutput) -> Result<(), Error> {
+        xcb::configure_window(&self.conn, self.window, &[
+            (xcb::CONFIG_WINDOW_X as u16, output.x as u32),
+            (xcb::CONFIG_WINDOW_Y as u16, output.y as u32),
+            (xcb::CONFIG_WINDOW_WIDTH as u16, output.width as u32),
+            (xcb::CONFIG_WINDOW_HEIGHT as u16, output.height as u32),
+        ]).request_check()?;
+        self.output = Some(output);
+
+        Ok(())
+    }
+
+    /// The RandR output the window was placed on
+    pub fn output(&self) -> Option<&XOutput> {
+        self.output.as_ref()
+    }
 
+    pub fn set_wm_name(&self, name: &str) -> Result<(), Error> {
+        // WM_NAME is nominally latin-1, so modern panels prefer the UTF-8 _NET_WM_NAME
         xcb::change_property(&self.conn,
             xcb::PROP_MODE_REPLACE as _,
             self.window,
@@ -157,6 +355,13 @@ impl XContext {
             xcb::ATOM_STRING, 8,
             name.as_bytes()
         ).request_check()?;
+        xcb::change_property(&self.conn,
+            xcb::PROP_MODE_REPLACE as _,
+            self.window,
+            self.atom_net_wm_name,
+            self.atom_utf8_string, 8,
+            name.as_bytes()
+        ).request_check()?;
 
         Ok(())
     }
@@ -278,6 +483,39 @@ impl XContext {
         }
     }
 
+    fn keymap_info_(conn: &xcb::Connection, keys: &xcb::GetKeyboardMappingReply, mods: &xcb::GetModifierMappingReply, refresh_count: usize) -> XKeymapInfo {
+        let setup = conn.get_setup();
+        XKeymapInfo {
+            min_keycode: setup.min_keycode(),
+            max_keycode: setup.max_keycode(),
+            keysyms_per_keycode: keys.keysyms_per_keycode(),
+            keycodes_per_modifier: mods.keycodes_per_modifier(),
+            refresh_count,
+        }
+    }
+
+    pub fn keymap_info(&self) -> XKeymapInfo {
+        *self.keymap_info.lock().unwrap()
+    }
+
+    /// Follows keymap refreshes after the context is moved into its task
+    pub fn keymap_info_handle(&self) -> Arc<Mutex<XKeymapInfo>> {
+        self.keymap_info.clone()
+    }
+
+    pub fn refresh_keymaps(&mut self) -> Result<(), xcb::GenericError> {
+        let setup = self.conn.get_setup();
+        self.keys = xcb::get_keyboard_mapping(&self.conn, setup.min_keycode(), setup.max_keycode() - setup.min_keycode()).get_reply()?;
+        self.mods = xcb::get_modifier_mapping(&self.conn).get_reply()?;
+        self.keymap_dirty = false;
+        let info = Self::keymap_info_(&self.conn, &self.keys, &self.mods, self.keymap_info().refresh_count + 1);
+        *self.keymap_info.lock().unwrap() = info;
+
+        info!("X keyboard mapping refreshed: {}", info);
+
+        Ok(())
+    }
+
     pub fn stop(&mut self) {
         log::trace!("XContext::stop()");
 
@@ -287,8 +525,50 @@ impl XContext {
         }
     }
 
-    pub fn xmain(name: &str, instance: &str, class: &str) -> Result<Self, Error> {
+    /// Sets the range that pointer motion is scaled to
+    pub fn set_absolute_max(&mut self, maximum: i32) {
+        self.absolute_max = maximum;
+    }
+
+    /// Whether the X server looks like Xwayland, running under a Wayland compositor
+    pub fn is_xwayland(&self) -> bool {
+        self.xwayland
+    }
+
+    /// Works around Xwayland only knowing about its own windows: visibility
+    /// also requires focus, and the pointer isn't warped back after a grab
+    pub fn set_xwayland_compat(&mut self, compat: bool) {
+        self.xwayland_compat = compat;
+    }
+
+    fn push_visible(&mut self, visible: bool) {
+        if !self.xwayland_compat {
+            self.event_queue.push(XEvent::Visible(visible));
+            return
+        }
+
+        // an unobscured window may still be covered by a native Wayland one
+        self.state.unobscured = visible;
+        self.push_compat_visible();
+    }
+
+    fn push_compat_visible(&mut self) {
+        let visible = self.state.unobscured && self.state.focused;
+        if self.state.visible != Some(visible) {
+            self.state.visible = Some(visible);
+            self.event_queue.push(XEvent::Visible(visible));
+        }
+    }
+
+    /// Opens the fullscreen window, on the RandR output named `output` if given
+    pub fn xmain(name: &str, instance: &str, class: &str, output: Option<&str>) -> Result<Self, Error> {
         let mut xcontext = Self::connect()?;
+        if let Some(name) = output {
+            match xcontext.find_output(name)? {
+                Some(output) => xcontext.set_output(output)?,
+                None => return Err(format_err!("RandR output {} not found", name)),
+            }
+        }
         xcontext.state.running = true;
         xcontext.set_wm_name(name)?;
         xcontext.set_wm_class(instance, class)?;
@@ -330,8 +610,12 @@ impl XContext {
                     }
                 }
             },
-            XRequest::Grab { xcore, motion, confine, ref devices } => {
-                if xcore {
+            XRequest::Grab { xcore, motion, confine, keyboard, pointer, ref devices } => {
+                if xcore && pointer && self.grab_position.is_none() {
+                    let pointer = xcb::query_pointer(&self.conn, self.window).get_reply()?;
+                    self.grab_position = Some((pointer.root(), pointer.root_x(), pointer.root_y()));
+                }
+                if xcore && keyboard {
                     let status = xcb::grab_keyboard(&self.conn,
                         false, // owner_events, I don't quite understand how this works
                         self.window,
@@ -340,6 +624,8 @@ impl XContext {
                         xcb::GRAB_MODE_ASYNC as _,
                     ).get_reply()?.status();
                     self.handle_grab_status(status)?;
+                }
+                if xcore && pointer {
                     let status = xcb::grab_pointer(&self.conn,
                         false, // owner_events, I don't quite understand how this works
                         self.window,
@@ -356,6 +642,46 @@ impl XContext {
             XRequest::Ungrab => {
                 xcb::ungrab_keyboard(&self.conn, xcb::CURRENT_TIME).request_check()?;
                 xcb::ungrab_pointer(&self.conn, xcb::CURRENT_TIME).request_check()?;
+                let grab_position = self.grab_position.take();
+                // Xwayland can't move the host cursor outside of its own windows
+                if let Some((root, x, y)) = grab_position.filter(|_| !self.xwayland_compat) {
+                    xcb::warp_pointer(&self.conn,
+                        xcb::NONE, root,
+                        0, 0, 0, 0,
+                        x, y,
+                    ).request_check()?;
+                }
+            },
+            XRequest::RefreshKeymaps => {
+                self.refresh_keymaps()?;
+            },
+            XRequest::SyncCursor(ref reply) => {
+                let pointer = xcb::query_pointer(&self.conn, self.window).get_reply()?;
+                let mut events = Vec::new();
+                if pointer.same_screen() {
+                    let time = Default::default();
+                    events = self.absolute_events(time, pointer.win_x(), pointer.win_y());
+                    events.push(SynchronizeEvent::report(time).into());
+                }
+                let _ = reply.unbounded_send(events);
+            },
+            XRequest::SetTitle(ref title) => {
+                self.set_wm_name(title)?;
+            },
+            // resetting the screensaver would also wake a display we blanked
+            XRequest::InhibitIdle(true) => if !self.dpms_off {
+                xcb::force_screen_saver(&self.conn, xcb::SCREEN_SAVER_RESET as _).request_check()?;
+            },
+            XRequest::InhibitIdle(false) => (),
+            XRequest::DisplayPower(false) => {
+                // force_level does nothing unless DPMS is enabled
+                xcb::dpms::enable(&self.conn).request_check()?;
+                xcb::dpms::force_level(&self.conn, xcb::dpms::DPMS_MODE_OFF as _).request_check()?;
+                self.dpms_off = true;
+            },
+            XRequest::DisplayPower(true) => {
+                xcb::dpms::force_level(&self.conn, xcb::dpms::DPMS_MODE_ON as _).request_check()?;
+                self.dpms_off = false;
             },
         })
     }
@@ -368,28 +694,26 @@ impl XContext {
             xcb::VISIBILITY_NOTIFY => {
                 let event = unsafe { xcb::cast_event::<xcb::VisibilityNotifyEvent>(event) };
 
-                let dpms_blank = {
+                // our own blanking leaves the window where it was
+                let dpms_blank = !self.dpms_off && {
                     let power_level = xcb::dpms::info(&self.conn).get_reply()
                         .map(|info| info.power_level() as u32);
 
                     power_level.unwrap_or(xcb::dpms::DPMS_MODE_ON) != xcb::dpms::DPMS_MODE_ON
                 };
-                self.event_queue.push(if dpms_blank {
-                    XEvent::Visible(false)
+                let visible = if dpms_blank {
+                    false
                 } else {
                     match event.state() as _ {
-                        xcb::VISIBILITY_FULLY_OBSCURED => {
-                            XEvent::Visible(false)
-                        },
-                        xcb::VISIBILITY_UNOBSCURED => {
-                            XEvent::Visible(true)
-                        },
+                        xcb::VISIBILITY_FULLY_OBSCURED => false,
+                        xcb::VISIBILITY_UNOBSCURED => true,
                         state => {
                             warn!("unknown visibility {}", state);
                             return Ok(())
                         },
                     }
-                });
+                };
+                self.push_visible(visible);
             },
             xcb::CLIENT_MESSAGE => {
                 let event = unsafe { xcb::cast_event::<xcb::ClientMessageEvent>(event) };
@@ -421,7 +745,7 @@ impl XContext {
                         let window_state_iconic = 3;
                         match x.get(0) {
                             Some(&state) if state == window_state_withdrawn || state == window_state_iconic => {
-                                self.event_queue.push(XEvent::Visible(false));
+                                self.push_visible(false);
                             },
                             Some(&state) => {
                                 info!("unknown WM_STATE {}", state);
@@ -441,6 +765,10 @@ impl XContext {
             },
             xcb::FOCUS_OUT | xcb::FOCUS_IN => {
                 self.event_queue.push(XEvent::Focus(kind == xcb::FOCUS_IN));
+                if self.xwayland_compat {
+                    self.state.focused = kind == xcb::FOCUS_IN;
+                    self.push_compat_visible();
+                }
             },
             xcb::KEY_PRESS | xcb::KEY_RELEASE => {
                 let event = unsafe { xcb::cast_event::<xcb::KeyPressEvent>(event) };
@@ -504,9 +832,9 @@ impl XContext {
                 self.convert_x_events(&event)
             },
             xcb::MAPPING_NOTIFY => {
-                let setup = self.conn.get_setup();
-                self.keys = xcb::get_keyboard_mapping(&self.conn, setup.min_keycode(), setup.max_keycode() - setup.min_keycode()).get_reply()?;
-                self.mods = xcb::get_modifier_mapping(&self.conn).get_reply()?;
+                // layout switches tend to arrive in bursts, so only refresh
+                // once the pending events have been drained
+                self.keymap_dirty = true;
             },
             xcb::CONFIGURE_NOTIFY => {
                 let event = unsafe { xcb::cast_event::<xcb::ConfigureNotifyEvent>(event) };
@@ -526,7 +854,7 @@ impl XContext {
             xcb::BUTTON_INDEX_3 => Some(Key::ButtonRight),
             xcb::BUTTON_INDEX_4 => Some(Key::ButtonGearUp),
             xcb::BUTTON_INDEX_5 => Some(Key::ButtonWheel), // Key::ButtonGearDown
-            // TODO: 6/7 is horizontal scroll left/right, but I think this requires sending relative wheel events?
+            // 6/7 are horizontal scrolling, see x_hwheel
             8 => Some(Key::ButtonSide),
             9 => Some(Key::ButtonExtra),
             // qemu input-linux.c doesn't support fwd/back, but virtio probably does
@@ -536,6 +864,15 @@ impl XContext {
         }
     }
 
+    /// Horizontal scroll buttons, as REL_HWHEEL steps
+    fn x_hwheel(button: xcb::Button) -> Option<i32> {
+        match button {
+            6 => Some(-1),
+            7 => Some(1),
+            _ => None,
+        }
+    }
+
     fn x_keycode(key: xcb::Keycode) -> Option<Key> {
         match Key::from_code(key as _) {
             Ok(code) => Some(code),
@@ -559,25 +896,38 @@ impl XContext {
         EventTime::new(seconds as i64, usecs)
     }*/
 
+    fn absolute_events(&self, time: EventTime, x: i16, y: i16) -> Vec<InputEvent> {
+        let maximum = self.absolute_max;
+        [
+            (self.state.width, x, AbsoluteAxis::X),
+            (self.state.height, y, AbsoluteAxis::Y),
+        ].iter()
+            .filter(|&&(dim, _, _)| dim != 0)
+            .map(|&(dim, new, axis)| (dim, (new.max(0) as u16).min(dim), axis))
+            .map(|(dim, new, axis)| AbsoluteEvent::new(
+                time,
+                axis,
+                scale_absolute(new as f64, dim as u32, maximum),
+            ).into())
+            .collect()
+    }
+
     fn convert_x_events(&mut self, e: &XInputEvent) {
         //let time = Self::event_time(e.time);
         let time = Default::default();
         match e.data {
             XInputEventData::Mouse { x, y } => {
-                self.event_queue.extend([
-                    (self.state.width, x, AbsoluteAxis::X),
-                    (self.state.height, y, AbsoluteAxis::Y),
-                ].iter()
-                    .filter(|&&(dim, new, _)| dim != 0)
-                    .map(|&(dim, new, axis)| (dim, (new.max(0) as u16).min(dim), axis))
-                    .map(|(dim, new, axis)| AbsoluteEvent::new(
-                        time,
-                        axis,
-                        0x7fff.min(new as i32 * 0x8000 / dim as i32),
-                    )).map(|e| XEvent::Input(e.into())));
+                let events = self.absolute_events(time, x, y);
+                self.event_queue.extend(events.into_iter().map(XEvent::Input));
             },
             XInputEventData::Button { pressed, button, state: _ } => {
-                if let Some(button) = Self::x_button(button) {
+                if let Some(value) = Self::x_hwheel(button) {
+                    if !pressed {
+                        // each scroll step is a press and release, only one of them should count
+                        return
+                    }
+                    self.event_queue.push(XEvent::Input(RelativeEvent::new(time, RelativeAxis::HorizontalWheel, value).into()));
+                } else if let Some(button) = Self::x_button(button) {
                     self.event_queue.push(XEvent::Input(Self::key_event(time, button, pressed).into()));
                 } else {
                     warn!("unknown X button {}", button);
@@ -625,6 +975,8 @@ impl Stream for XContext {
             match this.poll()? {
                 Some(event) =>
                     tokio::task::block_in_place(|| this.process_event(&event))?,
+                None if this.keymap_dirty =>
+                    tokio::task::block_in_place(|| this.refresh_keymaps())?,
                 None => {
                     match this.fd.poll_read_ready(cx) {
                         Poll::Pending => {
@@ -686,3 +1038,68 @@ impl Sink<XRequest> for XContext {
         Poll::Ready(Ok(()))
     }
 }
+
+#[test]
+fn x_buttons() {
+    let expected = [
+        (1, Key::ButtonLeft), (2, Key::ButtonMiddle), (3, Key::ButtonRight),
+        (4, Key::ButtonGearUp), (5, Key::ButtonWheel),
+        (8, Key::ButtonSide), (9, Key::ButtonExtra), (10, Key::ButtonForward), (11, Key::ButtonBack),
+    ];
+    for button in 0..=255u8 {
+        let key = expected.iter().find(|&&(b, _)| b == button).map(|&(_, key)| key);
+        assert_eq!(XContext::x_button(button), key, "button {}", button);
+        if key.is_some() {
+            assert_eq!(XContext::x_hwheel(button), None);
+        }
+    }
+    assert_eq!(XContext::x_hwheel(6), Some(-1));
+    assert_eq!(XContext::x_hwheel(7), Some(1));
+}
+
+#[test]
+fn absolute_edges() {
+    for &maximum in &[ABSOLUTE_MAX, 0xffff, 1920] {
+        assert_eq!(scale_absolute(0.0, 1920, maximum), 0);
+        assert_eq!(scale_absolute(1919.0, 1920, maximum), maximum);
+        assert_eq!(scale_absolute(4000.0, 1920, maximum), maximum);
+    }
+    // a range matching the window maps every pixel onto its own value
+    for x in 0..1920 {
+        assert_eq!(scale_absolute(x as f64, 1920, 1919), x);
+    }
+}
+
+#[test]
+fn motion_accumulation() {
+    // xorshift, so the sequences are arbitrary but reproducible
+    let mut seed = 0x2545f4914f6cdd1du64;
+    let mut random = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+    for &resolution in &[1, 2, 3, 10] {
+        let resolution = NonZeroU32::new(resolution).unwrap();
+        for _ in 0..100 {
+            let mut motion = MotionAccumulator::new(resolution);
+            let (mut input, mut output) = (0i64, 0i64);
+            for _ in 0..1000 {
+                // touchpad-sized deltas in 1/256ths, in both directions
+                let delta = (random() % 2048) as i64 - 1024;
+                input += delta;
+                output += motion.add(delta as f64 / 256.0) as i64;
+                assert!(motion.remainder.abs() < motion.unit);
+                assert_eq!(output * motion.unit + motion.remainder, input);
+            }
+        }
+    }
+
+    let mut motion = MotionAccumulator::new(NonZeroU32::new(1).unwrap());
+    assert_eq!(motion.add(0.5), 0);
+    assert_eq!(motion.add(0.5), 1);
+    assert_eq!(motion.add(-0.75), 0);
+    assert_eq!(motion.add(0.75), 0);
+    assert_eq!(motion.add(-1.5), -1);
+}